@@ -1,19 +1,135 @@
 use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Module {
+    /// From the `xform version` prolog; 2.0 when there is none.
+    pub version: LangVersion,
     pub functions: HashMap<String, FunctionDef>,
     pub rules: HashMap<String, Vec<RuleDef>>,
     pub vars: HashMap<String, Expr>,
     pub namespaces: HashMap<String, String>,
     pub imports: Vec<(String, Option<String>)>,
     pub expr: Option<Expr>,
+    /// From `declare boundary-space preserve|strip;`; `None` defers to
+    /// `EvalOptions::preserve_boundary_space`.
+    pub preserve_boundary_space: Option<bool>,
+    /// From `declare option strict-variables "true"|"false";`; `None` defers
+    /// to `EvalOptions::strict_variables`.
+    pub strict_variables: Option<bool>,
+    /// Top-level declarations in source order, with their character spans.
+    pub decls: Vec<DeclSpan>,
+}
+
+impl Module {
+    /// Render the module as XForm source that parses back to an equivalent
+    /// module. Declarations keep their source order when known.
+    pub fn to_source(&self) -> String {
+        let keep_space = self.preserve_boundary_space != Some(false);
+        crate::printer::Printer::new(None, keep_space).module(self)
+    }
+
+    /// Names of the functions the module defines, sorted.
+    pub fn function_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.functions.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Names of the rule sets the module has rules for, sorted.
+    pub fn rule_sets(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.rules.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Each function with its parameters, sorted by name.
+    pub fn signatures(&self) -> Vec<(&str, &[Param])> {
+        let mut sigs: Vec<(&str, &[Param])> =
+            self.functions.iter().map(|(name, fd)| (name.as_str(), &fd.params[..])).collect();
+        sigs.sort_unstable_by_key(|(name, _)| *name);
+        sigs
+    }
+}
+
+/// Language version declared by `xform version "..."`. Semantics that
+/// changed after 2.0 follow the version, so existing transforms keep their
+/// meaning and new ones opt in:
+///
+/// | Behavior     | 2.0                             | 2.1                                 |
+/// |--------------|---------------------------------|-------------------------------------|
+/// | `=` and `!=` | string values of whole operands | some pair of items equal, numbers   |
+/// |              |                                 | by value (`"1.0" = 1`)              |
+/// | `path[n]`    | a number predicate is true      | a number predicate keeps the item   |
+/// |              | unless 0 or NaN                 | at that position, as in XPath       |
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LangVersion {
+    #[default]
+    V2_0,
+    V2_1,
+}
+
+impl LangVersion {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "2.0" => Some(LangVersion::V2_0),
+            "2.1" => Some(LangVersion::V2_1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LangVersion::V2_0 => "2.0",
+            LangVersion::V2_1 => "2.1",
+        }
+    }
+}
+
+/// Render an expression as XForm source, parenthesized only where needed.
+pub fn to_source(expr: &Expr) -> String {
+    crate::printer::Printer::new(None, true).expr(expr, 0, 0)
+}
+
+/// Character offsets of a construct in the transform source, `end`
+/// exclusive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeclSpan {
+    pub decl: DeclRef,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Points a source-order entry at the declaration stored in `Module`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeclRef {
+    Prolog,
+    BoundarySpace,
+    StrictVariables,
+    Ns(String),
+    /// Index into `Module::imports`.
+    Import(usize),
+    Var(String),
+    Def(String),
+    /// Rule set name and index within it.
+    Rule(String, usize),
+    Expr,
 }
 
 #[derive(Debug, Clone)]
 pub struct FunctionDef {
     pub params: Vec<Param>,
     pub body: Expr,
+    /// Declared `def memo name(...)`: results are cached per run by argument
+    /// values, and by the context item when the body reads it, so the body
+    /// must depend on nothing else. Calls given an inline function are not
+    /// cached.
+    pub memo: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -27,11 +143,15 @@ pub struct Param {
 pub struct RuleDef {
     pub pattern: Pattern,
     pub body: Expr,
+    /// From `rule` to the closing `;`.
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub enum Expr {
-    Literal(LiteralValue),
+    /// A literal and where it was written, which the formatter uses to keep
+    /// its spelling; `Span::default()` for one no source text wrote.
+    Literal(LiteralValue, Span),
     VarRef(String),
     IfExpr(Box<IfExpr>),
     LetExpr(Box<LetExpr>),
@@ -41,8 +161,17 @@ pub enum Expr {
     UnaryOp { op: String, expr: Box<Expr> },
     BinaryOp { op: String, left: Box<Expr>, right: Box<Expr> },
     PathExpr(Box<PathExpr>),
+    /// Parenthesized comma list `(a, b, ...)`; `()` is the empty sequence.
+    Sequence(Vec<Expr>),
+    /// Array literal `[a, b, ...]`, one member per expression.
+    Array(Vec<Expr>),
+    /// Inline function `fn(x, y) := body`, closing over the variables in
+    /// scope where it is evaluated.
+    Lambda(Box<FunctionDef>),
     Constructor(Box<Constructor>),
     TextConstructor(Box<Expr>),
+    /// `document{ content }`, building a document node.
+    DocumentConstructor(Box<Expr>),
     CharData(String),
     Interp(Box<Expr>),
 }
@@ -59,7 +188,8 @@ pub enum LiteralValue {
 pub struct IfExpr {
     pub cond: Expr,
     pub then_expr: Expr,
-    pub else_expr: Expr,
+    /// `None` when the `else` branch is omitted; evaluates to the empty sequence.
+    pub else_expr: Option<Expr>,
 }
 
 #[derive(Debug, Clone)]
@@ -69,18 +199,33 @@ pub struct LetExpr {
     pub body: Expr,
 }
 
+/// Loop variable of `seq -> body`, which binds no name a transform can use.
+pub const ARROW_VAR: &str = ".";
+
 #[derive(Debug, Clone)]
 pub struct ForExpr {
     pub name: String,
     pub seq: Expr,
     pub where_clause: Option<Expr>,
+    /// `order by` keys, most significant first; empty keeps sequence order.
+    pub order_by: Vec<OrderSpec>,
+    /// `separated-by` value placed as text between the iterations' results.
+    pub separator: Option<Expr>,
     pub body: Expr,
 }
 
+#[derive(Debug, Clone)]
+pub struct OrderSpec {
+    pub key: Expr,
+    pub descending: bool,
+    /// `collation "name"`, for keys compared as strings.
+    pub collation: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct MatchExpr {
     pub target: Expr,
-    pub cases: Vec<(Pattern, Expr)>,
+    pub cases: Vec<(Vec<Pattern>, Expr)>,
     pub default: Option<Expr>,
 }
 
@@ -94,12 +239,19 @@ pub struct FuncCall {
 pub struct PathExpr {
     pub start: PathStart,
     pub steps: Vec<PathStep>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct Constructor {
     pub name: String,
     pub attrs: Vec<(String, Expr)>,
+    /// Attribute maps spliced in with `{expr}`, each with the number of
+    /// `attrs` written before it. Entries override attributes written
+    /// earlier and are overridden by those written later.
+    pub splats: Vec<(usize, Expr)>,
+    /// `xmlns` / `xmlns:prefix` declarations as (prefix, uri expression).
+    pub namespaces: Vec<(String, Expr)>,
     pub contents: Vec<Expr>,
 }
 
@@ -107,6 +259,9 @@ pub struct Constructor {
 pub struct PathStart {
     pub kind: PathStartKind,
     pub name: Option<String>,
+    /// The primary expression an `Expr` start takes its items from, as in
+    /// `children(.)/name`.
+    pub base: Option<Box<Expr>>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -116,6 +271,7 @@ pub enum PathStartKind {
     Desc,
     DescRoot,
     Var,
+    Expr,
 }
 
 #[derive(Debug, Clone)]
@@ -154,6 +310,9 @@ impl StepTest {
 pub enum StepTestKind {
     Name,
     Wildcard,
+    /// Element names matching the glob in `StepTest::name`, where `*` stands
+    /// for any run of characters, as in `h*` or `*-item`.
+    NamePattern,
     Text,
     Node,
     Comment,
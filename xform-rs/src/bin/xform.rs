@@ -1,12 +1,87 @@
+use std::cell::RefCell;
 use std::process;
+use std::rc::Rc;
 
-use xform::{eval_module, serialize_items, Parser};
-use xform::xmlmodel::parse_xml;
+use xform::{
+    eval_module_with, format_source, scope_warnings, serialize_items_with, validate, AccessPolicy,
+    EvalOptions, Parser, Profile, SerializeOptions, TraceSink, WriteSink,
+};
+use xform::xinclude::parse_xml_xinclude;
+use xform::ast::LangVersion;
+use xform::eval::Item;
+use xform::xmlmodel::{parse_xml, XmlNode};
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    if std::env::args().nth(1).as_deref() == Some("fmt") {
+        format_transform(std::env::args().skip(2).collect());
+    }
+    let mut opts = SerializeOptions::default();
+    let mut check = false;
+    let mut fs_access: Option<bool> = None;
+    let mut shallow_text = false;
+    let mut strip_space = false;
+    let mut strip_output_space = false;
+    let mut strict_variables = false;
+    let mut profile = false;
+    let mut trace = false;
+    let mut lang_version = None;
+    let mut seed = None;
+    let mut html_input = false;
+    let mut xinclude = false;
+    let mut args: Vec<String> = Vec::new();
+    let mut argv = std::env::args();
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--json-maps" => opts.maps_as_json = true,
+            "--xml-decl" => opts.xml_declaration = true,
+            "--sort-attributes" => opts.sort_attributes = true,
+            "--check" => check = true,
+            "--allow-fs" => fs_access = Some(true),
+            "--no-fs" => fs_access = Some(false),
+            "--shallow-text" => shallow_text = true,
+            "--strip-space" => strip_space = true,
+            "--strip-output-space" => strip_output_space = true,
+            "--strict-variables" => strict_variables = true,
+            "--profile" => profile = true,
+            "--trace" => trace = true,
+            "--xinclude" => xinclude = true,
+            "--input-format" => match argv.next().as_deref() {
+                Some("xml") => html_input = false,
+                Some("html") => html_input = true,
+                other => {
+                    eprintln!("Unsupported input format {:?}", other.unwrap_or_default());
+                    process::exit(1);
+                }
+            },
+            "--seed" => {
+                let n = argv.next().unwrap_or_default();
+                seed = Some(n.parse::<u64>().unwrap_or_else(|_| {
+                    eprintln!("Invalid seed {:?}", n);
+                    process::exit(1);
+                }));
+            }
+            "--lang-version" => {
+                let ver = argv.next().unwrap_or_default();
+                lang_version = Some(LangVersion::parse(&ver).unwrap_or_else(|| {
+                    eprintln!("Unsupported language version {:?}", ver);
+                    process::exit(1);
+                }));
+            }
+            _ => args.push(arg),
+        }
+    }
+    if check && args.len() == 2 {
+        check_transform(&args[1]);
+    }
     if args.len() < 3 {
-        eprintln!("Usage: xform <input.xml> <transform.xform>");
+        eprintln!(
+            "Usage: xform [--json-maps] [--xml-decl] [--sort-attributes] [--allow-fs | --no-fs] \
+             [--shallow-text] [--strip-space] [--strip-output-space] [--strict-variables] \
+             [--profile] [--trace] [--lang-version <2.0|2.1>] [--input-format <xml|html>] \
+             [--xinclude] [--seed <n>] <input.xml> <transform.xform>"
+        );
+        eprintln!("       xform --check <transform.xform>");
+        eprintln!("       xform fmt [--stdout] <transform.xform>");
         process::exit(1);
     }
     let xml_path = &args[1];
@@ -21,14 +96,6 @@ fn main() {
         process::exit(1);
     });
 
-    let doc = match parse_xml(&xml_text) {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("XML parse error: {}", e);
-            process::exit(1);
-        }
-    };
-
     let module = match Parser::new(&xform_text).parse_module() {
         Ok(m) => m,
         Err(e) => {
@@ -37,11 +104,129 @@ fn main() {
         }
     };
 
-    match eval_module(&module, doc) {
-        Ok(items) => print!("{}", serialize_items(&items)),
+    // Files next to the input are readable by default
+    let input_dir = std::path::Path::new(xml_path)
+        .parent()
+        .map(|p| if p.as_os_str().is_empty() { std::path::Path::new(".") } else { p })
+        .unwrap_or(std::path::Path::new("."))
+        .to_path_buf();
+    let profile = profile.then(|| Rc::new(RefCell::new(Profile::default())));
+    let trace = trace.then(|| Rc::new(WriteSink::new(std::io::stderr())) as Rc<dyn TraceSink>);
+    let eval_opts = EvalOptions {
+        access: match fs_access {
+            Some(true) => AccessPolicy::AllowAll,
+            Some(false) => AccessPolicy::Deny,
+            None => AccessPolicy::AllowUnder(input_dir.clone()),
+        },
+        base_dir: Some(input_dir),
+        document_uri: Some(xml_path.clone()),
+        shallow_text,
+        strip_whitespace: strip_space,
+        strict_variables,
+        lang_version,
+        profile: profile.clone(),
+        trace,
+        xinclude,
+        seed,
+        ..Default::default()
+    };
+
+    let doc = match (html_input, xinclude) {
+        (true, _) => parse_html_input(&xml_text),
+        (false, true) => {
+            let path = std::path::Path::new(xml_path);
+            parse_xml_xinclude(&xml_text, path, &eval_opts).unwrap_or_else(|e| {
+                eprintln!("XInclude error: {}", e);
+                process::exit(1);
+            })
+        }
+        (false, false) => parse_xml(&xml_text).unwrap_or_else(|e| {
+            eprintln!("XML parse error: {}", e);
+            process::exit(1);
+        }),
+    };
+
+    let result = eval_module_with(&module, doc, &eval_opts).and_then(|mut items| {
+        if strip_output_space {
+            for item in items.iter_mut() {
+                if let Item::Node(n) = item {
+                    *n = xform::strip_space(n, &[]);
+                }
+            }
+        }
+        serialize_items_with(&items, &opts)
+    });
+    // Reported even when evaluation fails, as for a run that timed out
+    if let Some(profile) = profile {
+        eprint!("{}", profile.borrow().report(&xform_text));
+    }
+    match result {
+        Ok(out) => print!("{}", out),
         Err(e) => {
             eprintln!("Evaluation error: {}", e);
+            // Items without an XML form fail the run rather than being
+            // dropped from the output, so this is where they are reported
+            if e.starts_with("XFDY0014") {
+                eprintln!("hint: --json-maps writes maps, arrays and functions as JSON");
+            }
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "html")]
+fn parse_html_input(text: &str) -> Rc<XmlNode> {
+    xform::parse_html(text)
+}
+
+#[cfg(not(feature = "html"))]
+fn parse_html_input(_text: &str) -> Rc<XmlNode> {
+    eprintln!("HTML input needs xform built with the `html` feature");
+    process::exit(1);
+}
+
+/// Report every syntax error in a transform, or its scoping warnings when
+/// there are none, and exit. Warnings alone do not fail the check.
+fn check_transform(path: &str) -> ! {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Error reading {}: {}", path, e);
+        process::exit(1);
+    });
+    let errors = validate(&text);
+    for e in &errors {
+        eprintln!("{}: {}", path, e);
+    }
+    if let (true, Ok(module)) = (errors.is_empty(), Parser::new(&text).parse_module()) {
+        for w in scope_warnings(&module) {
+            eprintln!("{}: {}", path, w);
+        }
+    }
+    process::exit(if errors.is_empty() { 0 } else { 1 });
+}
+
+/// Reformat a transform in place, or print it with `--stdout`, and exit.
+fn format_transform(args: Vec<String>) -> ! {
+    let to_stdout = args.iter().any(|a| a == "--stdout");
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--stdout").collect();
+    let [path] = paths[..] else {
+        eprintln!("Usage: xform fmt [--stdout] <transform.xform>");
+        process::exit(1);
+    };
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Error reading {}: {}", path, e);
+        process::exit(1);
+    });
+    let formatted = format_source(&text).unwrap_or_else(|e| {
+        eprintln!("XForm parse error: {}", e);
+        process::exit(1);
+    });
+    if to_stdout {
+        print!("{}", formatted);
+    } else if formatted != text {
+        if let Err(e) = std::fs::write(path, formatted) {
+            eprintln!("Error writing {}: {}", path, e);
             process::exit(1);
         }
     }
+    process::exit(0);
 }
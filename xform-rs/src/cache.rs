@@ -0,0 +1,75 @@
+//! Parsed standalone expressions kept by their source text, for embedders
+//! that evaluate the same small expressions many times. A hit hands out the
+//! shared AST without lexing the source again. The cache holds at most
+//! `capacity` expressions and drops the least recently used one to make
+//! room. Like the ASTs themselves it is not `Send`; keep one per thread.
+
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use crate::ast::Expr;
+use crate::parser::Parser;
+
+pub struct ExprCache {
+    capacity: usize,
+    /// Each expression with the tick of its last use.
+    entries: HashMap<String, (Rc<Expr>, u64)>,
+    /// Source of each entry by the tick of its last use, oldest first.
+    by_use: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl ExprCache {
+    pub fn new(capacity: usize) -> Self {
+        ExprCache { capacity, entries: HashMap::new(), by_use: BTreeMap::new(), tick: 0 }
+    }
+
+    /// The expression `src` parses to, from the cache when it was compiled
+    /// before. Errors are not cached.
+    pub fn compile(&mut self, src: &str) -> Result<Rc<Expr>, String> {
+        self.tick += 1;
+        if let Some((expr, used)) = self.entries.get_mut(src) {
+            let key = self.by_use.remove(used).unwrap_or_default();
+            *used = self.tick;
+            self.by_use.insert(self.tick, key);
+            return Ok(expr.clone());
+        }
+        let expr = Rc::new(Parser::new(src).parse_standalone_expr()?);
+        if self.capacity == 0 {
+            return Ok(expr);
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.by_use.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(src.to_string(), (expr.clone(), self.tick));
+        self.by_use.insert(self.tick, src.to_string());
+        Ok(expr)
+    }
+
+    /// Drop `src`, so the next `compile` parses it again. Whether it was
+    /// cached.
+    pub fn invalidate(&mut self, src: &str) -> bool {
+        match self.entries.remove(src) {
+            Some((_, used)) => {
+                self.by_use.remove(&used);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.by_use.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
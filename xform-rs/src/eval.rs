@@ -1,15 +1,30 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use crate::ast::*;
+use crate::json::{json_string, json_to_xml, xml_to_json};
+use crate::printer::Printer;
+use crate::profile::Profile;
+use crate::trace::{node_path, TraceSink};
+use crate::xinclude::parse_xml_xinclude;
 use crate::xmlmodel::{
-    deep_copy, iter_descendants, make_attr, make_element, make_text, serialize, XmlNode,
-    NodeKind,
+    ancestry, deep_copy, descendants, document_order, is_xml_char, link, make_attr,
+    make_document, make_element, make_text, ParentLink,
+    indent_tree, next_node_id, normalize_text_nodes, parse_xml_fragment, parse_xml_with,
+    rename_attrs, serialize, serialize_with, sort_attributes, space_attr, strip_space,
+    strip_whitespace,
+    InvalidChars, XmlNode, NodeKind,
 };
 
 pub type Seq = Vec<Item>;
 pub type SeqRef = Rc<Seq>;
 pub type XMap = HashMap<String, Seq>;
+/// Comparator placing nodes in order, see `EvalOptions::node_order`.
+pub type NodeOrder = fn(&Rc<XmlNode>, &Rc<XmlNode>) -> Ordering;
 
 #[derive(Clone, Debug)]
 pub enum Item {
@@ -19,7 +34,181 @@ pub enum Item {
     Bool(bool),
     Null,
     Map(Rc<XMap>),
-    FuncRef(String),
+    /// A named user function and its number of parameters.
+    FuncRef(String, usize),
+    Closure(Rc<Closure>),
+    /// Members kept apart, unlike the items of a sequence.
+    Array(Rc<Vec<Seq>>),
+}
+
+/// An inline function together with the variables in scope where it was
+/// evaluated.
+#[derive(Debug)]
+pub struct Closure {
+    pub params: Vec<Param>,
+    pub body: Expr,
+    pub env: HashMap<String, SeqRef>,
+}
+
+/// Evaluation switches fixed for the whole run.
+#[derive(Clone, Debug, Default)]
+pub struct EvalOptions {
+    /// Make `??` fall back on an empty string as well as an empty sequence.
+    pub coalesce_empty_string: bool,
+    /// Keep whitespace-only literal text in constructors, as in
+    /// `<p> {a} {b} </p>` where the three spaces would otherwise be dropped.
+    /// Text with any other character is always kept whole. A module's
+    /// `declare boundary-space` overrides this.
+    pub preserve_boundary_space: bool,
+    /// Make a reference to an unbound variable fail with `XFST0006` instead
+    /// of reading a child element of that name. A module's
+    /// `declare option strict-variables` overrides this.
+    pub strict_variables: bool,
+    /// Make `text(node)` without a second argument return only the node's
+    /// own text children, like XPath's `text()`, instead of its deep string
+    /// value. `stringValue(node)` is always deep.
+    pub shallow_text: bool,
+    /// Drop whitespace-only text between elements of the input document and
+    /// of documents read by `doc()`. Whitespace in mixed content and under
+    /// `xml:space="preserve"` is kept; see `xmlmodel::strip_whitespace`.
+    pub strip_whitespace: bool,
+    /// Make `document{...}` fail with `XFDY0010` when it would have more than
+    /// one element child.
+    pub strict_documents: bool,
+    /// Fail with `XFLM0001` after this many expression evaluations.
+    pub max_steps: Option<u64>,
+    /// Fail with `XFLM0002` once the run has taken longer than this. Checked
+    /// every `TIME_CHECK_INTERVAL` steps.
+    pub timeout: Option<Duration>,
+    /// Fail with `XFLM0003` after constructing this many nodes.
+    pub max_nodes: Option<u64>,
+    /// Fail with `XFLM0005` when an expression yields a longer sequence.
+    /// Loops, sequences, paths and rule applications check as they grow.
+    pub max_seq_len: Option<usize>,
+    /// Fail with `XFLM0007` when calls of user and inline functions and
+    /// rule applications nest deeper than this. Self tail calls do not nest.
+    pub max_depth: Option<usize>,
+    /// Whether something that recovers from errors may recover from a
+    /// limit error; see `LimitPolicy`.
+    pub limit_policy: LimitPolicy,
+    /// Resolve XInclude in documents read by `doc()`; see `xinclude`. The
+    /// input document is resolved by whoever parses it, with
+    /// `parse_xml_xinclude`.
+    pub xinclude: bool,
+    /// Which files `doc()`, the `unparsed-text` functions and XInclude may read.
+    pub access: AccessPolicy,
+    /// Directory relative URIs resolve against; the working directory when
+    /// unset.
+    pub base_dir: Option<PathBuf>,
+    /// Where the input document was read from, for `document-uri()` and as
+    /// the default base of `resolve-uri()`. The CLI sets its input path.
+    pub document_uri: Option<String>,
+    /// How `|` and `outermost` order node sets, in place of
+    /// `xmlmodel::document_order`. Nodes comparing equal are the same node.
+    pub node_order: Option<NodeOrder>,
+    /// Evaluate with this language version in place of the module's
+    /// `xform version`; see `LangVersion` for what it changes.
+    pub lang_version: Option<LangVersion>,
+    /// Seed `uuid()` and `randomNumber()` so that a run repeats their
+    /// values; they are seeded from the OS when unset.
+    pub seed: Option<u64>,
+    /// Collect per-rule and per-path counters here; see `profile::Profile`.
+    pub profile: Option<Rc<RefCell<Profile>>>,
+    /// Report rules fired, calls, constructed elements and the final error
+    /// here; see `trace::TraceSink`.
+    pub trace: Option<Rc<dyn TraceSink>>,
+}
+
+/// Filesystem access granted to a transform. Denied reads fail with
+/// `XFSE0001`.
+#[derive(Clone, Default)]
+pub enum AccessPolicy {
+    #[default]
+    Deny,
+    /// Only files inside this directory, after resolving `..` and symlinks.
+    /// Paths that do not resolve are denied.
+    AllowUnder(PathBuf),
+    AllowAll,
+    /// Decide per URI, as written in the transform.
+    Custom(Rc<dyn Fn(&str) -> bool>),
+}
+
+impl std::fmt::Debug for AccessPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessPolicy::Deny => write!(f, "Deny"),
+            AccessPolicy::AllowUnder(dir) => write!(f, "AllowUnder({:?})", dir),
+            AccessPolicy::AllowAll => write!(f, "AllowAll"),
+            AccessPolicy::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// What exceeding one of the `XFLM` limits of `EvalOptions` does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// The limit error is a dynamic error like any other: it ends the run
+    /// unless raised where errors are recovered from, as in a `sort` key
+    /// function, whose failure sorts the item by an empty key.
+    #[default]
+    Catchable,
+    /// The first limit error ends the run: every later step fails with it,
+    /// so no part of the transform can recover and carry on.
+    Fatal,
+}
+
+const TIME_CHECK_INTERVAL: u64 = 1024;
+
+/// Iterations `fixpoint` allows when not given a limit.
+const FIXPOINT_MAX_ITERATIONS: u64 = 10_000;
+
+/// Counters shared by all contexts of one run, for the limits in
+/// `EvalOptions`.
+#[derive(Debug)]
+pub struct EvalState {
+    steps: Cell<u64>,
+    nodes: Cell<u64>,
+    /// Function calls and rule bodies being evaluated, for `max_depth`.
+    depth: Cell<usize>,
+    started: Instant,
+    /// The limit error that ended the run under `LimitPolicy::Fatal`.
+    breached: RefCell<Option<String>>,
+    /// Named counters of `counterNext`/`counterValue`. Their values depend
+    /// on evaluation order, so a transform using them must be evaluated
+    /// sequentially.
+    counters: RefCell<HashMap<String, f64>>,
+    /// Results of `def memo` functions by `memo_key`. A memo function that
+    /// uses counters or other run state sees it only on its first call.
+    memo: RefCell<HashMap<String, Seq>>,
+    /// Whether the body of each `def memo` function called so far reads
+    /// the focus; see `reads_focus`.
+    memo_focus: RefCell<HashMap<String, bool>>,
+    /// Nodes considered so far by the steps of the innermost profiled path.
+    visited: Cell<u64>,
+    /// State of the stream behind `uuid()` and `randomNumber()`, seeded
+    /// from `EvalOptions::seed`. Like counters, what a call gets depends on
+    /// evaluation order.
+    rng: Cell<u64>,
+    /// Paths of the documents `doc()` read, by document node id.
+    document_uris: RefCell<HashMap<u64, String>>,
+}
+
+impl Default for EvalState {
+    fn default() -> Self {
+        EvalState {
+            steps: Cell::new(0),
+            nodes: Cell::new(0),
+            depth: Cell::new(0),
+            started: Instant::now(),
+            breached: RefCell::new(None),
+            counters: RefCell::new(HashMap::new()),
+            memo: RefCell::new(HashMap::new()),
+            memo_focus: RefCell::new(HashMap::new()),
+            visited: Cell::new(0),
+            rng: Cell::new(random_seed()),
+            document_uris: RefCell::new(HashMap::new()),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -31,6 +220,10 @@ pub struct Context {
     pub rules: HashMap<String, Vec<RuleDef>>,
     pub position: Option<f64>,
     pub last: Option<f64>,
+    /// Ruleset of the innermost `apply`, which `identity` applies again.
+    pub ruleset: Option<String>,
+    pub options: Rc<EvalOptions>,
+    pub state: Rc<EvalState>,
 }
 
 impl Context {
@@ -40,10 +233,108 @@ impl Context {
     fn with_vars(&self, vars: HashMap<String, SeqRef>) -> Context {
         Context { variables: vars, ..self.clone() }
     }
+
+    /// Language version the run follows.
+    fn version(&self) -> LangVersion {
+        self.options.lang_version.unwrap_or_default()
+    }
+
+    /// Count one evaluation step against the step and time budgets.
+    fn tick(&self) -> Result<(), String> {
+        if let Some(e) = &*self.state.breached.borrow() {
+            return Err(e.clone());
+        }
+        let steps = self.state.steps.get() + 1;
+        self.state.steps.set(steps);
+        if self.options.max_steps.is_some_and(|max| steps > max) {
+            return self.limit(format!("XFLM0001: step budget of {} exceeded", steps - 1));
+        }
+        if let Some(limit) = self.options.timeout {
+            if steps.is_multiple_of(TIME_CHECK_INTERVAL) && self.state.started.elapsed() > limit {
+                return self.limit(format!("XFLM0002: time limit of {:?} exceeded", limit));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail with limit error `e`, which under `LimitPolicy::Fatal` every
+    /// later step fails with too.
+    fn limit<T>(&self, e: String) -> Result<T, String> {
+        if self.options.limit_policy == LimitPolicy::Fatal {
+            self.state.breached.replace(Some(e.clone()));
+        }
+        Err(e)
+    }
+
+    /// Fail with `XFLM0005` if a sequence of `len` items is over the limit.
+    fn check_len(&self, len: usize) -> Result<(), String> {
+        match self.options.max_seq_len {
+            Some(max) if len > max => {
+                self.limit(format!("XFLM0005: sequence of {} items exceeds limit of {}", len, max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Run `f` one call deeper, failing with `XFLM0007` past the depth limit.
+    fn nested<T>(&self, f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+        let depth = self.state.depth.get() + 1;
+        if let Some(max) = self.options.max_depth.filter(|max| depth > *max) {
+            return self.limit(format!("XFLM0007: call depth of {} exceeded", max));
+        }
+        self.state.depth.set(depth);
+        let result = f();
+        self.state.depth.set(depth - 1);
+        result
+    }
+
+    /// Append `items` to `out`, failing before `out` grows past the
+    /// sequence limit rather than once it is complete.
+    fn extend_seq(&self, out: &mut Seq, items: Seq) -> Result<(), String> {
+        self.check_len(out.len() + items.len())?;
+        out.extend(items);
+        Ok(())
+    }
+
+    /// Sort `nodes` by the configured node order, dropping duplicates.
+    fn sort_nodes(&self, nodes: &mut Vec<Rc<XmlNode>>) {
+        let order = self.options.node_order.unwrap_or(document_order);
+        nodes.sort_by(order);
+        nodes.dedup_by(|a, b| order(a, b) == Ordering::Equal);
+    }
+
+    /// Count `n` constructed nodes against the node limit.
+    fn add_nodes(&self, n: u64) -> Result<(), String> {
+        let nodes = self.state.nodes.get() + n;
+        self.state.nodes.set(nodes);
+        match self.options.max_nodes {
+            Some(max) if nodes > max => {
+                self.limit(format!("XFLM0003: limit of {} constructed nodes exceeded", max))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 pub fn eval_module(module: &Module, doc: Rc<XmlNode>) -> Result<Seq, String> {
+    eval_module_with(module, doc, &EvalOptions::default())
+}
+
+pub fn eval_module_with(
+    module: &Module,
+    doc: Rc<XmlNode>,
+    opts: &EvalOptions,
+) -> Result<Seq, String> {
+    let result = run_module(module, doc, opts);
+    if let (Err(e), Some(trace)) = (&result, &opts.trace) {
+        trace.on_error(e);
+    }
+    result
+}
+
+fn run_module(module: &Module, doc: Rc<XmlNode>, opts: &EvalOptions) -> Result<Seq, String> {
     let mut variables: HashMap<String, SeqRef> = HashMap::new();
+    let doc = if opts.strip_whitespace { strip_whitespace(&doc) } else { doc };
     let root = doc.clone();
     let mut ctx = Context {
         context_item: Some(Item::Node(doc)),
@@ -53,7 +344,20 @@ pub fn eval_module(module: &Module, doc: Rc<XmlNode>) -> Result<Seq, String> {
         rules: module.rules.clone(),
         position: None,
         last: None,
+        ruleset: None,
+        options: Rc::new(EvalOptions {
+            preserve_boundary_space: module
+                .preserve_boundary_space
+                .unwrap_or(opts.preserve_boundary_space),
+            strict_variables: module.strict_variables.unwrap_or(opts.strict_variables),
+            lang_version: Some(opts.lang_version.unwrap_or(module.version)),
+            ..opts.clone()
+        }),
+        state: Rc::new(EvalState::default()),
     };
+    if let Some(seed) = opts.seed {
+        ctx.state.rng.set(seed);
+    }
     for (name, expr) in &module.vars {
         let val = eval_expr(expr, &ctx)?;
         let rc = Rc::new(val.clone());
@@ -67,15 +371,25 @@ pub fn eval_module(module: &Module, doc: Rc<XmlNode>) -> Result<Seq, String> {
 }
 
 pub fn eval_expr(expr: &Expr, ctx: &Context) -> Result<Seq, String> {
+    ctx.tick()?;
+    let out = eval_expr_inner(expr, ctx)?;
+    ctx.check_len(out.len())?;
+    Ok(out)
+}
+
+fn eval_expr_inner(expr: &Expr, ctx: &Context) -> Result<Seq, String> {
     match expr {
-        Expr::Literal(lit) => Ok(vec![lit_to_item(lit)]),
+        Expr::Literal(lit, _) => Ok(vec![lit_to_item(lit)]),
 
         Expr::VarRef(name) => {
             if let Some(val) = ctx.variables.get(name) {
                 return Ok((**val).clone());
             }
-            if ctx.functions.contains_key(name.as_str()) {
-                return Ok(vec![Item::FuncRef(name.clone())]);
+            if let Some(fd) = ctx.functions.get(name) {
+                return Ok(vec![Item::FuncRef(name.clone(), fd.params.len())]);
+            }
+            if ctx.options.strict_variables {
+                return Err(unbound_variable(name));
             }
             // Fall back to child axis from context
             if let Some(Item::Node(node)) = &ctx.context_item {
@@ -95,11 +409,13 @@ pub fn eval_expr(expr: &Expr, ctx: &Context) -> Result<Seq, String> {
         }
 
         Expr::IfExpr(ie) => {
-            let cond = eval_expr(&ie.cond, ctx)?;
-            if to_boolean(&cond) {
+            if eval_boolean(&ie.cond, ctx)? {
                 eval_expr(&ie.then_expr, ctx)
             } else {
-                eval_expr(&ie.else_expr, ctx)
+                match &ie.else_expr {
+                    Some(e) => eval_expr(e, ctx),
+                    None => Ok(vec![]),
+                }
             }
         }
 
@@ -111,53 +427,105 @@ pub fn eval_expr(expr: &Expr, ctx: &Context) -> Result<Seq, String> {
         }
 
         Expr::ForExpr(fe) => {
-            let seq = eval_expr(&fe.seq, ctx)?;
-            let total = seq.len();
+            // An array alone iterates over its members, each a whole value
+            let members: Vec<Seq> = match eval_expr(&fe.seq, ctx)?.as_slice() {
+                [Item::Array(a)] => a.to_vec(),
+                seq => seq.iter().map(|item| vec![item.clone()]).collect(),
+            };
+            let total = members.len();
+            let separator = match &fe.separator {
+                Some(s) => Some(to_string(&eval_expr(s, ctx)?)),
+                None => None,
+            };
             let mut out = Vec::new();
-            for (idx, item) in seq.into_iter().enumerate() {
+            let mut emitted = false;
+            let mut tuples: Vec<(Vec<Seq>, Context)> = Vec::new();
+            for (idx, member) in members.into_iter().enumerate() {
                 let mut vars = ctx.variables.clone();
-                vars.insert(fe.name.clone(), Rc::new(vec![item.clone()]));
+                let item = member.first().cloned();
+                vars.insert(fe.name.clone(), Rc::new(member));
                 let new_ctx = Context {
-                    context_item: Some(item),
+                    context_item: item,
                     variables: vars,
                     position: Some((idx + 1) as f64),
                     last: Some(total as f64),
                     ..ctx.clone()
                 };
                 if let Some(w) = &fe.where_clause {
-                    if !to_boolean(&eval_expr(w, &new_ctx)?) {
+                    if !eval_boolean(w, &new_ctx)? {
                         continue;
                     }
                 }
-                out.extend(eval_expr(&fe.body, &new_ctx)?);
+                if fe.order_by.is_empty() {
+                    push_separated(&mut out, &separator, &mut emitted);
+                    ctx.extend_seq(&mut out, eval_expr(&fe.body, &new_ctx)?)?;
+                } else {
+                    let keys: Result<Vec<Seq>, String> =
+                        fe.order_by.iter().map(|spec| eval_expr(&spec.key, &new_ctx)).collect();
+                    tuples.push((keys?, new_ctx));
+                }
+            }
+            if !fe.order_by.is_empty() {
+                // Per key: by value when every tuple has a single number
+                let numeric: Vec<bool> = (0..fe.order_by.len())
+                    .map(|i| tuples.iter().all(|(k, _)| matches!(k[i][..], [Item::Num(_)])))
+                    .collect();
+                let collations = fe
+                    .order_by
+                    .iter()
+                    .map(|spec| Collation::parse(spec.collation.as_deref().unwrap_or_default()))
+                    .collect::<Result<Vec<_>, String>>()?;
+                tuples.sort_by(|(a, _), (b, _)| {
+                    fe.order_by
+                        .iter()
+                        .enumerate()
+                        .map(|(i, spec)| {
+                            let ord = cmp_sort_keys(&a[i], &b[i], numeric[i], collations[i]);
+                            if spec.descending { ord.reverse() } else { ord }
+                        })
+                        .find(|ord| ord.is_ne())
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                for (_, tuple_ctx) in &tuples {
+                    push_separated(&mut out, &separator, &mut emitted);
+                    ctx.extend_seq(&mut out, eval_expr(&fe.body, tuple_ctx)?)?;
+                }
             }
             Ok(out)
         }
 
         Expr::MatchExpr(me) => {
             let target_seq = eval_expr(&me.target, ctx)?;
+            let total = target_seq.len();
             let mut out = Vec::new();
-            for target in target_seq {
+            for (idx, target) in target_seq.into_iter().enumerate() {
                 let mut matched = false;
-                for (pat, body) in &me.cases {
-                    if let Some(bindings) = match_pattern(pat, &target) {
+                for (pats, body) in &me.cases {
+                    let hit = pats.iter().find_map(|pat| match_pattern(pat, &target));
+                    if let Some(bindings) = hit {
                         matched = true;
                         let mut vars = ctx.variables.clone();
                         vars.extend(bindings);
                         let new_ctx = Context {
                             context_item: Some(target.clone()),
                             variables: vars,
+                            position: Some((idx + 1) as f64),
+                            last: Some(total as f64),
                             ..ctx.clone()
                         };
-                        out.extend(eval_expr(body, &new_ctx)?);
+                        ctx.extend_seq(&mut out, eval_expr(body, &new_ctx)?)?;
                         break;
                     }
                 }
                 if !matched {
                     match &me.default {
                         Some(d) => {
-                            let new_ctx = ctx.with_item(target);
-                            out.extend(eval_expr(d, &new_ctx)?);
+                            let new_ctx = Context {
+                                position: Some((idx + 1) as f64),
+                                last: Some(total as f64),
+                                ..ctx.with_item(target)
+                            };
+                            ctx.extend_seq(&mut out, eval_expr(d, &new_ctx)?)?;
                         }
                         None => return Err("XFDY0001: no matching case".into()),
                     }
@@ -167,16 +535,24 @@ pub fn eval_expr(expr: &Expr, ctx: &Context) -> Result<Seq, String> {
         }
 
         Expr::FuncCall(fc) => {
+            if let Some(trace) = &ctx.options.trace {
+                trace.on_function_call(&fc.name, fc.args.len());
+            }
+            if let Some(out) = eval_path_aggregate(fc, ctx)? {
+                return Ok(out);
+            }
             let args: Result<Vec<Seq>, String> =
                 fc.args.iter().map(|a| eval_expr(a, ctx)).collect();
             call_function(&fc.name, args?, ctx)
         }
 
+        Expr::UnaryOp { op, expr } if op == "not" => {
+            Ok(vec![Item::Bool(!eval_boolean(expr, ctx)?)])
+        }
         Expr::UnaryOp { op, expr } => {
             let val = eval_expr(expr, ctx)?;
             match op.as_str() {
                 "-" => Ok(vec![Item::Num(-to_number(&val)?)]),
-                "not" => Ok(vec![Item::Bool(!to_boolean(&val))]),
                 _ => Err(format!("Unknown unary op {}", op)),
             }
         }
@@ -184,39 +560,97 @@ pub fn eval_expr(expr: &Expr, ctx: &Context) -> Result<Seq, String> {
         Expr::BinaryOp { op, left, right } => {
             match op.as_str() {
                 "and" => {
-                    let l = eval_expr(left, ctx)?;
-                    if !to_boolean(&l) {
+                    if !eval_boolean(left, ctx)? {
                         return Ok(vec![Item::Bool(false)]);
                     }
-                    let r = eval_expr(right, ctx)?;
-                    Ok(vec![Item::Bool(to_boolean(&r))])
+                    Ok(vec![Item::Bool(eval_boolean(right, ctx)?)])
                 }
                 "or" => {
-                    let l = eval_expr(left, ctx)?;
-                    if to_boolean(&l) {
+                    if eval_boolean(left, ctx)? {
                         return Ok(vec![Item::Bool(true)]);
                     }
-                    let r = eval_expr(right, ctx)?;
-                    Ok(vec![Item::Bool(to_boolean(&r))])
+                    Ok(vec![Item::Bool(eval_boolean(right, ctx)?)])
+                }
+                "??" => {
+                    let l = eval_expr(left, ctx)?;
+                    let blank = match l.as_slice() {
+                        [] => true,
+                        [Item::Str(s)] => ctx.options.coalesce_empty_string && s.is_empty(),
+                        _ => false,
+                    };
+                    if blank {
+                        eval_expr(right, ctx)
+                    } else {
+                        Ok(l)
+                    }
+                }
+                "|" => {
+                    let mut nodes = Vec::new();
+                    for item in eval_expr(left, ctx)?.into_iter().chain(eval_expr(right, ctx)?) {
+                        match item {
+                            Item::Node(n) => nodes.push(n),
+                            _ => return Err("XFDY0018: union operand is not a node".into()),
+                        }
+                    }
+                    ctx.sort_nodes(&mut nodes);
+                    Ok(nodes.into_iter().map(Item::Node).collect())
+                }
+                // The distinct items of the left operand, in its order, that
+                // are not or are also in the right one
+                "except" | "intersect" => {
+                    let l = eval_expr(left, ctx)?;
+                    let r: std::collections::HashSet<String> =
+                        eval_expr(right, ctx)?.iter().map(set_key).collect();
+                    let mut seen = std::collections::HashSet::new();
+                    let keep = op == "intersect";
+                    Ok(l.into_iter()
+                        .filter(|item| {
+                            let key = set_key(item);
+                            r.contains(&key) == keep && seen.insert(key)
+                        })
+                        .collect())
                 }
                 _ => {
                     let l = eval_expr(left, ctx)?;
                     let r = eval_expr(right, ctx)?;
-                    Ok(vec![eval_binary(op, &l, &r)?])
+                    Ok(vec![eval_binary(op, &l, &r, ctx.version())?])
                 }
             }
         }
 
         Expr::PathExpr(pe) => eval_path(pe, ctx),
 
+        Expr::Sequence(items) => {
+            let mut out = Vec::new();
+            for e in items {
+                ctx.extend_seq(&mut out, eval_expr(e, ctx)?)?;
+            }
+            Ok(out)
+        }
+
+        Expr::Array(members) => {
+            let members: Result<Vec<Seq>, String> =
+                members.iter().map(|m| eval_expr(m, ctx)).collect();
+            Ok(vec![Item::Array(Rc::new(members?))])
+        }
+
+        Expr::Lambda(fd) => Ok(vec![Item::Closure(Rc::new(Closure {
+            params: fd.params.clone(),
+            body: fd.body.clone(),
+            env: ctx.variables.clone(),
+        }))]),
+
         Expr::Constructor(c) => Ok(vec![Item::Node(eval_constructor(c, ctx)?)]),
 
         Expr::TextConstructor(e) => {
             let val = eval_expr(e, ctx)?;
+            ctx.add_nodes(1)?;
             Ok(vec![Item::Node(make_text(&to_string(&val)))])
         }
 
-        Expr::CharData(s) => Ok(vec![Item::Str(s.clone())]),
+        Expr::DocumentConstructor(e) => Ok(vec![Item::Node(eval_document(e, ctx)?)]),
+
+        Expr::CharData(s) => Ok(vec![Item::Str(decode_char_refs(s).into_owned())]),
 
         Expr::Interp(e) => eval_expr(e, ctx),
     }
@@ -231,10 +665,13 @@ fn lit_to_item(lit: &LiteralValue) -> Item {
     }
 }
 
-fn eval_binary(op: &str, left: &Seq, right: &Seq) -> Result<Item, String> {
+fn eval_binary(op: &str, left: &Seq, right: &Seq, version: LangVersion) -> Result<Item, String> {
     match op {
-        "=" => Ok(Item::Bool(value_equal(left, right))),
-        "!=" => Ok(Item::Bool(!value_equal(left, right))),
+        "=" => Ok(Item::Bool(value_equal(left, right, version))),
+        "!=" => Ok(Item::Bool(!value_equal(left, right, version))),
+        "in" => Ok(Item::Bool(
+            left.iter().any(|l| right.iter().any(|r| items_equal(l, r))),
+        )),
         "+" => Ok(Item::Num(to_number(left)? + to_number(right)?)),
         "-" => Ok(Item::Num(to_number(left)? - to_number(right)?)),
         "*" => Ok(Item::Num(to_number(left)? * to_number(right)?)),
@@ -248,7 +685,42 @@ fn eval_binary(op: &str, left: &Seq, right: &Seq) -> Result<Item, String> {
     }
 }
 
+/// Evaluate a path, handing each item of its final step to `f` as it is found.
+/// `f` returns `false` to stop the walk early.
+fn visit_path(
+    pe: &PathExpr,
+    ctx: &Context,
+    f: &mut dyn FnMut(Item) -> bool,
+) -> Result<(), String> {
+    match &ctx.options.profile {
+        None => walk_path(pe, ctx, f),
+        Some(profile) => {
+            // Paths in predicates keep their count apart from this one's
+            let outer = ctx.state.visited.replace(0);
+            let res = walk_path(pe, ctx, f);
+            let visited = ctx.state.visited.replace(outer);
+            profile.borrow_mut().path_walked(pe.span, visited);
+            res
+        }
+    }
+}
+
 fn eval_path(pe: &PathExpr, ctx: &Context) -> Result<Seq, String> {
+    let max = ctx.options.max_seq_len.unwrap_or(usize::MAX);
+    let mut out = Vec::new();
+    visit_path(pe, ctx, &mut |item| {
+        out.push(item);
+        out.len() <= max
+    })?;
+    ctx.check_len(out.len())?;
+    Ok(out)
+}
+
+fn walk_path(
+    pe: &PathExpr,
+    ctx: &Context,
+    f: &mut dyn FnMut(Item) -> bool,
+) -> Result<(), String> {
     let mut extra_steps: Vec<PathStep> = Vec::new();
 
     let base: Seq = match &pe.start.kind {
@@ -266,10 +738,16 @@ fn eval_path(pe: &PathExpr, ctx: &Context) -> Result<Seq, String> {
             }
         }
         PathStartKind::DescRoot => vec![Item::Node(ctx.root.clone())],
+        PathStartKind::Expr => match &pe.start.base {
+            Some(base) => eval_expr(base, ctx)?,
+            None => vec![],
+        },
         PathStartKind::Var => {
             let name = pe.start.name.as_deref().unwrap_or("");
             if let Some(val) = ctx.variables.get(name) {
                 (**val).clone()
+            } else if ctx.options.strict_variables {
+                return Err(unbound_variable(name));
             } else {
                 // Treat as child axis from context
                 let child_step = PathStep {
@@ -289,35 +767,131 @@ fn eval_path(pe: &PathExpr, ctx: &Context) -> Result<Seq, String> {
     let all_steps: Vec<&PathStep> =
         extra_steps.iter().chain(pe.steps.iter()).collect();
 
-    let mut current = base;
-    for step in all_steps {
-        current = apply_step(&current, step, ctx)?;
+    let (last, init) = match all_steps.split_last() {
+        Some(split) => split,
+        None => {
+            for item in base {
+                if !f(item) {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+    };
+    visit_steps(&base, init, last, ctx, f)?;
+    Ok(())
+}
+
+/// Walk `steps` then `last` depth first from each of `items`, so that no step
+/// is materialized and `f` returning `false` stops the walk at once. The
+/// items come in the same order as applying one step after the other.
+/// Returns whether the walk ran to the end.
+fn visit_steps(
+    items: &Seq,
+    steps: &[&PathStep],
+    last: &PathStep,
+    ctx: &Context,
+    f: &mut dyn FnMut(Item) -> bool,
+) -> Result<bool, String> {
+    let (step, rest) = match steps.split_first() {
+        Some(split) => split,
+        None => {
+            let mut more = true;
+            visit_step(items, last, ctx, &mut |item| {
+                more = f(item);
+                more
+            })?;
+            return Ok(more);
+        }
+    };
+    let mut result = Ok(true);
+    visit_step(items, step, ctx, &mut |item| {
+        result = visit_steps(&vec![item], rest, last, ctx, f);
+        matches!(result, Ok(true))
+    })?;
+    result
+}
+
+fn unbound_variable(name: &str) -> String {
+    format!("XFST0006: variable {} is not bound (strict-variables)", name)
+}
+
+/// Effective boolean value of `expr`, stopping a path at its first true item.
+fn eval_boolean(expr: &Expr, ctx: &Context) -> Result<bool, String> {
+    match expr {
+        Expr::PathExpr(pe) => {
+            let mut found = false;
+            visit_path(pe, ctx, &mut |item| {
+                found = to_boolean(std::slice::from_ref(&item));
+                !found
+            })?;
+            Ok(found)
+        }
+        _ => Ok(to_boolean(&eval_expr(expr, ctx)?)),
     }
-    Ok(current)
 }
 
-fn apply_step(items: &Seq, step: &PathStep, ctx: &Context) -> Result<Seq, String> {
-    let mut out: Seq = Vec::new();
-    for item in items {
+/// `count`, `exists`, `empty` and `boolean` over a single path argument,
+/// answered while walking the path instead of materializing it.
+fn eval_path_aggregate(fc: &FuncCall, ctx: &Context) -> Result<Option<Seq>, String> {
+    let pe = match fc.args.as_slice() {
+        [Expr::PathExpr(pe)]
+            if !ctx.functions.contains_key(&fc.name) && !ctx.variables.contains_key(&fc.name) =>
+        {
+            pe
+        }
+        _ => return Ok(None),
+    };
+    match fc.name.as_str() {
+        "count" => {
+            let mut n = 0usize;
+            visit_path(pe, ctx, &mut |_| {
+                n += 1;
+                true
+            })?;
+            Ok(Some(vec![Item::Num(n as f64)]))
+        }
+        "boolean" => Ok(Some(vec![Item::Bool(eval_boolean(&fc.args[0], ctx)?)])),
+        "exists" | "empty" => {
+            let mut found = false;
+            visit_path(pe, ctx, &mut |_| {
+                found = true;
+                false
+            })?;
+            Ok(Some(vec![Item::Bool(found == (fc.name == "exists"))]))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Apply one step to `items`, handing matches to `f` until it returns `false`.
+fn visit_step(
+    items: &Seq,
+    step: &PathStep,
+    ctx: &Context,
+    f: &mut dyn FnMut(Item) -> bool,
+) -> Result<(), String> {
+    let mut visited = 0u64;
+    let positional = ctx.version() >= LangVersion::V2_1;
+    let needs_last = step.predicates.iter().any(calls_last);
+    'items: for item in items {
         let node = match item {
             Item::Node(n) => n.clone(),
             _ => continue,
         };
 
-        let candidates: Vec<Rc<XmlNode>> = match step.axis {
-            PathAxis::SelfAxis => vec![node.clone()],
+        let candidates: Box<dyn Iterator<Item = Rc<XmlNode>>> = match step.axis {
+            PathAxis::SelfAxis => Box::new(std::iter::once(node.clone())),
             PathAxis::Parent => {
                 // We don't track parents; skip
                 continue;
             }
             PathAxis::DescOrSelf => {
-                let mut v = vec![node.clone()];
-                v.extend(iter_descendants(&node));
-                v
+                Box::new(std::iter::once(node.clone()).chain(descendants(&node)))
             }
-            PathAxis::Desc => iter_descendants(&node),
+            PathAxis::Desc => Box::new(descendants(&node)),
             PathAxis::Attr => {
-                if node.kind == NodeKind::Element {
+                let attrs: Vec<Rc<XmlNode>> = if node.kind == NodeKind::Element {
                     match &step.test.kind {
                         StepTestKind::Name => {
                             let name = step.test.name.as_deref().unwrap_or("");
@@ -338,77 +912,450 @@ fn apply_step(items: &Seq, step: &PathStep, ctx: &Context) -> Result<Seq, String
                     }
                 } else {
                     vec![]
+                };
+                for attr in &attrs {
+                    attr.set_parent(&node);
                 }
+                Box::new(attrs.into_iter())
             }
             PathAxis::Child => {
                 if node.kind == NodeKind::Element || node.kind == NodeKind::Document {
-                    node.children.clone()
+                    Box::new(node.children.clone().into_iter())
                 } else {
-                    vec![]
+                    Box::new(std::iter::empty())
                 }
             }
         };
 
+        // last() is the number of candidates reaching a predicate, so with
+        // it the candidates are collected and filtered one predicate at a time
+        if needs_last {
+            let mut kept: Vec<Rc<XmlNode>> = candidates
+                .inspect(|_| visited += 1)
+                .filter(|cand| matches_test(cand, &step.test))
+                .collect();
+            for pred in &step.predicates {
+                let last = Some(kept.len() as f64);
+                let mut next = Vec::with_capacity(kept.len());
+                for (i, cand) in kept.into_iter().enumerate() {
+                    if predicate_holds(pred, Item::Node(cand.clone()), i + 1, last, ctx)? {
+                        next.push(cand);
+                    }
+                }
+                kept = next;
+            }
+            for cand in kept {
+                if !f(Item::Node(cand)) {
+                    break 'items;
+                }
+            }
+            continue;
+        }
+
+        // Position of the candidate among those reaching each predicate. From
+        // 2.1 a literal number predicate ends the walk from this node once
+        // its position has been reached.
+        let mut positions = vec![0usize; step.predicates.len()];
         for cand in candidates {
+            visited += 1;
             if matches_test(&cand, &step.test) {
-                // Apply predicates
                 let item_cand = Item::Node(cand.clone());
-                let pred_ctx = ctx.with_item(item_cand.clone());
                 let mut ok = true;
-                for pred in &step.predicates {
-                    if !to_boolean(&eval_expr(pred, &pred_ctx)?) {
+                for (pred, pos) in step.predicates.iter().zip(positions.iter_mut()) {
+                    *pos += 1;
+                    if !predicate_holds(pred, item_cand.clone(), *pos, None, ctx)? {
                         ok = false;
                         break;
                     }
                 }
-                if ok {
-                    out.push(item_cand);
+                if ok && !f(item_cand) {
+                    break 'items;
+                }
+                let exhausted = positional
+                    && step.predicates.iter().zip(&positions).any(|(pred, pos)| {
+                        matches!(pred, Expr::Literal(LiteralValue::Num(n), _) if *pos as f64 >= *n)
+                    });
+                if exhausted {
+                    break;
                 }
             }
         }
     }
-    Ok(out)
+    if ctx.options.profile.is_some() {
+        ctx.state.visited.set(ctx.state.visited.get() + visited);
+    }
+    Ok(())
+}
+
+/// Whether predicate `pred` keeps `cand`, at `pos` among the candidates
+/// reaching it. The focus of a predicate is the candidate alone, so a path in
+/// it walks from there without the position of this step. From 2.1 a number
+/// keeps the candidate at that position, as in XPath; in 2.0 it is tested for
+/// truth like any other value.
+fn predicate_holds(
+    pred: &Expr,
+    cand: Item,
+    pos: usize,
+    last: Option<f64>,
+    ctx: &Context,
+) -> Result<bool, String> {
+    let positional = ctx.version() >= LangVersion::V2_1;
+    if let (Expr::Literal(LiteralValue::Num(n), _), true) = (pred, positional) {
+        return Ok(*n == pos as f64);
+    }
+    let pred_ctx = Context {
+        context_item: Some(cand),
+        position: Some(pos as f64),
+        last,
+        ..ctx.clone()
+    };
+    Ok(match eval_expr(pred, &pred_ctx)?.as_slice() {
+        [Item::Num(n)] if positional => *n == pos as f64,
+        val => to_boolean(val),
+    })
+}
+
+/// Whether `expr` calls `last()` for the focus it is evaluated in.
+fn calls_last(expr: &Expr) -> bool {
+    match expr {
+        Expr::FuncCall(fc) if fc.name == "last" && fc.args.is_empty() => true,
+        _ => same_focus(expr).into_iter().any(calls_last),
+    }
+}
+
+/// Whether evaluating `expr` may read the focus, the context item or its
+/// position: a relative path does, as does a call without arguments, which
+/// many builtins take as a call on `.`, and a call of a user function whose
+/// body does. `functions` are the user functions, `seen` those already
+/// being looked at.
+fn reads_focus(
+    expr: &Expr,
+    functions: &HashMap<String, FunctionDef>,
+    seen: &mut Vec<String>,
+) -> bool {
+    match expr {
+        Expr::PathExpr(pe)
+            if matches!(pe.start.kind, PathStartKind::Context | PathStartKind::Desc) =>
+        {
+            true
+        }
+        Expr::FuncCall(fc) if fc.args.is_empty() => true,
+        Expr::FuncCall(fc) if functions.contains_key(&fc.name) && !seen.contains(&fc.name) => {
+            seen.push(fc.name.clone());
+            reads_focus(&functions[&fc.name].body, functions, seen)
+                || fc.args.iter().any(|arg| reads_focus(arg, functions, seen))
+        }
+        Expr::Lambda(fd) => reads_focus(&fd.body, functions, seen),
+        _ => same_focus(expr).into_iter().any(|e| reads_focus(e, functions, seen)),
+    }
+}
+
+/// The parts of `expr` evaluated with the focus `expr` itself has. Left out
+/// are the predicates of paths and the bodies of `for` and `match`, which
+/// have a focus of their own, and the bodies of inline functions, which run
+/// where they are called.
+fn same_focus(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::FuncCall(fc) => fc.args.iter().collect(),
+        Expr::IfExpr(ie) => {
+            let mut parts = vec![&ie.cond, &ie.then_expr];
+            parts.extend(&ie.else_expr);
+            parts
+        }
+        Expr::LetExpr(le) => vec![&le.value, &le.body],
+        Expr::ForExpr(fe) => {
+            let mut parts = vec![&fe.seq];
+            parts.extend(&fe.separator);
+            parts
+        }
+        Expr::MatchExpr(me) => vec![&me.target],
+        Expr::UnaryOp { expr, .. }
+        | Expr::Interp(expr)
+        | Expr::TextConstructor(expr)
+        | Expr::DocumentConstructor(expr) => vec![expr],
+        Expr::BinaryOp { left, right, .. } => vec![left, right],
+        Expr::PathExpr(pe) => pe.start.base.iter().map(|b| &**b).collect(),
+        Expr::Sequence(items) | Expr::Array(items) => items.iter().collect(),
+        Expr::Constructor(c) => {
+            let attrs = c.attrs.iter().map(|(_, v)| v);
+            let splats = c.splats.iter().map(|(_, v)| v);
+            let namespaces = c.namespaces.iter().map(|(_, v)| v);
+            attrs.chain(splats).chain(namespaces).chain(&c.contents).collect()
+        }
+        Expr::Literal(..) | Expr::VarRef(_) | Expr::Lambda(_) | Expr::CharData(_) => vec![],
+    }
 }
 
 fn matches_test(node: &Rc<XmlNode>, test: &StepTest) -> bool {
     match test.kind {
         StepTestKind::Node => true,
-        StepTestKind::Wildcard => node.kind == NodeKind::Element,
+        // Only the attribute axis yields attributes, so this is `@*` there
+        StepTestKind::Wildcard => {
+            node.kind == NodeKind::Element || node.kind == NodeKind::Attribute
+        }
         StepTestKind::Text => node.kind == NodeKind::Text,
         StepTestKind::Comment => node.kind == NodeKind::Comment,
         StepTestKind::Pi => node.kind == NodeKind::Pi,
         StepTestKind::Name => node.name.as_deref() == test.name.as_deref(),
+        StepTestKind::NamePattern => {
+            node.kind == NodeKind::Element
+                && glob_match(test.name.as_deref().unwrap_or(""), node.name.as_deref().unwrap_or(""))
+        }
+    }
+}
+
+/// Whether `name` matches `glob`, where each `*` stands for any run of
+/// characters.
+fn glob_match(glob: &str, name: &str) -> bool {
+    let parts: Vec<&str> = glob.split('*').collect();
+    let (first, rest) = parts.split_first().expect("split yields a part");
+    let Some(mut tail) = name.strip_prefix(first) else {
+        return false;
+    };
+    let Some((last, middle)) = rest.split_last() else {
+        return tail.is_empty();
+    };
+    for part in middle {
+        match tail.find(part) {
+            Some(i) => tail = &tail[i + part.len()..],
+            None => return false,
+        }
     }
+    tail.len() >= last.len() && tail.ends_with(last)
 }
 
+/// The element `c` builds. Its attributes are in the order written, with
+/// spliced maps in key order at their place; an attribute set again keeps
+/// its first position and takes the last value.
 fn eval_constructor(c: &Constructor, ctx: &Context) -> Result<Rc<XmlNode>, String> {
-    let mut attrs = Vec::new();
-    for (aname, aexpr) in &c.attrs {
-        let val = eval_expr(aexpr, ctx)?;
-        attrs.push((aname.clone(), to_string(&val)));
+    let id = next_node_id();
+    let mut attrs: Vec<(String, String)> = Vec::new();
+    for (i, (aname, aexpr)) in c.attrs.iter().enumerate() {
+        splice_attrs(c, i, &mut attrs, ctx)?;
+        if c.attrs[..i].iter().any(|(k, _)| k == aname) {
+            return Err(format!("XFDY0007: duplicate attribute {} on <{}>", aname, c.name));
+        }
+        let val = to_string(&eval_expr(aexpr, ctx)?);
+        set_attr(&mut attrs, aname, val);
+    }
+    splice_attrs(c, c.attrs.len(), &mut attrs, ctx)?;
+
+    let mut namespaces = Vec::new();
+    for (prefix, uexpr) in &c.namespaces {
+        let uri = eval_expr(uexpr, ctx)?;
+        namespaces.push((prefix.clone(), to_string(&uri)));
     }
 
+    // Adjacent text, literal or computed, is merged into one text node.
+    // Attribute nodes in the content attach to the element, replacing any
+    // attribute of the same name, but only before other content.
+    // `xml:space="preserve"` keeps literal whitespace here and in nested
+    // constructors
+    let preserved;
+    let ctx = if !ctx.options.preserve_boundary_space && space_attr(&attrs) == Some(true) {
+        let options = EvalOptions::clone(&ctx.options);
+        let options = Rc::new(EvalOptions { preserve_boundary_space: true, ..options });
+        preserved = Context { options, ..ctx.clone() };
+        &preserved
+    } else {
+        ctx
+    };
     let mut children: Vec<Rc<XmlNode>> = Vec::new();
+    let mut text = String::new();
     for content in &c.contents {
         match content {
             Expr::CharData(s) => {
-                if !s.trim().is_empty() {
-                    children.push(make_text(s));
+                if ctx.options.preserve_boundary_space || !s.trim().is_empty() {
+                    text.push_str(&decode_char_refs(s));
                 }
             }
             _ => {
                 let seq = eval_expr(content, ctx)?;
                 for item in seq {
-                    match item {
-                        Item::Node(n) => children.push(deep_copy(&n)),
-                        other => children.push(make_text(&to_string(&[other]))),
-                    }
+                    add_content(item, (id, &c.name), &mut attrs, &mut children, &mut text)?;
                 }
             }
         }
     }
+    if !text.is_empty() {
+        children.push(make_text(&text));
+    }
+
+    ctx.add_nodes(1 + children.len() as u64)?;
+    if let Some(trace) = &ctx.options.trace {
+        let attrs: String = attrs.iter().map(|(k, v)| format!(" {}={:?}", k, v)).collect();
+        trace.on_construct(&format!("<{}{}>", c.name, attrs));
+    }
+    Ok(make_element(id, &c.name, attrs, namespaces, children))
+}
+
+/// Constructor text with the character references `&#160;` and `&#xA0;`
+/// replaced by their characters. Anything else starting with `&`, such as
+/// `&amp;`, `&#+65;` or a reference to a character XML does not allow like
+/// `&#1;`, stays as written.
+fn decode_char_refs(s: &str) -> std::borrow::Cow<'_, str> {
+    if !s.contains("&#") {
+        return s.into();
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find("&#") {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let decoded = rest[2..].split_once(';').and_then(|(num, _)| {
+            let (digits, radix) = match num.strip_prefix(['x', 'X']) {
+                Some(hex) => (hex, 16),
+                None => (num, 10),
+            };
+            if digits.is_empty() || !digits.chars().all(|d| d.is_digit(radix)) {
+                return None;
+            }
+            let code = u32::from_str_radix(digits, radix).ok()?;
+            let c = char::from_u32(code).filter(|c| is_xml_char(*c))?;
+            Some((c, num.len() + 3))
+        });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out.into()
+}
+
+/// Apply the attribute maps `c` splices in before its attribute `at`, in
+/// key order; an entry whose value is the empty sequence is left out.
+fn splice_attrs(
+    c: &Constructor,
+    at: usize,
+    attrs: &mut Vec<(String, String)>,
+    ctx: &Context,
+) -> Result<(), String> {
+    for (_, e) in c.splats.iter().filter(|(i, _)| *i == at) {
+        let seq = eval_expr(e, ctx)?;
+        let map = match seq.as_slice() {
+            [] => continue,
+            [Item::Map(m)] => m,
+            _ => return Err(format!("XFDY0002: attribute splat on <{}> is not a map", c.name)),
+        };
+        let mut entries: Vec<(&String, &Seq)> = map.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        for (k, v) in entries.into_iter().filter(|(_, v)| !v.is_empty()) {
+            if !is_xml_name(k) {
+                return Err(format!("XFDY0002: invalid attribute name {:?}", k));
+            }
+            set_attr(attrs, k, to_string(v));
+        }
+    }
+    Ok(())
+}
+
+/// Set attribute `name`, replacing its value in place when already there.
+fn set_attr(attrs: &mut Vec<(String, String)>, name: &str, value: String) {
+    match attrs.iter_mut().find(|(k, _)| k == name) {
+        Some(existing) => existing.1 = value,
+        None => attrs.push((name.to_string(), value)),
+    }
+}
+
+/// Add one content item to the element `(id, name)` being built, with the
+/// text pending since the last node in `text`.
+fn add_content(
+    item: Item,
+    (id, name): (u64, &str),
+    attrs: &mut Vec<(String, String)>,
+    children: &mut Vec<Rc<XmlNode>>,
+    text: &mut String,
+) -> Result<(), String> {
+    match item {
+        Item::Node(n) if n.kind == NodeKind::Text => {
+            text.push_str(n.value.as_deref().unwrap_or_default());
+        }
+        Item::Node(n) if n.kind == NodeKind::Attribute => {
+            let aname = n.name.clone().unwrap_or_default();
+            if !children.is_empty() || !text.is_empty() {
+                return Err(format!(
+                    "XFDY0006: attribute {} added to <{}> after its content",
+                    aname, name
+                ));
+            }
+            set_attr(attrs, &aname, n.value.clone().unwrap_or_default());
+        }
+        Item::Node(n) => {
+            if !text.is_empty() {
+                children.push(make_text(&std::mem::take(text)));
+            }
+            let floor = children.last().map_or(id, |c| c.id);
+            children.push(adopt(n, floor));
+        }
+        other => text.push_str(&to_string(&[other])),
+    }
+    Ok(())
+}
+
+/// `n` as the next child of a node being built: `n` itself when nothing
+/// else holds it and it was built after `floor`, the id of the preceding
+/// child or else of the new parent, otherwise a deep copy. A node built
+/// while evaluating the content and not kept anywhere cannot be observed
+/// elsewhere, and its ids already follow document order in the new tree.
+fn adopt(n: Rc<XmlNode>, floor: u64) -> Rc<XmlNode> {
+    if n.id > floor && Rc::strong_count(&n) == 1 {
+        n
+    } else {
+        deep_copy(&n)
+    }
+}
 
-    Ok(make_element(&c.name, attrs, children))
+/// Document node holding copies of the nodes `content` yields, with
+/// adjacent text merged. A document in the content contributes its
+/// children.
+fn eval_document(content: &Expr, ctx: &Context) -> Result<Rc<XmlNode>, String> {
+    let id = next_node_id();
+    let mut children: Vec<Rc<XmlNode>> = Vec::new();
+    let mut text = String::new();
+    let mut items = eval_expr(content, ctx)?;
+    items.reverse();
+    while let Some(item) = items.pop() {
+        match item {
+            Item::Node(n) if n.kind == NodeKind::Document => {
+                items.extend(n.children.iter().rev().map(|c| Item::Node(c.clone())));
+            }
+            Item::Node(n) if n.kind == NodeKind::Text => {
+                text.push_str(n.value.as_deref().unwrap_or_default());
+            }
+            Item::Node(n) if n.kind == NodeKind::Attribute => {
+                return Err(format!(
+                    "XFDY0010: attribute {} added to a document node",
+                    n.name.as_deref().unwrap_or_default()
+                ));
+            }
+            Item::Node(n) => {
+                if !text.is_empty() {
+                    children.push(make_text(&std::mem::take(&mut text)));
+                }
+                let floor = children.last().map_or(id, |c| c.id);
+                children.push(adopt(n, floor));
+            }
+            other => text.push_str(&to_string(&[other])),
+        }
+    }
+    if !text.is_empty() {
+        children.push(make_text(&text));
+    }
+    if ctx.options.strict_documents {
+        let elements = children.iter().filter(|c| c.kind == NodeKind::Element).count();
+        if elements > 1 {
+            return Err(format!("XFDY0010: document has {} element children", elements));
+        }
+    }
+    ctx.add_nodes(1 + children.len() as u64)?;
+    Ok(make_document(id, children))
 }
 
 fn match_pattern(pat: &Pattern, item: &Item) -> Option<HashMap<String, SeqRef>> {
@@ -464,49 +1411,364 @@ fn match_pattern(pat: &Pattern, item: &Item) -> Option<HashMap<String, SeqRef>>
     }
 }
 
+enum Tail {
+    Done(Seq),
+    /// A call back to the current function: its arguments and call-site context.
+    Call(Vec<Seq>, Box<Context>),
+}
+
+/// Evaluate the body of user function `fname`, following `if` and `let` into
+/// tail position. A direct call to `fname` there is returned unevaluated.
+fn eval_tail(expr: &Expr, ctx: &Context, fname: &str) -> Result<Tail, String> {
+    match expr {
+        Expr::IfExpr(ie) => {
+            if eval_boolean(&ie.cond, ctx)? {
+                eval_tail(&ie.then_expr, ctx, fname)
+            } else {
+                match &ie.else_expr {
+                    Some(e) => eval_tail(e, ctx, fname),
+                    None => Ok(Tail::Done(vec![])),
+                }
+            }
+        }
+        Expr::LetExpr(le) => {
+            let val = eval_expr(&le.value, ctx)?;
+            let mut vars = ctx.variables.clone();
+            vars.insert(le.name.clone(), Rc::new(val));
+            eval_tail(&le.body, &ctx.with_vars(vars), fname)
+        }
+        Expr::FuncCall(fc) if fc.name == fname => {
+            let args: Result<Vec<Seq>, String> =
+                fc.args.iter().map(|a| eval_expr(a, ctx)).collect();
+            Ok(Tail::Call(args?, Box::new(ctx.clone())))
+        }
+        _ => Ok(Tail::Done(eval_expr(expr, ctx)?)),
+    }
+}
+
 // ── Built-in functions ───────────────────────────────────────────────────────
 
-fn call_function(name: &str, args: Vec<Seq>, ctx: &Context) -> Result<Seq, String> {
-    // User-defined function?
-    if let Some(fd) = ctx.functions.get(name) {
-        let fd = fd.clone();
-        let mut vars = ctx.variables.clone();
+fn call_user(name: &str, fd: &FunctionDef, args: Vec<Seq>, ctx: &Context) -> Result<Seq, String> {
+    ctx.nested(|| run_user(name, fd, args, ctx))
+}
+
+fn run_user(name: &str, fd: &FunctionDef, args: Vec<Seq>, ctx: &Context) -> Result<Seq, String> {
+    // Self tail calls come back from eval_tail unevaluated and are run by
+    // this loop, so tail recursion does not grow the native stack.
+    let mut args = args;
+    let mut call_ctx = ctx.clone();
+    loop {
+        let mut vars = call_ctx.variables.clone();
         for (i, param) in fd.params.iter().enumerate() {
             if i < args.len() {
                 vars.insert(param.name.clone(), Rc::new(args[i].clone()));
             } else if let Some(def) = &param.default {
-                vars.insert(param.name.clone(), Rc::new(eval_expr(def, ctx)?));
+                vars.insert(param.name.clone(), Rc::new(eval_expr(def, &call_ctx)?));
             } else {
                 return Err(format!("XFDY0002: wrong arity for {}", name));
             }
         }
-        return eval_expr(&fd.body, &Context { variables: vars, ..ctx.clone() });
+        let body_ctx = Context { variables: vars, ..call_ctx };
+        match eval_tail(&fd.body, &body_ctx, name)? {
+            Tail::Done(seq) => return Ok(seq),
+            Tail::Call(next_args, next_ctx) => {
+                args = next_args;
+                call_ctx = *next_ctx;
+            }
+        }
     }
+}
 
-    match name {
-        "string" => {
-            let seq = args.into_iter().next().unwrap_or_default();
-            Ok(vec![Item::Str(to_string(&seq))])
+/// Cache key of a `def memo` call, from its arguments and, with `focus`,
+/// the context item, position and last the body sees. Elements and
+/// documents count by identity; attributes and text, which are fresh nodes
+/// on each path step and have no parent to reach, by name and value; named
+/// functions by name; other items by type and string value. `None` for a
+/// call given an inline function, which is not cached: closures have no
+/// value to compare.
+fn memo_key(name: &str, args: &[Seq], focus: bool, ctx: &Context) -> Option<String> {
+    let mut key = name.to_string();
+    let focus: Vec<Seq> = match focus {
+        true => vec![
+            ctx.context_item.iter().cloned().collect(),
+            ctx.position.map(Item::Num).into_iter().collect(),
+            ctx.last.map(Item::Num).into_iter().collect(),
+        ],
+        false => vec![],
+    };
+    for arg in args.iter().chain(&focus) {
+        key.push('\u{1}');
+        for item in arg {
+            let part = match item {
+                Item::Node(n) => match n.kind {
+                    NodeKind::Attribute | NodeKind::Text => format!(
+                        "t{}={}",
+                        n.name.as_deref().unwrap_or(""),
+                        n.value.as_deref().unwrap_or("")
+                    ),
+                    _ => format!("n{}", n.id),
+                },
+                Item::Num(n) => format!("d{}", fmt_num(*n)),
+                Item::Bool(b) => format!("b{}", b),
+                Item::Null => "z".to_string(),
+                Item::Str(v) => format!("s{}", v),
+                Item::FuncRef(f, _) => format!("f{}", f),
+                Item::Closure(_) => return None,
+                other => format!("o{}", to_string(std::slice::from_ref(other))),
+            };
+            key.push_str(&part);
+            key.push('\u{2}');
         }
-        "number" => {
-            let seq = args.into_iter().next().unwrap_or_default();
+    }
+    Some(key)
+}
+
+fn call_function(name: &str, args: Vec<Seq>, ctx: &Context) -> Result<Seq, String> {
+    // User-defined function?
+    if let Some(fd) = ctx.functions.get(name) {
+        let fd = fd.clone();
+        if !fd.memo {
+            return call_user(name, &fd, args, ctx);
+        }
+        let mut memo_focus = ctx.state.memo_focus.borrow_mut();
+        let focus = *memo_focus.entry(name.to_string()).or_insert_with(|| {
+            reads_focus(&fd.body, &ctx.functions, &mut vec![name.to_string()])
+        });
+        drop(memo_focus);
+        let Some(key) = memo_key(name, &args, focus, ctx) else {
+            return call_user(name, &fd, args, ctx);
+        };
+        if let Some(seq) = ctx.state.memo.borrow().get(&key) {
+            return Ok(seq.clone());
+        }
+        let seq = call_user(name, &fd, args, ctx)?;
+        ctx.state.memo.borrow_mut().insert(key, seq.clone());
+        return Ok(seq);
+    }
+
+    // A variable bound to a function item?
+    if let Some(f @ (Item::FuncRef(..) | Item::Closure(_))) =
+        ctx.variables.get(name).and_then(|v| v.first())
+    {
+        return call_item(&f.clone(), args, ctx);
+    }
+
+    match name {
+        "string" => {
+            let seq = args.into_iter().next().unwrap_or_default();
+            Ok(vec![Item::Str(to_string(&seq))])
+        }
+        // serialize(seq, options?) with the options map keys of
+        // `SerializeOptions::from_map`; pretty(seq) indents by two
+        "serialize" | "pretty" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let opts = match (name, it.next().as_deref()) {
+                ("pretty", _) => SerializeOptions { indent: Some(2), ..Default::default() },
+                (_, None | Some([])) => SerializeOptions::default(),
+                (_, Some([Item::Map(m)])) => SerializeOptions::from_map(m)?,
+                _ => return Err("XFDY0002: serialize options must be a map".into()),
+            };
+            Ok(vec![Item::Str(serialize_items_with(&seq, &opts)?)])
+        }
+        "hash" => {
+            let seq = args.into_iter().next().unwrap_or_default();
+            let text = serialize_items(&seq)?;
+            Ok(vec![Item::Str(format!("{:016x}", fnv1a_64(text.as_bytes())))])
+        }
+        // Digests of the UTF-8 bytes of the string value, in lowercase hex
+        #[cfg(feature = "hash")]
+        "sha256" | "md5" => {
+            use sha2::Digest;
+            let text = to_string(&args.into_iter().next().unwrap_or_default());
+            let digest: Vec<u8> = match name {
+                "sha256" => sha2::Sha256::digest(text.as_bytes()).to_vec(),
+                _ => md5::Md5::digest(text.as_bytes()).to_vec(),
+            };
+            let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+            Ok(vec![Item::Str(hex)])
+        }
+        #[cfg(feature = "hash")]
+        "base64Encode" | "base64Decode" => {
+            use base64::Engine;
+            let text = to_string(&args.into_iter().next().unwrap_or_default());
+            let engine = base64::engine::general_purpose::STANDARD;
+            if name == "base64Encode" {
+                return Ok(vec![Item::Str(engine.encode(text.as_bytes()))]);
+            }
+            let bytes = engine
+                .decode(text.trim())
+                .map_err(|e| format!("XFDY0013: invalid base64: {}", e))?;
+            let decoded = String::from_utf8(bytes)
+                .map_err(|_| "XFDY0013: base64 data is not UTF-8 text".to_string())?;
+            Ok(vec![Item::Str(decoded)])
+        }
+        #[cfg(not(feature = "hash"))]
+        "sha256" | "md5" | "base64Encode" | "base64Decode" => {
+            Err(format!("XFST0003: {} needs xform built with the `hash` feature", name))
+        }
+        // XPath encode-for-uri: percent-encodes all but unreserved characters
+        "encodeForUri" => {
+            let text = to_string(&args.into_iter().next().unwrap_or_default());
+            let mut out = String::with_capacity(text.len());
+            for b in text.bytes() {
+                if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+                    out.push(b as char);
+                } else {
+                    out.push_str(&format!("%{:02X}", b));
+                }
+            }
+            Ok(vec![Item::Str(out)])
+        }
+        "escapeHtml" => {
+            let text = to_string(&args.into_iter().next().unwrap_or_default());
+            let mut out = String::with_capacity(text.len());
+            for c in text.chars() {
+                match c {
+                    '&' => out.push_str("&amp;"),
+                    '<' => out.push_str("&lt;"),
+                    '>' => out.push_str("&gt;"),
+                    '"' => out.push_str("&quot;"),
+                    '\'' => out.push_str("&#39;"),
+                    _ => out.push(c),
+                }
+            }
+            Ok(vec![Item::Str(out)])
+        }
+        "doc" => {
+            let uri = to_string(&args.into_iter().next().unwrap_or_default());
+            let path = resource_path(&uri, ctx.options.base_dir.as_deref());
+            let text = read_allowed(&uri, &path, &ctx.options)?;
+            let doc = if ctx.options.xinclude {
+                parse_xml_xinclude(&text, &path, &ctx.options)?
+            } else {
+                parse_xml_with(&text, ctx.options.strip_whitespace)
+                    .map_err(|e| format!("XFDY0004: {}: {}", uri, e))?
+            };
+            let path = path.strip_prefix(".").unwrap_or(&path).to_string_lossy().into_owned();
+            ctx.state.document_uris.borrow_mut().insert(doc.id, path);
+            Ok(vec![Item::Node(doc)])
+        }
+        // (doc?): where the input document, or a document doc() read, came
+        // from; empty for other nodes and for documents built or parsed from
+        // a string
+        "document-uri" => {
+            let uri = match args.into_iter().next().as_deref() {
+                None => ctx.options.document_uri.clone(),
+                Some([Item::Node(n)]) if Rc::ptr_eq(n, &ctx.root) => {
+                    ctx.options.document_uri.clone()
+                }
+                Some([Item::Node(n)]) => ctx.state.document_uris.borrow().get(&n.id).cloned(),
+                _ => None,
+            };
+            Ok(uri.map(Item::Str).into_iter().collect())
+        }
+        // (relative, base?) with the input document's URI as the default base
+        "resolve-uri" => {
+            let mut it = args.into_iter();
+            let rel = to_string(&it.next().unwrap_or_default());
+            let base = match it.next() {
+                Some(b) => to_string(&b),
+                None => ctx.options.document_uri.clone().unwrap_or_default(),
+            };
+            Ok(vec![Item::Str(resolve_uri(&rel, &base))])
+        }
+        "stripSpace" | "normalizeTextNodes" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let preserve: Vec<String> = it
+                .next()
+                .unwrap_or_default()
+                .iter()
+                .map(|item| to_string(std::slice::from_ref(item)))
+                .collect();
+            Ok(seq
+                .into_iter()
+                .map(|item| match item {
+                    Item::Node(n) if name == "stripSpace" => Item::Node(strip_space(&n, &preserve)),
+                    Item::Node(n) => Item::Node(normalize_text_nodes(&n)),
+                    other => other,
+                })
+                .collect())
+        }
+        // XPath 3.1 parse-xml, read like the input document
+        "parse-xml" => {
+            let text = to_string(&args.into_iter().next().unwrap_or_default());
+            let doc = parse_xml_with(&text, ctx.options.strip_whitespace)
+                .map_err(|e| format!("XFDY0004: {}", e))?;
+            Ok(vec![Item::Node(doc)])
+        }
+        // Top-level nodes of markup without a single root, text included
+        "parseXmlFragment" => {
+            let text = to_string(&args.into_iter().next().unwrap_or_default());
+            let nodes = parse_xml_fragment(&text).map_err(|e| format!("XFDY0004: {}", e))?;
+            Ok(nodes.into_iter().map(Item::Node).collect())
+        }
+        // XPath 3.1 JSON-as-XML, see `crate::json`
+        "json-to-xml" => {
+            let text = to_string(&args.into_iter().next().unwrap_or_default());
+            let doc = json_to_xml(&text).map_err(|e| format!("XFDY0011: {}", e))?;
+            Ok(vec![Item::Node(doc)])
+        }
+        "xml-to-json" => match args.into_iter().next().unwrap_or_default().as_slice() {
+            [Item::Node(n)] => {
+                let json = xml_to_json(n).map_err(|e| format!("XFDY0011: {}", e))?;
+                Ok(vec![Item::Str(json)])
+            }
+            _ => Err("XFDY0002: xml-to-json expects a single node".into()),
+        },
+        // JSON as items: objects become maps and arrays arrays, so that
+        // serializeJson(parseJson(text)) gives text back with keys sorted
+        "parseJson" => {
+            let text = to_string(&args.into_iter().next().unwrap_or_default());
+            let doc = json_to_xml(&text).map_err(|e| format!("XFDY0011: {}", e))?;
+            let value = doc.children.iter().find(|c| c.kind == NodeKind::Element);
+            Ok(value.map(json_item).into_iter().collect())
+        }
+        "serializeJson" => {
+            let seq = args.into_iter().next().unwrap_or_default();
+            Ok(vec![Item::Str(seq_to_json(&seq))])
+        }
+        "unparsed-text" => {
+            let uri = to_string(&args.into_iter().next().unwrap_or_default());
+            Ok(vec![Item::Str(read_resource(&uri, ctx)?)])
+        }
+        // Lines end at "\n" or "\r\n"; a final line ending adds no empty line
+        "unparsed-text-lines" => {
+            let uri = to_string(&args.into_iter().next().unwrap_or_default());
+            let text = read_resource(&uri, ctx)?;
+            Ok(text.lines().map(|l| Item::Str(l.to_string())).collect())
+        }
+        "number" => {
+            let seq = args.into_iter().next().unwrap_or_default();
             Ok(vec![Item::Num(to_number(&seq)?)])
         }
         "boolean" => {
             let seq = args.into_iter().next().unwrap_or_default();
             Ok(vec![Item::Bool(to_boolean(&seq))])
         }
-        "typeOf" => {
+        "true" | "false" => Ok(vec![Item::Bool(name == "true")]),
+        // kind-of is typeOf with the node kind in place of "node"
+        "typeOf" | "kind-of" => {
             let seq = args.into_iter().next().unwrap_or_default();
             let t = match seq.first() {
                 None => "null",
+                Some(Item::Node(n)) if name == "kind-of" => match n.kind {
+                    NodeKind::Document => "document",
+                    NodeKind::Element => "element",
+                    NodeKind::Attribute => "attribute",
+                    NodeKind::Text => "text",
+                    NodeKind::Comment => "comment",
+                    NodeKind::Pi => "pi",
+                },
                 Some(Item::Node(_)) => "node",
                 Some(Item::Map(_)) => "map",
                 Some(Item::Bool(_)) => "boolean",
                 Some(Item::Num(_)) => "number",
                 Some(Item::Null) => "null",
                 Some(Item::Str(_)) => "string",
-                Some(Item::FuncRef(_)) => "function",
+                Some(Item::FuncRef(..) | Item::Closure(_)) => "function",
+                Some(Item::Array(_)) => "array",
             };
             Ok(vec![Item::Str(t.to_string())])
         }
@@ -518,47 +1780,319 @@ fn call_function(name: &str, args: Vec<Seq>, ctx: &Context) -> Result<Seq, Strin
             };
             Ok(vec![Item::Str(s)])
         }
-        "attr" => {
+        // The nearest ancestor-or-self setting `xml:space` decides; a copy
+        // outside the declaring element keeps the mark it was parsed with.
+        "preserveSpace" => {
+            let node = match args.into_iter().next().unwrap_or_default().first() {
+                Some(Item::Node(n)) => n.clone(),
+                _ => return Ok(vec![Item::Bool(false)]),
+            };
+            let preserve = node.preserve_space
+                || ancestry(&node).iter().rev().find_map(|n| space_attr(&n.attrs)).unwrap_or(false);
+            Ok(vec![Item::Bool(preserve)])
+        }
+        // attr gives "" for a missing attribute, attribute the empty sequence
+        "attr" | "attribute" => {
             let mut it = args.into_iter();
             let node_seq = it.next().unwrap_or_default();
             let key_seq = it.next().unwrap_or_default();
             let key = to_string(&key_seq);
-            match node_seq.first() {
+            let value = match node_seq.first() {
                 Some(Item::Node(n)) if n.kind == NodeKind::Element => {
-                    let v = n
-                        .attrs
-                        .iter()
-                        .find(|(k, _)| k == &key)
-                        .map(|(_, v)| v.clone())
-                        .unwrap_or_default();
-                    Ok(vec![Item::Str(v)])
+                    n.attrs.iter().find(|(k, _)| k == &key).map(|(_, v)| v.clone())
                 }
-                _ => Ok(vec![Item::Str(String::new())]),
+                _ => None,
+            };
+            match value {
+                Some(v) => Ok(vec![Item::Str(v)]),
+                None if name == "attr" => Ok(vec![Item::Str(String::new())]),
+                None => Ok(vec![]),
             }
         }
+        // text(node, mode?, separator?): mode is a deep flag, or a string of
+        // the words deep, shallow and normalize
         "text" => {
             let mut it = args.into_iter();
             let node_seq = it.next().unwrap_or_default();
-            let deep_seq = it.next();
-            let deep = deep_seq.as_ref().map_or(true, |s| to_boolean(s));
+            let mode_seq = it.next();
+            let separator = it.next().map(|s| to_string(&s));
+            let mut deep = !ctx.options.shallow_text;
+            let mut normalize = false;
+            match mode_seq.as_deref() {
+                Some([Item::Str(mode)]) => {
+                    for word in mode.split([' ', ',']).filter(|w| !w.is_empty()) {
+                        match word {
+                            "deep" => deep = true,
+                            "shallow" => deep = false,
+                            "normalize" => normalize = true,
+                            _ => return Err(format!("XFDY0002: unknown text mode {:?}", word)),
+                        }
+                    }
+                }
+                Some(s) => deep = to_boolean(s),
+                None => {}
+            }
             match node_seq.first() {
                 Some(Item::Node(n)) => {
-                    let s = if deep {
-                        n.string_value()
-                    } else {
-                        n.children
+                    let mut pieces = Vec::new();
+                    text_pieces(n, deep, &mut pieces);
+                    let s = match (&separator, normalize) {
+                        (None, false) => pieces.concat(),
+                        (None, true) => collapse_space(&pieces.concat()),
+                        (Some(sep), false) => pieces.join(sep),
+                        (Some(sep), true) => pieces
                             .iter()
-                            .filter(|c| c.kind == NodeKind::Text)
-                            .map(|c| c.value.clone().unwrap_or_default())
+                            .map(|p| collapse_space(p))
+                            .filter(|p| !p.is_empty())
                             .collect::<Vec<_>>()
-                            .join("")
+                            .join(sep),
                     };
                     Ok(vec![Item::Str(s)])
                 }
-                Some(item) => Ok(vec![Item::Str(to_string(&[item.clone()]))]),
+                Some(item) => {
+                    let s = to_string(std::slice::from_ref(item));
+                    Ok(vec![Item::Str(if normalize { collapse_space(&s) } else { s })])
+                }
+                None => Ok(vec![Item::Str(String::new())]),
+            }
+        }
+        "matches" => {
+            let mut it = args.into_iter();
+            let input = to_string(&it.next().unwrap_or_default());
+            let pattern = to_string(&it.next().unwrap_or_default());
+            let flags = it.next().map(|f| to_string(&f)).unwrap_or_default();
+            let re = compile_regex(&pattern, &flags)?;
+            Ok(vec![Item::Bool(re.is_match(&input))])
+        }
+        "replace" => {
+            let mut it = args.into_iter();
+            let input = to_string(&it.next().unwrap_or_default());
+            let pattern = to_string(&it.next().unwrap_or_default());
+            let replacement = to_string(&it.next().unwrap_or_default());
+            let flags = it.next().map(|f| to_string(&f)).unwrap_or_default();
+            let re = compile_regex(&pattern, &flags)?;
+            Ok(vec![Item::Str(re.replace_all(&input, replacement.as_str()).into_owned())])
+        }
+        "tokenize" => {
+            let mut it = args.into_iter();
+            let input = to_string(&it.next().unwrap_or_default());
+            let out: Seq = match it.next() {
+                // Without a pattern, split on runs of (Unicode) whitespace
+                None => input.split_whitespace().map(|t| Item::Str(t.into())).collect(),
+                Some(p) => {
+                    let flags = it.next().map(|f| to_string(&f)).unwrap_or_default();
+                    let re = compile_regex(&to_string(&p), &flags)?;
+                    if input.is_empty() {
+                        vec![]
+                    } else {
+                        re.split(&input).map(|t| Item::Str(t.into())).collect()
+                    }
+                }
+            };
+            Ok(out)
+        }
+        "stringToCodepoints" => {
+            let text = to_string(&args.into_iter().next().unwrap_or_default());
+            Ok(text.chars().map(|c| Item::Num(c as u32 as f64)).collect())
+        }
+        "codepointsToString" => {
+            let mut out = String::new();
+            for item in args.into_iter().next().unwrap_or_default() {
+                let n = to_number(std::slice::from_ref(&item))?;
+                match char::from_u32(n as u32).filter(|_| n.fract() == 0.0 && n >= 0.0) {
+                    Some(c) => out.push(c),
+                    None => return Err(format!("XFDY0002: invalid codepoint {}", fmt_num(n))),
+                }
+            }
+            Ok(vec![Item::Str(out)])
+        }
+        // normalizeUnicode(s, form?) with form NFC (the default), NFD, NFKC
+        // or NFKD, any case; the empty form leaves s as it is
+        "normalizeUnicode" => {
+            let mut it = args.into_iter();
+            let text = to_string(&it.next().unwrap_or_default());
+            let form = match it.next() {
+                Some(f) => to_string(&f).trim().to_uppercase(),
+                None => "NFC".to_string(),
+            };
+            if form.is_empty() {
+                return Ok(vec![Item::Str(text)]);
+            }
+            Ok(vec![Item::Str(normalize_unicode(text, &form)?)])
+        }
+        // compare(a, b, collation?) is -1, 0 or 1, and empty if a or b is
+        "compare" | "equals-ci" => {
+            let mut it = args.into_iter();
+            let (a, b) = (it.next().unwrap_or_default(), it.next().unwrap_or_default());
+            let collation = match name {
+                "equals-ci" => Collation::CaseInsensitive,
+                _ => Collation::parse(&it.next().map(|c| to_string(&c)).unwrap_or_default())?,
+            };
+            if a.is_empty() || b.is_empty() {
+                return Ok(if name == "compare" { vec![] } else { vec![Item::Bool(false)] });
+            }
+            Ok(vec![match (name, collation.compare(&to_string(&a), &to_string(&b))) {
+                ("equals-ci", ord) => Item::Bool(ord == Ordering::Equal),
+                (_, ord) => Item::Num(ord as i8 as f64),
+            }])
+        }
+        "deep-contains" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let needle = to_string(&it.next().unwrap_or_default());
+            let haystack = match seq.first() {
+                Some(Item::Node(n)) => n.string_value(),
+                _ => to_string(&seq),
+            };
+            Ok(vec![Item::Bool(haystack.contains(&needle))])
+        }
+        "find-text" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let needle = to_string(&it.next().unwrap_or_default());
+            let mut out = Vec::new();
+            for item in &seq {
+                if let Item::Node(root) = item {
+                    out.extend(
+                        descendants(root)
+                            .filter(|n| n.kind == NodeKind::Element)
+                            .filter(|n| n.string_value().contains(&needle))
+                            .map(Item::Node),
+                    );
+                }
+            }
+            Ok(out)
+        }
+        "stringValue" => {
+            let seq = args.into_iter().next().unwrap_or_default();
+            match seq.first() {
+                Some(Item::Node(n)) => Ok(vec![Item::Str(n.string_value())]),
+                Some(item) => Ok(vec![Item::Str(to_string(std::slice::from_ref(item)))]),
                 None => Ok(vec![Item::Str(String::new())]),
             }
         }
+        "textNodes" => {
+            let seq = args.into_iter().next().unwrap_or_default();
+            let mut out = Vec::new();
+            for item in seq {
+                if let Item::Node(n) = item {
+                    out.extend(
+                        n.children
+                            .iter()
+                            .filter(|c| c.kind == NodeKind::Text)
+                            .map(|c| Item::Node(c.clone())),
+                    );
+                }
+            }
+            Ok(out)
+        }
+        // (node, level?, picture?) like xsl:number, counting nodes of the same
+        // kind and name. "single" numbers the node among its siblings,
+        // "multiple" each same-named ancestor-or-self among its siblings, and
+        // "any" counts all such nodes up to this one in document order.
+        "numberOf" => {
+            let mut it = args.into_iter();
+            let node = match it.next().unwrap_or_default().first() {
+                Some(Item::Node(n)) => n.clone(),
+                _ => return Ok(vec![]),
+            };
+            let level = it.next().map(|s| to_string(&s)).filter(|s| !s.is_empty());
+            let picture = it.next().map(|s| to_string(&s)).filter(|s| !s.is_empty());
+            let path = ancestry(&node);
+            if path.len() < 2 {
+                return Ok(vec![]);
+            }
+            let same = |c: &Rc<XmlNode>| c.kind == node.kind && c.name == node.name;
+            let sibling_pos = |parent: &Rc<XmlNode>, n: &Rc<XmlNode>| {
+                let earlier = parent.children.iter().take_while(|c| !Rc::ptr_eq(c, n));
+                earlier.filter(|c| same(c)).count() + 1
+            };
+            let numbers: Vec<usize> = match level.as_deref().unwrap_or("single") {
+                "single" => vec![sibling_pos(&path[path.len() - 2], &node)],
+                "multiple" => path
+                    .windows(2)
+                    .filter(|w| same(&w[1]))
+                    .map(|w| sibling_pos(&w[0], &w[1]))
+                    .collect(),
+                "any" => {
+                    let upto = descendants(&path[0]).position(|d| Rc::ptr_eq(&d, &node));
+                    let count = descendants(&path[0]).take(upto.unwrap_or(0) + 1);
+                    vec![count.filter(|d| same(d)).count()]
+                }
+                other => return Err(format!("XFDY0002: unknown numbering level {:?}", other)),
+            };
+            Ok(vec![Item::Str(format_numbers(&numbers, picture.as_deref().unwrap_or("1")))])
+        }
+        "index-in-parent" | "index-of-type" => {
+            let seq = args.into_iter().next().unwrap_or_default();
+            let node = match seq.first() {
+                Some(Item::Node(n)) => n.clone(),
+                _ => return Ok(vec![]),
+            };
+            let parent = match node.parent() {
+                Some(p) => p,
+                None => return Ok(vec![]),
+            };
+            let same_type = name == "index-of-type";
+            let idx = parent
+                .children
+                .iter()
+                .filter(|c| !same_type || (c.kind == node.kind && c.name == node.name))
+                .position(|c| Rc::ptr_eq(c, &node));
+            Ok(idx.map(|i| Item::Num((i + 1) as f64)).into_iter().collect())
+        }
+        // Through parent links: outermost looks up from each node for one in
+        // the set, innermost marks the ancestors of each node once
+        "outermost" | "innermost" => {
+            let mut nodes: Vec<Rc<XmlNode>> = args
+                .into_iter()
+                .next()
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|item| match item {
+                    Item::Node(n) => Some(n),
+                    _ => None,
+                })
+                .collect();
+            ctx.sort_nodes(&mut nodes);
+            let ancestors = |n: &Rc<XmlNode>| std::iter::successors(n.parent(), |p| p.parent());
+            let dropped: std::collections::HashSet<u64> = if name == "outermost" {
+                let ids: std::collections::HashSet<u64> = nodes.iter().map(|n| n.id).collect();
+                nodes
+                    .iter()
+                    .filter(|n| ancestors(n).any(|a| ids.contains(&a.id)))
+                    .map(|n| n.id)
+                    .collect()
+            } else {
+                let mut marked = std::collections::HashSet::new();
+                for n in &nodes {
+                    for a in ancestors(n) {
+                        if !marked.insert(a.id) {
+                            break;
+                        }
+                    }
+                }
+                marked
+            };
+            Ok(nodes.into_iter().filter(|n| !dropped.contains(&n.id)).map(Item::Node).collect())
+        }
+        // (node, name?): whether the node has any child, or a child element
+        // of that name
+        "hasChildren" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let name = it.next().map(|s| to_string(&s));
+            let found = match seq.first() {
+                Some(Item::Node(n)) => match &name {
+                    None => !n.children.is_empty(),
+                    Some(name) => n.children.iter().any(|c| {
+                        c.kind == NodeKind::Element && c.name.as_deref() == Some(name)
+                    }),
+                },
+                _ => false,
+            };
+            Ok(vec![Item::Bool(found)])
+        }
         "children" => {
             let seq = args.into_iter().next().unwrap_or_default();
             match seq.first() {
@@ -584,7 +2118,7 @@ fn call_function(name: &str, args: Vec<Seq>, ctx: &Context) -> Result<Seq, Strin
                             c.kind == NodeKind::Element
                                 && name_filter
                                     .as_ref()
-                                    .map_or(true, |nf| nf.is_empty() || c.name.as_deref() == Some(nf))
+                                    .is_none_or(|nf| nf.is_empty() || c.name.as_deref() == Some(nf))
                         })
                         .map(|c| Item::Node(c.clone()))
                         .collect();
@@ -601,6 +2135,16 @@ fn call_function(name: &str, args: Vec<Seq>, ctx: &Context) -> Result<Seq, Strin
                 _ => Ok(vec![]),
             }
         }
+        "rename-attributes" => {
+            let mut it = args.into_iter();
+            let node_seq = it.next().unwrap_or_default();
+            let old = to_string(&it.next().unwrap_or_default());
+            let new = to_string(&it.next().unwrap_or_default());
+            match node_seq.first() {
+                Some(Item::Node(n)) => Ok(vec![Item::Node(rename_attrs(n, &old, &new))]),
+                _ => Ok(vec![]),
+            }
+        }
         "count" => {
             let seq = args.into_iter().next().unwrap_or_default();
             Ok(vec![Item::Num(seq.len() as f64)])
@@ -609,43 +2153,107 @@ fn call_function(name: &str, args: Vec<Seq>, ctx: &Context) -> Result<Seq, Strin
             let seq = args.into_iter().next().unwrap_or_default();
             Ok(vec![Item::Bool(seq.is_empty())])
         }
-        "distinct" => {
+        "exists" => {
+            let seq = args.into_iter().next().unwrap_or_default();
+            Ok(vec![Item::Bool(!seq.is_empty())])
+        }
+        // The ByNode variants of distinct, index and groupBy tell nodes apart
+        // by identity, as generateId does, instead of by string value
+        "distinct" | "distinctByNode" => {
+            let by_node = name == "distinctByNode";
             let seq = args.into_iter().next().unwrap_or_default();
+            // All-numeric input dedups by value, so 1 and 1.0 are one item
+            let numeric = seq.iter().all(|item| matches!(item, Item::Num(_)));
+            let mut seen_nums = std::collections::HashSet::new();
             let mut seen = std::collections::HashSet::new();
             let out: Seq = seq
                 .into_iter()
-                .filter(|item| seen.insert(to_string(&[item.clone()])))
+                .filter(|item| match item {
+                    Item::Num(n) if numeric => seen_nums.insert(num_key(*n)),
+                    _ => seen.insert(item_key(item, by_node)),
+                })
                 .collect();
             Ok(out)
         }
+        // count(distinct(seq)) without building the result, or the number of
+        // distinct values of keyFn over seq
+        "count-distinct" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let keys: Seq = match it.next().as_deref() {
+                None => seq,
+                Some([kf @ (Item::FuncRef(..) | Item::Closure(_))]) => {
+                    let mut keys = Vec::with_capacity(seq.len());
+                    for item in seq {
+                        let key = call_item(kf, vec![vec![item]], ctx)?;
+                        keys.push(match key.as_slice() {
+                            [single] => single.clone(),
+                            _ => Item::Str(to_string(&key)),
+                        });
+                    }
+                    keys
+                }
+                Some(_) => return Err("XFDY0015: count-distinct key must be a function".into()),
+            };
+            let numeric = keys.iter().all(|item| matches!(item, Item::Num(_)));
+            let distinct = if numeric {
+                let nums = keys.iter().filter_map(|item| match item {
+                    Item::Num(n) => Some(num_key(*n)),
+                    _ => None,
+                });
+                nums.collect::<std::collections::HashSet<u64>>().len()
+            } else {
+                let strs = keys.iter().map(|item| item_key(item, false));
+                strs.collect::<std::collections::HashSet<String>>().len()
+            };
+            Ok(vec![Item::Num(distinct as f64)])
+        }
+        // Identifier unique to the node within the run; empty for anything else
+        "generateId" => match args.into_iter().next().unwrap_or_default().first() {
+            Some(item @ Item::Node(_)) => Ok(vec![Item::Str(item_key(item, true))]),
+            _ => Ok(vec![Item::Str(String::new())]),
+        },
+        // sort(seq, keyFn?, collation?), or sort(seq, collation); the
+        // collation names are those of compare
         "sort" => {
             let mut it = args.into_iter();
-            let mut seq = it.next().unwrap_or_default();
+            let seq = it.next().unwrap_or_default();
             let key_seq = it.next();
             let key_fn = key_seq.as_ref().and_then(|s| match s.first() {
-                Some(Item::FuncRef(n)) => Some(n.clone()),
+                Some(f @ (Item::FuncRef(..) | Item::Closure(_))) => Some(f.clone()),
                 _ => None,
             });
-            let mut keyed: Vec<(String, Item)> = seq
+            let collation = Collation::parse(&match (&key_seq, it.next()) {
+                (_, Some(c)) => to_string(&c),
+                (Some(s), None) if matches!(s[..], [Item::Str(_)]) => to_string(s),
+                _ => String::new(),
+            })?;
+            let mut keyed: Vec<(Seq, Item)> = seq
                 .iter()
                 .map(|item| {
                     let key = if let Some(ref kf) = key_fn {
-                        call_function(kf, vec![vec![item.clone()]], ctx)
-                            .map(|s| to_string(&s))
-                            .unwrap_or_default()
+                        call_item(kf, vec![vec![item.clone()]], ctx).unwrap_or_default()
                     } else {
-                        to_string(&[item.clone()])
+                        vec![item.clone()]
                     };
                     (key, item.clone())
                 })
                 .collect();
-            keyed.sort_by(|a, b| a.0.cmp(&b.0));
+            // Numeric keys sort by value (NaN last); anything else by string
+            let numeric = keyed.iter().all(|(k, _)| matches!(k[..], [Item::Num(_)]));
+            if numeric {
+                keyed.sort_by(|a, b| cmp_sort_keys(&a.0, &b.0, true, collation));
+                return Ok(keyed.into_iter().map(|(_, v)| v).collect());
+            }
+            let mut keyed: Vec<(String, Item)> =
+                keyed.into_iter().map(|(k, v)| (to_string(&k), v)).collect();
+            keyed.sort_by(|a, b| collation.compare(&a.0, &b.0));
             Ok(keyed.into_iter().map(|(_, v)| v).collect())
         }
         "concat" | "seq" => {
             let mut out = Vec::new();
             for seq in args {
-                out.extend(seq);
+                ctx.extend_seq(&mut out, seq)?;
             }
             Ok(out)
         }
@@ -667,29 +2275,259 @@ fn call_function(name: &str, args: Vec<Seq>, ctx: &Context) -> Result<Seq, Strin
             }
             Ok(vec![seq.into_iter().last().unwrap()])
         }
+        // Unlike XPath, a negative position counts from the end: -1 is the
+        // last item, -2 the one before it
+        "nth" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let pos = from_end(to_number(&it.next().unwrap_or_default())?.round(), seq.len());
+            if pos.is_nan() || pos < 1.0 {
+                return Ok(vec![]);
+            }
+            Ok(seq.into_iter().nth(pos as usize - 1).into_iter().collect())
+        }
+        // (seq, start, length?), with a negative start counting from the end
+        "subsequence" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let start = from_end(to_number(&it.next().unwrap_or_default())?.round(), seq.len());
+            let end = match it.next() {
+                Some(len) => start + to_number(&len)?.round(),
+                None => f64::INFINITY,
+            };
+            Ok(seq
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| (start..end).contains(&((i + 1) as f64)))
+                .map(|(_, item)| item)
+                .collect())
+        }
+        // An element whose name is computed; attributes come from a map,
+        // content as in a constructor
+        "build-element" => {
+            let id = next_node_id();
+            let mut it = args.into_iter();
+            let ename = to_string(&it.next().unwrap_or_default());
+            if !is_xml_name(&ename) {
+                return Err(format!("XFDY0002: invalid element name {:?}", ename));
+            }
+            let mut attrs: Vec<(String, String)> = match it.next().as_deref() {
+                None | Some([]) => vec![],
+                // An empty sequence leaves its attribute out
+                Some([Item::Map(m)]) => m
+                    .iter()
+                    .filter(|(_, v)| !v.is_empty())
+                    .map(|(k, v)| (k.clone(), to_string(v)))
+                    .collect(),
+                _ => return Err("XFDY0002: build-element attributes must be a map".into()),
+            };
+            // Map order is arbitrary; parsed elements sort theirs too
+            attrs.sort_by(|a, b| a.0.cmp(&b.0));
+            if let Some((bad, _)) = attrs.iter().find(|(k, _)| !is_xml_name(k)) {
+                return Err(format!("XFDY0002: invalid attribute name {:?}", bad));
+            }
+            let (mut children, mut text) = (Vec::new(), String::new());
+            for item in it.next().unwrap_or_default() {
+                add_content(item, (id, &ename), &mut attrs, &mut children, &mut text)?;
+            }
+            if !text.is_empty() {
+                children.push(make_text(&text));
+            }
+            ctx.add_nodes(1 + children.len() as u64)?;
+            Ok(vec![Item::Node(make_element(id, &ename, attrs, vec![], children))])
+        }
+        // Each argument is one member, so array((1, 2), 3) has two
+        "array" => Ok(vec![Item::Array(Rc::new(args))]),
+        // map(key1, value1, key2, value2, ...); a later key replaces an earlier
+        "map" => {
+            if !args.len().is_multiple_of(2) {
+                return Err("XFDY0002: map expects key and value pairs".into());
+            }
+            let mut m: XMap = HashMap::new();
+            let mut it = args.into_iter();
+            while let (Some(key), Some(value)) = (it.next(), it.next()) {
+                m.insert(to_string(&key), value);
+            }
+            Ok(vec![Item::Map(Rc::new(m))])
+        }
+        "arrayGet" => {
+            let mut it = args.into_iter();
+            let arr = it.next().unwrap_or_default();
+            let pos = to_number(&it.next().unwrap_or_default())?.round();
+            match arr.first() {
+                Some(Item::Array(a)) => {
+                    let pos = from_end(pos, a.len());
+                    if pos.is_nan() || pos < 1.0 {
+                        return Ok(vec![]);
+                    }
+                    Ok(a.get(pos as usize - 1).cloned().unwrap_or_default())
+                }
+                _ => Ok(vec![]),
+            }
+        }
+        "arraySize" => {
+            let arr = args.into_iter().next().unwrap_or_default();
+            match arr.first() {
+                Some(Item::Array(a)) => Ok(vec![Item::Num(a.len() as f64)]),
+                _ => Ok(vec![]),
+            }
+        }
+        "arrayToSeq" => {
+            let arr = args.into_iter().next().unwrap_or_default();
+            match arr.first() {
+                Some(Item::Array(a)) => Ok(a.iter().flatten().cloned().collect()),
+                _ => Ok(arr),
+            }
+        }
+        "deep-equal" => {
+            let mut it = args.into_iter();
+            let a = it.next().unwrap_or_default();
+            let b = it.next().unwrap_or_default();
+            Ok(vec![Item::Bool(deep_equal(&a, &b))])
+        }
+        // (init, fn, max-iterations?): apply fn until its result is
+        // deep-equal to its input
+        "fixpoint" => {
+            let mut it = args.into_iter();
+            let mut current = it.next().unwrap_or_default();
+            let f = match it.next().as_deref() {
+                Some([f @ (Item::FuncRef(..) | Item::Closure(_))]) => f.clone(),
+                _ => return Err("XFDY0015: fixpoint expects a function".into()),
+            };
+            let max = match it.next() {
+                Some(m) => to_number(&m)?,
+                None => FIXPOINT_MAX_ITERATIONS as f64,
+            };
+            let mut n = 0.0;
+            loop {
+                if n >= max {
+                    return Err(format!(
+                        "XFLM0006: fixpoint not reached after {} iterations",
+                        fmt_num(max)
+                    ));
+                }
+                ctx.tick()?;
+                let next = call_item(&f, vec![current.clone()], ctx)?;
+                if deep_equal(&next, &current) {
+                    return Ok(next);
+                }
+                current = next;
+                n += 1.0;
+            }
+        }
+        // Run-wide counters, starting at 0, for numbering across a whole
+        // traversal such as footnotes spread over chapters
+        "counterNext" | "counterValue" => {
+            let key = to_string(&args.into_iter().next().unwrap_or_default());
+            let mut counters = ctx.state.counters.borrow_mut();
+            let value = counters.entry(key).or_insert(0.0);
+            if name == "counterNext" {
+                *value += 1.0;
+            }
+            Ok(vec![Item::Num(*value)])
+        }
+        // randomNumber() draws from the run's stream; randomNumber(seed)
+        // is the same number in [0, 1) for the same seed, every time
+        "randomNumber" => {
+            let mut state = match args.into_iter().next() {
+                Some(seed) if !seed.is_empty() => fnv1a_64(to_string(&seed).as_bytes()),
+                _ => ctx.state.next_random(),
+            };
+            Ok(vec![Item::Num(unit_interval(splitmix64(&mut state)))])
+        }
+        // Version 4 UUID from the run's stream
+        "uuid" => {
+            let (hi, lo) = (ctx.state.next_random(), ctx.state.next_random());
+            let hi = (hi & !0xf000) | 0x4000;
+            let lo = (lo & !(0b11 << 62)) | (0b10 << 62);
+            Ok(vec![Item::Str(format!(
+                "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+                hi >> 32,
+                (hi >> 16) & 0xffff,
+                hi & 0xffff,
+                lo >> 48,
+                lo & 0xffff_ffff_ffff
+            ))])
+        }
         "position" => match ctx.position {
             Some(p) => Ok(vec![Item::Num(p)]),
             None => Ok(vec![]),
         },
-        "index" => {
+        // join(left, right, leftKeyFn, rightKeyFn, combineFn): for each left
+        // item, combineFn(left, matches) with the right items sharing one of
+        // its keys, in right order and possibly none, so a left outer join.
+        // Keys compare as strings, as for index. One pass over each side
+        // through a hash index on the right, O(n + m) besides the calls.
+        "join" => {
+            let mut it = args.into_iter();
+            let left = it.next().unwrap_or_default();
+            let right = it.next().unwrap_or_default();
+            let mut func = || match it.next().as_deref() {
+                Some([f @ (Item::FuncRef(..) | Item::Closure(_))]) => Ok(f.clone()),
+                _ => Err("XFDY0015: join needs key and combine functions".to_string()),
+            };
+            let (left_key, right_key, combine) = (func()?, func()?, func()?);
+            let mut by_key: HashMap<String, Vec<usize>> = HashMap::new();
+            for (i, item) in right.iter().enumerate() {
+                for key in call_item(&right_key, vec![vec![item.clone()]], ctx)? {
+                    let positions = by_key.entry(item_key(&key, false)).or_default();
+                    if positions.last() != Some(&i) {
+                        positions.push(i);
+                    }
+                }
+            }
+            let mut out = Vec::new();
+            for item in left {
+                let mut matched: Vec<usize> = Vec::new();
+                for key in call_item(&left_key, vec![vec![item.clone()]], ctx)? {
+                    matched.extend(by_key.get(&item_key(&key, false)).into_iter().flatten());
+                }
+                matched.sort_unstable();
+                matched.dedup();
+                let matches: Seq = matched.into_iter().map(|i| right[i].clone()).collect();
+                out.extend(call_item(&combine, vec![vec![item], matches], ctx)?);
+            }
+            Ok(out)
+        }
+        // Each value the key function returns files the item under that key,
+        // compared as a string; an item whose key function returns nothing is
+        // left out. indexUnique fails with XFDY0009 when two items share a key.
+        "index" | "indexUnique" | "indexByNode" => {
+            let by_node = name == "indexByNode";
             let mut it = args.into_iter();
             let seq = it.next().unwrap_or_default();
             let key_seq = it.next();
             let key_fn = key_seq.as_ref().and_then(|s| match s.first() {
-                Some(Item::FuncRef(n)) => Some(n.clone()),
+                Some(f @ (Item::FuncRef(..) | Item::Closure(_))) => Some(f.clone()),
                 _ => None,
             });
             let mut map: XMap = HashMap::new();
             for item in seq {
-                let key = if let Some(ref kf) = key_fn {
-                    to_string(&call_function(kf, vec![vec![item.clone()]], ctx)?)
-                } else {
-                    to_string(&[item.clone()])
+                let keys = match &key_fn {
+                    Some(kf) => call_item(kf, vec![vec![item.clone()]], ctx)?,
+                    None => vec![item.clone()],
                 };
-                map.entry(key).or_default().push(item);
+                let mut seen = std::collections::HashSet::new();
+                for key in keys {
+                    let key = item_key(&key, by_node);
+                    if !seen.insert(key.clone()) {
+                        continue;
+                    }
+                    if name == "indexUnique" && map.contains_key(&key) {
+                        return Err(format!("XFDY0009: duplicate key {:?} in indexUnique", key));
+                    }
+                    map.entry(key).or_default().push(item.clone());
+                }
             }
             Ok(vec![Item::Map(Rc::new(map))])
         }
+        "string-join" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let sep = to_string(&it.next().unwrap_or_default());
+            let parts: Vec<String> = seq.iter().map(|i| to_string(std::slice::from_ref(i))).collect();
+            Ok(vec![Item::Str(parts.join(&sep))])
+        }
         "lookup" => {
             let mut it = args.into_iter();
             let map_seq = it.next().unwrap_or_default();
@@ -700,48 +2538,278 @@ fn call_function(name: &str, args: Vec<Seq>, ctx: &Context) -> Result<Seq, Strin
                 _ => Ok(vec![]),
             }
         }
-        "groupBy" => {
+        // (seq, key-fn?, typed?, order?): typed is a boolean and order one
+        // of "insertion", "ascending" or "descending". Groups come in order
+        // of first appearance, or sorted by key, as numbers when all keys
+        // are numeric. Groups keep their items in input order. Typed
+        // grouping compares numeric keys by value, so "1" and "1.0" share a
+        // group keyed by the number.
+        "groupBy" | "groupByNode" => {
+            let by_node = name == "groupByNode";
             let mut it = args.into_iter();
             let seq = it.next().unwrap_or_default();
-            let key_seq = it.next();
-            let key_fn = key_seq.as_ref().and_then(|s| match s.first() {
-                Some(Item::FuncRef(n)) => Some(n.clone()),
+            let mut rest = it.peekable();
+            let key_fn = match rest.peek().and_then(|s| s.first()) {
+                Some(f @ (Item::FuncRef(..) | Item::Closure(_))) => {
+                    let f = f.clone();
+                    rest.next();
+                    Some(f)
+                }
                 _ => None,
-            });
+            };
+            let (mut typed, mut key_order) = (false, "insertion".to_string());
+            for arg in rest {
+                match arg.as_slice() {
+                    [Item::Str(s)] if ["insertion", "ascending", "descending"].contains(&&**s) => {
+                        key_order = s.clone()
+                    }
+                    [Item::Str(s)] => {
+                        return Err(format!("XFDY0002: unknown group order {:?}", s))
+                    }
+                    [Item::Bool(b)] => typed = *b,
+                    _ => {
+                        return Err(format!(
+                            "XFDY0002: {} takes a boolean or an order, not {:?}",
+                            name,
+                            to_string(&arg)
+                        ))
+                    }
+                }
+            }
             // Use a Vec to preserve insertion order (like Python dicts)
-            let mut order: Vec<String> = Vec::new();
+            let mut order: Vec<(String, Item)> = Vec::new();
             let mut groups: HashMap<String, Seq> = HashMap::new();
             for item in seq {
-                let key = if let Some(ref kf) = key_fn {
-                    to_string(&call_function(kf, vec![vec![item.clone()]], ctx)?)
-                } else {
-                    to_string(&[item.clone()])
+                let key_seq = match &key_fn {
+                    Some(kf) => call_item(kf, vec![vec![item.clone()]], ctx)?,
+                    None => vec![item.clone()],
+                };
+                let text = to_string(&key_seq);
+                let (key, key_item) = match (to_number(&key_seq), key_seq.as_slice()) {
+                    (_, [node @ Item::Node(_)]) if by_node => (item_key(node, true), node.clone()),
+                    (Ok(n), _) if typed && !text.trim().is_empty() => (fmt_num(n), Item::Num(n)),
+                    _ => (text.clone(), Item::Str(text)),
                 };
                 if !groups.contains_key(&key) {
-                    order.push(key.clone());
+                    order.push((key.clone(), key_item));
                 }
                 groups.entry(key).or_default().push(item);
             }
+            if key_order != "insertion" {
+                let numeric = order.iter().all(|(_, k)| match k {
+                    Item::Num(_) => true,
+                    other => {
+                        let key = std::slice::from_ref(other);
+                        !to_string(key).trim().is_empty() && to_number(key).is_ok()
+                    }
+                });
+                let as_key = |k: &Item| {
+                    let key = std::slice::from_ref(k);
+                    match k {
+                        Item::Num(n) => Item::Num(*n),
+                        _ if numeric => Item::Num(to_number(key).unwrap_or(f64::NAN)),
+                        _ => Item::Str(to_string(key)),
+                    }
+                };
+                order.sort_by(|(_, a), (_, b)| {
+                    let ord =
+                        cmp_sort_keys(&[as_key(a)], &[as_key(b)], numeric, Collation::Codepoint);
+                    if key_order == "descending" {
+                        ord.reverse()
+                    } else {
+                        ord
+                    }
+                });
+            }
             let out: Seq = order
                 .into_iter()
-                .map(|k| {
+                .map(|(k, key_item)| {
                     let items = groups.remove(&k).unwrap_or_default();
                     let mut m: XMap = HashMap::new();
-                    m.insert("key".into(), vec![Item::Str(k)]);
+                    m.insert("key".into(), vec![key_item]);
                     m.insert("items".into(), items);
                     Item::Map(Rc::new(m))
                 })
                 .collect();
             Ok(out)
         }
-        "sum" => {
+        // countBy(seq, keyFn) and sumBy(seq, keyFn, valueFn?) in one pass: a
+        // {key, count} or {key, sum} map per key, keys grouped and ordered as
+        // groupBy's. sumBy without valueFn sums the items themselves.
+        "countBy" | "sumBy" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let key_fn = it.next().and_then(|s| s.into_iter().next());
+            let value_fn = it.next().and_then(|s| s.into_iter().next());
+            let mut order: Vec<String> = Vec::new();
+            let mut totals: HashMap<String, f64> = HashMap::new();
+            for item in seq {
+                let key = match &key_fn {
+                    Some(kf) => to_string(&call_item(kf, vec![vec![item.clone()]], ctx)?),
+                    None => to_string(std::slice::from_ref(&item)),
+                };
+                let amount = match (name, &value_fn) {
+                    ("countBy", _) => 1.0,
+                    (_, Some(vf)) => to_number(&call_item(vf, vec![vec![item]], ctx)?)?,
+                    (_, None) => to_number(&[item])?,
+                };
+                match totals.get_mut(&key) {
+                    Some(total) => *total += amount,
+                    None => {
+                        order.push(key.clone());
+                        totals.insert(key, amount);
+                    }
+                }
+            }
+            let field = if name == "countBy" { "count" } else { "sum" };
+            let out: Seq = order
+                .into_iter()
+                .map(|k| {
+                    let total = totals[&k];
+                    let mut m: XMap = HashMap::new();
+                    m.insert("key".into(), vec![Item::Str(k)]);
+                    m.insert(field.into(), vec![Item::Num(total)]);
+                    Item::Map(Rc::new(m))
+                })
+                .collect();
+            Ok(out)
+        }
+        // Parts of a group made by groupBy or group-adjacent
+        "groupKey" | "groupItems" => {
             let seq = args.into_iter().next().unwrap_or_default();
-            let mut total = 0.0f64;
+            let field = if name == "groupKey" { "key" } else { "items" };
+            match seq.first() {
+                Some(Item::Map(m)) => Ok(m.get(field).cloned().unwrap_or_default()),
+                _ => Ok(vec![]),
+            }
+        }
+        // Map with the items the predicate accepts under `matched` and the
+        // rest under `unmatched`, both in input order
+        "partition" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let pred = match it.next().as_deref() {
+                Some([f @ (Item::FuncRef(..) | Item::Closure(_))]) => f.clone(),
+                _ => return Err("XFDY0002: partition expects a predicate function".into()),
+            };
+            let (mut matched, mut unmatched) = (Vec::new(), Vec::new());
+            for item in seq {
+                if to_boolean(&call_item(&pred, vec![vec![item.clone()]], ctx)?) {
+                    matched.push(item);
+                } else {
+                    unmatched.push(item);
+                }
+            }
+            let mut m: XMap = HashMap::new();
+            m.insert("matched".into(), matched);
+            m.insert("unmatched".into(), unmatched);
+            Ok(vec![Item::Map(Rc::new(m))])
+        }
+        "group-adjacent" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let key_seq = it.next();
+            let key_fn = key_seq.as_ref().and_then(|s| match s.first() {
+                Some(f @ (Item::FuncRef(..) | Item::Closure(_))) => Some(f.clone()),
+                _ => None,
+            });
+            // A new group starts whenever the key differs from the previous item's
+            let mut runs: Vec<(String, Seq)> = Vec::new();
+            for item in seq {
+                let key = if let Some(ref kf) = key_fn {
+                    to_string(&call_item(kf, vec![vec![item.clone()]], ctx)?)
+                } else {
+                    to_string(std::slice::from_ref(&item))
+                };
+                match runs.last_mut() {
+                    Some((k, items)) if *k == key => items.push(item),
+                    _ => runs.push((key, vec![item])),
+                }
+            }
+            let out: Seq = runs
+                .into_iter()
+                .map(|(k, items)| {
+                    let mut m: XMap = HashMap::new();
+                    m.insert("key".into(), vec![Item::Str(k)]);
+                    m.insert("items".into(), items);
+                    Item::Map(Rc::new(m))
+                })
+                .collect();
+            Ok(out)
+        }
+        // (seq, keyFn?, default?, options?) where default is the result for
+        // an empty sequence, otherwise 0 for sum and empty for the others, and
+        // the options map's "invalid" says what to do with an item that is not
+        // a number: "error", "skip", or a number to use in its place. Any
+        // other policy fails with XFDY0017
+        "sum" | "avg" | "min" | "max" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let mut rest = it.peekable();
+            let key_fn = match rest.peek().and_then(|s| s.first()) {
+                Some(f @ (Item::FuncRef(..) | Item::Closure(_))) => {
+                    let f = f.clone();
+                    rest.next();
+                    Some(f)
+                }
+                _ => None,
+            };
+            let (mut default, mut invalid) = (None, None);
+            for arg in rest {
+                match arg.as_slice() {
+                    [Item::Map(m)] => invalid = m.get("invalid").cloned(),
+                    _ => default = Some(arg),
+                }
+            }
+            enum Invalid {
+                Error,
+                Skip,
+                Use(f64),
+            }
+            let policy = match invalid.as_deref() {
+                None | Some([]) => Invalid::Error,
+                Some([Item::Num(n)]) => Invalid::Use(*n),
+                Some(policy) => match to_string(policy).as_str() {
+                    "error" => Invalid::Error,
+                    "skip" => Invalid::Skip,
+                    other => return Err(format!("XFDY0017: unknown invalid policy {:?}", other)),
+                },
+            };
+            let mut values = Vec::with_capacity(seq.len());
+            let mut skipped = 0;
             for item in seq {
-                total += to_number(&[item])?;
+                let v = match &key_fn {
+                    Some(kf) => to_number(&call_item(kf, vec![vec![item]], ctx)?),
+                    None => to_number(&[item]),
+                };
+                match (v, &policy) {
+                    (Ok(v), _) => values.push(v),
+                    (Err(_), Invalid::Skip) => skipped += 1,
+                    (Err(_), Invalid::Use(s)) => values.push(*s),
+                    (Err(e), Invalid::Error) => return Err(e),
+                }
+            }
+            if skipped > 0 {
+                if let Some(trace) = &ctx.options.trace {
+                    trace.on_items_skipped(name, skipped);
+                }
             }
-            Ok(vec![Item::Num(total)])
+            if values.is_empty() {
+                return Ok(match (default, name) {
+                    (Some(d), _) => d,
+                    (None, "sum") => vec![Item::Num(0.0)],
+                    (None, _) => vec![],
+                });
+            }
+            let result = match name {
+                "sum" => values.iter().sum(),
+                "avg" => values.iter().sum::<f64>() / values.len() as f64,
+                "min" => values.iter().copied().fold(f64::INFINITY, f64::min),
+                _ => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            };
+            Ok(vec![Item::Num(result)])
         }
+        // (seq, ruleset?, copy-unmatched?)
         "apply" => {
             let mut it = args.into_iter();
             let seq = it.next().unwrap_or_default();
@@ -751,36 +2819,271 @@ fn call_function(name: &str, args: Vec<Seq>, ctx: &Context) -> Result<Seq, Strin
                 .map(|s| to_string(s))
                 .filter(|s| !s.is_empty())
                 .unwrap_or_else(|| "main".into());
-            let rules = ctx.rules.get(&ruleset).cloned().unwrap_or_default();
+            let copy_unmatched = it.next().is_some_and(|s| to_boolean(&s));
+            apply_rules(seq, &ruleset, copy_unmatched, ctx)
+        }
+        // Functions the module defines, not builtins: their names sorted, the
+        // number of parameters of one, counting those with defaults, and
+        // whether one exists
+        "functionNames" => {
+            let mut names: Vec<&String> = ctx.functions.keys().collect();
+            names.sort();
+            Ok(names.into_iter().map(|n| Item::Str(n.clone())).collect())
+        }
+        "functionArity" | "functionExists" => {
+            let fname = to_string(&args.into_iter().next().unwrap_or_default());
+            let fd = ctx.functions.get(&fname);
+            Ok(match name {
+                "functionExists" => vec![Item::Bool(fd.is_some())],
+                _ => fd.map(|fd| Item::Num(fd.params.len() as f64)).into_iter().collect(),
+            })
+        }
+        // (seq, ruleset?): apply with unmatched nodes copied, so the rules
+        // rewrite what they match and everything else comes through as is
+        "transformWith" => {
+            let mut it = args.into_iter();
+            let seq = it.next().unwrap_or_default();
+            let ruleset = it
+                .next()
+                .map(|s| to_string(&s))
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(|| "main".into());
+            apply_rules(seq, &ruleset, true, ctx)
+        }
+        // Copy of a node with its children run through the current ruleset,
+        // for transforms that pass everything through except what rules match
+        "identity" => {
+            let seq = args.into_iter().next().unwrap_or_default();
+            let ruleset = ctx.ruleset.clone().unwrap_or_else(|| "main".into());
             let mut out = Vec::new();
             for item in seq {
-                let mut matched = false;
-                for rule in &rules {
-                    if let Some(bindings) = match_pattern(&rule.pattern, &item) {
-                        matched = true;
-                        let mut vars = ctx.variables.clone();
-                        vars.extend(bindings);
-                        let new_ctx = Context {
-                            context_item: Some(item.clone()),
-                            variables: vars,
-                            ..ctx.clone()
-                        };
-                        out.extend(eval_expr(&rule.body, &new_ctx)?);
-                        break;
+                out.extend(copy_through(item, &ruleset, ctx)?);
+            }
+            Ok(out)
+        }
+        _ => Err(format!("XFST0003: unknown function {}", name)),
+    }
+}
+
+/// Run each item through the first rule of `ruleset` whose pattern matches
+/// it. With `copy_unmatched`, an item no rule matches is copied instead of
+/// failing, see `copy_through`.
+fn apply_rules(
+    seq: Seq,
+    ruleset: &str,
+    copy_unmatched: bool,
+    ctx: &Context,
+) -> Result<Seq, String> {
+    let rules = ctx.rules.get(ruleset).cloned().unwrap_or_default();
+    let total = seq.len();
+    let mut out = Vec::new();
+    for (idx, item) in seq.into_iter().enumerate() {
+        let found = rules.iter().find_map(|r| match_pattern(&r.pattern, &item).map(|b| (r, b)));
+        match found {
+            Some((rule, bindings)) => {
+                if let Some(trace) = &ctx.options.trace {
+                    let at = match &item {
+                        Item::Node(n) => node_path(&ctx.root, n),
+                        other => format!("{:?}", to_string(std::slice::from_ref(other))),
+                    };
+                    let pattern = Printer::new(None, false).pattern(&rule.pattern);
+                    trace.on_rule_fired(ruleset, &pattern, &at);
+                }
+                let mut vars = ctx.variables.clone();
+                vars.extend(bindings);
+                let new_ctx = Context {
+                    context_item: Some(item.clone()),
+                    variables: vars,
+                    position: Some((idx + 1) as f64),
+                    last: Some(total as f64),
+                    ruleset: Some(ruleset.to_string()),
+                    ..ctx.clone()
+                };
+                match &ctx.options.profile {
+                    None => {
+                        let res = ctx.nested(|| eval_expr(&rule.body, &new_ctx))?;
+                        ctx.extend_seq(&mut out, res)?
+                    }
+                    Some(profile) => {
+                        let started = Instant::now();
+                        let res = ctx.nested(|| eval_expr(&rule.body, &new_ctx));
+                        profile.borrow_mut().rule_fired(rule.span, ruleset, started.elapsed());
+                        ctx.extend_seq(&mut out, res?)?;
                     }
                 }
-                if !matched {
-                    return Err("XFDY0001: no matching rule".into());
+            }
+            None if copy_unmatched => out.extend(copy_through(item, ruleset, ctx)?),
+            None => return Err("XFDY0001: no matching rule".into()),
+        }
+    }
+    Ok(out)
+}
+
+/// The identity template: an element is copied with its name, attributes
+/// and namespaces, its children going through `ruleset` again; a document
+/// yields its children that way. Other nodes are copied and atomic values
+/// pass through.
+fn copy_through(item: Item, ruleset: &str, ctx: &Context) -> Result<Seq, String> {
+    let node = match item {
+        Item::Node(n) => n,
+        other => return Ok(vec![other]),
+    };
+    let kids: Seq = node.children.iter().map(|c| Item::Node(c.clone())).collect();
+    match node.kind {
+        NodeKind::Document => apply_rules(kids, ruleset, true, ctx),
+        NodeKind::Element => {
+            let id = next_node_id();
+            let mut attrs = node.attrs.clone();
+            let mut children: Vec<Rc<XmlNode>> = Vec::new();
+            let mut text = String::new();
+            for item in apply_rules(kids, ruleset, true, ctx)? {
+                match item {
+                    Item::Node(n) if n.kind == NodeKind::Text => {
+                        text.push_str(n.value.as_deref().unwrap_or_default());
+                    }
+                    Item::Node(n) if n.kind == NodeKind::Attribute => {
+                        let aname = n.name.clone().unwrap_or_default();
+                        let value = n.value.clone().unwrap_or_default();
+                        match attrs.iter_mut().find(|(k, _)| *k == aname) {
+                            Some(existing) => existing.1 = value,
+                            None => attrs.push((aname, value)),
+                        }
+                    }
+                    Item::Node(n) => {
+                        if !text.is_empty() {
+                            children.push(make_text(&std::mem::take(&mut text)));
+                        }
+                        let floor = children.last().map_or(id, |c| c.id);
+                        children.push(adopt(n, floor));
+                    }
+                    other => text.push_str(&to_string(&[other])),
                 }
             }
-            Ok(out)
+            if !text.is_empty() {
+                children.push(make_text(&text));
+            }
+            ctx.add_nodes(1 + children.len() as u64)?;
+            let copy = link(XmlNode {
+                id,
+                kind: NodeKind::Element,
+                name: node.name.clone(),
+                value: None,
+                preserve_space: node.preserve_space || space_attr(&attrs).unwrap_or(false),
+                attrs,
+                namespaces: node.namespaces.clone(),
+                children,
+                parent: ParentLink::default(),
+            });
+            Ok(vec![Item::Node(copy)])
         }
-        _ => Err(format!("XFST0003: unknown function {}", name)),
+        _ => {
+            ctx.add_nodes(1)?;
+            Ok(vec![Item::Node(deep_copy(&node))])
+        }
+    }
+}
+
+/// Format `numbers` by an xsl:number picture such as "1.1", "(a)" or
+/// "I-1". Each alphanumeric token formats one number: digits as decimal
+/// padded to the token's width, `a`/`A` as letters, `i`/`I` as roman
+/// numerals. Extra numbers reuse the last token and separator.
+fn format_numbers(numbers: &[usize], picture: &str) -> String {
+    let chars: Vec<char> = picture.chars().collect();
+    let start = chars.iter().position(|c| c.is_alphanumeric()).unwrap_or(chars.len());
+    let end = chars.iter().rposition(|c| c.is_alphanumeric()).map_or(start, |i| i + 1);
+    let (mut tokens, mut seps): (Vec<String>, Vec<String>) = (Vec::new(), Vec::new());
+    let mut i = start;
+    while i < end {
+        let alnum = chars[i].is_alphanumeric();
+        let j = (i..end).find(|&k| chars[k].is_alphanumeric() != alnum).unwrap_or(end);
+        let run: String = chars[i..j].iter().collect();
+        if alnum { tokens.push(run) } else { seps.push(run) }
+        i = j;
+    }
+    if tokens.is_empty() {
+        tokens.push("1".into());
     }
+    let mut out: String = chars[..start].iter().collect();
+    for (idx, &n) in numbers.iter().enumerate() {
+        if idx > 0 {
+            out.push_str(seps.get(idx - 1).or(seps.last()).map_or(".", |s| s.as_str()));
+        }
+        let token = tokens.get(idx).or(tokens.last()).unwrap();
+        out.push_str(&format_number(n, token));
+    }
+    out.extend(&chars[end.max(start)..]);
+    out
+}
+
+fn format_number(n: usize, token: &str) -> String {
+    match token {
+        "a" | "A" if n > 0 => {
+            let mut letters = Vec::new();
+            let mut k = n;
+            while k > 0 {
+                k -= 1;
+                letters.push((b'a' + (k % 26) as u8) as char);
+                k /= 26;
+            }
+            let s: String = letters.into_iter().rev().collect();
+            if token == "A" { s.to_uppercase() } else { s }
+        }
+        "i" | "I" if n > 0 && n < 4000 => {
+            const NUMERALS: [(usize, &str); 13] = [
+                (1000, "m"), (900, "cm"), (500, "d"), (400, "cd"), (100, "c"), (90, "xc"),
+                (50, "l"), (40, "xl"), (10, "x"), (9, "ix"), (5, "v"), (4, "iv"), (1, "i"),
+            ];
+            let mut s = String::new();
+            let mut k = n;
+            for (value, numeral) in NUMERALS {
+                while k >= value {
+                    s.push_str(numeral);
+                    k -= value;
+                }
+            }
+            if token == "I" { s.to_uppercase() } else { s }
+        }
+        _ => format!("{:0width$}", n, width = token.len()),
+    }
+}
+
+/// 1-based position `pos` in a sequence of `len` items, with negative
+/// positions counted back from the end.
+fn from_end(pos: f64, len: usize) -> f64 {
+    if pos < 0.0 {
+        len as f64 + pos + 1.0
+    } else {
+        pos
+    }
+}
+
+/// Call a function item, a reference to a named function or a closure. Any
+/// other item fails with `XFDY0015`, as do builtins given one where they
+/// take a function.
+fn call_item(f: &Item, args: Vec<Seq>, ctx: &Context) -> Result<Seq, String> {
+    let closure = match f {
+        Item::FuncRef(name, _) => return call_function(name, args, ctx),
+        Item::Closure(c) => c,
+        _ => return Err("XFDY0015: not a function".into()),
+    };
+    let env_ctx = ctx.with_vars(closure.env.clone());
+    let mut vars = closure.env.clone();
+    for (i, param) in closure.params.iter().enumerate() {
+        if i < args.len() {
+            vars.insert(param.name.clone(), Rc::new(args[i].clone()));
+        } else if let Some(def) = &param.default {
+            vars.insert(param.name.clone(), Rc::new(eval_expr(def, &env_ctx)?));
+        } else {
+            return Err("XFDY0002: wrong arity for inline function".into());
+        }
+    }
+    ctx.nested(|| eval_expr(&closure.body, &ctx.with_vars(vars)))
 }
 
 // ── Coercions ────────────────────────────────────────────────────────────────
 
+/// Effective boolean value. A map is true only when it has entries; a function
+/// reference is always true.
 pub fn to_boolean(seq: &[Item]) -> bool {
     if seq.is_empty() {
         return false;
@@ -793,11 +3096,15 @@ pub fn to_boolean(seq: &[Item]) -> bool {
         Item::Num(n) => *n != 0.0,
         Item::Str(s) => !s.is_empty(),
         Item::Null => false,
-        Item::Map(_) | Item::FuncRef(_) => true,
+        Item::Map(m) => !m.is_empty(),
+        Item::Array(a) => !a.is_empty(),
+        Item::FuncRef(..) | Item::Closure(_) => true,
         Item::Node(_) => true,
     })
 }
 
+/// String value of the first item. A map or array is written as JSON, as with
+/// `SerializeOptions::maps_as_json`, and a function as by `function_repr`.
 pub fn to_string(seq: &[Item]) -> String {
     match seq.first() {
         None => String::new(),
@@ -806,8 +3113,9 @@ pub fn to_string(seq: &[Item]) -> String {
         Some(Item::Bool(b)) => if *b { "true".into() } else { "false".into() },
         Some(Item::Num(n)) => fmt_num(*n),
         Some(Item::Str(s)) => s.clone(),
-        Some(Item::Map(_)) => "[map]".into(),
-        Some(Item::FuncRef(s)) => s.clone(),
+        Some(Item::Map(m)) => map_to_json(m),
+        Some(item @ (Item::FuncRef(..) | Item::Closure(_))) => function_repr(item),
+        Some(item @ Item::Array(_)) => item_to_json(item),
     }
 }
 
@@ -828,31 +3136,636 @@ pub fn to_number(seq: &[Item]) -> Result<f64, String> {
     }
 }
 
-fn value_equal(left: &[Item], right: &[Item]) -> bool {
-    to_string(left) == to_string(right)
+fn value_equal(left: &[Item], right: &[Item], version: LangVersion) -> bool {
+    match version {
+        LangVersion::V2_0 => to_string(left) == to_string(right),
+        LangVersion::V2_1 => left.iter().any(|l| right.iter().any(|r| items_equal(l, r))),
+    }
 }
 
+/// Compile a pattern for `matches`, `replace` and `tokenize`. Flags: `i`
+/// case-insensitive, `m` multi-line, `s` dot matches newline, `x` ignore
+/// pattern whitespace, `u` Unicode classes. Unicode is on by default, so
+/// `\w`, `\d`, `\s` and `\p{L}` cover non-ASCII text.
+fn compile_regex(pattern: &str, flags: &str) -> Result<regex::Regex, String> {
+    let mut builder = regex::RegexBuilder::new(pattern);
+    builder.unicode(true);
+    for flag in flags.chars() {
+        match flag {
+            'i' => builder.case_insensitive(true),
+            'm' => builder.multi_line(true),
+            's' => builder.dot_matches_new_line(true),
+            'x' => builder.ignore_whitespace(true),
+            'u' => builder.unicode(true),
+            _ => return Err(format!("XFDY0005: unknown regex flag '{}'", flag)),
+        };
+    }
+    builder.build().map_err(|e| format!("XFDY0005: invalid regular expression: {}", e))
+}
+
+/// Whether `s` can name an element or attribute: an XML name, prefixed or
+/// not.
+fn is_xml_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))
+}
+
+/// String order of `compare`, `sort` and `order by ... collation`. A name
+/// not listed here fails with `XFDY0016`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Collation {
+    /// `codepoint`, also the empty name.
+    Codepoint,
+    /// `ci` or `case-insensitive`: codepoints after lower-casing.
+    CaseInsensitive,
+    /// `natural`: runs of ASCII digits compare by value, so `item2` sorts
+    /// before `item10`.
+    Natural,
+    /// `NFC`, `NFD`, `NFKC` or `NFKD`: codepoints in that normalization form.
+    #[cfg(feature = "unicode")]
+    Normalized(&'static str),
+    /// `uca`: the Unicode Collation Algorithm with the CLDR root order, where
+    /// accented letters sort with their base letter.
+    #[cfg(feature = "uca")]
+    Uca,
+}
+
+#[cfg(feature = "uca")]
+thread_local! {
+    static UCA: RefCell<feruca::Collator> = RefCell::new(feruca::Collator::default());
+}
+
+impl Collation {
+    fn parse(name: &str) -> Result<Collation, String> {
+        match name {
+            "" | "codepoint" => Ok(Collation::Codepoint),
+            "ci" | "case-insensitive" => Ok(Collation::CaseInsensitive),
+            "natural" => Ok(Collation::Natural),
+            #[cfg(feature = "unicode")]
+            _ if NORMALIZATION_FORMS.contains(&name) => {
+                let form = NORMALIZATION_FORMS.iter().find(|f| **f == name).unwrap();
+                Ok(Collation::Normalized(form))
+            }
+            #[cfg(not(feature = "unicode"))]
+            _ if NORMALIZATION_FORMS.contains(&name) => {
+                Err(format!("XFST0003: {} needs xform built with the `unicode` feature", name))
+            }
+            #[cfg(feature = "uca")]
+            "uca" => Ok(Collation::Uca),
+            #[cfg(not(feature = "uca"))]
+            "uca" => Err("XFST0003: uca needs xform built with the `uca` feature".into()),
+            _ => Err(format!("XFDY0016: unknown collation {:?}", name)),
+        }
+    }
+
+    fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Codepoint => a.cmp(b),
+            Collation::CaseInsensitive => a.to_lowercase().cmp(&b.to_lowercase()),
+            Collation::Natural => natural_cmp(a, b),
+            #[cfg(feature = "unicode")]
+            Collation::Normalized(form) => {
+                let a = normalize_unicode(a.to_string(), form).unwrap_or_default();
+                let b = normalize_unicode(b.to_string(), form).unwrap_or_default();
+                a.cmp(&b)
+            }
+            #[cfg(feature = "uca")]
+            Collation::Uca => UCA.with(|c| c.borrow_mut().collate(a, b)),
+        }
+    }
+}
+
+/// Compare runs of ASCII digits by value and everything else by codepoint,
+/// falling back to plain codepoint order between equal-valued strings such
+/// as `a01` and `a1`.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (mut x, mut y) = (a, b);
+    loop {
+        match (x.chars().next(), y.chars().next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(c), Some(d)) if c.is_ascii_digit() && d.is_ascii_digit() => {
+                let (m, n) = (digits(x), digits(y));
+                let p = x[..m].trim_start_matches('0');
+                let q = y[..n].trim_start_matches('0');
+                let ord = p.len().cmp(&q.len()).then_with(|| p.cmp(q));
+                if ord.is_ne() {
+                    return ord;
+                }
+                (x, y) = (&x[m..], &y[n..]);
+            }
+            (Some(c), Some(d)) => {
+                if c != d {
+                    return c.cmp(&d);
+                }
+                (x, y) = (&x[c.len_utf8()..], &y[d.len_utf8()..]);
+            }
+        }
+    }
+}
+
+const NORMALIZATION_FORMS: &[&str] = &["NFC", "NFD", "NFKC", "NFKD"];
+
+/// `s` in Unicode normalization `form`, one of `NORMALIZATION_FORMS`.
+#[cfg(feature = "unicode")]
+fn normalize_unicode(s: String, form: &str) -> Result<String, String> {
+    use unicode_normalization::UnicodeNormalization;
+    match form {
+        "NFC" => Ok(s.nfc().collect()),
+        "NFD" => Ok(s.nfd().collect()),
+        "NFKC" => Ok(s.nfkc().collect()),
+        "NFKD" => Ok(s.nfkd().collect()),
+        _ => Err(format!("XFDY0002: unknown normalization form {:?}", form)),
+    }
+}
+
+#[cfg(not(feature = "unicode"))]
+fn normalize_unicode(_s: String, form: &str) -> Result<String, String> {
+    if NORMALIZATION_FORMS.contains(&form) {
+        Err(format!("XFST0003: {} needs xform built with the `unicode` feature", form))
+    } else {
+        Err(format!("XFDY0002: unknown normalization form {:?}", form))
+    }
+}
+
+/// Read the file a transform refers to, subject to `EvalOptions::access`.
+/// Every builtin that touches the filesystem goes through here.
+fn read_resource(uri: &str, ctx: &Context) -> Result<String, String> {
+    read_allowed(uri, &resource_path(uri, ctx.options.base_dir.as_deref()), &ctx.options)
+}
+
+/// `rel` resolved against `base` the way RFC 3986 merges paths: it takes
+/// the place of the last segment of `base`, and `.` and `..` segments are
+/// removed. URIs with a scheme stand as they are, and an absolute path
+/// keeps only the scheme and host of `base`. File paths are treated like
+/// URI paths.
+fn resolve_uri(rel: &str, base: &str) -> String {
+    let has_scheme = rel.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.len() > 1
+            && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    });
+    if has_scheme || base.is_empty() {
+        return rel.to_string();
+    }
+    let base = base.split('#').next().unwrap_or_default();
+    if rel.is_empty() || rel.starts_with('#') {
+        return format!("{}{}", base, rel);
+    }
+    let base = base.split('?').next().unwrap_or_default();
+    if rel.starts_with('?') {
+        return format!("{}{}", base, rel);
+    }
+    let (authority, path) = match base.find("://") {
+        Some(i) => base.split_at(base[i + 3..].find('/').map_or(base.len(), |j| i + 3 + j)),
+        None => ("", base),
+    };
+    let merged = match path.rfind('/') {
+        _ if rel.starts_with('/') => rel.to_string(),
+        Some(i) => format!("{}{}", &path[..=i], rel),
+        None if !authority.is_empty() => format!("/{}", rel),
+        None => rel.to_string(),
+    };
+    format!("{}{}", authority, remove_dot_segments(&merged))
+}
+
+/// `path` without `.` segments and with each `..` taking away the segment
+/// before it. A relative path keeps the `..` it cannot resolve.
+fn remove_dot_segments(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').collect();
+    let mut out: Vec<&str> = Vec::new();
+    for (i, seg) in segments.iter().enumerate() {
+        match *seg {
+            "." => {}
+            ".." => match out.last() {
+                None | Some(&"..") => out.push(".."),
+                Some(&"") if out.len() == 1 => {}
+                Some(_) => {
+                    out.pop();
+                }
+            },
+            s => out.push(s),
+        }
+        if i == segments.len() - 1 && (*seg == "." || *seg == "..") {
+            out.push("");
+        }
+    }
+    out.join("/")
+}
+
+/// Path of `uri` resolved against `base`, or the working directory.
+pub(crate) fn resource_path(uri: &str, base: Option<&Path>) -> PathBuf {
+    let rel = uri.strip_prefix("file://").unwrap_or(uri);
+    match base {
+        Some(base) => base.join(rel),
+        None => PathBuf::from(rel),
+    }
+}
+
+/// Read `path`, which `uri` names, if `opts.access` allows it.
+pub(crate) fn read_allowed(uri: &str, path: &Path, opts: &EvalOptions) -> Result<String, String> {
+    let allowed = match &opts.access {
+        AccessPolicy::Deny => false,
+        AccessPolicy::AllowAll => true,
+        AccessPolicy::AllowUnder(root) => match (path.canonicalize(), root.canonicalize()) {
+            (Ok(p), Ok(r)) => p.starts_with(r),
+            _ => false,
+        },
+        AccessPolicy::Custom(allow) => allow(uri),
+    };
+    if !allowed {
+        return Err(format!("XFSE0001: access denied to {}", uri));
+    }
+    std::fs::read_to_string(path).map_err(|e| format!("XFDY0003: cannot read {}: {}", uri, e))
+}
+
+impl EvalState {
+    /// Advance the random stream.
+    fn next_random(&self) -> u64 {
+        let mut state = self.rng.get();
+        let value = splitmix64(&mut state);
+        self.rng.set(state);
+        value
+    }
+}
+
+/// SplitMix64: small, fast and statistically sound for identifiers and
+/// sampling, though not for cryptography.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// `bits` as a number in [0, 1), from its top 53 bits.
+fn unit_interval(bits: u64) -> f64 {
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A seed that differs between runs, from the randomly keyed std hasher and
+/// the clock.
+fn random_seed() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+    if let Ok(t) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        h.write_u128(t.as_nanos());
+    }
+    h.finish()
+}
+
+/// 64-bit FNV-1a, the algorithm behind `hash()`. Stable across runs and
+/// platforms; not suitable where collisions must be hard to find.
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h
+}
+
+/// Put the `separated-by` text before every iteration's result but the first.
+fn push_separated(out: &mut Seq, separator: &Option<String>, emitted: &mut bool) {
+    if let Some(sep) = separator {
+        if *emitted {
+            out.push(Item::Node(make_text(sep)));
+        }
+        *emitted = true;
+    }
+}
+
+/// Order two sort keys: by value when `numeric` (NaN last), otherwise by
+/// string value.
+fn cmp_sort_keys(a: &[Item], b: &[Item], numeric: bool, collation: Collation) -> Ordering {
+    match (a, b) {
+        ([Item::Num(x)], [Item::Num(y)]) if numeric => {
+            x.partial_cmp(y).unwrap_or_else(|| x.is_nan().cmp(&y.is_nan()))
+        }
+        _ => collation.compare(&to_string(a), &to_string(b)),
+    }
+}
+
+/// The text node values `text()` collects from `node`: its string value in
+/// pieces when `deep`, else its direct text children.
+fn text_pieces(node: &XmlNode, deep: bool, out: &mut Vec<String>) {
+    if !deep {
+        let direct = node.children.iter().filter(|c| c.kind == NodeKind::Text);
+        out.extend(direct.map(|c| c.value.clone().unwrap_or_default()));
+        return;
+    }
+    match node.kind {
+        NodeKind::Text | NodeKind::Attribute => out.push(node.value.clone().unwrap_or_default()),
+        NodeKind::Element | NodeKind::Document => {
+            for c in &node.children {
+                text_pieces(c, true, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `s` trimmed, with each run of whitespace replaced by one space.
+fn collapse_space(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Key of `item` for distinct, index and groupBy: its string value, or for
+/// a node with `by_node` its identity.
+fn item_key(item: &Item, by_node: bool) -> String {
+    match item {
+        Item::Node(n) if by_node => format!("n{}", n.id),
+        _ => to_string(std::slice::from_ref(item)),
+    }
+}
+
+/// Key of a node by identity, except for attributes, which are made afresh
+/// by each step that reaches them and so go by name and value.
+fn node_key(n: &XmlNode) -> String {
+    match n.kind {
+        NodeKind::Attribute => {
+            format!("a{}={}", n.name.as_deref().unwrap_or(""), n.value.as_deref().unwrap_or(""))
+        }
+        _ => format!("n{}", n.id),
+    }
+}
+
+/// Key of `item` for `except` and `intersect`: nodes by `node_key`, other
+/// items by type and value.
+fn set_key(item: &Item) -> String {
+    match item {
+        Item::Node(n) => node_key(n),
+        Item::Num(n) => format!("d{}", num_key(*n)),
+        Item::Bool(b) => format!("b{}", b),
+        Item::Str(s) => format!("s{}", s),
+        other => format!("o{}", to_string(std::slice::from_ref(other))),
+    }
+}
+
+/// Hash key for a number that treats 0 and -0, and all NaNs, as equal.
+fn num_key(n: f64) -> u64 {
+    if n == 0.0 {
+        0
+    } else if n.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        n.to_bits()
+    }
+}
+
+/// Item-by-item equality of two sequences, comparing nodes by structure
+/// and values by type and value.
+fn deep_equal(a: &[Item], b: &[Item]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|pair| match pair {
+            (Item::Node(x), Item::Node(y)) => nodes_equal(x, y),
+            (Item::Str(x), Item::Str(y)) => x == y,
+            (Item::Num(x), Item::Num(y)) => x == y || (x.is_nan() && y.is_nan()),
+            (Item::Bool(x), Item::Bool(y)) => x == y,
+            (Item::Null, Item::Null) => true,
+            (Item::Map(x), Item::Map(y)) => {
+                x.len() == y.len()
+                    && x.iter().all(|(k, v)| y.get(k).is_some_and(|w| deep_equal(v, w)))
+            }
+            (Item::FuncRef(x, _), Item::FuncRef(y, _)) => x == y,
+            (Item::Closure(x), Item::Closure(y)) => Rc::ptr_eq(x, y),
+            (Item::Array(x), Item::Array(y)) => {
+                x.len() == y.len() && x.iter().zip(y.iter()).all(|(v, w)| deep_equal(v, w))
+            }
+            _ => false,
+        })
+}
+
+fn nodes_equal(a: &Rc<XmlNode>, b: &Rc<XmlNode>) -> bool {
+    let sorted = |n: &XmlNode| {
+        let mut attrs = n.attrs.clone();
+        attrs.sort();
+        attrs
+    };
+    Rc::ptr_eq(a, b)
+        || (a.kind == b.kind
+            && a.name == b.name
+            && a.value == b.value
+            && sorted(a) == sorted(b)
+            && a.children.len() == b.children.len()
+            && a.children.iter().zip(&b.children).all(|(x, y)| nodes_equal(x, y)))
+}
+
+/// Typed equality of two items: a number compares numerically against anything
+/// convertible to a number, all other pairs compare by string value.
+fn items_equal(a: &Item, b: &Item) -> bool {
+    match (a, b) {
+        (Item::Num(x), other) | (other, Item::Num(x)) => {
+            to_number(std::slice::from_ref(other)).is_ok_and(|y| *x == y)
+        }
+        _ => to_string(std::slice::from_ref(a)) == to_string(std::slice::from_ref(b)),
+    }
+}
+
+/// Significant digits `fmt_num` keeps of a number that is not an integer,
+/// as for an XPath `xs:double`. Fewer than the 17 a double can need, so
+/// `0.1 + 0.2` prints as `0.3`.
+const NUM_SIGNIFICANT_DIGITS: usize = 15;
+
+/// Integers print exactly, up to 2^53 as computed; other numbers rounded to
+/// `NUM_SIGNIFICANT_DIGITS`.
 pub fn fmt_num(n: f64) -> String {
-    if n.fract() == 0.0 && n.is_finite() && n.abs() < 1e15 {
+    if n.fract() == 0.0 && n.abs() <= 9007199254740992.0 {
         format!("{}", n as i64)
+    } else if n.fract() == 0.0 {
+        format!("{}", n)
+    } else if n.is_finite() {
+        // Shortest form of the value rounded to the significant digits
+        let rounded: f64 = format!("{:.*e}", NUM_SIGNIFICANT_DIGITS - 1, n).parse().unwrap_or(n);
+        if rounded.fract() == 0.0 && rounded.abs() < 1e15 {
+            format!("{}", rounded as i64)
+        } else {
+            format!("{}", rounded)
+        }
     } else {
-        // Use Python-compatible repr for simple decimals
-        let s = format!("{}", n);
-        s
-    }
-}
-
-pub fn serialize_items(items: &Seq) -> String {
-    items
-        .iter()
-        .map(|item| match item {
-            Item::Node(n) => serialize(n),
-            Item::Str(s) => s.clone(),
-            Item::Num(n) => fmt_num(*n),
-            Item::Bool(b) => if *b { "true".into() } else { "false".into() },
-            Item::Null => String::new(),
-            Item::Map(_) => String::new(),
-            Item::FuncRef(_) => String::new(),
-        })
-        .collect()
+        format!("{}", n)
+    }
+}
+
+/// Controls how `serialize_items` renders items that have no XML form.
+#[derive(Clone, Debug, Default)]
+pub struct SerializeOptions {
+    /// Render maps and arrays as JSON and functions as `function_repr` does
+    /// instead of failing with `XFDY0014`.
+    pub maps_as_json: bool,
+    /// Fail with `XFLM0004` when the output grows past this many bytes.
+    pub max_output_bytes: Option<usize>,
+    /// What to do with characters XML does not allow in node output.
+    pub invalid_chars: InvalidChars,
+    /// Write an XML declaration before each document node.
+    pub xml_declaration: bool,
+    /// Put the children of element-only content on lines of their own,
+    /// indented by this many spaces per level; see `xmlmodel::indent_tree`.
+    pub indent: Option<usize>,
+    /// Write attributes in name order. Otherwise they keep the order of the
+    /// node: name order for parsed elements, and for constructed ones the
+    /// order they were written in, an attribute set again staying in place.
+    pub sort_attributes: bool,
+}
+
+impl SerializeOptions {
+    /// Options from a `serialize()` options map, on top of the defaults.
+    /// Unknown keys fail with `XFDY0002`.
+    fn from_map(map: &XMap) -> Result<SerializeOptions, String> {
+        let mut opts = SerializeOptions::default();
+        for (key, value) in map {
+            match key.as_str() {
+                "indent" => {
+                    opts.indent = match value.as_slice() {
+                        [Item::Bool(b)] => b.then_some(2),
+                        _ => Some(to_number(value)?.max(0.0) as usize),
+                    }
+                }
+                "xmlDecl" => opts.xml_declaration = to_boolean(value),
+                "sortAttributes" => opts.sort_attributes = to_boolean(value),
+                "jsonMaps" => opts.maps_as_json = to_boolean(value),
+                "invalidChars" => {
+                    opts.invalid_chars = match to_string(value).as_str() {
+                        "replace" => InvalidChars::Replace,
+                        "strip" => InvalidChars::Strip,
+                        "error" => InvalidChars::Error,
+                        other => {
+                            return Err(format!("XFDY0002: unknown invalidChars {:?}", other))
+                        }
+                    }
+                }
+                "maxOutputBytes" => opts.max_output_bytes = Some(to_number(value)? as usize),
+                _ => return Err(format!("XFDY0002: unknown serialization option {:?}", key)),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+pub fn serialize_items(items: &Seq) -> Result<String, String> {
+    serialize_items_with(items, &SerializeOptions::default())
+}
+
+pub fn serialize_items_with(items: &Seq, opts: &SerializeOptions) -> Result<String, String> {
+    let mut out = String::new();
+    for item in items {
+        match item {
+            Item::Node(n) => {
+                if opts.xml_declaration && n.kind == NodeKind::Document {
+                    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+                }
+                let sorted;
+                let n = if opts.sort_attributes {
+                    sorted = sort_attributes(n);
+                    &sorted
+                } else {
+                    n
+                };
+                match opts.indent {
+                    Some(width) => {
+                        out.push_str(&serialize_with(&indent_tree(n, width), opts.invalid_chars)?)
+                    }
+                    None => out.push_str(&serialize_with(n, opts.invalid_chars)?),
+                }
+            }
+            Item::Str(s) => out.push_str(s),
+            Item::Num(n) => out.push_str(&fmt_num(*n)),
+            Item::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Item::Null => {}
+            Item::Map(m) if opts.maps_as_json => out.push_str(&map_to_json(m)),
+            Item::FuncRef(..) | Item::Closure(_) if opts.maps_as_json => {
+                out.push_str(&function_repr(item))
+            }
+            Item::Array(_) if opts.maps_as_json => out.push_str(&item_to_json(item)),
+            Item::Array(_) => return Err("XFDY0014: cannot serialize an array".into()),
+            Item::Map(m) => {
+                let mut keys: Vec<&String> = m.keys().collect();
+                keys.sort();
+                let keys: Vec<&str> = keys.into_iter().map(|k| k.as_str()).collect();
+                return Err(format!(
+                    "XFDY0014: cannot serialize a map [map: {}]",
+                    keys.join(", ")
+                ));
+            }
+            Item::FuncRef(..) | Item::Closure(_) => {
+                return Err(format!("XFDY0014: cannot serialize {}", function_repr(item)));
+            }
+        }
+        if let Some(max) = opts.max_output_bytes {
+            if out.len() > max {
+                return Err(format!("XFLM0004: output exceeds limit of {} bytes", max));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// JSON object for a map, with keys sorted for stable output. Single-item
+/// values are written bare, other sequences as arrays.
+fn map_to_json(m: &XMap) -> String {
+    let mut keys: Vec<&String> = m.keys().collect();
+    keys.sort();
+    let fields: Vec<String> = keys
+        .into_iter()
+        .map(|k| format!("{}:{}", json_string(k), seq_to_json(&m[k])))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn seq_to_json(seq: &[Item]) -> String {
+    if seq.len() == 1 {
+        return item_to_json(&seq[0]);
+    }
+    let parts: Vec<String> = seq.iter().map(item_to_json).collect();
+    format!("[{}]", parts.join(","))
+}
+
+/// Item for an element of the `json_to_xml` vocabulary.
+fn json_item(node: &Rc<XmlNode>) -> Item {
+    let members = node.children.iter().filter(|c| c.kind == NodeKind::Element);
+    match node.name.as_deref().unwrap_or("") {
+        "map" => Item::Map(Rc::new(
+            members
+                .map(|c| {
+                    let key = c.attrs.iter().find(|(k, _)| k == "key").map(|(_, v)| v.clone());
+                    (key.unwrap_or_default(), vec![json_item(c)])
+                })
+                .collect(),
+        )),
+        "array" => Item::Array(Rc::new(members.map(|c| vec![json_item(c)]).collect())),
+        "number" => Item::Num(node.string_value().parse().unwrap_or(f64::NAN)),
+        "boolean" => Item::Bool(node.string_value() == "true"),
+        "null" => Item::Null,
+        _ => Item::Str(node.string_value()),
+    }
+}
+
+/// `function name#arity` for a named function, `function fn#arity` for an
+/// inline one.
+fn function_repr(item: &Item) -> String {
+    match item {
+        Item::FuncRef(name, arity) => format!("function {}#{}", name, arity),
+        Item::Closure(c) => format!("function fn#{}", c.params.len()),
+        _ => to_string(std::slice::from_ref(item)),
+    }
+}
+
+fn item_to_json(item: &Item) -> String {
+    match item {
+        Item::Node(n) => json_string(&serialize(n)),
+        Item::Str(s) => json_string(s),
+        Item::Num(n) if n.is_finite() => fmt_num(*n),
+        Item::Num(_) | Item::Null => "null".into(),
+        Item::Bool(b) => if *b { "true".into() } else { "false".into() },
+        Item::Map(m) => map_to_json(m),
+        Item::FuncRef(..) | Item::Closure(_) => json_string(&function_repr(item)),
+        Item::Array(a) => {
+            let members: Vec<String> = a.iter().map(|m| seq_to_json(m)).collect();
+            format!("[{}]", members.join(","))
+        }
+    }
 }
@@ -0,0 +1,391 @@
+//! Lenient HTML input, mapped into the same tree `parse_xml` builds. This is
+//! a recovery parser rather than the HTML5 algorithm: it never fails, closes
+//! what the markup leaves open the way browsers do for the common cases
+//! (`<li>`, `<p>`, table cells, `<option>`), and knows void and raw-text
+//! elements and the usual named entities. Element names are lowercased;
+//! attributes keep their names and values as written.
+
+use std::rc::Rc;
+
+use crate::xmlmodel::{
+    make_document, make_element, make_text, next_node_id, NodeKind, ParentLink, XmlNode,
+};
+
+const VOID: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Elements whose content is text up to the matching end tag.
+const RAW_TEXT: &[&str] = &["script", "style", "textarea", "title"];
+
+/// Elements whose start tag ends an open `<p>`.
+const CLOSES_P: &[&str] = &[
+    "address", "article", "aside", "blockquote", "details", "div", "dl", "fieldset", "figure",
+    "footer", "form", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hr", "main", "nav", "ol", "p",
+    "pre", "section", "table", "ul",
+];
+
+/// Named entities recognized without a DTD, besides the five XML ones.
+const ENTITIES: &[(&str, char)] = &[
+    ("nbsp", '\u{a0}'),
+    ("copy", '©'),
+    ("reg", '®'),
+    ("trade", '™'),
+    ("euro", '€'),
+    ("pound", '£'),
+    ("yen", '¥'),
+    ("cent", '¢'),
+    ("sect", '§'),
+    ("para", '¶'),
+    ("deg", '°'),
+    ("plusmn", '±'),
+    ("times", '×'),
+    ("divide", '÷'),
+    ("middot", '·'),
+    ("bull", '•'),
+    ("hellip", '…'),
+    ("ndash", '–'),
+    ("mdash", '—'),
+    ("lsquo", '‘'),
+    ("rsquo", '’'),
+    ("sbquo", '‚'),
+    ("ldquo", '“'),
+    ("rdquo", '”'),
+    ("bdquo", '„'),
+    ("laquo", '«'),
+    ("raquo", '»'),
+    ("shy", '\u{ad}'),
+    ("iexcl", '¡'),
+    ("iquest", '¿'),
+    ("auml", 'ä'),
+    ("ouml", 'ö'),
+    ("uuml", 'ü'),
+    ("Auml", 'Ä'),
+    ("Ouml", 'Ö'),
+    ("Uuml", 'Ü'),
+    ("szlig", 'ß'),
+    ("eacute", 'é'),
+    ("egrave", 'è'),
+    ("agrave", 'à'),
+    ("ccedil", 'ç'),
+];
+
+/// Open element: (id, name, attrs, children). The id is taken at the start
+/// tag so the element precedes its content, as in `parse_xml`.
+type Frame = (u64, String, Vec<(String, String)>, Vec<Rc<XmlNode>>);
+
+/// Parse HTML into a document node. Any input parses; markup that cannot be
+/// read as a tag is kept as text.
+pub fn parse_html(text: &str) -> Rc<XmlNode> {
+    let doc_id = next_node_id();
+    let mut p = HtmlParser { chars: text.chars().collect(), pos: 0, stack: Vec::new() };
+    let mut top: Vec<Rc<XmlNode>> = Vec::new();
+    p.run(&mut top);
+    while !p.stack.is_empty() {
+        p.close_top(&mut top);
+    }
+    make_document(doc_id, top)
+}
+
+struct HtmlParser {
+    chars: Vec<char>,
+    pos: usize,
+    stack: Vec<Frame>,
+}
+
+impl HtmlParser {
+    fn run(&mut self, top: &mut Vec<Rc<XmlNode>>) {
+        let mut text = String::new();
+        while self.pos < self.chars.len() {
+            if self.chars[self.pos] != '<' {
+                if self.chars[self.pos] == '&' {
+                    text.push_str(&self.entity());
+                } else {
+                    text.push(self.chars[self.pos]);
+                    self.pos += 1;
+                }
+                continue;
+            }
+            let markup = self.starts_with("<!--")
+                || self.starts_with("<!")
+                || self.starts_with("<?")
+                || self.starts_with("</")
+                || self.chars.get(self.pos + 1).is_some_and(|c| c.is_ascii_alphabetic());
+            if !markup {
+                text.push('<');
+                self.pos += 1;
+                continue;
+            }
+            self.flush_text(&mut text, top);
+            if self.starts_with("<!--") {
+                self.comment(top);
+            } else if self.starts_with("<!") || self.starts_with("<?") {
+                // Doctype and processing instructions carry nothing we keep
+                self.skip_past(">");
+            } else if self.starts_with("</") {
+                self.end_tag(top);
+            } else {
+                self.start_tag(top);
+            }
+        }
+        self.flush_text(&mut text, top);
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    /// Move past the next `end`, or to the end of the input.
+    fn skip_past(&mut self, end: &str) -> String {
+        let start = self.pos;
+        while self.pos < self.chars.len() && !self.starts_with(end) {
+            self.pos += 1;
+        }
+        let skipped = self.chars[start..self.pos].iter().collect();
+        self.pos = (self.pos + end.chars().count()).min(self.chars.len());
+        skipped
+    }
+
+    fn children<'a>(&'a mut self, top: &'a mut Vec<Rc<XmlNode>>) -> &'a mut Vec<Rc<XmlNode>> {
+        match self.stack.last_mut() {
+            Some(frame) => &mut frame.3,
+            None => top,
+        }
+    }
+
+    fn flush_text(&mut self, text: &mut String, top: &mut Vec<Rc<XmlNode>>) {
+        if !text.is_empty() {
+            let node = make_text(&std::mem::take(text));
+            self.children(top).push(node);
+        }
+    }
+
+    fn comment(&mut self, top: &mut Vec<Rc<XmlNode>>) {
+        self.pos += 4;
+        let value = self.skip_past("-->");
+        let node = Rc::new(XmlNode {
+            id: next_node_id(),
+            kind: NodeKind::Comment,
+            name: None,
+            value: Some(value),
+            attrs: vec![],
+            namespaces: vec![],
+            children: vec![],
+            preserve_space: false,
+            parent: ParentLink::default(),
+        });
+        self.children(top).push(node);
+    }
+
+    fn name(&mut self) -> String {
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| {
+            !c.is_whitespace() && !matches!(c, '/' | '>' | '=' | '"' | '\'' | '<')
+        }) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn start_tag(&mut self, top: &mut Vec<Rc<XmlNode>>) {
+        self.pos += 1;
+        let name = self.name().to_ascii_lowercase();
+        let mut attrs: Vec<(String, String)> = Vec::new();
+        let mut self_closing = false;
+        loop {
+            self.skip_ws();
+            match self.chars.get(self.pos) {
+                None => break,
+                Some('>') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('/') => {
+                    self.pos += 1;
+                    self_closing = self.chars.get(self.pos) == Some(&'>');
+                }
+                Some(_) => {
+                    let aname = self.name();
+                    if aname.is_empty() {
+                        // A stray quote or `=`; drop it
+                        self.pos += 1;
+                        continue;
+                    }
+                    self.skip_ws();
+                    let value = if self.chars.get(self.pos) == Some(&'=') {
+                        self.pos += 1;
+                        self.skip_ws();
+                        self.attr_value()
+                    } else {
+                        // A boolean attribute such as `checked`
+                        aname.clone()
+                    };
+                    if !attrs.iter().any(|(k, _)| *k == aname) {
+                        attrs.push((aname, value));
+                    }
+                }
+            }
+        }
+        self.close_implied(&name, top);
+        let id = next_node_id();
+        if VOID.contains(&name.as_str()) || self_closing {
+            let node = make_element(id, &name, attrs, vec![], vec![]);
+            self.children(top).push(node);
+            return;
+        }
+        if RAW_TEXT.contains(&name.as_str()) {
+            let raw = self.raw_text(&name);
+            let content = if name == "textarea" || name == "title" { decode(&raw) } else { raw };
+            let children = if content.is_empty() { vec![] } else { vec![make_text(&content)] };
+            let node = make_element(id, &name, attrs, vec![], children);
+            self.children(top).push(node);
+            return;
+        }
+        self.stack.push((id, name, attrs, vec![]));
+    }
+
+    /// Text up to the end tag of raw-text element `name`, moving past it.
+    /// The end tag matches in any ASCII case and only as a whole name, so
+    /// `</SCRIPT >` ends a script and `</scripts>` does not.
+    fn raw_text(&mut self, name: &str) -> String {
+        let start = self.pos;
+        while self.pos < self.chars.len() && !self.at_end_tag(name) {
+            self.pos += 1;
+        }
+        let raw = self.chars[start..self.pos].iter().collect();
+        self.skip_past(">");
+        raw
+    }
+
+    fn at_end_tag(&self, name: &str) -> bool {
+        let end = self.pos + 2 + name.len();
+        self.starts_with("</")
+            && self.chars.get(self.pos + 2..end).is_some_and(|tag| {
+                tag.iter().zip(name.chars()).all(|(a, b)| a.eq_ignore_ascii_case(&b))
+            })
+            && self.chars.get(end).is_none_or(|c| c.is_whitespace() || matches!(c, '/' | '>'))
+    }
+
+    fn attr_value(&mut self) -> String {
+        match self.chars.get(self.pos) {
+            Some(&q @ ('"' | '\'')) => {
+                self.pos += 1;
+                decode(&self.skip_past(&q.to_string()))
+            }
+            _ => {
+                let start = self.pos;
+                while self.chars.get(self.pos).is_some_and(|c| !c.is_whitespace() && *c != '>') {
+                    self.pos += 1;
+                }
+                decode(&self.chars[start..self.pos].iter().collect::<String>())
+            }
+        }
+    }
+
+    fn end_tag(&mut self, top: &mut Vec<Rc<XmlNode>>) {
+        self.pos += 2;
+        let name = self.name().to_ascii_lowercase();
+        self.skip_past(">");
+        // An end tag with nothing open to match is ignored
+        if let Some(depth) = self.stack.iter().rposition(|f| f.1 == name) {
+            while self.stack.len() > depth {
+                self.close_top(top);
+            }
+        }
+    }
+
+    /// Close the elements a start tag `name` ends implicitly.
+    fn close_implied(&mut self, name: &str, top: &mut Vec<Rc<XmlNode>>) {
+        let (closes, scope): (&[&str], &[&str]) = match name {
+            "li" => (&["li"], &["ul", "ol", "menu"]),
+            "dt" | "dd" => (&["dt", "dd"], &["dl"]),
+            "tr" => (&["tr", "td", "th"], &["table", "thead", "tbody", "tfoot"]),
+            "td" | "th" => (&["td", "th"], &["tr", "table"]),
+            "thead" | "tbody" | "tfoot" => {
+                (&["thead", "tbody", "tfoot", "tr", "td", "th"], &["table"])
+            }
+            "option" => (&["option"], &["select", "datalist", "optgroup"]),
+            "optgroup" => (&["optgroup", "option"], &["select"]),
+            _ if CLOSES_P.contains(&name) => (&["p"], &["div", "td", "th", "li", "body"]),
+            _ => return,
+        };
+        // The outermost such element inside the nearest enclosing scope
+        let floor =
+            self.stack.iter().rposition(|f| scope.contains(&f.1.as_str())).map_or(0, |i| i + 1);
+        let found =
+            (floor..self.stack.len()).find(|&i| closes.contains(&self.stack[i].1.as_str()));
+        if let Some(depth) = found {
+            while self.stack.len() > depth {
+                self.close_top(top);
+            }
+        }
+    }
+
+    fn close_top(&mut self, top: &mut Vec<Rc<XmlNode>>) {
+        if let Some((id, name, attrs, children)) = self.stack.pop() {
+            let node = make_element(id, &name, attrs, vec![], children);
+            self.children(top).push(node);
+        }
+    }
+
+    /// The character reference at `pos`, or a lone `&` when there is none.
+    fn entity(&mut self) -> String {
+        let end = (self.pos + 1..self.chars.len().min(self.pos + 34))
+            .find(|&i| {
+                let c = self.chars[i];
+                c == ';' || !(c.is_alphanumeric() || c == '#')
+            });
+        if let Some(end) = end.filter(|&i| self.chars[i] == ';') {
+            let body: String = self.chars[self.pos + 1..end].iter().collect();
+            if let Some(c) = resolve(&body) {
+                self.pos = end + 1;
+                return c.to_string();
+            }
+        }
+        self.pos += 1;
+        "&".into()
+    }
+}
+
+fn resolve(body: &str) -> Option<char> {
+    if let Some(num) = body.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    match body {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ => ENTITIES.iter().find(|(name, _)| *name == body).map(|(_, c)| *c),
+    }
+}
+
+/// `s` with its character references replaced.
+fn decode(s: &str) -> String {
+    if !s.contains('&') {
+        return s.to_string();
+    }
+    let mut p = HtmlParser { chars: s.chars().collect(), pos: 0, stack: Vec::new() };
+    let mut out = String::new();
+    while p.pos < p.chars.len() {
+        if p.chars[p.pos] == '&' {
+            out.push_str(&p.entity());
+        } else {
+            out.push(p.chars[p.pos]);
+            p.pos += 1;
+        }
+    }
+    out
+}
@@ -0,0 +1,323 @@
+//! JSON as XML in the XPath 3.1 convention, used by `json-to-xml` and
+//! `xml-to-json`: objects become `<map>`, arrays `<array>`, and scalars
+//! `<string>`, `<number>`, `<boolean>` and `<null/>`. Entries of a map carry
+//! their name in a `key` attribute.
+
+use std::rc::Rc;
+
+use crate::xmlmodel::{make_document, make_element, make_text, next_node_id, NodeKind, XmlNode};
+
+/// Namespace of the element vocabulary, declared on the outermost element.
+pub const FN_NS: &str = "http://www.w3.org/2005/xpath-functions";
+
+/// Parse JSON text into a document node holding the converted value.
+pub fn json_to_xml(text: &str) -> Result<Rc<XmlNode>, String> {
+    let doc_id = next_node_id();
+    let namespaces = vec![(String::new(), FN_NS.to_string())];
+    let mut p = JsonParser { chars: text.chars().collect(), pos: 0, namespaces };
+    p.skip_ws();
+    let root = p.value(None)?;
+    p.skip_ws();
+    if p.pos < p.chars.len() {
+        return Err(p.error("trailing characters"));
+    }
+    Ok(make_document(doc_id, vec![root]))
+}
+
+/// Render a tree in the `json_to_xml` vocabulary as JSON text. `node` may be
+/// the document or its element; whitespace-only text between elements is
+/// ignored.
+pub fn xml_to_json(node: &Rc<XmlNode>) -> Result<String, String> {
+    let elem = match node.kind {
+        NodeKind::Document => {
+            let mut elems = node.children.iter().filter(|c| c.kind == NodeKind::Element);
+            match (elems.next(), elems.next()) {
+                (Some(e), None) => e,
+                _ => return Err("document must hold exactly one element".into()),
+            }
+        }
+        _ => node,
+    };
+    let mut out = String::new();
+    write_value(elem, &mut out)?;
+    Ok(out)
+}
+
+fn write_value(node: &Rc<XmlNode>, out: &mut String) -> Result<(), String> {
+    let name = node.name.as_deref().unwrap_or("");
+    if node.kind != NodeKind::Element {
+        return Err("expected an element".into());
+    }
+    match name {
+        "map" | "array" => {
+            let is_map = name == "map";
+            out.push(if is_map { '{' } else { '[' });
+            let mut first = true;
+            for child in &node.children {
+                match child.kind {
+                    NodeKind::Element => {}
+                    NodeKind::Text if child.string_value().trim().is_empty() => continue,
+                    NodeKind::Comment | NodeKind::Pi => continue,
+                    _ => return Err(format!("unexpected text in <{}>", name)),
+                }
+                if !first {
+                    out.push(',');
+                }
+                first = false;
+                if is_map {
+                    let key = child
+                        .attrs
+                        .iter()
+                        .find(|(k, _)| k == "key")
+                        .map(|(_, v)| v)
+                        .ok_or("map entry without a key attribute")?;
+                    out.push_str(&json_string(key));
+                    out.push(':');
+                }
+                write_value(child, out)?;
+            }
+            out.push(if is_map { '}' } else { ']' });
+        }
+        "string" => out.push_str(&json_string(&node.string_value())),
+        // Text that is not a JSON number, such as `+5`, `.5` or `NaN`, is
+        // written as a string rather than as invalid JSON
+        "number" => {
+            let text = node.string_value();
+            let text = text.trim();
+            if is_json_number(text) {
+                out.push_str(text);
+            } else {
+                out.push_str(&json_string(text));
+            }
+        }
+        "boolean" => match node.string_value().trim() {
+            "true" | "1" => out.push_str("true"),
+            "false" | "0" => out.push_str("false"),
+            other => return Err(format!("invalid boolean {:?}", other)),
+        },
+        "null" => out.push_str("null"),
+        other => return Err(format!("unknown element <{}>", other)),
+    }
+    Ok(())
+}
+
+/// Whether `s` is a number in JSON's grammar: an optional minus, an integer
+/// part without leading zeros, then optional fraction and exponent parts.
+fn is_json_number(s: &str) -> bool {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let s = s.strip_prefix('-').unwrap_or(s);
+    let int = digits(s);
+    if int == 0 || (int > 1 && s.starts_with('0')) {
+        return false;
+    }
+    let mut rest = &s[int..];
+    if let Some(frac) = rest.strip_prefix('.') {
+        let n = digits(frac);
+        if n == 0 {
+            return false;
+        }
+        rest = &frac[n..];
+    }
+    if let Some(exp) = rest.strip_prefix(['e', 'E']) {
+        let exp = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+        let n = digits(exp);
+        if n == 0 {
+            return false;
+        }
+        rest = &exp[n..];
+    }
+    rest.is_empty()
+}
+
+/// JSON string literal for `s`.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Declarations for the outermost element, taken by the first value.
+    namespaces: Vec<(String, String)>,
+}
+
+impl JsonParser {
+    fn error(&self, msg: &str) -> String {
+        format!("invalid JSON at offset {}: {}", self.pos, msg)
+    }
+
+    fn skip_ws(&mut self) {
+        while self.pos < self.chars.len() && matches!(self.chars[self.pos], ' ' | '\t' | '\n' | '\r')
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn keyword(&mut self, word: &str) -> bool {
+        let end = self.pos + word.chars().count();
+        if end <= self.chars.len() && self.chars[self.pos..end].iter().copied().eq(word.chars()) {
+            self.pos = end;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// One value as an element, with `key` set on entries of a map.
+    fn value(&mut self, key: Option<String>) -> Result<Rc<XmlNode>, String> {
+        let id = next_node_id();
+        let namespaces = std::mem::take(&mut self.namespaces);
+        let attrs = key.map(|k| vec![("key".to_string(), k)]).unwrap_or_default();
+        let (name, children) = match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let mut children = Vec::new();
+                self.skip_ws();
+                if !self.eat('}') {
+                    loop {
+                        self.skip_ws();
+                        if self.peek() != Some('"') {
+                            return Err(self.error("expected a string key"));
+                        }
+                        let key = self.string()?;
+                        self.skip_ws();
+                        if !self.eat(':') {
+                            return Err(self.error("expected ':'"));
+                        }
+                        self.skip_ws();
+                        children.push(self.value(Some(key))?);
+                        self.skip_ws();
+                        if self.eat('}') {
+                            break;
+                        }
+                        if !self.eat(',') {
+                            return Err(self.error("expected ',' or '}'"));
+                        }
+                    }
+                }
+                ("map", children)
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut children = Vec::new();
+                self.skip_ws();
+                if !self.eat(']') {
+                    loop {
+                        self.skip_ws();
+                        children.push(self.value(None)?);
+                        self.skip_ws();
+                        if self.eat(']') {
+                            break;
+                        }
+                        if !self.eat(',') {
+                            return Err(self.error("expected ',' or ']'"));
+                        }
+                    }
+                }
+                ("array", children)
+            }
+            Some('"') => ("string", text_child(self.string()?)),
+            Some('-' | '0'..='9') => ("number", text_child(self.number()?)),
+            _ if self.keyword("true") => ("boolean", text_child("true".into())),
+            _ if self.keyword("false") => ("boolean", text_child("false".into())),
+            _ if self.keyword("null") => ("null", vec![]),
+            _ => return Err(self.error("expected a value")),
+        };
+        Ok(make_element(id, name, attrs, namespaces, children))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1; // opening quote
+        let mut out = String::new();
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let esc = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    match esc {
+                        '"' | '\\' | '/' => out.push(esc),
+                        'b' => out.push('\u{8}'),
+                        'f' => out.push('\u{c}'),
+                        'n' => out.push('\n'),
+                        'r' => out.push('\r'),
+                        't' => out.push('\t'),
+                        'u' => {
+                            let hi = self.hex4()?;
+                            let code = if (0xD800..0xDC00).contains(&hi) && self.keyword("\\u") {
+                                let lo = self.hex4()?;
+                                0x10000 + ((hi - 0xD800) << 10) + (lo.wrapping_sub(0xDC00) & 0x3FF)
+                            } else {
+                                hi
+                            };
+                            out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c if (c as u32) < 0x20 => return Err(self.error("control character in string")),
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let end = self.pos + 4;
+        let digits: String = self.chars.get(self.pos..end).unwrap_or_default().iter().collect();
+        let code = u32::from_str_radix(&digits, 16)
+            .ok()
+            .filter(|_| digits.len() == 4)
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.pos = end;
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<String, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9')) {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        if is_json_number(&text) {
+            Ok(text)
+        } else {
+            Err(format!("invalid JSON at offset {}: invalid number {:?}", start, text))
+        }
+    }
+}
+
+fn text_child(s: String) -> Vec<Rc<XmlNode>> {
+    if s.is_empty() {
+        vec![]
+    } else {
+        vec![make_text(&s)]
+    }
+}
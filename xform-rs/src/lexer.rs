@@ -1,4 +1,6 @@
-const KEYWORDS: &[&str] = &[
+use std::collections::BTreeMap;
+
+pub(crate) const KEYWORDS: &[&str] = &[
     "xform", "version", "import", "as", "ns", "def", "var", "let", "in",
     "for", "where", "return", "if", "then", "else", "match", "case",
     "default", "and", "or", "not", "div", "mod", "rule",
@@ -6,7 +8,7 @@ const KEYWORDS: &[&str] = &[
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TK {
-    Kw, Ident, Str, Num, Op, Punct, Dot, Slash, At, Eof,
+    Kw, Ident, Str, Num, Op, Punct, Dot, Slash, At, Eof, Error,
 }
 
 #[derive(Debug, Clone)]
@@ -16,15 +18,30 @@ pub struct Token {
     pub pos: usize,
 }
 
+/// Source details the AST does not keep, collected for the formatter.
+#[derive(Debug, Clone, Default)]
+pub struct Trivia {
+    /// `#` comments as (position, text after the `#`).
+    pub comments: Vec<(usize, String)>,
+    /// Value and source spelling of each string and number literal, keyed
+    /// by its position, the start of the literal's `Span`.
+    pub literals: BTreeMap<usize, (String, String)>,
+}
+
 pub struct Lexer {
     pub chars: Vec<char>,
     pub pos: usize,
     pub buf: Option<Token>,
+    /// Where the whitespace before the most recently lexed token began, i.e.
+    /// the end of the source text preceding that token.
+    pub prev_end: usize,
+    /// Collected only when set, see `Parser::with_trivia`.
+    pub trivia: Option<Trivia>,
 }
 
 impl Lexer {
     pub fn new(text: &str) -> Self {
-        Lexer { chars: text.chars().collect(), pos: 0, buf: None }
+        Lexer { chars: text.chars().collect(), pos: 0, buf: None, prev_end: 0, trivia: None }
     }
 
     pub fn peek(&mut self) -> &Token {
@@ -34,6 +51,7 @@ impl Lexer {
         self.buf.as_ref().unwrap()
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Token {
         if let Some(tok) = self.buf.take() {
             return tok;
@@ -43,7 +61,10 @@ impl Lexer {
 
     pub fn expect(&mut self, kind: TK, value: Option<&str>) -> Result<Token, String> {
         let tok = self.next();
-        if tok.kind != kind || value.map_or(false, |v| tok.value != v) {
+        if tok.kind == TK::Error {
+            return Err(tok.value);
+        }
+        if tok.kind != kind || value.is_some_and(|v| tok.value != v) {
             return Err(format!(
                 "Expected {:?} {:?} at pos {}, got {:?} {:?}",
                 kind, value, tok.pos, tok.kind, tok.value
@@ -58,16 +79,73 @@ impl Lexer {
             if ch.is_whitespace() {
                 self.pos += 1;
             } else if ch == '#' {
+                let start = self.pos;
                 while self.pos < self.chars.len() && self.chars[self.pos] != '\n' {
                     self.pos += 1;
                 }
+                if let Some(trivia) = &mut self.trivia {
+                    // The parser may rewind and lex the same stretch twice
+                    if !trivia.comments.iter().any(|(p, _)| *p == start) {
+                        let text: String = self.chars[start + 1..self.pos].iter().collect();
+                        trivia.comments.push((start, text));
+                    }
+                }
             } else {
                 break;
             }
         }
     }
 
+    /// Read the four hex digits following a `\u` at `self.pos`, leaving `self.pos`
+    /// on the last digit consumed.
+    fn hex4(&mut self) -> Result<u32, String> {
+        let at = self.pos;
+        if at + 5 > self.chars.len()
+            || !self.chars[at + 1..at + 5].iter().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(format!("Invalid \\u escape at pos {}: expected 4 hex digits", at - 1));
+        }
+        let hex: String = self.chars[at + 1..at + 5].iter().collect();
+        self.pos += 4;
+        Ok(u32::from_str_radix(&hex, 16).unwrap())
+    }
+
+    /// Decode a `\uXXXX` escape, combining a UTF-16 surrogate pair
+    /// (`\uD83D\uDE00`) into a single astral character.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let at = self.pos - 1;
+        let hi = self.hex4()?;
+        if (0xDC00..0xE000).contains(&hi) {
+            return Err(format!("Unpaired surrogate in \\u escape at pos {}", at));
+        }
+        if !(0xD800..0xDC00).contains(&hi) {
+            return Ok(char::from_u32(hi).unwrap());
+        }
+        let p = self.pos;
+        if p + 2 < self.chars.len() && self.chars[p + 1] == '\\' && self.chars[p + 2] == 'u' {
+            self.pos += 2;
+            let lo = self.hex4()?;
+            if (0xDC00..0xE000).contains(&lo) {
+                let cp = 0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00);
+                return Ok(char::from_u32(cp).unwrap());
+            }
+        }
+        Err(format!("Unpaired surrogate in \\u escape at pos {}", at))
+    }
+
     fn next_token(&mut self) -> Token {
+        self.prev_end = self.pos;
+        let tok = self.lex_token();
+        if let Some(trivia) = &mut self.trivia {
+            if matches!(tok.kind, TK::Str | TK::Num) {
+                let raw: String = self.chars[tok.pos..self.pos].iter().collect();
+                trivia.literals.insert(tok.pos, (tok.value.clone(), raw));
+            }
+        }
+        tok
+    }
+
+    fn lex_token(&mut self) -> Token {
         self.skip_ws();
         if self.pos >= self.chars.len() {
             return Token { kind: TK::Eof, value: String::new(), pos: self.pos };
@@ -81,6 +159,24 @@ impl Lexer {
             return Token { kind: TK::Op, value: ":=".into(), pos: start };
         }
 
+        // ??
+        if ch == '?' && self.pos + 1 < self.chars.len() && self.chars[self.pos + 1] == '?' {
+            self.pos += 2;
+            return Token { kind: TK::Op, value: "??".into(), pos: start };
+        }
+
+        // Arrow
+        if ch == '-' && self.pos + 1 < self.chars.len() && self.chars[self.pos + 1] == '>' {
+            self.pos += 2;
+            return Token { kind: TK::Op, value: "->".into(), pos: start };
+        }
+
+        // Union
+        if ch == '|' {
+            self.pos += 1;
+            return Token { kind: TK::Op, value: "|".into(), pos: start };
+        }
+
         // Punctuation
         if "(){}[],:;".contains(ch) {
             self.pos += 1;
@@ -125,6 +221,26 @@ impl Lexer {
             return Token { kind: TK::Op, value: ch.to_string(), pos: start };
         }
 
+        // Raw strings: r"..." / r'...' take their contents verbatim
+        if ch == 'r'
+            && self.pos + 1 < self.chars.len()
+            && (self.chars[self.pos + 1] == '\'' || self.chars[self.pos + 1] == '"')
+        {
+            let quote = self.chars[self.pos + 1];
+            self.pos += 2;
+            let body_start = self.pos;
+            while self.pos < self.chars.len() && self.chars[self.pos] != quote {
+                self.pos += 1;
+            }
+            if self.pos >= self.chars.len() {
+                let msg = format!("Unterminated raw string at pos {}", start);
+                return Token { kind: TK::Error, value: msg, pos: start };
+            }
+            let out: String = self.chars[body_start..self.pos].iter().collect();
+            self.pos += 1;
+            return Token { kind: TK::Str, value: out, pos: start };
+        }
+
         // Strings
         if ch == '\'' || ch == '"' {
             let quote = ch;
@@ -140,16 +256,14 @@ impl Lexer {
                             'n' => out.push('\n'),
                             't' => out.push('\t'),
                             'r' => out.push('\r'),
-                            'u' if self.pos + 4 < self.chars.len() => {
-                                let hex: String =
-                                    self.chars[self.pos + 1..self.pos + 5].iter().collect();
-                                if let Ok(n) = u32::from_str_radix(&hex, 16) {
-                                    if let Some(uc) = char::from_u32(n) {
-                                        out.push(uc);
-                                    }
+                            '\\' => out.push('\\'),
+                            '/' => out.push('/'),
+                            'u' => match self.unicode_escape() {
+                                Ok(uc) => out.push(uc),
+                                Err(msg) => {
+                                    return Token { kind: TK::Error, value: msg, pos: start }
                                 }
-                                self.pos += 4;
-                            }
+                            },
                             _ => out.push(esc),
                         }
                         self.pos += 1;
@@ -177,6 +291,23 @@ impl Lexer {
             return Token { kind: TK::Num, value: s, pos: start };
         }
 
+        // Backtick-quoted names may hold characters an identifier cannot,
+        // such as the dot in `xs.extension`
+        if ch == '`' {
+            self.pos += 1;
+            let name_start = self.pos;
+            while self.pos < self.chars.len() && self.chars[self.pos] != '`' {
+                self.pos += 1;
+            }
+            if self.pos >= self.chars.len() || self.pos == name_start {
+                let msg = format!("Unterminated or empty quoted name at pos {}", start);
+                return Token { kind: TK::Error, value: msg, pos: start };
+            }
+            let s: String = self.chars[name_start..self.pos].iter().collect();
+            self.pos += 1;
+            return Token { kind: TK::Ident, value: s, pos: start };
+        }
+
         // Identifiers / keywords
         if ch.is_alphabetic() || ch == '_' {
             while self.pos < self.chars.len() {
@@ -192,7 +323,9 @@ impl Lexer {
                     }
                     break;
                 }
-                if c.is_alphanumeric() || c == '_' || c == '-' {
+                // A `-` before `>` is the arrow, as in `items->name`
+                let arrow = c == '-' && self.chars.get(self.pos + 1) == Some(&'>');
+                if c.is_alphanumeric() || c == '_' || (c == '-' && !arrow) {
                     self.pos += 1;
                 } else {
                     break;
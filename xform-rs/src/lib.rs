@@ -1,9 +1,32 @@
 pub mod ast;
+pub mod cache;
 pub mod eval;
+#[cfg(feature = "html")]
+pub mod html;
+pub mod json;
 pub mod lexer;
 pub mod parser;
+pub mod printer;
+pub mod profile;
+pub mod scope;
+pub mod trace;
+pub mod xinclude;
 pub mod xmlmodel;
 
-pub use eval::{eval_module, serialize_items};
-pub use parser::Parser;
-pub use xmlmodel::{parse_xml, serialize};
+pub use cache::ExprCache;
+pub use eval::{
+    eval_module, eval_module_with, serialize_items, serialize_items_with, AccessPolicy,
+    EvalOptions, LimitPolicy, SerializeOptions,
+};
+#[cfg(feature = "html")]
+pub use html::parse_html;
+pub use parser::{validate, Parser};
+pub use printer::format_source;
+pub use profile::Profile;
+pub use scope::scope_warnings;
+pub use trace::{TraceSink, WriteSink};
+pub use xinclude::parse_xml_xinclude;
+pub use xmlmodel::{
+    document_order, normalize_text_nodes, parse_xml, parse_xml_fragment, serialize, serialize_with,
+    strip_space, strip_whitespace, InvalidChars,
+};
@@ -1,75 +1,277 @@
 use crate::ast::*;
-use crate::lexer::{Lexer, TK};
+use crate::lexer::{Lexer, Token, TK};
+
+/// Keywords that may follow a complete expression.
+pub(crate) const CONTINUATION_KEYWORDS: &[&str] = &[
+    "and", "or", "div", "mod", "in", "then", "else", "return", "where",
+];
 
 pub struct Parser {
     pub lexer: Lexer,
+    /// Set while parsing a `let` value, where `in` ends the value instead of
+    /// being the membership operator.
+    no_in: bool,
+    /// From the prolog once it has been read, for syntax that depends on it.
+    pub version: LangVersion,
 }
 
 impl Parser {
     pub fn new(text: &str) -> Self {
-        Parser { lexer: Lexer::new(text) }
+        Parser { lexer: Lexer::new(text), no_in: false, version: LangVersion::default() }
+    }
+
+    /// A parser that also collects comments and literal spellings into
+    /// `lexer.trivia`, for tools that print the source back.
+    pub fn with_trivia(text: &str) -> Self {
+        let mut p = Parser::new(text);
+        p.lexer.trivia = Some(Default::default());
+        p
     }
 
     pub fn parse_module(&mut self) -> Result<Module, String> {
+        self.parse_module_impl(&mut None)
+    }
+
+    /// Parse a module without stopping at the first error: after a failed
+    /// declaration, skip to the next declaration and keep going. Returns the
+    /// partial module together with every error encountered.
+    pub fn parse_module_recovering(&mut self) -> (Module, Vec<String>) {
+        let mut errors = Some(Vec::new());
+        let module = self.parse_module_impl(&mut errors).unwrap_or_else(|e| {
+            errors.get_or_insert_with(Vec::new).push(e);
+            Module::default()
+        });
+        (module, errors.unwrap_or_default())
+    }
+
+    /// With `errors` set, statement-level errors are collected there and
+    /// parsing resumes at the next declaration; otherwise the first error is
+    /// returned.
+    fn parse_module_impl(
+        &mut self,
+        errors: &mut Option<Vec<String>>,
+    ) -> Result<Module, String> {
         let mut functions = std::collections::HashMap::new();
         let mut rules: std::collections::HashMap<String, Vec<RuleDef>> =
             std::collections::HashMap::new();
         let mut vars = std::collections::HashMap::new();
         let mut namespaces = std::collections::HashMap::new();
         let mut imports = Vec::new();
+        let mut preserve_boundary_space = None;
+        let mut strict_variables = None;
+        let mut decls = Vec::new();
 
         // Optional prolog
         if self.lexer.peek().kind == TK::Kw && self.lexer.peek().value == "xform" {
-            self.lexer.next();
-            self.lexer.expect(TK::Kw, Some("version"))?;
-            let ver = self.lexer.expect(TK::Str, None)?.value;
-            if ver != "2.0" {
-                return Err("XFST0005: unsupported version".into());
+            let start = self.lexer.peek().pos;
+            match self.parse_prolog() {
+                Ok(()) => decls.push(DeclSpan { decl: DeclRef::Prolog, start, end: self.lexer.pos }),
+                Err(e) => match errors {
+                    Some(errs) => {
+                        errs.push(e);
+                        self.resync();
+                    }
+                    None => return Err(e),
+                },
             }
-            self.lexer.expect(TK::Punct, Some(";"))?;
         }
 
         loop {
             let pk = self.lexer.peek().kind.clone();
             let pv = self.lexer.peek().value.clone();
-            if pk == TK::Kw && pv == "ns" {
-                self.parse_ns(&mut namespaces)?;
+            let start = self.lexer.peek().pos;
+            let res = if pk == TK::Ident && pv == "declare" && self.at_declare() {
+                self.parse_declare().map(|(decl, on)| {
+                    match decl {
+                        DeclRef::BoundarySpace => preserve_boundary_space = Some(on),
+                        _ => strict_variables = Some(on),
+                    }
+                    decl
+                })
+            } else if pk == TK::Kw && pv == "ns" {
+                self.parse_ns(&mut namespaces).map(DeclRef::Ns)
             } else if pk == TK::Kw && pv == "import" {
-                self.parse_import(&mut imports)?;
+                self.parse_import(&mut imports).map(|()| DeclRef::Import(imports.len() - 1))
             } else if pk == TK::Kw && pv == "var" {
-                let (name, expr) = self.parse_var()?;
-                vars.insert(name, expr);
+                self.parse_var().map(|(name, expr)| {
+                    vars.insert(name.clone(), expr);
+                    DeclRef::Var(name)
+                })
             } else if pk == TK::Kw && pv == "def" {
-                let (name, fd) = self.parse_def()?;
-                functions.insert(name, fd);
+                self.parse_def().map(|(name, fd)| {
+                    functions.insert(name.clone(), fd);
+                    DeclRef::Def(name)
+                })
             } else if pk == TK::Kw && pv == "rule" {
-                let (name, rd) = self.parse_rule()?;
-                rules.entry(name).or_default().push(rd);
+                self.parse_rule().map(|(name, rd)| {
+                    let set = rules.entry(name.clone()).or_default();
+                    set.push(rd);
+                    DeclRef::Rule(name, set.len() - 1)
+                })
             } else {
                 break;
+            };
+            if let Ok(decl) = &res {
+                decls.push(DeclSpan { decl: decl.clone(), start, end: self.lexer.pos });
+            }
+            if let Err(e) = res {
+                match errors {
+                    Some(errs) => {
+                        errs.push(e);
+                        if !self.resync() {
+                            break;
+                        }
+                    }
+                    None => return Err(e),
+                }
             }
         }
 
         let expr = if self.lexer.peek().kind != TK::Eof {
-            Some(self.parse_expr()?)
+            let start = self.lexer.peek().pos;
+            match (self.parse_expr(), errors) {
+                (Ok(e), _) => {
+                    let end = self.last_end();
+                    decls.push(DeclSpan { decl: DeclRef::Expr, start, end });
+                    Some(e)
+                }
+                (Err(e), Some(errs)) => {
+                    errs.push(e);
+                    None
+                }
+                (Err(e), None) => return Err(e),
+            }
         } else {
             None
         };
 
-        Ok(Module { functions, rules, vars, namespaces, imports, expr })
+        Ok(Module {
+            version: self.version,
+            functions,
+            rules,
+            vars,
+            namespaces,
+            imports,
+            expr,
+            preserve_boundary_space,
+            strict_variables,
+            decls,
+        })
+    }
+
+    fn parse_prolog(&mut self) -> Result<(), String> {
+        self.lexer.expect(TK::Kw, Some("xform"))?;
+        self.lexer.expect(TK::Kw, Some("version"))?;
+        let ver = self.lexer.expect(TK::Str, None)?.value;
+        self.version = LangVersion::parse(&ver)
+            .ok_or_else(|| format!("XFST0005: unsupported version {:?}", ver))?;
+        self.lexer.expect(TK::Punct, Some(";"))?;
+        Ok(())
+    }
+
+    /// End of the last token consumed, whether or not one has been peeked.
+    fn last_end(&self) -> usize {
+        if self.lexer.buf.is_some() { self.lexer.prev_end } else { self.lexer.pos }
+    }
+
+    /// Move the lexer to the next line that starts with a declaration keyword.
+    /// Works on raw characters because constructor content cannot be skipped
+    /// reliably token by token. Returns false when no declaration follows.
+    fn resync(&mut self) -> bool {
+        let chars = &self.lexer.chars;
+        let mut pos = self.lexer.pos;
+        while pos < chars.len() {
+            let line_start = pos == 0 || chars[pos - 1] == '\n';
+            if line_start {
+                let mut i = pos;
+                while i < chars.len() && (chars[i] == ' ' || chars[i] == '\t') {
+                    i += 1;
+                }
+                let word_end = (i..chars.len())
+                    .find(|&j| !chars[j].is_alphanumeric())
+                    .unwrap_or(chars.len());
+                let word: String = chars[i..word_end].iter().collect();
+                let declares =
+                    ["ns", "import", "var", "def", "rule", "declare"].contains(&word.as_str());
+                if declares && word_end < chars.len() && chars[word_end].is_whitespace() {
+                    self.lexer.pos = i;
+                    self.lexer.buf = None;
+                    return true;
+                }
+            }
+            pos += 1;
+        }
+        self.lexer.pos = chars.len();
+        self.lexer.buf = None;
+        false
+    }
+
+    /// Whether the upcoming `declare` starts a declaration rather than the
+    /// main expression (a path or variable named `declare`).
+    fn at_declare(&mut self) -> bool {
+        let saved_pos = self.lexer.pos;
+        let saved_buf = self.lexer.buf.take();
+        if saved_buf.is_none() {
+            self.lexer.next();
+        }
+        let is_decl = self.lexer.peek().kind == TK::Ident
+            && ["boundary-space", "option"].contains(&self.lexer.peek().value.as_str());
+        self.lexer.pos = saved_pos;
+        self.lexer.buf = saved_buf;
+        is_decl
+    }
+
+    /// `declare boundary-space preserve|strip;`, returning whether to preserve,
+    /// or `declare option strict-variables "true"|"false";`.
+    fn parse_declare(&mut self) -> Result<(DeclRef, bool), String> {
+        self.lexer.expect(TK::Ident, Some("declare"))?;
+        if self.lexer.peek().kind == TK::Ident && self.lexer.peek().value == "option" {
+            self.lexer.next();
+            let name = self.lexer.expect(TK::Ident, None)?;
+            if name.value != "strict-variables" {
+                return Err(format!("Unknown option {:?} at {}", name.value, name.pos));
+            }
+            let tok = self.lexer.expect(TK::Str, None)?;
+            let on = match tok.value.as_str() {
+                "true" => true,
+                "false" => false,
+                other => {
+                    return Err(format!(
+                        "Expected \"true\" or \"false\", got {:?} at {}",
+                        other, tok.pos
+                    ))
+                }
+            };
+            self.lexer.expect(TK::Punct, Some(";"))?;
+            return Ok((DeclRef::StrictVariables, on));
+        }
+        self.lexer.expect(TK::Ident, Some("boundary-space"))?;
+        let tok = self.lexer.expect(TK::Ident, None)?;
+        let preserve = match tok.value.as_str() {
+            "preserve" => true,
+            "strip" => false,
+            other => {
+                return Err(format!(
+                    "Expected preserve or strip, got {:?} at {}",
+                    other, tok.pos
+                ))
+            }
+        };
+        self.lexer.expect(TK::Punct, Some(";"))?;
+        Ok((DeclRef::BoundarySpace, preserve))
     }
 
     fn parse_ns(
         &mut self,
         ns: &mut std::collections::HashMap<String, String>,
-    ) -> Result<(), String> {
+    ) -> Result<String, String> {
         self.lexer.expect(TK::Kw, Some("ns"))?;
         let prefix = self.lexer.expect(TK::Str, None)?.value;
         self.lexer.expect(TK::Op, Some("="))?;
         let uri = self.lexer.expect(TK::Str, None)?.value;
         self.lexer.expect(TK::Punct, Some(";"))?;
-        ns.insert(prefix, uri);
-        Ok(())
+        ns.insert(prefix.clone(), uri);
+        Ok(prefix)
     }
 
     fn parse_import(
@@ -100,7 +302,22 @@ impl Parser {
 
     fn parse_def(&mut self) -> Result<(String, FunctionDef), String> {
         self.lexer.expect(TK::Kw, Some("def"))?;
-        let name = self.parse_qname()?;
+        let mut name = self.parse_qname()?;
+        // `memo` is a modifier only when a name follows, so `def memo(x)`
+        // still defines a function called memo
+        let memo = name == "memo" && self.lexer.peek().kind == TK::Ident;
+        if memo {
+            name = self.parse_qname()?;
+        }
+        let params = self.parse_params()?;
+        self.lexer.expect(TK::Op, Some(":="))?;
+        let body = self.parse_expr()?;
+        self.lexer.expect(TK::Punct, Some(";"))?;
+        Ok((name, FunctionDef { params, body, memo }))
+    }
+
+    /// A parenthesized parameter list.
+    fn parse_params(&mut self) -> Result<Vec<Param>, String> {
         self.lexer.expect(TK::Punct, Some("("))?;
         let params = if self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == ")" {
             vec![]
@@ -113,10 +330,7 @@ impl Parser {
             ps
         };
         self.lexer.expect(TK::Punct, Some(")"))?;
-        self.lexer.expect(TK::Op, Some(":="))?;
-        let body = self.parse_expr()?;
-        self.lexer.expect(TK::Punct, Some(";"))?;
-        Ok((name, FunctionDef { params, body }))
+        Ok(params)
     }
 
     fn parse_param(&mut self) -> Result<Param, String> {
@@ -147,6 +361,7 @@ impl Parser {
     }
 
     fn parse_rule(&mut self) -> Result<(String, RuleDef), String> {
+        let start = self.lexer.peek().pos;
         self.lexer.expect(TK::Kw, Some("rule"))?;
         let name = self.parse_qname()?;
         self.lexer.expect(TK::Kw, Some("match"))?;
@@ -154,7 +369,15 @@ impl Parser {
         self.lexer.expect(TK::Op, Some(":="))?;
         let body = self.parse_expr()?;
         self.lexer.expect(TK::Punct, Some(";"))?;
-        Ok((name, RuleDef { pattern, body }))
+        let span = Span { start, end: self.last_end() };
+        Ok((name, RuleDef { pattern, body, span }))
+    }
+
+    /// Parse the whole text as one expression, without a prolog.
+    pub fn parse_standalone_expr(&mut self) -> Result<Expr, String> {
+        let expr = self.parse_expr()?;
+        self.lexer.expect(TK::Eof, None)?;
+        Ok(expr)
     }
 
     pub fn parse_expr(&mut self) -> Result<Expr, String> {
@@ -172,7 +395,52 @@ impl Parser {
         if pk == TK::Kw && pv == "match" {
             return self.parse_match();
         }
-        self.parse_or()
+        if pk == TK::Ident && pv == "fn" {
+            if let Some(params) = self.try_lambda_head() {
+                let body = self.parse_expr()?;
+                return Ok(Expr::Lambda(Box::new(FunctionDef { params, body, memo: false })));
+            }
+        }
+        let left = self.parse_or()?;
+        if self.lexer.peek().kind == TK::Op && self.lexer.peek().value == "->" {
+            // `seq -> body` is `for` with each item as the context item only
+            self.lexer.next();
+            let body = self.parse_expr()?;
+            return Ok(Expr::ForExpr(Box::new(ForExpr {
+                name: ARROW_VAR.into(),
+                seq: left,
+                where_clause: None,
+                order_by: vec![],
+                separator: None,
+                body,
+            })));
+        }
+        Ok(left)
+    }
+
+    /// The `fn(params) :=` opening an inline function, or `None` with the
+    /// lexer left as it was when this is a call to a function named `fn`.
+    fn try_lambda_head(&mut self) -> Option<Vec<Param>> {
+        let saved_pos = self.lexer.pos;
+        let saved_buf = self.lexer.buf.clone();
+        self.lexer.next(); // consume "fn"
+        let params = self.parse_params().ok();
+        if params.is_some() && self.lexer.peek().kind == TK::Op && self.lexer.peek().value == ":=" {
+            self.lexer.next();
+            return params;
+        }
+        self.lexer.pos = saved_pos;
+        self.lexer.buf = saved_buf;
+        None
+    }
+
+    /// Parse an expression enclosed in brackets, where `in` is always the
+    /// membership operator.
+    fn parse_inner_expr(&mut self) -> Result<Expr, String> {
+        let saved = std::mem::replace(&mut self.no_in, false);
+        let e = self.parse_expr();
+        self.no_in = saved;
+        e
     }
 
     fn parse_if(&mut self) -> Result<Expr, String> {
@@ -180,8 +448,12 @@ impl Parser {
         let cond = self.parse_expr()?;
         self.lexer.expect(TK::Kw, Some("then"))?;
         let then_expr = self.parse_expr()?;
-        self.lexer.expect(TK::Kw, Some("else"))?;
-        let else_expr = self.parse_expr()?;
+        let else_expr = if self.lexer.peek().kind == TK::Kw && self.lexer.peek().value == "else" {
+            self.lexer.next();
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
         Ok(Expr::IfExpr(Box::new(IfExpr { cond, then_expr, else_expr })))
     }
 
@@ -189,7 +461,10 @@ impl Parser {
         self.lexer.expect(TK::Kw, Some("let"))?;
         let name = self.lexer.expect(TK::Ident, None)?.value;
         self.lexer.expect(TK::Op, Some(":="))?;
-        let value = self.parse_expr()?;
+        let saved = std::mem::replace(&mut self.no_in, true);
+        let value = self.parse_expr();
+        self.no_in = saved;
+        let value = value?;
         self.lexer.expect(TK::Kw, Some("in"))?;
         let body = self.parse_expr()?;
         Ok(Expr::LetExpr(Box::new(LetExpr { name, value, body })))
@@ -207,9 +482,43 @@ impl Parser {
             } else {
                 None
             };
+        // `order`, `by`, `ascending`, `descending`, `collation` and
+        // `separated-by` are only keywords here
+        let mut order_by = Vec::new();
+        if self.lexer.peek().kind == TK::Ident && self.lexer.peek().value == "order" {
+            self.lexer.next();
+            self.lexer.expect(TK::Ident, Some("by"))?;
+            loop {
+                let key = self.parse_expr()?;
+                let tok = self.lexer.peek();
+                let descending = tok.kind == TK::Ident && tok.value == "descending";
+                if tok.kind == TK::Ident && (descending || tok.value == "ascending") {
+                    self.lexer.next();
+                }
+                let tok = self.lexer.peek();
+                let collation = if tok.kind == TK::Ident && tok.value == "collation" {
+                    self.lexer.next();
+                    Some(self.lexer.expect(TK::Str, None)?.value)
+                } else {
+                    None
+                };
+                order_by.push(OrderSpec { key, descending, collation });
+                if self.lexer.peek().kind != TK::Punct || self.lexer.peek().value != "," {
+                    break;
+                }
+                self.lexer.next();
+            }
+        }
+        let separator =
+            if self.lexer.peek().kind == TK::Ident && self.lexer.peek().value == "separated-by" {
+                self.lexer.next();
+                Some(self.parse_expr()?)
+            } else {
+                None
+            };
         self.lexer.expect(TK::Kw, Some("return"))?;
         let body = self.parse_expr()?;
-        Ok(Expr::ForExpr(Box::new(ForExpr { name, seq, where_clause, body })))
+        Ok(Expr::ForExpr(Box::new(ForExpr { name, seq, where_clause, order_by, separator, body })))
     }
 
     fn parse_match(&mut self) -> Result<Expr, String> {
@@ -222,14 +531,30 @@ impl Parser {
             let pk = self.lexer.peek().kind.clone();
             let pv = self.lexer.peek().value.clone();
             if pk == TK::Kw && pv == "case" {
-                self.lexer.next();
-                let pat = self.parse_pattern()?;
+                let case_pos = self.lexer.next().pos;
+                let mut pats = vec![self.parse_pattern()?];
+                while self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "," {
+                    self.lexer.next();
+                    pats.push(self.parse_pattern()?);
+                }
+                let mut bound = pattern_vars(&pats[0]);
+                bound.sort();
+                for pat in &pats[1..] {
+                    let mut other = pattern_vars(pat);
+                    other.sort();
+                    if other != bound {
+                        return Err(format!(
+                            "Case patterns bind different variables at {}",
+                            case_pos
+                        ));
+                    }
+                }
                 // "=>" is two tokens: "=" then ">"
                 self.lexer.expect(TK::Op, Some("="))?;
                 self.lexer.expect(TK::Op, Some(">"))?;
                 let expr = self.parse_expr()?;
                 self.lexer.expect(TK::Punct, Some(";"))?;
-                cases.push((pat, expr));
+                cases.push((pats, expr));
             } else if pk == TK::Kw && pv == "default" {
                 self.lexer.next();
                 self.lexer.expect(TK::Op, Some("="))?;
@@ -245,15 +570,25 @@ impl Parser {
     }
 
     fn parse_or(&mut self) -> Result<Expr, String> {
-        let mut expr = self.parse_and()?;
+        let mut expr = self.parse_coalesce()?;
         while self.lexer.peek().kind == TK::Kw && self.lexer.peek().value == "or" {
             self.lexer.next();
-            let right = self.parse_and()?;
+            let right = self.parse_coalesce()?;
             expr = Expr::BinaryOp { op: "or".into(), left: Box::new(expr), right: Box::new(right) };
         }
         Ok(expr)
     }
 
+    fn parse_coalesce(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+        while self.lexer.peek().kind == TK::Op && self.lexer.peek().value == "??" {
+            self.lexer.next();
+            let right = self.parse_and()?;
+            expr = Expr::BinaryOp { op: "??".into(), left: Box::new(expr), right: Box::new(right) };
+        }
+        Ok(expr)
+    }
+
     fn parse_and(&mut self) -> Result<Expr, String> {
         let mut expr = self.parse_eq()?;
         while self.lexer.peek().kind == TK::Kw && self.lexer.peek().value == "and" {
@@ -270,24 +605,40 @@ impl Parser {
 
     fn parse_eq(&mut self) -> Result<Expr, String> {
         let mut expr = self.parse_rel()?;
-        while self.lexer.peek().kind == TK::Op
-            && (self.lexer.peek().value == "=" || self.lexer.peek().value == "!=")
-        {
-            let op = self.lexer.next().value;
+        // The previous `=` or `!=` of this chain
+        let mut chained: Option<String> = None;
+        loop {
+            let pk = self.lexer.peek().kind.clone();
+            let pv = self.lexer.peek().value.clone();
+            let is_eq = pk == TK::Op && (pv == "=" || pv == "!=");
+            let is_in = pk == TK::Kw && pv == "in" && !self.no_in;
+            if !is_eq && !is_in {
+                break;
+            }
+            let tok = self.lexer.next();
             let right = self.parse_rel()?;
-            expr = Expr::BinaryOp { op, left: Box::new(expr), right: Box::new(right) };
+            if is_eq {
+                if let Some(prev) = chained.replace(tok.value.clone()) {
+                    return Err(chain_error(&expr, &prev, &tok, &right));
+                }
+            }
+            expr = Expr::BinaryOp { op: tok.value, left: Box::new(expr), right: Box::new(right) };
         }
         Ok(expr)
     }
 
     fn parse_rel(&mut self) -> Result<Expr, String> {
         let mut expr = self.parse_add()?;
+        let mut chained: Option<String> = None;
         while self.lexer.peek().kind == TK::Op
             && ["<", "<=", ">", ">="].contains(&self.lexer.peek().value.as_str())
         {
-            let op = self.lexer.next().value;
+            let tok = self.lexer.next();
             let right = self.parse_add()?;
-            expr = Expr::BinaryOp { op, left: Box::new(expr), right: Box::new(right) };
+            if let Some(prev) = chained.replace(tok.value.clone()) {
+                return Err(chain_error(&expr, &prev, &tok, &right));
+            }
+            expr = Expr::BinaryOp { op: tok.value, left: Box::new(expr), right: Box::new(right) };
         }
         Ok(expr)
     }
@@ -336,40 +687,99 @@ impl Parser {
             let e = self.parse_unary()?;
             return Ok(Expr::UnaryOp { op: "not".into(), expr: Box::new(e) });
         }
-        self.parse_primary()
+        self.parse_union()
+    }
+
+    fn parse_union(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_intersect()?;
+        while self.lexer.peek().kind == TK::Op && self.lexer.peek().value == "|" {
+            self.lexer.next();
+            let right = self.parse_intersect()?;
+            expr = Expr::BinaryOp { op: "|".into(), left: Box::new(expr), right: Box::new(right) };
+        }
+        Ok(expr)
+    }
+
+    /// `except` and `intersect` bind tighter than `|`. They are operators
+    /// only after an operand, so they stay usable as names elsewhere.
+    fn parse_intersect(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_primary()?;
+        while self.lexer.peek().kind == TK::Ident
+            && matches!(self.lexer.peek().value.as_str(), "except" | "intersect")
+        {
+            let op = self.lexer.next().value;
+            let right = self.parse_primary()?;
+            expr = Expr::BinaryOp { op, left: Box::new(expr), right: Box::new(right) };
+        }
+        Ok(expr)
     }
 
     fn parse_primary(&mut self) -> Result<Expr, String> {
         let pk = self.lexer.peek().kind.clone();
         let pv = self.lexer.peek().value.clone();
 
+        if pk == TK::Error {
+            return Err(pv);
+        }
         if pk == TK::Num {
-            let v = self.lexer.next().value;
-            let n: f64 = v.parse().map_err(|e| format!("Bad number: {}", e))?;
-            return Ok(Expr::Literal(LiteralValue::Num(n)));
+            let tok = self.lexer.next();
+            let n: f64 = tok.value.parse().map_err(|e| format!("Bad number: {}", e))?;
+            let span = Span { start: tok.pos, end: self.lexer.pos };
+            return Ok(Expr::Literal(LiteralValue::Num(n), span));
         }
         if pk == TK::Str {
-            let v = self.lexer.next().value;
-            return Ok(Expr::Literal(LiteralValue::Str(v)));
+            let tok = self.lexer.next();
+            let span = Span { start: tok.pos, end: self.lexer.pos };
+            return Ok(Expr::Literal(LiteralValue::Str(tok.value), span));
         }
         if pk == TK::Punct && pv == "(" {
-            self.lexer.next();
-            let e = self.parse_expr()?;
+            let from = self.lexer.next().pos;
+            if self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == ")" {
+                self.lexer.next();
+                return Ok(Expr::Sequence(vec![]));
+            }
+            let e = self.parse_inner_expr()?;
+            if self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "," {
+                let mut items = vec![e];
+                while self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "," {
+                    self.lexer.next();
+                    items.push(self.parse_inner_expr()?);
+                }
+                self.lexer.expect(TK::Punct, Some(")"))?;
+                return self.path_after(Expr::Sequence(items), from);
+            }
             self.lexer.expect(TK::Punct, Some(")"))?;
-            return Ok(e);
+            return self.path_after(e, from);
         }
-        // text{...} constructor vs text(...) function call
-        if pk == TK::Ident && pv == "text" {
+        if pk == TK::Punct && pv == "[" {
+            self.lexer.next();
+            let mut members = Vec::new();
+            if !(self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "]") {
+                members.push(self.parse_inner_expr()?);
+                while self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "," {
+                    self.lexer.next();
+                    members.push(self.parse_inner_expr()?);
+                }
+            }
+            self.lexer.expect(TK::Punct, Some("]"))?;
+            return Ok(Expr::Array(members));
+        }
+        // text{...} / document{...} constructors vs function calls
+        if pk == TK::Ident && (pv == "text" || pv == "document") {
             let saved_pos = self.lexer.pos;
             let saved_buf = self.lexer.buf.clone();
-            self.lexer.next(); // consume "text"
+            self.lexer.next(); // consume the name
             if self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "{" {
                 self.lexer.next(); // consume "{"
-                let e = self.parse_expr()?;
+                let e = Box::new(self.parse_inner_expr()?);
                 self.lexer.expect(TK::Punct, Some("}"))?;
-                return Ok(Expr::TextConstructor(Box::new(e)));
+                return Ok(if pv == "text" {
+                    Expr::TextConstructor(e)
+                } else {
+                    Expr::DocumentConstructor(e)
+                });
             }
-            // Not text{...}, restore
+            // Not a constructor, restore
             self.lexer.pos = saved_pos;
             self.lexer.buf = saved_buf;
         }
@@ -379,17 +789,20 @@ impl Parser {
         }
         // Path starting with . or /
         if pk == TK::Dot || pk == TK::Slash {
-            return self.parse_path(None);
+            let from = self.lexer.peek().pos;
+            return self.parse_path(None, from);
         }
         // Identifier: variable, function call, or path start
         if pk == TK::Ident {
+            let from = self.lexer.peek().pos;
             let name = self.lexer.next().value;
             if self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "(" {
-                return self.parse_func_call(name);
+                let call = self.parse_func_call(name)?;
+                return self.path_after(call, from);
             }
             if self.path_continues() {
-                let start = PathStart { kind: PathStartKind::Var, name: Some(name) };
-                return self.parse_path(Some(start));
+                let start = PathStart { kind: PathStartKind::Var, name: Some(name), base: None };
+                return self.parse_path(Some(start), from);
             }
             return Ok(Expr::VarRef(name));
         }
@@ -400,33 +813,45 @@ impl Parser {
         self.lexer.expect(TK::Punct, Some("("))?;
         let mut args = Vec::new();
         if !(self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == ")") {
-            args.push(self.parse_expr()?);
+            args.push(self.parse_inner_expr()?);
             while self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "," {
                 self.lexer.next();
-                args.push(self.parse_expr()?);
+                args.push(self.parse_inner_expr()?);
             }
         }
         self.lexer.expect(TK::Punct, Some(")"))?;
         Ok(Expr::FuncCall(Box::new(FuncCall { name, args })))
     }
 
+    /// `base` itself, or the path continuing from it with `/` or `//`.
+    fn path_after(&mut self, base: Expr, from: usize) -> Result<Expr, String> {
+        if self.lexer.peek().kind != TK::Slash {
+            return Ok(base);
+        }
+        let start = PathStart { kind: PathStartKind::Expr, name: None, base: Some(Box::new(base)) };
+        self.parse_path(Some(start), from)
+    }
+
     fn path_continues(&mut self) -> bool {
         let pk = self.lexer.peek().kind.clone();
         pk == TK::Slash || pk == TK::Dot || pk == TK::At
     }
 
-    fn parse_path(&mut self, start: Option<PathStart>) -> Result<Expr, String> {
+    /// Parse a path whose first token is at `from`, after its start when
+    /// the caller has already read it.
+    fn parse_path(&mut self, start: Option<PathStart>, from: usize) -> Result<Expr, String> {
         let start = if let Some(s) = start {
             s
         } else {
             let tok = self.lexer.next();
-            match (tok.kind, tok.value.as_str()) {
-                (TK::Dot, ".//") => PathStart { kind: PathStartKind::Desc, name: None },
-                (TK::Dot, _) => PathStart { kind: PathStartKind::Context, name: None },
-                (TK::Slash, "//") => PathStart { kind: PathStartKind::DescRoot, name: None },
-                (TK::Slash, _) => PathStart { kind: PathStartKind::Root, name: None },
+            let kind = match (tok.kind, tok.value.as_str()) {
+                (TK::Dot, ".//") => PathStartKind::Desc,
+                (TK::Dot, _) => PathStartKind::Context,
+                (TK::Slash, "//") => PathStartKind::DescRoot,
+                (TK::Slash, _) => PathStartKind::Root,
                 (_, _) => return Err(format!("Invalid path start at {}", tok.pos)),
-            }
+            };
+            PathStart { kind, name: None, base: None }
         };
 
         let mut steps = Vec::new();
@@ -434,7 +859,7 @@ impl Parser {
         // For .// or // starts, the immediate name is a desc-or-self step
         if start.kind == PathStartKind::Desc || start.kind == PathStartKind::DescRoot {
             let pk = self.lexer.peek().kind.clone();
-            if pk == TK::Ident || (pk == TK::Op && self.lexer.peek().value == "*") {
+            if self.at_name() || (pk == TK::Op && self.lexer.peek().value == "*") {
                 let test = self.parse_step_test()?;
                 let preds = self.parse_predicates()?;
                 steps.push(PathStep { axis: PathAxis::DescOrSelf, test, predicates: preds });
@@ -446,13 +871,13 @@ impl Parser {
             let pk = self.lexer.peek().kind.clone();
             if pk == TK::At {
                 self.lexer.next();
-                let name = self.parse_qname()?;
+                let test = self.parse_attr_test()?;
                 steps.push(PathStep {
                     axis: PathAxis::Attr,
-                    test: StepTest::named(&name),
+                    test,
                     predicates: vec![],
                 });
-            } else if pk == TK::Ident || (pk == TK::Op && self.lexer.peek().value == "*") {
+            } else if self.at_name() || (pk == TK::Op && self.lexer.peek().value == "*") {
                 let test = self.parse_step_test()?;
                 let preds = self.parse_predicates()?;
                 steps.push(PathStep { axis: PathAxis::Child, test, predicates: preds });
@@ -464,14 +889,22 @@ impl Parser {
             let pv = self.lexer.peek().value.clone();
 
             if pk == TK::Slash {
-                let axis = if pv == "/" { PathAxis::Child } else { PathAxis::Desc };
+                // `v//name` searches from the variable's nodes themselves, as
+                // `.//name` does from the context item
+                let axis = match (pv.as_str(), start.kind) {
+                    ("/", _) => PathAxis::Child,
+                    (_, PathStartKind::Var | PathStartKind::Expr) if steps.is_empty() => {
+                        PathAxis::DescOrSelf
+                    }
+                    _ => PathAxis::Desc,
+                };
                 self.lexer.next();
                 if self.lexer.peek().kind == TK::At {
                     self.lexer.next();
-                    let name = self.parse_qname()?;
+                    let test = self.parse_attr_test()?;
                     steps.push(PathStep {
                         axis: PathAxis::Attr,
-                        test: StepTest::named(&name),
+                        test,
                         predicates: vec![],
                     });
                 } else {
@@ -486,10 +919,10 @@ impl Parser {
                     self.lexer.next();
                     if self.lexer.peek().kind == TK::At {
                         self.lexer.next();
-                        let name = self.parse_qname()?;
+                        let test = self.parse_attr_test()?;
                         steps.push(PathStep {
                             axis: PathAxis::Attr,
-                            test: StepTest::named(&name),
+                            test,
                             predicates: vec![],
                         });
                     } else {
@@ -513,10 +946,10 @@ impl Parser {
             }
             if pk == TK::At {
                 self.lexer.next();
-                let name = self.parse_qname()?;
+                let test = self.parse_attr_test()?;
                 steps.push(PathStep {
                     axis: PathAxis::Attr,
-                    test: StepTest::named(&name),
+                    test,
                     predicates: vec![],
                 });
                 continue;
@@ -524,10 +957,14 @@ impl Parser {
             break;
         }
 
-        Ok(Expr::PathExpr(Box::new(PathExpr { start, steps })))
+        let span = Span { start: from, end: self.last_end() };
+        Ok(Expr::PathExpr(Box::new(PathExpr { start, steps, span })))
     }
 
     fn parse_step_test(&mut self) -> Result<StepTest, String> {
+        if let Some(glob) = self.name_pattern() {
+            return Ok(StepTest { kind: StepTestKind::NamePattern, name: Some(glob) });
+        }
         let pk = self.lexer.peek().kind.clone();
         let pv = self.lexer.peek().value.clone();
         if pk == TK::Op && pv == "*" {
@@ -548,27 +985,84 @@ impl Parser {
                 _ => StepTest { kind: crate::ast::StepTestKind::Pi, name: None },
             });
         }
-        if pk == TK::Ident {
+        if pk == TK::Ident || pk == TK::Kw {
             let name = self.parse_qname()?;
             return Ok(StepTest::named(&name));
         }
         Err(format!("Invalid step test at {}", self.lexer.peek().pos))
     }
 
+    /// A name glued to `*`, such as `h*`, `ns:*` or `*-item`, read from the
+    /// raw characters and consumed. Only a `*` at either end makes a glob: one
+    /// between other characters is multiplication, as in `price*qty` or
+    /// `price*2`, and so is a trailing one followed by `(`.
+    fn name_pattern(&mut self) -> Option<String> {
+        let tok = self.lexer.peek();
+        if !matches!(tok.kind, TK::Ident | TK::Kw | TK::Op) {
+            return None;
+        }
+        let start = tok.pos;
+        let chars = &self.lexer.chars;
+        let mut end = start;
+        while let Some(&c) = chars.get(end) {
+            let arrow = c == '-' && chars.get(end + 1) == Some(&'>');
+            if c.is_alphanumeric() || c == '_' || c == ':' || c == '*' || (c == '-' && !arrow) {
+                end += 1;
+            } else {
+                break;
+            }
+        }
+        let glob: String = chars[start..end].iter().collect();
+        let inner = chars.get(start + 1..end.saturating_sub(1)).unwrap_or_default();
+        let call = glob.ends_with('*') && chars.get(end) == Some(&'(');
+        let arithmetic = inner.contains(&'*') || call;
+        if glob == "*" || !glob.contains('*') || arithmetic {
+            return None;
+        }
+        self.lexer.buf = None;
+        self.lexer.pos = end;
+        Some(glob)
+    }
+
+    /// The name after `@`, or `*` for all attributes.
+    fn parse_attr_test(&mut self) -> Result<StepTest, String> {
+        if self.lexer.peek().kind == TK::Op && self.lexer.peek().value == "*" {
+            self.lexer.next();
+            return Ok(StepTest::wildcard());
+        }
+        Ok(StepTest::named(&self.parse_qname()?))
+    }
+
     fn parse_predicates(&mut self) -> Result<Vec<Expr>, String> {
         let mut preds = Vec::new();
         while self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "[" {
             self.lexer.next();
-            preds.push(self.parse_expr()?);
+            preds.push(self.parse_inner_expr()?);
             self.lexer.expect(TK::Punct, Some("]"))?;
         }
         Ok(preds)
     }
 
+    /// Names may spell a keyword (`<if>`, `./default`) wherever the grammar
+    /// expects a name.
     fn parse_qname(&mut self) -> Result<String, String> {
+        if self.lexer.peek().kind == TK::Kw {
+            return Ok(self.lexer.next().value);
+        }
         Ok(self.lexer.expect(TK::Ident, None)?.value)
     }
 
+    /// Whether the next token can start an optional name step (after `/`,
+    /// `//` or `.//`). Keywords that continue an expression are not names.
+    fn at_name(&mut self) -> bool {
+        let tok = self.lexer.peek();
+        match tok.kind {
+            TK::Ident => true,
+            TK::Kw => !CONTINUATION_KEYWORDS.contains(&tok.value.as_str()),
+            _ => false,
+        }
+    }
+
     fn parse_pattern(&mut self) -> Result<Pattern, String> {
         let pk = self.lexer.peek().kind.clone();
         let pv = self.lexer.peek().value.clone();
@@ -591,6 +1085,11 @@ impl Parser {
         if pk == TK::Op && pv == "<" {
             self.lexer.next();
             let name = self.parse_qname()?;
+            if self.lexer.peek().kind == TK::Slash && self.lexer.peek().value == "/" {
+                self.lexer.next();
+                self.lexer.expect(TK::Op, Some(">"))?;
+                return Ok(Pattern::Element(ElementPattern { name, var: None, child: None }));
+            }
             self.lexer.expect(TK::Op, Some(">"))?;
             let (var, child) =
                 if self.lexer.peek().kind == TK::Punct && self.lexer.peek().value == "{" {
@@ -621,6 +1120,8 @@ impl Parser {
         let name = self.parse_qname()?;
 
         let mut attrs = Vec::new();
+        let mut splats = Vec::new();
+        let mut namespaces = Vec::new();
         loop {
             let pk = self.lexer.peek().kind.clone();
             let pv = self.lexer.peek().value.clone();
@@ -634,15 +1135,29 @@ impl Parser {
                 return Ok(Expr::Constructor(Box::new(Constructor {
                     name,
                     attrs,
+                    splats,
+                    namespaces,
                     contents: vec![],
                 })));
             }
+            if pk == TK::Punct && pv == "{" {
+                self.lexer.next();
+                splats.push((attrs.len(), self.parse_inner_expr()?));
+                self.lexer.expect(TK::Punct, Some("}"))?;
+                continue;
+            }
             let aname = self.parse_qname()?;
             self.lexer.expect(TK::Op, Some("="))?;
             self.lexer.expect(TK::Punct, Some("{"))?;
-            let aexpr = self.parse_expr()?;
+            let aexpr = self.parse_inner_expr()?;
             self.lexer.expect(TK::Punct, Some("}"))?;
-            attrs.push((aname, aexpr));
+            if aname == "xmlns" {
+                namespaces.push((String::new(), aexpr));
+            } else if let Some(prefix) = aname.strip_prefix("xmlns:") {
+                namespaces.push((prefix.to_string(), aexpr));
+            } else {
+                attrs.push((aname, aexpr));
+            }
         }
 
         // Parse content by inspecting raw chars
@@ -675,7 +1190,7 @@ impl Parser {
                 self.lexer.pos += 4; // "text"
                 self.lexer.buf = None;
                 self.lexer.expect(TK::Punct, Some("{"))?;
-                let e = self.parse_expr()?;
+                let e = self.parse_inner_expr()?;
                 self.lexer.expect(TK::Punct, Some("}"))?;
                 contents.push(Expr::TextConstructor(Box::new(e)));
                 continue;
@@ -690,21 +1205,20 @@ impl Parser {
             if ch == '{' {
                 self.lexer.pos += 1;
                 self.lexer.buf = None;
-                let e = self.parse_expr()?;
+                let e = self.parse_inner_expr()?;
                 self.lexer.expect(TK::Punct, Some("}"))?;
                 contents.push(Expr::Interp(Box::new(e)));
                 continue;
             }
+            // Whitespace-only runs are kept; the boundary-space policy decides
+            // at evaluation time whether they produce text
             let cd = self.parse_chardata();
-            if !cd.trim().is_empty() {
+            if !cd.is_empty() {
                 contents.push(Expr::CharData(cd));
-            } else if !cd.is_empty() {
-                // preserve whitespace-only chardata as empty to match Python
-                // (Python: `if text and text.strip(): ...`)
             }
         }
 
-        Ok(Expr::Constructor(Box::new(Constructor { name, attrs, contents })))
+        Ok(Expr::Constructor(Box::new(Constructor { name, attrs, splats, namespaces, contents })))
     }
 
     fn starts_with_at(&self, s: &str) -> bool {
@@ -738,16 +1252,27 @@ impl Parser {
             return Err("Expected end tag".into());
         }
         pos += 2;
+        let quoted = pos < self.lexer.chars.len() && self.lexer.chars[pos] == '`';
+        if quoted {
+            pos += 1;
+        }
         let start = pos;
         while pos < self.lexer.chars.len()
             && (self.lexer.chars[pos].is_alphanumeric()
                 || self.lexer.chars[pos] == '_'
                 || self.lexer.chars[pos] == ':'
-                || self.lexer.chars[pos] == '-')
+                || self.lexer.chars[pos] == '-'
+                || self.lexer.chars[pos] == '.')
         {
             pos += 1;
         }
         let end_name: String = self.lexer.chars[start..pos].iter().collect();
+        if quoted {
+            if pos >= self.lexer.chars.len() || self.lexer.chars[pos] != '`' {
+                return Err("Unterminated quoted end tag name".into());
+            }
+            pos += 1;
+        }
         while pos < self.lexer.chars.len() && self.lexer.chars[pos].is_whitespace() {
             pos += 1;
         }
@@ -757,3 +1282,44 @@ impl Parser {
         Ok((end_name, pos + 1))
     }
 }
+
+/// Error for `a < b < c` and the like, which would compare the boolean
+/// `a < b` with `c`. `left` is the comparison using `prev`, followed by
+/// operator token `op` and `right`.
+fn chain_error(left: &Expr, prev: &str, op: &Token, right: &Expr) -> String {
+    let (a, b) = match left {
+        Expr::BinaryOp { left, right, .. } => (to_source(left), to_source(right)),
+        other => (String::new(), to_source(other)),
+    };
+    format!(
+        "Chained comparison at pos {}: write `{} {} {} and {} {} {}`, or parenthesize to \
+         compare the result",
+        op.pos,
+        a,
+        prev,
+        b,
+        b,
+        op.value,
+        to_source(right)
+    )
+}
+
+/// Variable names bound by a pattern, in binding order.
+pub(crate) fn pattern_vars(pat: &Pattern) -> Vec<String> {
+    match pat {
+        Pattern::Element(ep) => {
+            let mut out: Vec<String> = ep.var.iter().cloned().collect();
+            if let Some(child) = &ep.child {
+                out.extend(pattern_vars(child));
+            }
+            out
+        }
+        _ => vec![],
+    }
+}
+
+/// Check a transform for syntax errors, reporting all of them rather than
+/// only the first.
+pub fn validate(text: &str) -> Vec<String> {
+    Parser::new(text).parse_module_recovering().1
+}
@@ -0,0 +1,805 @@
+//! Renders an AST back into XForm source.
+//!
+//! Expressions are printed on one line when they fit in `WIDTH` columns and
+//! broken over several lines otherwise. Parentheses are inserted only where
+//! operator precedence or the grammar requires them.
+
+use crate::ast::*;
+use crate::lexer::{Trivia, KEYWORDS};
+use crate::parser::{Parser, CONTINUATION_KEYWORDS};
+
+const WIDTH: usize = 80;
+const INDENT: &str = "  ";
+
+/// Binding strength of `op`; higher binds tighter.
+fn op_prec(op: &str) -> u8 {
+    match op {
+        "or" => 1,
+        "??" => 2,
+        "and" => 3,
+        "=" | "!=" | "in" => 4,
+        "<" | "<=" | ">" | ">=" => 5,
+        "+" | "-" => 6,
+        "|" => 9,
+        "except" | "intersect" => 10,
+        _ => 7, // * div mod
+    }
+}
+
+const UNARY_PREC: u8 = 8;
+const PRIMARY_PREC: u8 = 11;
+
+fn prec(e: &Expr) -> u8 {
+    match e {
+        Expr::IfExpr(_)
+        | Expr::LetExpr(_)
+        | Expr::ForExpr(_)
+        | Expr::MatchExpr(_)
+        | Expr::Lambda(_) => 0,
+        Expr::BinaryOp { op, .. } => op_prec(op),
+        Expr::UnaryOp { .. } => UNARY_PREC,
+        // Printed with a leading minus
+        Expr::Literal(LiteralValue::Num(n), _) if n.is_sign_negative() && !n.is_nan() => UNARY_PREC,
+        _ => PRIMARY_PREC,
+    }
+}
+
+/// Whether `e` ends in an `if` without `else`, which would capture an `else`
+/// printed after it.
+fn ends_open(e: &Expr) -> bool {
+    match e {
+        Expr::IfExpr(ie) => match &ie.else_expr {
+            None => true,
+            Some(els) => ends_open(els),
+        },
+        Expr::LetExpr(le) => ends_open(&le.body),
+        Expr::ForExpr(fe) => ends_open(&fe.body),
+        Expr::Lambda(fd) => ends_open(&fd.body),
+        _ => false,
+    }
+}
+
+/// A path that is only `/`, `//` or `.//`, which would absorb a following
+/// name or `*` as its first step.
+fn is_bare_root(e: &Expr) -> bool {
+    matches!(e, Expr::PathExpr(pe) if pe.steps.is_empty()
+        && pe.start.kind != PathStartKind::Context
+        && pe.start.kind != PathStartKind::Var
+        && pe.start.kind != PathStartKind::Expr)
+}
+
+fn is_plain_name(n: &str) -> bool {
+    let chars: Vec<char> = n.chars().collect();
+    match chars.first() {
+        Some(c) if c.is_alphabetic() || *c == '_' => {}
+        _ => return false,
+    }
+    chars.iter().enumerate().all(|(i, &c)| {
+        c.is_alphanumeric()
+            || c == '_'
+            || c == '-'
+            || (c == ':'
+                && chars.get(i + 1).is_some_and(|&d| d.is_alphanumeric() || d == '_' || d == '-'))
+    })
+}
+
+/// A name where the grammar wants an identifier token (variables, params,
+/// function names): keywords must be quoted.
+fn ident(n: &str) -> String {
+    if is_plain_name(n) && !KEYWORDS.contains(&n) {
+        n.to_string()
+    } else {
+        format!("`{}`", n)
+    }
+}
+
+/// A name where the grammar accepts keywords too (tags, attributes, steps).
+fn qname(n: &str) -> String {
+    if is_plain_name(n) {
+        n.to_string()
+    } else {
+        format!("`{}`", n)
+    }
+}
+
+fn pad(indent: usize) -> String {
+    INDENT.repeat(indent)
+}
+
+pub struct Printer<'a> {
+    trivia: Option<&'a Trivia>,
+    /// Print whitespace-only constructor text as is. When off it is treated
+    /// as insignificant (boundary-space strip) and constructors are laid out
+    /// freely.
+    keep_boundary_space: bool,
+}
+
+impl<'a> Printer<'a> {
+    pub fn new(trivia: Option<&'a Trivia>, keep_boundary_space: bool) -> Self {
+        Printer { trivia, keep_boundary_space }
+    }
+
+    /// Print `e` starting at column `col`, on one line if it fits, otherwise
+    /// broken with continuation lines at `indent`.
+    pub fn expr(&self, e: &Expr, indent: usize, col: usize) -> String {
+        self.fit(e, indent, col, 0, false)
+    }
+
+    fn fit(&self, e: &Expr, indent: usize, col: usize, min_prec: u8, no_in: bool) -> String {
+        let flat = self.render(e, None, min_prec, no_in);
+        if !flat.contains('\n') && col + flat.len() <= WIDTH {
+            return flat;
+        }
+        self.render(e, Some(indent), min_prec, no_in)
+    }
+
+    /// Print a child expression: flat when the parent is flat, otherwise
+    /// fitted at the given position.
+    fn sub(
+        &self,
+        e: &Expr,
+        layout: Option<usize>,
+        col: usize,
+        min_prec: u8,
+        no_in: bool,
+    ) -> String {
+        match layout {
+            None => self.render(e, None, min_prec, no_in),
+            Some(indent) => self.fit(e, indent, col, min_prec, no_in),
+        }
+    }
+
+    /// `layout` is `None` for single-line output, or the indent level of
+    /// continuation lines. `no_in` is set inside a `let` value, where a bare
+    /// membership `in` would end the value.
+    fn render(&self, e: &Expr, layout: Option<usize>, min_prec: u8, no_in: bool) -> String {
+        let needs_parens = prec(e) < min_prec
+            || (no_in && matches!(e, Expr::BinaryOp { op, .. } if op == "in"));
+        if needs_parens {
+            return format!("({})", self.render(e, layout, 0, false));
+        }
+        let ind = layout.unwrap_or(0);
+        let col = ind * INDENT.len();
+        match e {
+            Expr::Literal(lit, span) => self.literal(lit, *span),
+            Expr::VarRef(name) => ident(name),
+            Expr::IfExpr(ie) => {
+                let cond = self.sub(&ie.cond, layout, col + 3, 0, no_in);
+                let then_open = ie.else_expr.is_some() && ends_open(&ie.then_expr);
+                let then_min = if then_open { PRIMARY_PREC } else { 0 };
+                match layout {
+                    None => {
+                        let mut out = format!(
+                            "if {} then {}",
+                            cond,
+                            self.render(&ie.then_expr, None, then_min, no_in)
+                        );
+                        if let Some(els) = &ie.else_expr {
+                            out.push_str(" else ");
+                            out.push_str(&self.render(els, None, 0, no_in));
+                        }
+                        out
+                    }
+                    Some(i) => {
+                        let then_s =
+                            self.fit(&ie.then_expr, i + 1, (i + 1) * 2, then_min, no_in);
+                        let mut out = format!("if {} then\n{}{}", cond, pad(i + 1), then_s);
+                        match &ie.else_expr {
+                            Some(els @ Expr::IfExpr(_)) => {
+                                out.push_str(&format!("\n{}else ", pad(i)));
+                                out.push_str(&self.fit(els, i, col + 5, 0, no_in));
+                            }
+                            Some(els) => {
+                                out.push_str(&format!("\n{}else\n{}", pad(i), pad(i + 1)));
+                                out.push_str(&self.fit(els, i + 1, (i + 1) * 2, 0, no_in));
+                            }
+                            None => {}
+                        }
+                        out
+                    }
+                }
+            }
+            Expr::LetExpr(le) => {
+                let head = format!("let {} := ", ident(&le.name));
+                let value = self.sub(&le.value, layout, col + head.len(), 0, true);
+                match layout {
+                    None => {
+                        format!("{}{} in {}", head, value, self.render(&le.body, None, 0, no_in))
+                    }
+                    Some(i) => format!(
+                        "{}{} in\n{}{}",
+                        head,
+                        value,
+                        pad(i),
+                        self.fit(&le.body, i, col, 0, no_in)
+                    ),
+                }
+            }
+            Expr::ForExpr(fe) if fe.name == ARROW_VAR => {
+                let seq = self.sub(&fe.seq, layout, col, 1, no_in);
+                match layout {
+                    None => format!("{} -> {}", seq, self.render(&fe.body, None, 0, no_in)),
+                    Some(i) => {
+                        format!("{} ->\n{}{}", seq, pad(i), self.fit(&fe.body, i, col, 0, no_in))
+                    }
+                }
+            }
+            Expr::ForExpr(fe) => {
+                let head = format!("for {} in ", ident(&fe.name));
+                let seq = self.sub(&fe.seq, layout, col + head.len(), 0, no_in);
+                let sep = match layout {
+                    None => " ".to_string(),
+                    Some(i) => format!("\n{}", pad(i)),
+                };
+                // A bare `/` would take the `order` or `separated-by` that
+                // follows as a step
+                let ordered = !fe.order_by.is_empty();
+                let clauses = ordered || fe.separator.is_some();
+                let guard = |e: &Expr, text: String| {
+                    if clauses && is_bare_root(e) { format!("({})", text) } else { text }
+                };
+                let mut out = format!("{}{}", head, guard(&fe.seq, seq));
+                if let Some(w) = &fe.where_clause {
+                    out.push_str(&sep);
+                    out.push_str("where ");
+                    out.push_str(&guard(w, self.sub(w, layout, col + 6, 0, no_in)));
+                }
+                if ordered {
+                    let keys: Vec<String> = fe
+                        .order_by
+                        .iter()
+                        .map(|spec| {
+                            let key = self.render(&spec.key, None, 0, no_in);
+                            let bare = is_bare_root(&spec.key);
+                            let key = match (spec.descending, bare) {
+                                (true, true) => format!("({}) descending", key),
+                                (true, false) => format!("{} descending", key),
+                                (false, true)
+                                    if fe.separator.is_some() || spec.collation.is_some() =>
+                                {
+                                    format!("({})", key)
+                                }
+                                (false, _) => key,
+                            };
+                            match &spec.collation {
+                                Some(c) => format!("{} collation {}", key, self.written(c)),
+                                None => key,
+                            }
+                        })
+                        .collect();
+                    out.push_str(&sep);
+                    out.push_str("order by ");
+                    out.push_str(&keys.join(", "));
+                }
+                if let Some(s) = &fe.separator {
+                    out.push_str(&sep);
+                    out.push_str("separated-by ");
+                    out.push_str(&guard(s, self.sub(s, layout, col + 13, 0, no_in)));
+                }
+                out.push_str(&sep);
+                out.push_str("return ");
+                out.push_str(&self.sub(&fe.body, layout, col + 7, 0, no_in));
+                out
+            }
+            Expr::MatchExpr(me) => {
+                let target = self.sub(&me.target, layout, col + 6, 0, no_in);
+                let mut arms: Vec<String> = me
+                    .cases
+                    .iter()
+                    .map(|(pats, body)| {
+                        let pats: Vec<String> = pats.iter().map(|p| self.pattern(p)).collect();
+                        let head = format!("case {} => ", pats.join(", "));
+                        let body = self.sub(body, layout.map(|i| i + 1), col + head.len(), 0, no_in);
+                        format!("{}{};", head, body)
+                    })
+                    .collect();
+                if let Some(d) = &me.default {
+                    let body = self.sub(d, layout.map(|i| i + 1), col + 13, 0, no_in);
+                    arms.push(format!("default => {};", body));
+                }
+                match layout {
+                    None => format!("match {}: {}", target, arms.join(" ")),
+                    Some(i) => {
+                        let sep = format!("\n{}", pad(i + 1));
+                        format!("match {}:{}{}", target, sep, arms.join(&sep))
+                    }
+                }
+            }
+            Expr::FuncCall(fc) => self.call(&ident(&fc.name), &fc.args, layout),
+            Expr::UnaryOp { op, expr } => {
+                let operand = self.sub(expr, layout, col + op.len() + 1, UNARY_PREC, no_in);
+                if op == "not" {
+                    format!("not {}", operand)
+                } else {
+                    format!("{}{}", op, operand)
+                }
+            }
+            Expr::BinaryOp { op, left, right } => {
+                let p = op_prec(op);
+                // A bare `/` or `//` would take a following `*` as a name test
+                let l = if op == "*" && is_bare_root(left) {
+                    format!("({})", self.render(left, None, 0, false))
+                } else {
+                    // Comparisons do not chain, so a comparison on the left
+                    // needs parentheses too
+                    let left_min = if (4..=5).contains(&p) { p + 1 } else { p };
+                    self.sub(left, layout, col, left_min, no_in)
+                };
+                let r = self.sub(right, layout, col, p + 1, no_in);
+                format!("{} {} {}", l, op, r)
+            }
+            Expr::PathExpr(pe) => self.path(pe),
+            Expr::Sequence(items) => self.call("", items, layout),
+            Expr::Array(members) => self.list("[", "]", members, layout),
+            Expr::Lambda(fd) => {
+                let params: Vec<String> = fd.params.iter().map(|p| self.param(p)).collect();
+                let head = format!("fn({}) := ", params.join(", "));
+                let body = self.sub(&fd.body, layout, col + head.len(), 0, no_in);
+                format!("{}{}", head, body)
+            }
+            Expr::Constructor(c) => self.constructor(c, layout),
+            Expr::TextConstructor(inner) => {
+                format!("text{{{}}}", self.sub(inner, layout, col + 5, 0, false))
+            }
+            Expr::DocumentConstructor(inner) => {
+                format!("document{{{}}}", self.sub(inner, layout, col + 9, 0, false))
+            }
+            Expr::CharData(s) => self.string(s),
+            Expr::Interp(inner) => self.render(inner, layout, min_prec, no_in),
+        }
+    }
+
+    /// `name(args)`, or a parenthesized sequence when `name` is empty.
+    fn call(&self, name: &str, args: &[Expr], layout: Option<usize>) -> String {
+        self.list(&format!("{}(", name), ")", args, layout)
+    }
+
+    /// `args` between `open` and `close`, one per line if they do not fit.
+    fn list(&self, open: &str, close: &str, args: &[Expr], layout: Option<usize>) -> String {
+        let flat: Vec<String> = args.iter().map(|a| self.render(a, None, 0, false)).collect();
+        let flat = format!("{}{}{}", open, flat.join(", "), close);
+        match layout {
+            Some(i) if !args.is_empty() && (flat.contains('\n') || i * 2 + flat.len() > WIDTH) => {
+                let inner = pad(i + 1);
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|a| format!("{}{}", inner, self.fit(a, i + 1, (i + 1) * 2, 0, false)))
+                    .collect();
+                format!("{}\n{}\n{}{}", open, args.join(",\n"), pad(i), close)
+            }
+            _ => flat,
+        }
+    }
+
+    fn literal(&self, lit: &LiteralValue, span: Span) -> String {
+        let written = self.trivia.filter(|_| span != Span::default());
+        if let Some((_, raw)) = written.and_then(|t| t.literals.get(&span.start)) {
+            return raw.clone();
+        }
+        match lit {
+            LiteralValue::Str(s) => self.string(s),
+            LiteralValue::Num(n) => self.number(*n),
+            LiteralValue::Bool(b) => if *b { "(1 = 1)".into() } else { "(1 = 0)".into() },
+            // Null has no literal syntax; the empty sequence is the closest
+            LiteralValue::Null => "()".into(),
+        }
+    }
+
+    /// A string the AST keeps without a position (a namespace, an import or
+    /// a collation): the first source spelling of that value, if any.
+    fn written(&self, s: &str) -> String {
+        let raw = self.trivia.and_then(|t| {
+            t.literals.values().find(|(v, raw)| v == s && raw.starts_with(['"', '\'']))
+        });
+        raw.map_or_else(|| self.string(s), |(_, raw)| raw.clone())
+    }
+
+    fn string(&self, s: &str) -> String {
+        let mut out = String::from("\"");
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                '\r' => out.push_str("\\r"),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+        out
+    }
+
+    fn number(&self, n: f64) -> String {
+        if n.is_nan() {
+            "(0 div 0)".into()
+        } else if n.is_infinite() {
+            if n > 0.0 { "(1 div 0)".into() } else { "(-1 div 0)".into() }
+        } else if n.fract() == 0.0 && n.abs() < 1e15 {
+            format!("{}", n as i64)
+        } else {
+            format!("{}", n)
+        }
+    }
+
+    fn path(&self, pe: &PathExpr) -> String {
+        let mut out = match pe.start.kind {
+            PathStartKind::Context => ".".to_string(),
+            PathStartKind::Root => "/".to_string(),
+            PathStartKind::Desc => ".//".to_string(),
+            PathStartKind::DescRoot => "//".to_string(),
+            PathStartKind::Var => ident(pe.start.name.as_deref().unwrap_or("")),
+            PathStartKind::Expr => match pe.start.base.as_deref() {
+                Some(call @ Expr::FuncCall(_)) => self.render(call, None, 0, false),
+                Some(base @ Expr::Sequence(_)) => self.render(base, None, 0, false),
+                Some(base) => format!("({})", self.render(base, None, 0, false)),
+                None => "()".to_string(),
+            },
+        };
+        let mut steps = pe.steps.iter().peekable();
+        // Right after `/`, `//` and `.//` the first step is written bare
+        if let Some(first) = steps.peek() {
+            let bare = match pe.start.kind {
+                PathStartKind::Root => matches!(first.axis, PathAxis::Child | PathAxis::Attr),
+                PathStartKind::Desc | PathStartKind::DescRoot => {
+                    first.axis == PathAxis::DescOrSelf
+                }
+                _ => false,
+            };
+            if bare {
+                if first.axis == PathAxis::Attr {
+                    out.push('@');
+                    out.push_str(&self.step_test(&first.test));
+                } else {
+                    let test = self.step_test(&first.test);
+                    let name = first.test.name.as_deref().unwrap_or("");
+                    if first.test.kind == StepTestKind::Name
+                        && CONTINUATION_KEYWORDS.contains(&name)
+                    {
+                        out.push_str(&format!("`{}`", name));
+                    } else {
+                        out.push_str(&test);
+                    }
+                    out.push_str(&self.predicates(&first.predicates));
+                }
+                steps.next();
+            } else if !matches!(
+                pe.start.kind,
+                PathStartKind::Var | PathStartKind::Context | PathStartKind::Expr
+            ) {
+                // Keep a following `/` or `//` from fusing with the start
+                out.push(' ');
+            }
+        }
+        let mut prev_dot = out.ends_with('.');
+        for step in steps {
+            // `..` and `.//` would lex as single tokens
+            let dot_step = matches!(step.axis, PathAxis::SelfAxis | PathAxis::Parent);
+            let desc_step = matches!(step.axis, PathAxis::Desc | PathAxis::DescOrSelf);
+            if prev_dot && (dot_step || desc_step) {
+                out.push(' ');
+            }
+            match step.axis {
+                PathAxis::Child => out.push('/'),
+                PathAxis::Desc | PathAxis::DescOrSelf => out.push_str("//"),
+                PathAxis::Attr => out.push_str("/@"),
+                PathAxis::SelfAxis => out.push('.'),
+                PathAxis::Parent => out.push_str(".."),
+            }
+            match step.axis {
+                PathAxis::Attr => out.push_str(&self.step_test(&step.test)),
+                PathAxis::SelfAxis | PathAxis::Parent => {}
+                _ => {
+                    out.push_str(&self.step_test(&step.test));
+                    out.push_str(&self.predicates(&step.predicates));
+                }
+            }
+            prev_dot = dot_step;
+        }
+        out
+    }
+
+    fn step_test(&self, test: &StepTest) -> String {
+        match test.kind {
+            StepTestKind::Name => qname(test.name.as_deref().unwrap_or("")),
+            StepTestKind::Wildcard => "*".into(),
+            StepTestKind::NamePattern => test.name.clone().unwrap_or_default(),
+            StepTestKind::Text => "text()".into(),
+            StepTestKind::Node => "node()".into(),
+            StepTestKind::Comment => "comment()".into(),
+            StepTestKind::Pi => "pi()".into(),
+        }
+    }
+
+    fn predicates(&self, preds: &[Expr]) -> String {
+        preds.iter().map(|p| format!("[{}]", self.render(p, None, 0, false))).collect()
+    }
+
+    pub fn pattern(&self, p: &Pattern) -> String {
+        match p {
+            Pattern::Wildcard => "_".into(),
+            Pattern::Attribute(name) => format!("@{}", qname(name)),
+            Pattern::Typed(kind) => format!("{}()", kind),
+            Pattern::Element(ep) => {
+                let name = qname(&ep.name);
+                match (&ep.var, &ep.child) {
+                    (Some(v), _) => format!("<{}>{{{}}}</{}>", name, ident(v), name),
+                    (None, Some(child)) => {
+                        format!("<{}>{}</{}>", name, self.pattern(child), name)
+                    }
+                    (None, None) => format!("<{}/>", name),
+                }
+            }
+        }
+    }
+
+    fn constructor(&self, c: &Constructor, layout: Option<usize>) -> String {
+        let name = qname(&c.name);
+        let mut open = format!("<{}", name);
+        for (prefix, uri) in &c.namespaces {
+            let attr = if prefix.is_empty() { "xmlns".to_string() } else { format!("xmlns:{}", prefix) };
+            open.push_str(&format!(" {}={{{}}}", attr, self.render(uri, None, 0, false)));
+        }
+        for (i, (aname, aexpr)) in c.attrs.iter().enumerate() {
+            self.splats(c, i, &mut open);
+            open.push_str(&format!(" {}={{{}}}", qname(aname), self.render(aexpr, None, 0, false)));
+        }
+        self.splats(c, c.attrs.len(), &mut open);
+        // `xml:space="preserve"` content is printed exactly as written
+        let keep_space = self.keep_boundary_space
+            || c.attrs.iter().any(|(k, v)| {
+                let literal =
+                    matches!(v, Expr::Literal(LiteralValue::Str(s), _) if s == "preserve");
+                k == "xml:space" && literal
+            });
+        let contents: Vec<&Expr> = c
+            .contents
+            .iter()
+            .filter(|e| keep_space || !matches!(e, Expr::CharData(s) if s.trim().is_empty()))
+            .collect();
+        if contents.is_empty() {
+            return format!("{}/>", open);
+        }
+        // Mixed content keeps its text exactly, so only element-only content
+        // is laid out one child per line, and only when added whitespace is
+        // insignificant.
+        let mixed = keep_space || contents.iter().any(|e| matches!(e, Expr::CharData(_)));
+        let children: Vec<String> = match layout {
+            Some(i) if !mixed => contents
+                .iter()
+                .map(|e| format!("{}{}", pad(i + 1), self.content(e, Some(i + 1))))
+                .collect(),
+            // Nested constructors inherit the preserved space
+            _ if keep_space && !self.keep_boundary_space => {
+                let inner = Printer { trivia: self.trivia, keep_boundary_space: true };
+                contents.iter().map(|e| inner.content(e, None)).collect()
+            }
+            _ => contents.iter().map(|e| self.content(e, None)).collect(),
+        };
+        match layout {
+            Some(i) if !mixed => {
+                format!("{}>\n{}\n{}</{}>", open, children.join("\n"), pad(i), name)
+            }
+            _ => format!("{}>{}</{}>", open, children.concat(), name),
+        }
+    }
+
+    /// The attribute maps spliced in before attribute `at`.
+    fn splats(&self, c: &Constructor, at: usize, open: &mut String) {
+        for (_, e) in c.splats.iter().filter(|(i, _)| *i == at) {
+            open.push_str(&format!(" {{{}}}", self.render(e, None, 0, false)));
+        }
+    }
+
+    fn content(&self, e: &Expr, layout: Option<usize>) -> String {
+        let col = layout.map_or(0, |i| i * 2);
+        match e {
+            // Only reached when boundary space is kept
+            Expr::CharData(s) if s.trim().is_empty() => s.clone(),
+            // `text` right before `{` would read as a text constructor
+            Expr::CharData(s) if !s.contains('<') && !s.contains('{') && s != "text" => s.clone(),
+            Expr::Constructor(_) | Expr::TextConstructor(_) => {
+                self.sub(e, layout, col, 0, false)
+            }
+            Expr::Interp(inner) => self.braced(inner, layout),
+            other => self.braced(other, layout),
+        }
+    }
+
+    fn braced(&self, e: &Expr, layout: Option<usize>) -> String {
+        let flat = self.render(e, None, 0, false);
+        match layout {
+            Some(i) if flat.contains('\n') || i * 2 + flat.len() + 2 > WIDTH => format!(
+                "{{\n{}{}\n{}}}",
+                pad(i + 1),
+                self.fit(e, i + 1, (i + 1) * 2, 0, false),
+                pad(i)
+            ),
+            _ => format!("{{{}}}", flat),
+        }
+    }
+
+    /// A whole module, one declaration per line.
+    pub fn module(&self, module: &Module) -> String {
+        let decls: Vec<String> =
+            decl_order(module).iter().filter_map(|d| self.decl(module, d)).collect();
+        let mut out = decls.join("\n");
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out
+    }
+
+    /// A top-level declaration, without comments.
+    fn decl(&self, module: &Module, decl: &DeclRef) -> Option<String> {
+        let out = match decl {
+            DeclRef::Prolog => format!("xform version \"{}\";", module.version.as_str()),
+            DeclRef::BoundarySpace => match module.preserve_boundary_space? {
+                true => "declare boundary-space preserve;".to_string(),
+                false => "declare boundary-space strip;".to_string(),
+            },
+            DeclRef::StrictVariables => format!(
+                "declare option strict-variables \"{}\";",
+                module.strict_variables?
+            ),
+            DeclRef::Ns(prefix) => {
+                let uri = module.namespaces.get(prefix)?;
+                format!("ns {} = {};", self.written(prefix), self.written(uri))
+            }
+            DeclRef::Import(idx) => {
+                let (iri, alias) = module.imports.get(*idx)?;
+                match alias {
+                    Some(a) => format!("import {} as {};", self.written(iri), ident(a)),
+                    None => format!("import {};", self.written(iri)),
+                }
+            }
+            DeclRef::Var(name) => {
+                let head = format!("var {} :=", ident(name));
+                self.definition(&head, module.vars.get(name)?)
+            }
+            DeclRef::Def(name) => {
+                let fd = module.functions.get(name)?;
+                let params: Vec<String> = fd.params.iter().map(|p| self.param(p)).collect();
+                let memo = if fd.memo { "memo " } else { "" };
+                let head = format!("def {}{}({}) :=", memo, qname(name), params.join(", "));
+                self.definition(&head, &fd.body)
+            }
+            DeclRef::Rule(name, idx) => {
+                let rd = module.rules.get(name)?.get(*idx)?;
+                let head = format!("rule {} match {} :=", qname(name), self.pattern(&rd.pattern));
+                self.definition(&head, &rd.body)
+            }
+            DeclRef::Expr => self.expr(module.expr.as_ref()?, 0, 0),
+        };
+        Some(out)
+    }
+
+    /// `head body;`, with the body on its own indented line if it does not fit.
+    fn definition(&self, head: &str, body: &Expr) -> String {
+        let flat = self.render(body, None, 0, false);
+        if !flat.contains('\n') && head.len() + 1 + flat.len() < WIDTH {
+            format!("{} {};", head, flat)
+        } else {
+            format!("{}\n{}{};", head, pad(1), self.expr(body, 1, 2))
+        }
+    }
+
+    fn param(&self, p: &Param) -> String {
+        let mut out = ident(&p.name);
+        if let Some(t) = &p.type_ref {
+            out.push_str(&format!(": {}", qname(t)));
+        }
+        if let Some(d) = &p.default {
+            out.push_str(&format!(" := {}", self.render(d, None, 0, false)));
+        }
+        out
+    }
+}
+
+/// Declarations in source order when the module was parsed; anything not
+/// recorded there (e.g. added programmatically) follows, with the main
+/// expression last.
+fn decl_order(module: &Module) -> Vec<DeclRef> {
+    let mut order: Vec<DeclRef> =
+        module.decls.iter().map(|d| d.decl.clone()).filter(|d| *d != DeclRef::Expr).collect();
+    let mut extra = Vec::new();
+    if module.preserve_boundary_space.is_some() {
+        extra.push(DeclRef::BoundarySpace);
+    }
+    if module.strict_variables.is_some() {
+        extra.push(DeclRef::StrictVariables);
+    }
+    let mut prefixes: Vec<&String> = module.namespaces.keys().collect();
+    prefixes.sort();
+    extra.extend(prefixes.into_iter().map(|p| DeclRef::Ns(p.clone())));
+    extra.extend((0..module.imports.len()).map(DeclRef::Import));
+    let mut vars: Vec<&String> = module.vars.keys().collect();
+    vars.sort();
+    extra.extend(vars.into_iter().map(|v| DeclRef::Var(v.clone())));
+    let mut defs: Vec<&String> = module.functions.keys().collect();
+    defs.sort();
+    extra.extend(defs.into_iter().map(|f| DeclRef::Def(f.clone())));
+    let mut rules: Vec<(&String, &Vec<RuleDef>)> = module.rules.iter().collect();
+    rules.sort_by_key(|(name, _)| *name);
+    for (name, set) in rules {
+        extra.extend((0..set.len()).map(|i| DeclRef::Rule(name.clone(), i)));
+    }
+    for d in extra {
+        if !order.contains(&d) {
+            order.push(d);
+        }
+    }
+    if module.expr.is_some() {
+        order.push(DeclRef::Expr);
+    }
+    order
+}
+
+/// Reformat XForm source: consistent indentation and spacing, comments and
+/// literal spellings kept. Comments inside a declaration are moved above it.
+/// Whitespace between constructor children is re-indented unless the module
+/// declares `boundary-space preserve`.
+pub fn format_source(text: &str) -> Result<String, String> {
+    let mut parser = Parser::with_trivia(text);
+    let module = parser.parse_module()?;
+    let trivia = parser.lexer.trivia.take().unwrap_or_default();
+    let chars = &parser.lexer.chars;
+    // Whitespace between constructor children is only layout under the
+    // default strip policy
+    let printer = Printer::new(Some(&trivia), module.preserve_boundary_space == Some(true));
+
+    let mut comments = trivia.comments.clone();
+    comments.sort_by_key(|(p, _)| *p);
+    let mut comments = comments.into_iter().peekable();
+
+    let mut out = String::new();
+    let mut prev_end: Option<usize> = None;
+    for span in &module.decls {
+        // Comments up to this declaration's end: trailing ones on the line of
+        // the previous declaration, the rest leading this one.
+        let mut leading = Vec::new();
+        while let Some((pos, text)) = comments.next_if(|(p, _)| *p < span.end) {
+            let trailing = prev_end.is_some_and(|e| {
+                pos < span.start && !chars[e..pos].contains(&'\n')
+            });
+            if trailing {
+                out.push_str(&format!(" #{}", text));
+            } else {
+                leading.push(text);
+            }
+        }
+        if let Some(e) = prev_end {
+            out.push('\n');
+            // A blank line is a whole line between two newlines
+            let lines: Vec<&[char]> = chars[e..span.start].split(|&c| c == '\n').collect();
+            let blank = lines.len() > 2
+                && lines[1..lines.len() - 1].iter().any(|l| l.iter().all(|c| c.is_whitespace()));
+            if blank {
+                out.push('\n');
+            }
+        }
+        for text in leading {
+            out.push_str(&format!("#{}\n", text));
+        }
+        if let Some(d) = printer.decl(&module, &span.decl) {
+            out.push_str(&d);
+        }
+        prev_end = Some(span.end);
+    }
+    for (pos, text) in comments {
+        let trailing = prev_end.is_some_and(|e| !chars[e..pos].contains(&'\n'));
+        if trailing {
+            out.push_str(&format!(" #{}", text));
+        } else {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("#{}", text));
+        }
+        prev_end = Some(pos);
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
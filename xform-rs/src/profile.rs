@@ -0,0 +1,116 @@
+//! Per-rule and per-path counters of one run, collected when
+//! `EvalOptions::profile` is set and keyed by the source span of the rule or
+//! path. `Profile::report` renders them as tables, hottest first.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::ast::Span;
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub rules: HashMap<Span, RuleProfile>,
+    pub paths: HashMap<Span, PathProfile>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RuleProfile {
+    pub ruleset: String,
+    /// Items the rule matched.
+    pub fired: u64,
+    /// Time spent in the rule body, including rules it applied in turn.
+    pub time: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PathProfile {
+    pub evaluations: u64,
+    /// Nodes considered by the path's steps, whether or not they matched.
+    /// Paths in predicates count their own nodes only.
+    pub visited: u64,
+}
+
+impl Profile {
+    pub(crate) fn rule_fired(&mut self, span: Span, ruleset: &str, time: Duration) {
+        let entry = self
+            .rules
+            .entry(span)
+            .or_insert_with(|| RuleProfile { ruleset: ruleset.to_string(), ..Default::default() });
+        entry.fired += 1;
+        entry.time += time;
+    }
+
+    pub(crate) fn path_walked(&mut self, span: Span, visited: u64) {
+        let entry = self.paths.entry(span).or_default();
+        entry.evaluations += 1;
+        entry.visited += visited;
+    }
+
+    /// Render the counters as a rule table sorted by time and a path table
+    /// sorted by visited nodes, locating each entry in `source` by line and
+    /// column with its first source line.
+    pub fn report(&self, source: &str) -> String {
+        let mut out = String::new();
+        let mut rules: Vec<(&Span, &RuleProfile)> = self.rules.iter().collect();
+        rules.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+        out.push_str(&format!(
+            "{:<9} {:<16} {:>8} {:>12} {:>10}  source\n",
+            "at", "rule", "fired", "total ms", "mean us"
+        ));
+        for (span, r) in rules {
+            let mean = r.time.as_secs_f64() * 1e6 / r.fired as f64;
+            out.push_str(&format!(
+                "{:<9} {:<16} {:>8} {:>12.3} {:>10.1}  {}\n",
+                location(source, span.start),
+                r.ruleset,
+                r.fired,
+                r.time.as_secs_f64() * 1e3,
+                mean,
+                excerpt(source, *span)
+            ));
+        }
+        let mut paths: Vec<(&Span, &PathProfile)> = self.paths.iter().collect();
+        paths.sort_by(|a, b| b.1.visited.cmp(&a.1.visited).then(a.0.cmp(b.0)));
+        out.push_str(&format!(
+            "\n{:<9} {:>8} {:>12} {:>10}  path\n",
+            "at", "walks", "visited", "per walk"
+        ));
+        for (span, p) in paths {
+            out.push_str(&format!(
+                "{:<9} {:>8} {:>12} {:>10.1}  {}\n",
+                location(source, span.start),
+                p.evaluations,
+                p.visited,
+                p.visited as f64 / p.evaluations as f64,
+                excerpt(source, *span)
+            ));
+        }
+        out
+    }
+}
+
+/// One-based `line:column` of character offset `pos`.
+fn location(source: &str, pos: usize) -> String {
+    let (mut line, mut col) = (1, 1);
+    for c in source.chars().take(pos) {
+        if c == '\n' {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+    }
+    format!("{}:{}", line, col)
+}
+
+/// The first line of `span`, shortened to 48 characters.
+fn excerpt(source: &str, span: Span) -> String {
+    let text: String = source.chars().skip(span.start).take(span.end - span.start).collect();
+    let first = text.lines().next().unwrap_or_default().trim_end();
+    if first.chars().count() > 48 || first.len() < text.trim_end().len() {
+        let short: String = first.chars().take(45).collect();
+        format!("{}...", short)
+    } else {
+        first.to_string()
+    }
+}
@@ -0,0 +1,300 @@
+//! Static scoping checks, reported as warnings by `xform --check`.
+//!
+//! Scopes are lexical: module variables are visible everywhere, a function
+//! sees its parameters, a rule or `match` case the variables its pattern
+//! binds, and `let`, `for` and inline functions their own binding within
+//! their body. A name that is not in scope is read as a child element name
+//! when evaluated, which is how terse paths like `price/text()` are written,
+//! so such a reference is only reported when the name is bound as a variable
+//! elsewhere in the module and is unlikely to mean a child. Under
+//! `strict-variables` the reference is an error when evaluated, and every
+//! one is reported. A binding that hides another one is always reported.
+
+use std::collections::HashSet;
+
+use crate::ast::*;
+use crate::parser::pattern_vars;
+
+/// Warnings about shadowed bindings and out-of-scope variable references, in
+/// declaration order.
+pub fn scope_warnings(module: &Module) -> Vec<String> {
+    let mut bound = HashSet::new();
+    for fd in module.functions.values() {
+        bound.extend(fd.params.iter().map(|p| p.name.clone()));
+        collect_bound(&fd.body, &mut bound);
+    }
+    for rd in module.rules.values().flatten() {
+        bound.extend(pattern_vars(&rd.pattern));
+        collect_bound(&rd.body, &mut bound);
+    }
+    for e in module.vars.values().chain(&module.expr) {
+        collect_bound(e, &mut bound);
+    }
+    let mut checker = Checker { module, bound, place: String::new(), warnings: Vec::new() };
+    for span in &module.decls {
+        let mut scope = Vec::new();
+        match &span.decl {
+            DeclRef::Var(name) => {
+                checker.place = format!("var {}", name);
+                if let Some(e) = module.vars.get(name) {
+                    checker.expr(e, &mut scope);
+                }
+            }
+            DeclRef::Def(name) => {
+                checker.place = format!("def {}", name);
+                if let Some(fd) = module.functions.get(name) {
+                    checker.function(fd, &mut scope);
+                }
+            }
+            DeclRef::Rule(name, idx) => {
+                checker.place = format!("rule {}", name);
+                if let Some(rd) = module.rules.get(name).and_then(|rs| rs.get(*idx)) {
+                    for n in pattern_vars(&rd.pattern) {
+                        checker.bind(n, &mut scope);
+                    }
+                    checker.expr(&rd.body, &mut scope);
+                }
+            }
+            DeclRef::Expr => {
+                checker.place = "main expression".into();
+                if let Some(e) = &module.expr {
+                    checker.expr(e, &mut scope);
+                }
+            }
+            _ => {}
+        }
+    }
+    checker.warnings
+}
+
+struct Checker<'a> {
+    module: &'a Module,
+    /// Every name bound anywhere other than by a module variable.
+    bound: HashSet<String>,
+    /// Declaration being checked, for messages.
+    place: String,
+    warnings: Vec<String>,
+}
+
+impl Checker<'_> {
+    fn bind(&mut self, name: String, scope: &mut Vec<String>) {
+        if scope.contains(&name) {
+            self.warnings.push(format!(
+                "warning: {}: `{}` shadows an enclosing binding",
+                self.place, name
+            ));
+        } else if self.module.vars.contains_key(&name) {
+            self.warnings.push(format!(
+                "warning: {}: `{}` shadows a module variable",
+                self.place, name
+            ));
+        }
+        scope.push(name);
+    }
+
+    fn reference(&mut self, name: &str, scope: &[String]) {
+        if scope.iter().any(|n| n == name)
+            || self.module.vars.contains_key(name)
+            || self.module.functions.contains_key(name)
+        {
+            return;
+        }
+        let message = if self.module.strict_variables == Some(true) {
+            format!("variable `{}` is not bound, which strict-variables makes an error", name)
+        } else if self.bound.contains(name) {
+            format!("variable `{}` is not in scope here and is read as a child element name", name)
+        } else {
+            return;
+        };
+        self.warnings.push(format!("warning: {}: {}", self.place, message));
+    }
+
+    fn function(&mut self, fd: &FunctionDef, scope: &mut Vec<String>) {
+        let depth = scope.len();
+        for p in &fd.params {
+            if let Some(d) = &p.default {
+                self.expr(d, scope);
+            }
+            self.bind(p.name.clone(), scope);
+        }
+        self.expr(&fd.body, scope);
+        scope.truncate(depth);
+    }
+
+    fn expr(&mut self, e: &Expr, scope: &mut Vec<String>) {
+        match e {
+            Expr::Literal(..) | Expr::CharData(_) => {}
+            Expr::VarRef(name) => self.reference(name, scope),
+            Expr::IfExpr(ie) => {
+                self.expr(&ie.cond, scope);
+                self.expr(&ie.then_expr, scope);
+                if let Some(els) = &ie.else_expr {
+                    self.expr(els, scope);
+                }
+            }
+            Expr::LetExpr(le) => {
+                self.expr(&le.value, scope);
+                self.bind(le.name.clone(), scope);
+                self.expr(&le.body, scope);
+                scope.pop();
+            }
+            Expr::ForExpr(fe) if fe.name == ARROW_VAR => {
+                self.expr(&fe.seq, scope);
+                self.expr(&fe.body, scope);
+            }
+            Expr::ForExpr(fe) => {
+                self.expr(&fe.seq, scope);
+                // The separator is evaluated once, outside the loop variable
+                if let Some(s) = &fe.separator {
+                    self.expr(s, scope);
+                }
+                self.bind(fe.name.clone(), scope);
+                if let Some(w) = &fe.where_clause {
+                    self.expr(w, scope);
+                }
+                for spec in &fe.order_by {
+                    self.expr(&spec.key, scope);
+                }
+                self.expr(&fe.body, scope);
+                scope.pop();
+            }
+            Expr::MatchExpr(me) => {
+                self.expr(&me.target, scope);
+                for (patterns, body) in &me.cases {
+                    // Every pattern of a case binds the same names
+                    let depth = scope.len();
+                    for n in patterns.first().map(pattern_vars).unwrap_or_default() {
+                        self.bind(n, scope);
+                    }
+                    self.expr(body, scope);
+                    scope.truncate(depth);
+                }
+                if let Some(d) = &me.default {
+                    self.expr(d, scope);
+                }
+            }
+            Expr::FuncCall(fc) => {
+                for a in &fc.args {
+                    self.expr(a, scope);
+                }
+            }
+            Expr::UnaryOp { expr, .. } => self.expr(expr, scope),
+            Expr::BinaryOp { left, right, .. } => {
+                self.expr(left, scope);
+                self.expr(right, scope);
+            }
+            Expr::PathExpr(pe) => {
+                if pe.start.kind == PathStartKind::Var {
+                    self.reference(pe.start.name.as_deref().unwrap_or(""), scope);
+                }
+                if let Some(base) = &pe.start.base {
+                    self.expr(base, scope);
+                }
+                for step in &pe.steps {
+                    for pred in &step.predicates {
+                        self.expr(pred, scope);
+                    }
+                }
+            }
+            Expr::Sequence(items) | Expr::Array(items) => {
+                for item in items {
+                    self.expr(item, scope);
+                }
+            }
+            Expr::Lambda(fd) => self.function(fd, scope),
+            Expr::Constructor(c) => {
+                for (_, v) in c.attrs.iter().chain(&c.namespaces) {
+                    self.expr(v, scope);
+                }
+                for (_, v) in &c.splats {
+                    self.expr(v, scope);
+                }
+                for content in &c.contents {
+                    self.expr(content, scope);
+                }
+            }
+            Expr::TextConstructor(inner)
+            | Expr::DocumentConstructor(inner)
+            | Expr::Interp(inner) => self.expr(inner, scope),
+        }
+    }
+}
+
+/// Names bound by `let`, `for`, `match` cases and inline functions in `e`.
+fn collect_bound(e: &Expr, out: &mut HashSet<String>) {
+    match e {
+        Expr::Literal(..) | Expr::CharData(_) | Expr::VarRef(_) => {}
+        Expr::IfExpr(ie) => {
+            collect_bound(&ie.cond, out);
+            collect_bound(&ie.then_expr, out);
+            if let Some(els) = &ie.else_expr {
+                collect_bound(els, out);
+            }
+        }
+        Expr::LetExpr(le) => {
+            out.insert(le.name.clone());
+            collect_bound(&le.value, out);
+            collect_bound(&le.body, out);
+        }
+        Expr::ForExpr(fe) => {
+            out.insert(fe.name.clone());
+            collect_bound(&fe.seq, out);
+            if let Some(w) = &fe.where_clause {
+                collect_bound(w, out);
+            }
+            for spec in &fe.order_by {
+                collect_bound(&spec.key, out);
+            }
+            if let Some(s) = &fe.separator {
+                collect_bound(s, out);
+            }
+            collect_bound(&fe.body, out);
+        }
+        Expr::MatchExpr(me) => {
+            collect_bound(&me.target, out);
+            for (patterns, body) in &me.cases {
+                for p in patterns {
+                    out.extend(pattern_vars(p));
+                }
+                collect_bound(body, out);
+            }
+            if let Some(d) = &me.default {
+                collect_bound(d, out);
+            }
+        }
+        Expr::FuncCall(fc) => fc.args.iter().for_each(|a| collect_bound(a, out)),
+        Expr::UnaryOp { expr, .. } => collect_bound(expr, out),
+        Expr::BinaryOp { left, right, .. } => {
+            collect_bound(left, out);
+            collect_bound(right, out);
+        }
+        Expr::PathExpr(pe) => {
+            pe.start.base.iter().for_each(|b| collect_bound(b, out));
+            for step in &pe.steps {
+                step.predicates.iter().for_each(|p| collect_bound(p, out));
+            }
+        }
+        Expr::Sequence(items) | Expr::Array(items) => {
+            items.iter().for_each(|i| collect_bound(i, out))
+        }
+        Expr::Lambda(fd) => {
+            for p in &fd.params {
+                out.insert(p.name.clone());
+                if let Some(d) = &p.default {
+                    collect_bound(d, out);
+                }
+            }
+            collect_bound(&fd.body, out);
+        }
+        Expr::Constructor(c) => {
+            for (_, v) in c.attrs.iter().chain(&c.namespaces) {
+                collect_bound(v, out);
+            }
+            c.splats.iter().for_each(|(_, v)| collect_bound(v, out));
+            c.contents.iter().for_each(|i| collect_bound(i, out));
+        }
+        Expr::TextConstructor(inner)
+        | Expr::DocumentConstructor(inner)
+        | Expr::Interp(inner) => collect_bound(inner, out),
+    }
+}
@@ -0,0 +1,107 @@
+//! Evaluation events for tools that embed xform, such as editor extensions
+//! and debuggers. A `TraceSink` set as `EvalOptions::trace` hears of each
+//! rule that fires, each function call, each element constructed and the
+//! error a run ends with. `WriteSink` prints them one per line, as
+//! `xform --trace` does.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::xmlmodel::{path_to, NodeKind, XmlNode};
+
+pub trait TraceSink {
+    /// A rule of ruleset `rule`, with the pattern printed as `pattern`,
+    /// matched the item at `node_path`; see `node_path`.
+    fn on_rule_fired(&self, rule: &str, pattern: &str, node_path: &str);
+    /// A call of `name` with `arg_count` arguments, before they are
+    /// evaluated.
+    fn on_function_call(&self, name: &str, arg_count: usize);
+    /// The error the run failed with. Errors a transform recovers from are
+    /// not reported.
+    fn on_error(&self, err: &str);
+    /// An element constructor built `node_summary`, its start tag.
+    fn on_construct(&self, node_summary: &str);
+    /// Aggregate function `name` left out `count` items that were not
+    /// numbers, as its `"invalid": "skip"` option asks.
+    fn on_items_skipped(&self, name: &str, count: usize);
+}
+
+impl fmt::Debug for dyn TraceSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TraceSink(..)")
+    }
+}
+
+/// Writes each event as a `trace:` line. Write errors are ignored.
+pub struct WriteSink<W: Write> {
+    out: RefCell<W>,
+}
+
+impl<W: Write> WriteSink<W> {
+    pub fn new(out: W) -> Self {
+        WriteSink { out: RefCell::new(out) }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner()
+    }
+
+    fn line(&self, event: fmt::Arguments) {
+        let _ = writeln!(self.out.borrow_mut(), "trace: {}", event);
+    }
+}
+
+impl<W: Write> TraceSink for WriteSink<W> {
+    fn on_rule_fired(&self, rule: &str, pattern: &str, node_path: &str) {
+        self.line(format_args!("rule {} {} at {}", rule, pattern, node_path));
+    }
+
+    fn on_function_call(&self, name: &str, arg_count: usize) {
+        self.line(format_args!("call {}/{}", name, arg_count));
+    }
+
+    fn on_error(&self, err: &str) {
+        self.line(format_args!("error {}", err));
+    }
+
+    fn on_construct(&self, node_summary: &str) {
+        self.line(format_args!("construct {}", node_summary));
+    }
+
+    fn on_items_skipped(&self, name: &str, count: usize) {
+        self.line(format_args!("skipped {} non-numeric items in {}", count, name));
+    }
+}
+
+/// Location of `node` below `root` as steps counted among siblings of the
+/// same kind and name, as in `/doc[1]/sec[2]/text()[1]`. A node outside
+/// `root`, such as one the transform built, is given by its own step alone.
+pub fn node_path(root: &Rc<XmlNode>, node: &Rc<XmlNode>) -> String {
+    match path_to(root, node) {
+        Some(path) if path.len() > 1 => {
+            path.windows(2).map(|w| format!("/{}", step(&w[1], Some(&w[0])))).collect()
+        }
+        _ => step(node, None),
+    }
+}
+
+fn step(node: &Rc<XmlNode>, parent: Option<&Rc<XmlNode>>) -> String {
+    let test = match node.kind {
+        NodeKind::Document => return "/".into(),
+        NodeKind::Attribute => return format!("@{}", node.name.as_deref().unwrap_or_default()),
+        NodeKind::Element => node.name.clone().unwrap_or_default(),
+        NodeKind::Text => "text()".into(),
+        NodeKind::Comment => "comment()".into(),
+        NodeKind::Pi => "pi()".into(),
+    };
+    match parent {
+        None => test,
+        Some(p) => {
+            let earlier = p.children.iter().take_while(|c| !Rc::ptr_eq(c, node));
+            let n = earlier.filter(|c| c.kind == node.kind && c.name == node.name).count();
+            format!("{}[{}]", test, n + 1)
+        }
+    }
+}
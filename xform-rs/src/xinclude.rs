@@ -0,0 +1,161 @@
+//! XInclude 1.0 over the tree `parse_xml` builds. `xi:include` elements are
+//! replaced by the document (or the element an `xpointer` names) or the text
+//! their `href` refers to, read through the same `AccessPolicy` as `doc()`.
+//! Relative hrefs resolve against the directory of the including file. When
+//! a resource cannot be read or parsed, the include's `xi:fallback` content
+//! takes its place; without one, the error stands. An inclusion cycle always
+//! fails with `XFDY0012`.
+//!
+//! Only the shorthand `xpointer="id"` and `xpointer="element(id)"` forms are
+//! understood, matching `id` or `xml:id`.
+
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::eval::{read_allowed, resource_path, EvalOptions};
+use crate::xmlmodel::{link, make_text, next_node_id, parse_xml_marking, NodeKind, XmlNode};
+
+pub const XINCLUDE_NS: &str = "http://www.w3.org/2001/XInclude";
+
+/// Parse `text`, read from `path`, and resolve the XInclude elements in it
+/// and in what they include.
+pub fn parse_xml_xinclude(
+    text: &str,
+    path: &Path,
+    opts: &EvalOptions,
+) -> Result<Rc<XmlNode>, String> {
+    let mut open = vec![identity(path)];
+    include_document(text, path, opts, &mut open).map_err(Failure::into_message)
+}
+
+enum Failure {
+    /// The resource could not be read or parsed; a fallback may stand in.
+    Resource(String),
+    Fatal(String),
+}
+
+impl Failure {
+    fn into_message(self) -> String {
+        match self {
+            Failure::Resource(e) | Failure::Fatal(e) => e,
+        }
+    }
+}
+
+/// Key of a file for cycle detection.
+fn identity(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// `text` parsed and expanded; `open` holds the files being included.
+fn include_document(
+    text: &str,
+    path: &Path,
+    opts: &EvalOptions,
+    open: &mut Vec<PathBuf>,
+) -> Result<Rc<XmlNode>, Failure> {
+    let doc = parse_xml_marking(text, opts.strip_whitespace, XINCLUDE_NS)
+        .map_err(|e| Failure::Resource(format!("XFDY0004: {}: {}", path.display(), e)))?;
+    let base = path.parent().unwrap_or(Path::new("."));
+    let mut out = expand(&doc, base, opts, open)?;
+    Ok(out.remove(0))
+}
+
+/// `node` with its includes replaced, as the nodes that take its place.
+/// Fresh ids are taken parent first, so the result is in document order.
+fn expand(
+    node: &Rc<XmlNode>,
+    base: &Path,
+    opts: &EvalOptions,
+    open: &mut Vec<PathBuf>,
+) -> Result<Vec<Rc<XmlNode>>, Failure> {
+    let include = format!("{{{}}}include", XINCLUDE_NS);
+    let fallback = format!("{{{}}}fallback", XINCLUDE_NS);
+    match node.name.as_deref() {
+        Some(name) if name == include => match include_resource(node, base, opts, open) {
+            Err(Failure::Resource(e)) => {
+                let Some(fb) = node.children.iter().find(|c| c.name.as_deref() == Some(&fallback))
+                else {
+                    return Err(Failure::Resource(e));
+                };
+                expand_children(fb, base, opts, open)
+            }
+            result => result,
+        },
+        // A fallback outside a failed include contributes nothing
+        Some(name) if name == fallback => Ok(vec![]),
+        _ => {
+            let id = next_node_id();
+            let children = expand_children(node, base, opts, open)?;
+            Ok(vec![link(XmlNode { id, children, ..(**node).clone() })])
+        }
+    }
+}
+
+fn expand_children(
+    node: &Rc<XmlNode>,
+    base: &Path,
+    opts: &EvalOptions,
+    open: &mut Vec<PathBuf>,
+) -> Result<Vec<Rc<XmlNode>>, Failure> {
+    let mut children = Vec::new();
+    for c in &node.children {
+        children.extend(expand(c, base, opts, open)?);
+    }
+    Ok(children)
+}
+
+/// The nodes one `xi:include` element stands for.
+fn include_resource(
+    node: &Rc<XmlNode>,
+    base: &Path,
+    opts: &EvalOptions,
+    open: &mut Vec<PathBuf>,
+) -> Result<Vec<Rc<XmlNode>>, Failure> {
+    let attr = |name: &str| node.attrs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+    let Some(href) = attr("href").filter(|h| !h.is_empty()) else {
+        return Err(Failure::Fatal("XFDY0012: xi:include without href".into()));
+    };
+    let path = resource_path(href, Some(base));
+    let parse = attr("parse").unwrap_or("xml");
+    if parse != "xml" && parse != "text" {
+        return Err(Failure::Fatal(format!("XFDY0012: unknown xi:include parse {:?}", parse)));
+    }
+    let text = read_allowed(href, &path, opts).map_err(Failure::Resource)?;
+    if parse == "text" {
+        return Ok(vec![make_text(&text)]);
+    }
+    let key = identity(&path);
+    if open.contains(&key) {
+        return Err(Failure::Fatal(format!("XFDY0012: inclusion cycle at {}", href)));
+    }
+    open.push(key);
+    let doc = include_document(&text, &path, opts, open);
+    open.pop();
+    let doc = doc?;
+    match attr("xpointer") {
+        None => Ok(doc.children.clone()),
+        Some(pointer) => {
+            let id = pointer
+                .strip_prefix("element(")
+                .and_then(|p| p.strip_suffix(')'))
+                .unwrap_or(pointer);
+            match find_id(&doc, id) {
+                Some(el) => Ok(vec![el]),
+                None => Err(Failure::Resource(format!(
+                    "XFDY0012: no element {:?} in {}",
+                    id, href
+                ))),
+            }
+        }
+    }
+}
+
+fn find_id(node: &Rc<XmlNode>, id: &str) -> Option<Rc<XmlNode>> {
+    let matches = node.kind == NodeKind::Element
+        && node.attrs.iter().any(|(k, v)| (k == "id" || k == "xml:id") && v == id);
+    if matches {
+        return Some(node.clone());
+    }
+    node.children.iter().find_map(|c| find_id(c, id))
+}
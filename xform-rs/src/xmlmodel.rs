@@ -1,4 +1,8 @@
-use std::rc::Rc;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum NodeKind {
@@ -12,15 +16,59 @@ pub enum NodeKind {
 
 #[derive(Debug, Clone)]
 pub struct XmlNode {
+    /// Identity of this node, see `next_node_id`. Copies get a new id.
+    pub id: u64,
     pub kind: NodeKind,
     pub name: Option<String>,
     pub value: Option<String>,
     /// Ordered list of (name, value) pairs for attributes
     pub attrs: Vec<(String, String)>,
+    /// Namespace declarations made on this element as (prefix, uri) pairs;
+    /// the empty prefix declares the default namespace.
+    pub namespaces: Vec<(String, String)>,
     pub children: Vec<Rc<XmlNode>>,
+    /// In the scope of `xml:space="preserve"` where the node was parsed or
+    /// built. Copies keep it, so whitespace stripping leaves a copied code
+    /// block alone even without the ancestor that declared it.
+    pub preserve_space: bool,
+    /// The node's parent, see `XmlNode::parent`.
+    pub parent: ParentLink,
+}
+
+/// Weak link from a node to the first parent it was built into that is
+/// still alive. Set by `link`; a clone starts out unlinked, since a copy of
+/// a node is not a child of anything yet.
+#[derive(Default)]
+pub struct ParentLink(RefCell<Weak<XmlNode>>);
+
+impl Clone for ParentLink {
+    fn clone(&self) -> Self {
+        ParentLink::default()
+    }
+}
+
+impl fmt::Debug for ParentLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ParentLink(..)")
+    }
 }
 
 impl XmlNode {
+    /// The element or document this node is a child of, or the element an
+    /// attribute was read from. `None` for a root, and for a node whose
+    /// tree is gone.
+    pub fn parent(&self) -> Option<Rc<XmlNode>> {
+        self.parent.0.borrow().upgrade()
+    }
+
+    /// Link this node to `parent` unless it already has a live parent.
+    pub fn set_parent(&self, parent: &Rc<XmlNode>) {
+        let mut link = self.parent.0.borrow_mut();
+        if link.strong_count() == 0 {
+            *link = Rc::downgrade(parent);
+        }
+    }
+
     pub fn string_value(&self) -> String {
         match self.kind {
             NodeKind::Text | NodeKind::Attribute => self.value.clone().unwrap_or_default(),
@@ -32,6 +80,22 @@ impl XmlNode {
     }
 }
 
+static NEXT_NODE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Allocate a node id. Ids only grow, and each tree built here takes them
+/// parent first, children left to right, so they follow document order.
+pub fn next_node_id() -> u64 {
+    NEXT_NODE_ID.fetch_add(1, AtomicOrdering::Relaxed)
+}
+
+/// Document order of two nodes of the same tree, by node id. Nodes of
+/// different trees order by when the trees were built. Attribute nodes are
+/// made fresh by each `@` step and so order after every node built before
+/// them.
+pub fn document_order(a: &Rc<XmlNode>, b: &Rc<XmlNode>) -> Ordering {
+    a.id.cmp(&b.id)
+}
+
 /// Extract entity name → value mappings from DOCTYPE internal subset.
 /// Only handles simple `<!ENTITY name "value">` or `<!ENTITY name 'value'>` forms.
 fn extract_entities(doctype_block: &str) -> Vec<(String, String)> {
@@ -89,7 +153,7 @@ fn preprocess(xml: &str) -> String {
             while i < bytes.len() {
                 match bytes[i] {
                     b'[' => { depth += 1; i += 1; }
-                    b']' => { if depth > 0 { depth -= 1; } i += 1; }
+                    b']' => { depth = depth.saturating_sub(1); i += 1; }
                     b'>' if depth == 0 => { i += 1; break; }
                     _ => { i += 1; }
                 }
@@ -106,8 +170,47 @@ fn preprocess(xml: &str) -> String {
     replace_entities(&without_doctype, &entities)
 }
 
+/// Open element on the parse stack: (id, node_kind, name, attrs, children).
+/// The id is taken at the start tag so the element precedes its content.
+type Frame = (u64, NodeKind, Option<String>, Vec<(String, String)>, Vec<Rc<XmlNode>>);
+
 pub fn parse_xml(text: &str) -> Result<Rc<XmlNode>, String> {
+    parse_xml_with(text, false)
+}
+
+/// Parse `text`, dropping the whitespace-only text nodes `strip_whitespace`
+/// would when `strip` is set. Nodes in the scope of `xml:space="preserve"`
+/// are marked with `preserve_space` either way.
+pub fn parse_xml_with(text: &str, strip: bool) -> Result<Rc<XmlNode>, String> {
+    parse_clean(&preprocess(text), strip, None)
+}
+
+/// `parse_xml_with`, except that elements in namespace `ns` are named
+/// `{ns}local` rather than by their local name, so that a later pass can
+/// find them. Used for XInclude.
+pub(crate) fn parse_xml_marking(text: &str, strip: bool, ns: &str) -> Result<Rc<XmlNode>, String> {
+    parse_clean(&preprocess(text), strip, Some(ns))
+}
+
+/// Element wrapped around a fragment so that it parses as a document.
+const FRAGMENT_ROOT: &str = "xform-fragment";
+
+/// Parse content that need not have a single root element, such as a run of
+/// `<li>` elements or text mixed with elements, into its top-level nodes.
+/// DOCTYPE entities apply as for `parse_xml`.
+pub fn parse_xml_fragment(text: &str) -> Result<Vec<Rc<XmlNode>>, String> {
     let clean = preprocess(text);
+    let body = match clean.trim_start().strip_prefix("<?xml") {
+        Some(rest) => rest.split_once("?>").map_or("", |(_, body)| body),
+        None => &clean,
+    };
+    let doc = parse_clean(&format!("<{0}>{1}</{0}>", FRAGMENT_ROOT, body), false, None)?;
+    Ok(doc.children.first().map(|root| root.children.clone()).unwrap_or_default())
+}
+
+/// Parse `clean`, already through `preprocess`, marking elements in namespace
+/// `mark` as for `parse_xml_marking`.
+fn parse_clean(clean: &str, strip: bool, mark: Option<&str>) -> Result<Rc<XmlNode>, String> {
     let cursor = std::io::Cursor::new(clean.as_bytes().to_vec());
 
     use xml::reader::{EventReader, XmlEvent, ParserConfig};
@@ -117,152 +220,592 @@ pub fn parse_xml(text: &str) -> Result<Rc<XmlNode>, String> {
         .ignore_comments(false);
     let reader = EventReader::new_with_config(cursor, config);
 
-    // Stack of (node_kind, name, attrs, children)
-    let mut stack: Vec<(NodeKind, Option<String>, Vec<(String, String)>, Vec<Rc<XmlNode>>)> =
-        vec![(NodeKind::Document, None, vec![], vec![])];
+    let mut stack: Vec<Frame> = vec![(next_node_id(), NodeKind::Document, None, vec![], vec![])];
+    // In-scope `xml:space` of each open frame
+    let mut spaces: Vec<bool> = vec![false];
 
     for event in reader {
         match event.map_err(|e| format!("XML parse error: {}", e))? {
             XmlEvent::StartElement { name, attributes, .. } => {
                 let mut attrs: Vec<(String, String)> = attributes
                     .into_iter()
-                    .map(|a| (a.name.local_name, a.value))
+                    .map(|a| match (a.name.prefix.as_deref(), a.name.local_name.as_str()) {
+                        // `xml:space` keeps its prefix so that `space_attr`
+                        // cannot mistake a plain `space` attribute for it;
+                        // other attributes, `xml:lang` included, go by their
+                        // local name as they always have
+                        (Some("xml"), "space") => ("xml:space".to_string(), a.value),
+                        _ => (a.name.local_name, a.value),
+                    })
                     .collect();
                 // Sort for determinism (xmltree uses HashMap, we want stable order)
                 attrs.sort_by(|a, b| a.0.cmp(&b.0));
-                stack.push((NodeKind::Element, Some(name.local_name), attrs, vec![]));
+                spaces.push(space_attr(&attrs).unwrap_or(spaces[spaces.len() - 1]));
+                let local = match mark {
+                    Some(ns) if name.namespace.as_deref() == Some(ns) => {
+                        format!("{{{}}}{}", ns, name.local_name)
+                    }
+                    _ => name.local_name,
+                };
+                stack.push((next_node_id(), NodeKind::Element, Some(local), attrs, vec![]));
             }
             XmlEvent::EndElement { .. } => {
-                let (kind, name, attrs, children) = stack.pop().unwrap();
-                let node = Rc::new(XmlNode { kind, name, value: None, attrs, children });
-                stack.last_mut().unwrap().3.push(node);
+                let (id, kind, name, attrs, mut children) = stack.pop().unwrap();
+                let preserve_space = spaces.pop().unwrap();
+                if strip && !preserve_space && !is_mixed(&children) {
+                    children.retain(|c| !is_blank(c));
+                }
+                let node = link(XmlNode {
+                    id,
+                    kind,
+                    name,
+                    value: None,
+                    attrs,
+                    namespaces: vec![],
+                    children,
+                    preserve_space,
+                    parent: ParentLink::default(),
+                });
+                stack.last_mut().unwrap().4.push(node);
             }
             XmlEvent::Characters(text) | XmlEvent::CData(text) => {
                 let node = Rc::new(XmlNode {
+                    id: next_node_id(),
                     kind: NodeKind::Text,
                     name: None,
                     value: Some(text),
                     attrs: vec![],
+                    namespaces: vec![],
                     children: vec![],
+                    preserve_space: spaces[spaces.len() - 1],
+                    parent: ParentLink::default(),
                 });
-                stack.last_mut().unwrap().3.push(node);
+                stack.last_mut().unwrap().4.push(node);
             }
             XmlEvent::Comment(text) => {
                 let node = Rc::new(XmlNode {
+                    id: next_node_id(),
                     kind: NodeKind::Comment,
                     name: None,
                     value: Some(text),
                     attrs: vec![],
+                    namespaces: vec![],
                     children: vec![],
+                    preserve_space: spaces[spaces.len() - 1],
+                    parent: ParentLink::default(),
                 });
-                stack.last_mut().unwrap().3.push(node);
+                stack.last_mut().unwrap().4.push(node);
             }
             XmlEvent::ProcessingInstruction { name, data } => {
                 let node = Rc::new(XmlNode {
+                    id: next_node_id(),
                     kind: NodeKind::Pi,
                     name: Some(name),
                     value: data,
                     attrs: vec![],
+                    namespaces: vec![],
                     children: vec![],
+                    preserve_space: spaces[spaces.len() - 1],
+                    parent: ParentLink::default(),
                 });
-                stack.last_mut().unwrap().3.push(node);
+                stack.last_mut().unwrap().4.push(node);
             }
             _ => {}
         }
     }
 
-    let (_, _, _, children) = stack.pop().unwrap();
-    Ok(Rc::new(XmlNode {
-        kind: NodeKind::Document,
-        name: None,
-        value: None,
-        attrs: vec![],
-        children,
-    }))
+    let (id, _, _, _, mut children) = stack.pop().unwrap();
+    if strip {
+        children.retain(|c| !is_blank(c));
+    }
+    Ok(make_document(id, children))
 }
 
 pub fn deep_copy(node: &Rc<XmlNode>) -> Rc<XmlNode> {
-    Rc::new(XmlNode {
+    link(XmlNode {
+        id: next_node_id(),
         kind: node.kind.clone(),
         name: node.name.clone(),
         value: node.value.clone(),
         attrs: node.attrs.clone(),
+        namespaces: node.namespaces.clone(),
         children: node.children.iter().map(deep_copy).collect(),
+        preserve_space: node.preserve_space,
+        parent: ParentLink::default(),
     })
 }
 
+/// Deep copy of `node` with every element attribute named `old` renamed to
+/// `new` in place. A pre-existing `new` attribute on the same element is
+/// replaced by the renamed one.
+pub fn rename_attrs(node: &Rc<XmlNode>, old: &str, new: &str) -> Rc<XmlNode> {
+    let attrs = if node.kind == NodeKind::Element && node.attrs.iter().any(|(k, _)| k == old) {
+        node.attrs
+            .iter()
+            .filter(|(k, _)| k != new)
+            .map(|(k, v)| {
+                let k = if k == old { new.to_string() } else { k.clone() };
+                (k, v.clone())
+            })
+            .collect()
+    } else {
+        node.attrs.clone()
+    };
+    link(XmlNode {
+        id: next_node_id(),
+        kind: node.kind.clone(),
+        name: node.name.clone(),
+        value: node.value.clone(),
+        attrs,
+        namespaces: node.namespaces.clone(),
+        children: node.children.iter().map(|c| rename_attrs(c, old, new)).collect(),
+        preserve_space: node.preserve_space,
+        parent: ParentLink::default(),
+    })
+}
+
+/// Copy of `node` without the whitespace-only text nodes of element-only
+/// content, i.e. those whose siblings are all elements, comments, processing
+/// instructions or other whitespace. Mixed content such as the spaces in
+/// `<p>Hello <b>world</b>!</p>` is kept, as is everything inside an
+/// `xml:space="preserve"` element or marked `preserve_space`.
+pub fn strip_whitespace(node: &Rc<XmlNode>) -> Rc<XmlNode> {
+    strip_scoped(node, false, None)
+}
+
+/// `strip_whitespace` for result trees, which also keeps everything inside
+/// elements named in `preserve`, and a whitespace-only text node without a
+/// line break between two elements: in `<p><b>big</b> <i>deal</i></p>` the
+/// space separates words, while indentation always starts on a new line.
+pub fn strip_space(node: &Rc<XmlNode>, preserve: &[String]) -> Rc<XmlNode> {
+    strip_scoped(node, false, Some(preserve))
+}
+
+fn strip_scoped(node: &Rc<XmlNode>, preserve: bool, names: Option<&[String]>) -> Rc<XmlNode> {
+    let named = names.is_some_and(|names| {
+        node.kind == NodeKind::Element && names.iter().any(|n| node.name.as_ref() == Some(n))
+    });
+    let preserve = match space_attr(&node.attrs) {
+        Some(p) => p || named,
+        None => preserve || named || node.preserve_space,
+    };
+    let mixed = is_mixed(&node.children);
+    let id = next_node_id();
+    let is_gap = |i: usize| {
+        let element = |j: Option<usize>| {
+            j.and_then(|j| node.children.get(j)).is_some_and(|c| c.kind == NodeKind::Element)
+        };
+        names.is_some()
+            && !node.children[i].value.as_deref().unwrap_or("").contains('\n')
+            && element(i.checked_sub(1))
+            && element(Some(i + 1))
+    };
+    let children = node
+        .children
+        .iter()
+        .enumerate()
+        .filter(|(i, c)| preserve || mixed || !is_blank(c) || is_gap(*i))
+        .map(|(_, c)| strip_scoped(c, preserve, names))
+        .collect();
+    link(XmlNode {
+        id,
+        kind: node.kind.clone(),
+        name: node.name.clone(),
+        value: node.value.clone(),
+        attrs: node.attrs.clone(),
+        namespaces: node.namespaces.clone(),
+        children,
+        preserve_space: node.preserve_space,
+        parent: ParentLink::default(),
+    })
+}
+
+/// Copy of `node` with adjacent text nodes merged and each run of whitespace
+/// in them collapsed to one space. Text is not trimmed, so words on either
+/// side of an inline element stay apart; empty text nodes are dropped. Text
+/// in the scope of `xml:space="preserve"` is only merged.
+pub fn normalize_text_nodes(node: &Rc<XmlNode>) -> Rc<XmlNode> {
+    normalize_scoped(node, false)
+}
+
+fn normalize_scoped(node: &Rc<XmlNode>, preserve: bool) -> Rc<XmlNode> {
+    let preserve = space_attr(&node.attrs).unwrap_or(preserve || node.preserve_space);
+    let id = next_node_id();
+    let mut children: Vec<Rc<XmlNode>> = Vec::new();
+    let mut text: Option<String> = None;
+    for c in &node.children {
+        if c.kind == NodeKind::Text {
+            text.get_or_insert_with(String::new).push_str(c.value.as_deref().unwrap_or(""));
+            continue;
+        }
+        push_text(&mut children, text.take(), preserve);
+        children.push(normalize_scoped(c, preserve));
+    }
+    push_text(&mut children, text, preserve);
+    link(XmlNode {
+        id,
+        kind: node.kind.clone(),
+        name: node.name.clone(),
+        value: node.value.clone(),
+        attrs: node.attrs.clone(),
+        namespaces: node.namespaces.clone(),
+        children,
+        preserve_space: node.preserve_space,
+        parent: ParentLink::default(),
+    })
+}
+
+fn push_text(children: &mut Vec<Rc<XmlNode>>, text: Option<String>, preserve: bool) {
+    let Some(text) = text.filter(|t| !t.is_empty()) else {
+        return;
+    };
+    let mut value = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if preserve || !ch.is_ascii_whitespace() {
+            value.push(ch);
+        } else if !value.ends_with(' ') {
+            value.push(' ');
+        }
+    }
+    children.push(Rc::new(XmlNode {
+        id: next_node_id(),
+        kind: NodeKind::Text,
+        name: None,
+        value: Some(value),
+        attrs: vec![],
+        namespaces: vec![],
+        children: vec![],
+        preserve_space: preserve,
+        parent: ParentLink::default(),
+    }));
+}
+
+/// Copy of `node` laid out with each child of element-only content on its
+/// own line, indented by `width` spaces per level. Whitespace-only text in
+/// such content is replaced. Mixed content and `xml:space="preserve"`
+/// scopes are left as they are, down to their last descendant.
+pub fn indent_tree(node: &Rc<XmlNode>, width: usize) -> Rc<XmlNode> {
+    indent_scoped(node, width, 0, false)
+}
+
+fn indent_scoped(node: &Rc<XmlNode>, width: usize, depth: usize, keep: bool) -> Rc<XmlNode> {
+    let keep = match space_attr(&node.attrs) {
+        Some(p) => p,
+        None => keep || node.preserve_space,
+    };
+    let id = next_node_id();
+    let children = if node.kind == NodeKind::Document {
+        node.children.iter().map(|c| indent_scoped(c, width, 0, keep)).collect()
+    } else if keep || is_mixed(&node.children) || node.children.iter().all(is_blank) {
+        node.children.iter().map(|c| indent_scoped(c, width, depth + 1, true)).collect()
+    } else {
+        let mut children = Vec::new();
+        for c in node.children.iter().filter(|c| !is_blank(c)) {
+            children.push(make_text(&format!("\n{}", " ".repeat(width * (depth + 1)))));
+            children.push(indent_scoped(c, width, depth + 1, false));
+        }
+        children.push(make_text(&format!("\n{}", " ".repeat(width * depth))));
+        children
+    };
+    link(XmlNode {
+        id,
+        kind: node.kind.clone(),
+        name: node.name.clone(),
+        value: node.value.clone(),
+        attrs: node.attrs.clone(),
+        namespaces: node.namespaces.clone(),
+        children,
+        preserve_space: node.preserve_space,
+        parent: ParentLink::default(),
+    })
+}
+
+/// Copy of `node` with the attributes of every element in name order, the
+/// order `parse_xml` gives them.
+pub fn sort_attributes(node: &Rc<XmlNode>) -> Rc<XmlNode> {
+    let mut attrs = node.attrs.clone();
+    attrs.sort_by(|a, b| a.0.cmp(&b.0));
+    link(XmlNode {
+        id: next_node_id(),
+        attrs,
+        children: node.children.iter().map(sort_attributes).collect(),
+        ..(**node).clone()
+    })
+}
+
+/// Whether `attrs` set `xml:space`, and to `preserve`.
+pub fn space_attr(attrs: &[(String, String)]) -> Option<bool> {
+    attrs.iter().find(|(k, _)| k == "xml:space").map(|(_, v)| v == "preserve")
+}
+
+fn is_blank(node: &Rc<XmlNode>) -> bool {
+    node.kind == NodeKind::Text
+        && node.value.as_deref().unwrap_or("").chars().all(|ch| ch.is_ascii_whitespace())
+}
+
+/// Whether `children` hold text other than whitespace.
+fn is_mixed(children: &[Rc<XmlNode>]) -> bool {
+    children.iter().any(|c| c.kind == NodeKind::Text && !is_blank(c))
+}
+
 pub fn iter_descendants(node: &Rc<XmlNode>) -> Vec<Rc<XmlNode>> {
-    let mut out = Vec::new();
-    for child in &node.children {
-        out.push(child.clone());
-        out.extend(iter_descendants(child));
+    descendants(node).collect()
+}
+
+/// Lazy document-order walk over the descendants of `node`.
+pub fn descendants(node: &Rc<XmlNode>) -> Descendants {
+    Descendants { stack: vec![(node.clone(), 0)] }
+}
+
+pub struct Descendants {
+    /// (node, index of the next child to visit)
+    stack: Vec<(Rc<XmlNode>, usize)>,
+}
+
+impl Iterator for Descendants {
+    type Item = Rc<XmlNode>;
+
+    fn next(&mut self) -> Option<Rc<XmlNode>> {
+        while let Some((node, idx)) = self.stack.last_mut() {
+            if let Some(child) = node.children.get(*idx).cloned() {
+                *idx += 1;
+                self.stack.push((child.clone(), 0));
+                return Some(child);
+            }
+            self.stack.pop();
+        }
+        None
     }
-    out
+}
+
+/// The nodes from the root of `node`'s tree down to `node` inclusive,
+/// following parent links.
+pub fn ancestry(node: &Rc<XmlNode>) -> Vec<Rc<XmlNode>> {
+    let mut path = vec![node.clone()];
+    while let Some(parent) = path[path.len() - 1].parent() {
+        path.push(parent);
+    }
+    path.reverse();
+    path
+}
+
+/// What serialization does with characters XML 1.0 does not allow, such as
+/// U+0000 to U+0008 or U+FFFE.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InvalidChars {
+    /// Write U+FFFD in their place.
+    #[default]
+    Replace,
+    /// Leave them out.
+    Strip,
+    /// Fail with `XFDY0008`.
+    Error,
+}
+
+/// The nodes from `root` down to `node` inclusive, found by identity, or
+/// `None` when `node` is not beneath `root`.
+pub fn path_to(root: &Rc<XmlNode>, node: &Rc<XmlNode>) -> Option<Vec<Rc<XmlNode>>> {
+    if Rc::ptr_eq(root, node) {
+        return Some(vec![root.clone()]);
+    }
+    for child in &root.children {
+        if let Some(mut path) = path_to(child, node) {
+            path.insert(0, root.clone());
+            return Some(path);
+        }
+    }
+    None
 }
 
 pub fn serialize(node: &Rc<XmlNode>) -> String {
+    serialize_with(node, InvalidChars::Replace).expect("replacing characters cannot fail")
+}
+
+pub fn serialize_with(node: &Rc<XmlNode>, invalid: InvalidChars) -> Result<String, String> {
+    serialize_scoped(node, &mut Vec::new(), invalid)
+}
+
+/// Serialize with `scope` holding the namespace bindings declared by
+/// ancestors, so a declaration repeating an in-scope binding is omitted.
+fn serialize_scoped(
+    node: &Rc<XmlNode>,
+    scope: &mut Vec<(String, String)>,
+    invalid: InvalidChars,
+) -> Result<String, String> {
+    let attr = |v: &str| Ok::<_, String>(escape_attr(&clean_chars(v, invalid)?));
     match node.kind {
-        NodeKind::Document => node.children.iter().map(serialize).collect(),
-        NodeKind::Text => escape_text(node.value.as_deref().unwrap_or("")),
-        NodeKind::Comment => String::new(), // omit comments in output
-        NodeKind::Pi => String::new(),
-        NodeKind::Attribute => escape_attr(node.value.as_deref().unwrap_or("")),
+        NodeKind::Document => {
+            node.children.iter().map(|c| serialize_scoped(c, scope, invalid)).collect()
+        }
+        NodeKind::Text => {
+            let text = clean_chars(node.value.as_deref().unwrap_or(""), invalid)?;
+            Ok(escape_text(&text))
+        }
+        NodeKind::Comment => Ok(String::new()), // omit comments in output
+        NodeKind::Pi => Ok(String::new()),
+        NodeKind::Attribute => attr(node.value.as_deref().unwrap_or("")),
         NodeKind::Element => {
             let name = node.name.as_deref().unwrap_or("");
+            let depth = scope.len();
+            let mut decls = String::new();
+            for (prefix, uri) in &node.namespaces {
+                let bound = scope.iter().rev().find(|(p, _)| p == prefix).map(|(_, u)| u);
+                if bound == Some(uri) {
+                    continue;
+                }
+                if prefix.is_empty() {
+                    decls.push_str(&format!(" xmlns=\"{}\"", attr(uri)?));
+                } else {
+                    decls.push_str(&format!(" xmlns:{}=\"{}\"", prefix, attr(uri)?));
+                }
+                scope.push((prefix.clone(), uri.clone()));
+            }
             let attrs: String = node
                 .attrs
                 .iter()
-                .map(|(k, v)| format!(" {}=\"{}\"", k, escape_attr(v)))
-                .collect();
-            if node.children.is_empty() {
-                format!("<{}{}/>", name, attrs)
+                .map(|(k, v)| Ok(format!(" {}=\"{}\"", k, attr(v)?)))
+                .collect::<Result<_, String>>()?;
+            let out = if node.children.is_empty() {
+                Ok(format!("<{}{}{}/>", name, decls, attrs))
             } else {
-                let inner: String = node.children.iter().map(serialize).collect();
-                format!("<{}{}>{}</{}>", name, attrs, inner, name)
-            }
+                node.children
+                    .iter()
+                    .map(|c| serialize_scoped(c, scope, invalid))
+                    .collect::<Result<String, String>>()
+                    .map(|inner| format!("<{}{}{}>{}</{}>", name, decls, attrs, inner, name))
+            };
+            scope.truncate(depth);
+            out
         }
     }
 }
 
+/// Whether XML 1.0 allows `c` in a document.
+pub(crate) fn is_xml_char(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\r' | '\u{20}'..='\u{FFFD}' | '\u{10000}'..)
+}
+
+/// `s` with the characters XML does not allow handled per `invalid`.
+fn clean_chars(s: &str, invalid: InvalidChars) -> Result<std::borrow::Cow<'_, str>, String> {
+    let Some(bad) = s.chars().find(|c| !is_xml_char(*c)) else {
+        return Ok(s.into());
+    };
+    match invalid {
+        InvalidChars::Replace => Ok(s
+            .chars()
+            .map(|c| if is_xml_char(c) { c } else { '\u{FFFD}' })
+            .collect::<String>()
+            .into()),
+        InvalidChars::Strip => Ok(s.chars().filter(|c| is_xml_char(*c)).collect::<String>().into()),
+        InvalidChars::Error => {
+            Err(format!("XFDY0008: character U+{:04X} is not allowed in XML", bad as u32))
+        }
+    }
+}
+
+/// Carriage returns become character references, as a parser would turn a
+/// literal one into a newline. So do DEL and the C1 controls, which XML 1.0
+/// allows but discourages and XML 1.1 only takes as references; characters
+/// XML does not allow at all are `clean_chars`' business.
 pub fn escape_text(s: &str) -> String {
-    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    let s = s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    let s = s.replace('\r', "&#13;");
+    if !s.contains(is_discouraged_control) {
+        return s;
+    }
+    let mut out = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        if is_discouraged_control(c) {
+            out.push_str(&format!("&#{};", c as u32));
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn is_discouraged_control(c: char) -> bool {
+    matches!(c, '\u{7F}'..='\u{9F}')
 }
 
+/// Newlines and tabs become character references too; written literally, a
+/// parser would normalize them to spaces.
 pub fn escape_attr(s: &str) -> String {
-    escape_text(s).replace('"', "&quot;")
+    escape_text(s).replace('"', "&quot;").replace('\n', "&#10;").replace('\t', "&#9;")
+}
+
+/// Wrap `node` in an `Rc` and make it the parent of those of its children
+/// that have no live parent yet. Every node with children is built this
+/// way; a child shared with another live tree keeps its first parent.
+pub fn link(node: XmlNode) -> Rc<XmlNode> {
+    let node = Rc::new(node);
+    for child in &node.children {
+        child.set_parent(&node);
+    }
+    node
 }
 
-/// Build a new element XmlNode (for eval_constructor)
+/// Build a new element XmlNode (for eval_constructor). `id` should be
+/// taken before the children are built to keep document order.
 pub fn make_element(
+    id: u64,
     name: &str,
     attrs: Vec<(String, String)>,
+    namespaces: Vec<(String, String)>,
     children: Vec<Rc<XmlNode>>,
 ) -> Rc<XmlNode> {
-    Rc::new(XmlNode {
+    link(XmlNode {
+        id,
         kind: NodeKind::Element,
         name: Some(name.to_string()),
         value: None,
+        preserve_space: space_attr(&attrs).unwrap_or(false),
         attrs,
+        namespaces,
+        children,
+        parent: ParentLink::default(),
+    })
+}
+
+/// Build a document node; `id` as for `make_element`.
+pub fn make_document(id: u64, children: Vec<Rc<XmlNode>>) -> Rc<XmlNode> {
+    link(XmlNode {
+        id,
+        kind: NodeKind::Document,
+        name: None,
+        value: None,
+        attrs: vec![],
+        namespaces: vec![],
         children,
+        preserve_space: false,
+        parent: ParentLink::default(),
     })
 }
 
 pub fn make_text(value: &str) -> Rc<XmlNode> {
     Rc::new(XmlNode {
+        id: next_node_id(),
         kind: NodeKind::Text,
         name: None,
         value: Some(value.to_string()),
         attrs: vec![],
+        namespaces: vec![],
         children: vec![],
+        preserve_space: false,
+        parent: ParentLink::default(),
     })
 }
 
 pub fn make_attr(name: &str, value: &str) -> Rc<XmlNode> {
     Rc::new(XmlNode {
+        id: next_node_id(),
         kind: NodeKind::Attribute,
         name: Some(name.to_string()),
         value: Some(value.to_string()),
         attrs: vec![],
+        namespaces: vec![],
         children: vec![],
+        preserve_space: false,
+        parent: ParentLink::default(),
     })
 }
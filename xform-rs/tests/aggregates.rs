@@ -0,0 +1,101 @@
+//! `sum`, `avg`, `min` and `max`, and their policy for items that are not
+//! numbers.
+
+mod common;
+
+use std::rc::Rc;
+
+use common::{run, run_err, run_with, Recorder};
+use xform::EvalOptions;
+
+/// Each aggregate over the same mixed sequence, with `options` appended to
+/// the arguments of each call.
+fn aggregates(options: &str) -> String {
+    let transform = format!(
+        r#"xform version "2.0";
+        let mixed := (3, "N/A", 4, "x", 5) in
+        <r sum={{sum(mixed{0})}} avg={{avg(mixed{0})}}
+           min={{min(mixed{0})}} max={{max(mixed{0})}}/>"#,
+        options
+    );
+    run(&transform, "<d/>")
+}
+
+#[test]
+fn invalid_items_fail_by_default_and_under_error() {
+    for options in ["", r#", map("invalid", "error")"#] {
+        let transform = format!(r#"xform version "2.0"; sum((3, "N/A", 4){})"#, options);
+        assert!(run_err(&transform, "<d/>").contains("N/A"), "options {:?}", options);
+    }
+}
+
+#[test]
+fn skip_leaves_invalid_items_out() {
+    assert_eq!(
+        aggregates(r#", map("invalid", "skip")"#),
+        r#"<r sum="12" avg="4" min="3" max="5"/>"#
+    );
+}
+
+#[test]
+fn a_number_replaces_invalid_items() {
+    assert_eq!(
+        aggregates(r#", map("invalid", 0)"#),
+        r#"<r sum="12" avg="2.4" min="0" max="5"/>"#
+    );
+    assert_eq!(
+        aggregates(r#", map("invalid", 10)"#),
+        r#"<r sum="32" avg="6.4" min="3" max="10"/>"#
+    );
+}
+
+#[test]
+fn skipped_items_are_reported_to_the_trace() {
+    let recorder = Rc::new(Recorder::default());
+    let opts = EvalOptions { trace: Some(recorder.clone()), ..Default::default() };
+    let out = run_with(
+        r#"xform version "2.0"; <r>{sum((3, "N/A", 4, "x", 5), map("invalid", "skip"))}</r>"#,
+        "<d/>",
+        &opts,
+    );
+    assert_eq!(out, "<r>12</r>");
+    let events = recorder.events.borrow();
+    assert_eq!(events.iter().filter(|e| e.starts_with("skipped")).collect::<Vec<_>>(), [
+        "skipped 2 in sum"
+    ]);
+}
+
+#[test]
+fn all_invalid_skipped_gives_the_empty_result() {
+    assert_eq!(
+        run(r#"xform version "2.0"; <r>{sum(("a", "b"), map("invalid", "skip"))}</r>"#, "<d/>"),
+        "<r>0</r>"
+    );
+    assert_eq!(
+        run(r#"xform version "2.0"; <r>{max(("a", "b"), -1, map("invalid", "skip"))}</r>"#, "<d/>"),
+        "<r>-1</r>"
+    );
+}
+
+#[test]
+fn unknown_policies_fail() {
+    assert_eq!(
+        run_err(r#"xform version "2.0"; sum((1, 2), map("invalid", "ignore"))"#, "<d/>"),
+        r#"XFDY0017: unknown invalid policy "ignore""#
+    );
+}
+
+#[test]
+fn a_default_stands_in_for_an_empty_input() {
+    let empty = |options: &str| {
+        let transform = format!(
+            r#"xform version "2.0";
+            <r sum={{sum((){0})}} avg={{avg((){0})}} min={{min((){0})}} max={{max((){0})}}/>"#,
+            options
+        );
+        run(&transform, "<d/>")
+    };
+    // Without one, the sum of nothing is 0 and the others are empty
+    assert_eq!(empty(""), r#"<r sum="0" avg="" min="" max=""/>"#);
+    assert_eq!(empty(", -1"), r#"<r sum="-1" avg="-1" min="-1" max="-1"/>"#);
+}
@@ -0,0 +1,46 @@
+//! Arrays, their accessors and JSON.
+
+mod common;
+
+use common::run;
+
+fn eval(expr: &str) -> String {
+    run(&format!(r#"xform version "2.0"; <r>{{{}}}</r>"#, expr), "<d/>")
+}
+
+#[test]
+fn array_members_stay_apart() {
+    assert_eq!(eval("arraySize(array((1, 2), 3))"), "<r>2</r>");
+    assert_eq!(eval("arraySize([(1, 2), 3])"), "<r>2</r>");
+    assert_eq!(eval("count(arrayToSeq([(1, 2), 3]))"), "<r>3</r>");
+    let counts = r#"string-join(for m in [(1, 2), 3] return string(count(m)), ",")"#;
+    assert_eq!(eval(counts), "<r>2,1</r>");
+}
+
+#[test]
+fn array_get_takes_positions_from_either_end() {
+    assert_eq!(eval(r#"arrayGet(["a", "b", "c"], 2)"#), "<r>b</r>");
+    assert_eq!(eval(r#"arrayGet(["a", "b", "c"], -1)"#), "<r>c</r>");
+    assert_eq!(eval(r#"arrayGet(["a", "b", "c"], 4)"#), "<r/>");
+    assert_eq!(eval(r#"arrayGet(["a", "b", "c"], -4)"#), "<r/>");
+    assert_eq!(eval(r#"arrayGet(["a", "b", "c"], 0 div 0)"#), "<r/>");
+}
+
+#[test]
+fn json_arrays_round_trip() {
+    for json in [
+        "[[1,2],[3],[]]",
+        r#"{"a":[1,[2,3]],"b":{"c":null,"d":[true,false]},"e":"x"}"#,
+        r#"[[],{},"",0,-1.5]"#,
+    ] {
+        let out = eval(&format!("serializeJson(parseJson('{}'))", json));
+        assert_eq!(out, format!("<r>{}</r>", json), "{}", json);
+    }
+}
+
+#[test]
+fn parsed_json_arrays_are_arrays() {
+    assert_eq!(eval("arraySize(parseJson('[[1,2],[3]]'))"), "<r>2</r>");
+    assert_eq!(eval("arraySize(arrayGet(parseJson('[[1,2],[3]]'), 1))"), "<r>2</r>");
+    assert_eq!(eval(r#"lookup(parseJson('{"k":"v"}'), "k")"#), "<r>v</r>");
+}
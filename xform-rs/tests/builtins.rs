@@ -0,0 +1,346 @@
+//! Built-in functions, each run through a small transform.
+
+mod common;
+
+use common::{run, run_err, run_with};
+use xform::EvalOptions;
+
+#[test]
+fn distinct_dedups_nodes_by_string_value() {
+    let out = run(
+        r#"xform version "2.0"; <r>{for t in distinct(.//t) return <t>{string(t)}</t>}</r>"#,
+        "<d><t>a</t><t>b</t><t>a</t></d>",
+    );
+    assert_eq!(out, "<r><t>a</t><t>b</t></r>");
+}
+
+#[test]
+fn distinct_dedups_numbers_by_value() {
+    let out = run(r#"xform version "2.0"; string-join(distinct((1, 1.0, 2, 2)), ",")"#, "<d/>");
+    assert_eq!(out, "1,2");
+}
+
+#[test]
+fn sort_orders_numbers_by_value_and_strings_by_text() {
+    let sorted = |seq: &str| {
+        run(&format!(r#"xform version "2.0"; string-join(sort({}), " ")"#, seq), "<d/>")
+    };
+    assert_eq!(sorted("(10, 2, 1)"), "1 2 10");
+    assert_eq!(sorted("(2.5, -1, 10, 1.0)"), "-1 1 2.5 10");
+    assert_eq!(sorted(r#"("10", "2", "1")"#), "1 10 2");
+}
+
+#[test]
+fn distinct_by_node_keeps_equal_looking_nodes() {
+    let out = run(
+        r#"xform version "2.0"; <r n={count(distinctByNode(.//t))}/>"#,
+        "<d><t>a</t><t>a</t></d>",
+    );
+    assert_eq!(out, r#"<r n="2"/>"#);
+}
+
+#[test]
+fn distinct_by_node_dedups_a_large_node_sequence() {
+    let input = format!("<d>{}</d>", "<t>x</t>".repeat(2000));
+    let out = run(
+        r#"xform version "2.0"; <r n={count(distinctByNode((.//t, .//t, .//t)))}/>"#,
+        &input,
+    );
+    assert_eq!(out, r#"<r n="2000"/>"#);
+}
+
+#[test]
+fn group_by_node_puts_equal_looking_nodes_in_separate_groups() {
+    let out = run(
+        r#"xform version "2.0";
+        <r by-text={count(groupBy(.//t))} by-node={count(groupByNode(.//t))}>{
+            for g in groupByNode(.//t) return <g n={count(groupItems(g))}>{groupKey(g)}</g>
+        }</r>"#,
+        "<d><t>a</t><t>a</t></d>",
+    );
+    assert_eq!(out, r#"<r by-text="1" by-node="2"><g n="1"><t>a</t></g><g n="1"><t>a</t></g></r>"#);
+}
+
+#[test]
+fn index_by_node_files_equal_looking_nodes_apart() {
+    let out = run(
+        r#"xform version "2.0";
+        <r by-text={count(lookup(index(.//t), "a"))}
+           by-node={count(lookup(indexByNode(.//t), "a"))}/>"#,
+        "<d><t>a</t><t>a</t></d>",
+    );
+    // Keyed by identity, neither node is filed under its text
+    assert_eq!(out, r#"<r by-text="2" by-node="0"/>"#);
+}
+
+#[test]
+fn nth_counts_negative_positions_from_the_end() {
+    let t = |pos: &str| {
+        run(&format!(r#"xform version "2.0"; <r>{{nth(("a", "b", "c"), {})}}</r>"#, pos), "<d/>")
+    };
+    assert_eq!(t("1"), "<r>a</r>");
+    assert_eq!(t("-1"), "<r>c</r>");
+    assert_eq!(t("-3"), "<r>a</r>");
+    assert_eq!(t("-4"), "<r/>");
+    assert_eq!(t("4"), "<r/>");
+    assert_eq!(t("0"), "<r/>");
+    assert_eq!(t("0 div 0"), "<r/>");
+}
+
+#[test]
+fn subsequence_counts_negative_starts_from_the_end() {
+    let out = run(
+        r#"xform version "2.0"; <r>{string-join(subsequence((1, 2, 3, 4), -2), ",")}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, "<r>3,4</r>");
+    let out = run(
+        r#"xform version "2.0"; <r>{string-join(subsequence((1, 2, 3), -9, 2), ",")}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, "<r/>");
+}
+
+#[test]
+fn join_matches_items_of_two_documents() {
+    let out = run(
+        r#"xform version "2.0";
+        let prices := parse-xml("<ps><p sku='b'>2</p><p sku='a'>1</p><p sku='b'>3</p></ps>") in
+        <r>{join(.//item, prices//p,
+                 fn(i) := string(i/@sku), fn(p) := string(p/@sku),
+                 fn(i, ps) := <line sku={string(i/@sku)}>{string-join(ps -> string(.), "+")}</line>
+        )}</r>"#,
+        r#"<order><item sku="a"/><item sku="b"/><item sku="c"/></order>"#,
+    );
+    assert_eq!(out, r#"<r><line sku="a">1</line><line sku="b">2+3</line><line sku="c"/></r>"#);
+}
+
+#[test]
+fn join_needs_functions() {
+    assert_eq!(
+        run_err(r#"xform version "2.0"; join(.//a, .//b, "k", "k", "c")"#, "<d/>"),
+        "XFDY0015: join needs key and combine functions"
+    );
+}
+
+#[test]
+fn count_distinct_counts_values_or_keys() {
+    let out = run(
+        r#"xform version "2.0";
+        <r a={count-distinct(.//t)} b={count-distinct((1, 1.0, 2))}
+           c={count-distinct(.//t, fn(t) := count(stringToCodepoints(string(t))))}/>"#,
+        "<d><t>a</t><t>bb</t><t>a</t><t>cc</t></d>",
+    );
+    assert_eq!(out, r#"<r a="3" b="2" c="2"/>"#);
+    assert_eq!(
+        run_err(r#"xform version "2.0"; count-distinct((1, 2), "k")"#, "<d/>"),
+        "XFDY0015: count-distinct key must be a function"
+    );
+}
+
+#[test]
+fn fixpoint_applies_until_nothing_changes() {
+    let out = run(
+        r#"xform version "2.0";
+        let fold := fn(s) := for x in s return (if (x > 3) then x - 3 else x) in
+        <r>{string-join(fixpoint((5, 9, 2), fold), " ")}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, "<r>2 3 2</r>");
+}
+
+#[test]
+fn fixpoint_gives_up_after_its_iteration_limit() {
+    assert_eq!(
+        run_err(r#"xform version "2.0"; fixpoint(0, fn(n) := n + 1, 50)"#, "<d/>"),
+        "XFLM0006: fixpoint not reached after 50 iterations"
+    );
+    assert_eq!(
+        run_err(r#"xform version "2.0"; fixpoint(0, 1)"#, "<d/>"),
+        "XFDY0015: fixpoint expects a function"
+    );
+}
+
+#[test]
+fn parse_xml_turns_escaped_markup_into_nodes() {
+    let out = run(
+        r#"xform version "2.0";
+        let doc := parse-xml(string(.//payload)) in
+        <r name={name(doc/b)} text={string(doc/b)}>{doc/b}</r>"#,
+        "<d><payload>&lt;b&gt;x&lt;/b&gt;</payload></d>",
+    );
+    assert_eq!(out, r#"<r name="b" text="x"><b>x</b></r>"#);
+}
+
+#[test]
+fn parse_xml_fails_on_malformed_markup() {
+    let err = run_err(r#"xform version "2.0"; parse-xml("<b>x</i>")"#, "<d/>");
+    assert!(err.starts_with("XFDY0004: "), "{}", err);
+}
+
+#[test]
+fn rename_attributes_renames_at_every_depth() {
+    let out = run(
+        r#"xform version "2.0"; rename-attributes(./d, "cls", "class")"#,
+        r#"<d cls="top" id="1"><p a="x" cls="mid"><b cls="low"/></p><p/></d>"#,
+    );
+    assert_eq!(
+        out,
+        r#"<d class="top" id="1"><p a="x" class="mid"><b class="low"/></p><p/></d>"#
+    );
+}
+
+#[test]
+fn text_string_value_and_text_nodes_of_mixed_content() {
+    let transform = r#"xform version "2.0";
+        let p := ./p in
+        <r deep={stringValue(p)} text={text(p)} shallow={text(p, false())}
+           nodes={count(textNodes(p))}>{string-join(textNodes(p), "|")}</r>"#;
+    let input = "<p>one <b>two</b> three <i>four</i></p>";
+    assert_eq!(
+        run(transform, input),
+        concat!(
+            r#"<r deep="one two three four" text="one two three four" shallow="one  three " "#,
+            r#"nodes="2">one | three </r>"#
+        )
+    );
+    // With shallow_text, text() without a mode reads only the text children
+    let opts = EvalOptions { shallow_text: true, ..Default::default() };
+    let out = run_with(transform, input, &opts);
+    assert!(out.contains(r#"deep="one two three four" text="one  three ""#), "{}", out);
+}
+
+#[test]
+fn regular_expressions_know_unicode_letters_and_spaces() {
+    let out = run(
+        r##"xform version "2.0";
+        <r letters={matches("Ærøskøbing", "^\\p{L}+$")} word={matches("é", "^\\w$", "u")}
+           digits={replace("x\u0661\u0662y", "\\d", "#")}/>"##,
+        "<d/>",
+    );
+    assert_eq!(out, r#"<r letters="true" word="true" digits="x##y"/>"#);
+    // No-break, em and ideographic spaces separate tokens like a plain one
+    let spaced = "a\u{a0}b\u{2003}c\u{3000}d e";
+    let out = run(
+        &format!(
+            r#"xform version "2.0";
+            <r n={{count(tokenize("{0}"))}}>{{string-join(tokenize("{0}", "\\s+"), "|")}}</r>"#,
+            spaced
+        ),
+        "<d/>",
+    );
+    assert_eq!(out, r#"<r n="5">a|b|c|d|e</r>"#);
+}
+
+#[test]
+fn deep_contains_and_find_text_see_text_across_child_boundaries() {
+    let out = run(
+        r#"xform version "2.0";
+        let ps := ./d/p in
+        <r here={deep-contains(nth(ps, 1), "bold text")} not={deep-contains(nth(ps, 2), "bold")}>{
+            string-join(for e in find-text(., "bold text") return name(e), ",")
+        }</r>"#,
+        "<d><p>Some <b>bo</b><i>ld</i> text</p><p>plain</p><s><p><em>bold</em> text</p></s></d>",
+    );
+    assert_eq!(out, r#"<r here="true" not="false">d,p,s,p</r>"#);
+}
+
+#[test]
+fn index_files_items_under_every_key_and_index_unique_checks_them() {
+    let input = r#"<d>
+        <o cust="a" year="2023" tags="x y"/><o cust="a" year="2024" tags="y"/>
+        <o cust="b" year="2023" tags=""/></d>"#;
+    let out = run(
+        r#"xform version "2.0";
+        let by_tag := index(./d/o, fn(o) := tokenize(string(o/@tags))) in
+        let by_year := indexUnique(./d/o, fn(o) := string-join((o/@cust, o/@year), "/")) in
+        <r x={count(lookup(by_tag, "x"))} y={count(lookup(by_tag, "y"))}
+           a23={count(lookup(by_year, "a/2023"))} b24={count(lookup(by_year, "b/2024"))}/>"#,
+        input,
+    );
+    assert_eq!(out, r#"<r x="1" y="2" a23="1" b24="0"/>"#);
+    let err = run_err(
+        r#"xform version "2.0"; indexUnique(./d/o, fn(o) := string(o/@cust))"#,
+        input,
+    );
+    assert_eq!(err, r#"XFDY0009: duplicate key "a" in indexUnique"#);
+}
+
+#[test]
+fn partition_splits_a_sequence_into_two_buckets_that_make_it_up() {
+    let out = run(
+        r#"xform version "2.0";
+        let parts := partition(./d/i, fn(i) := number(i) mod 2 = 1) in
+        let odd := lookup(parts, "matched") in
+        let even := lookup(parts, "unmatched") in
+        <r odd={string-join(odd, ",")} even={string-join(even, ",")}
+           whole={string-join(odd | even, ",") = string-join(./d/i, ",")}/>"#,
+        "<d><i>1</i><i>2</i><i>4</i><i>5</i><i>7</i></d>",
+    );
+    assert_eq!(out, r#"<r odd="1,5,7" even="2,4" whole="true"/>"#);
+}
+
+#[test]
+fn fragments_parse_without_a_single_root() {
+    let nodes = xform::parse_xml_fragment("lead <li>a</li><li>b</li> tail").unwrap();
+    let names: Vec<_> = nodes.iter().map(|n| n.name.clone().unwrap_or_default()).collect();
+    assert_eq!(names, ["", "li", "li", ""]);
+    assert_eq!(nodes[0].string_value(), "lead ");
+    assert!(xform::parse_xml_fragment("<li>a</b>").is_err());
+    let out = run(
+        r#"xform version "2.0";
+        let nodes := parseXmlFragment("lead <li>a</li><li>b</li>") in
+        <ul n={count(nodes)}>{nodes}</ul>"#,
+        "<d/>",
+    );
+    assert_eq!(out, r#"<ul n="3">lead <li>a</li><li>b</li></ul>"#);
+    let err = run_err(r#"xform version "2.0"; parseXmlFragment("<li>a</b>")"#, "<d/>");
+    assert!(err.starts_with("XFDY0004: "), "{}", err);
+}
+
+#[test]
+fn text_joins_pieces_with_a_separator_and_normalizes_space() {
+    let t = |input: &str, args: &str| {
+        run(&format!(r#"xform version "2.0"; <r>{{text(./p{})}}</r>"#, args), input)
+    };
+    let plain = "<p>Hello <b>big</b><i>world</i>!</p>";
+    assert_eq!(t(plain, r#", "deep", "|""#), "<r>Hello |big|world|!</r>");
+    assert_eq!(t(plain, r#", "shallow", "|""#), "<r>Hello |!</r>");
+    let spaced = "<p>\n  Hello <b>\n  big\n  </b><i>world</i>!</p>";
+    assert_eq!(t(spaced, r#", "normalize""#), "<r>Hello big world!</r>");
+    assert_eq!(t(spaced, r#", "deep normalize", " | ""#), "<r>Hello | big | world | !</r>");
+}
+
+#[test]
+fn attribute_tells_an_empty_attribute_from_a_missing_one() {
+    let out = run(
+        r#"xform version "2.0";
+        let e := ./d/e in
+        <r empty={exists(attribute(e, "a"))} missing={exists(attribute(e, "b"))}
+           attr-empty={exists(attr(e, "a"))} attr-missing={exists(attr(e, "b"))}/>"#,
+        r#"<d><e a=""/></d>"#,
+    );
+    assert_eq!(
+        out,
+        r#"<r empty="true" missing="false" attr-empty="true" attr-missing="true"/>"#
+    );
+}
+
+#[test]
+fn kind_of_names_each_node_kind_and_type_of_stays_coarse() {
+    let out = run(
+        r#"xform version "2.0";
+        let kinds := for n in (., ./d, ./d/@a, ./d/node()) return kind-of(n) in
+        <r kinds={string-join(kinds, " ")} types={string-join(distinct(
+            for n in (., ./d, ./d/@a, ./d/node()) return typeOf(n)), " ")}
+           other={string-join((kind-of(1), kind-of("x"), kind-of(map()), kind-of(())), " ")}/>"#,
+        r#"<d a="1">t<e/><!--c--><?p x?></d>"#,
+    );
+    assert_eq!(
+        out,
+        concat!(
+            r#"<r kinds="document element attribute text element comment pi" types="node" "#,
+            r#"other="number string map null"/>"#
+        )
+    );
+}
@@ -0,0 +1,73 @@
+//! `ExprCache`: hits, least-recently-used eviction and invalidation.
+
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use xform::{ExprCache, Parser};
+
+const PRICE: &str = "sum(for i in .//item[./@qty > 0] return number(i/price) * number(i/@qty))";
+
+#[test]
+fn a_hit_hands_out_the_same_expression() {
+    let mut cache = ExprCache::new(4);
+    let first = cache.compile(PRICE).unwrap();
+    let again = cache.compile(PRICE).unwrap();
+    assert!(Rc::ptr_eq(&first, &again));
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn the_least_recently_used_expression_is_evicted() {
+    let mut cache = ExprCache::new(2);
+    let a = cache.compile("1 + 1").unwrap();
+    let b = cache.compile("2 + 2").unwrap();
+    // Using `a` again leaves `b` the oldest
+    assert!(Rc::ptr_eq(&a, &cache.compile("1 + 1").unwrap()));
+    cache.compile("3 + 3").unwrap();
+    assert_eq!(cache.len(), 2);
+    assert!(Rc::ptr_eq(&a, &cache.compile("1 + 1").unwrap()));
+    assert!(!Rc::ptr_eq(&b, &cache.compile("2 + 2").unwrap()));
+}
+
+#[test]
+fn invalidated_expressions_are_parsed_again() {
+    let mut cache = ExprCache::new(4);
+    let a = cache.compile(PRICE).unwrap();
+    assert!(cache.invalidate(PRICE));
+    assert!(!cache.invalidate(PRICE));
+    assert!(cache.is_empty());
+    assert!(!Rc::ptr_eq(&a, &cache.compile(PRICE).unwrap()));
+    cache.clear();
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn errors_and_a_zero_capacity_cache_keep_nothing() {
+    let mut cache = ExprCache::new(4);
+    assert!(cache.compile("1 +").is_err());
+    assert!(cache.is_empty());
+    let mut uncached = ExprCache::new(0);
+    uncached.compile(PRICE).unwrap();
+    assert!(uncached.is_empty());
+}
+
+/// Time `compile` takes for `n` calls.
+fn timed(n: usize, mut compile: impl FnMut() -> Result<(), String>) -> Duration {
+    let started = Instant::now();
+    for _ in 0..n {
+        compile().unwrap();
+    }
+    started.elapsed()
+}
+
+/// Micro-benchmark: compiling the same expression repeatedly through the
+/// cache costs a lookup, not a parse.
+#[test]
+fn repeated_compilation_is_faster_through_the_cache() {
+    const N: usize = 2000;
+    let parsed = timed(N, || Parser::new(PRICE).parse_standalone_expr().map(drop));
+    let mut cache = ExprCache::new(16);
+    let cached = timed(N, || cache.compile(PRICE).map(drop));
+    eprintln!("{} compilations: parsed {:?}, cached {:?}", N, parsed, cached);
+    assert!(cached * 5 < parsed, "parsed {:?}, cached {:?}", parsed, cached);
+}
@@ -0,0 +1,46 @@
+//! The `xform` command line.
+
+use std::process::Command;
+
+/// Run `xform` over files written to a scratch directory named `case`;
+/// returns (stdout, stderr).
+fn xform(case: &str, flags: &[&str], transform: &str, input: &str) -> (String, String) {
+    let dir = std::env::temp_dir().join(format!("xform-cli-{}-{}", case, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (xml, xf) = (dir.join("input.xml"), dir.join("transform.xform"));
+    std::fs::write(&xml, input).unwrap();
+    std::fs::write(&xf, transform).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_xform"))
+        .args(flags)
+        .arg(&xml)
+        .arg(&xf)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    (String::from_utf8(out.stdout).unwrap(), String::from_utf8(out.stderr).unwrap())
+}
+
+#[test]
+fn unserializable_results_point_at_json_maps() {
+    let transform = r#"xform version "2.0"; map("a", 1)"#;
+    let (out, err) = xform("maps", &[], transform, "<d/>");
+    assert_eq!(out, "");
+    assert_eq!(
+        err,
+        "Evaluation error: XFDY0014: cannot serialize a map [map: a]\n\
+         hint: --json-maps writes maps, arrays and functions as JSON\n"
+    );
+    let (out, err) = xform("json-maps", &["--json-maps"], transform, "<d/>");
+    assert_eq!((out.as_str(), err.as_str()), (r#"{"a":1}"#, ""));
+}
+
+#[test]
+fn the_input_path_is_the_document_uri() {
+    let transform = r#"xform version "2.0"; <r uri={document-uri()} css={resolve-uri("s.css")}/>"#;
+    let (out, err) = xform("uri", &[], transform, "<d/>");
+    assert_eq!(err, "");
+    let dir = std::env::temp_dir().join(format!("xform-cli-uri-{}", std::process::id()));
+    let input = dir.join("input.xml").display().to_string();
+    let css = dir.join("s.css").display().to_string();
+    assert_eq!(out.trim_end(), format!(r#"<r uri="{}" css="{}"/>"#, input, css));
+}
@@ -0,0 +1,72 @@
+//! Collations of `compare`, `sort` and `order by`, and `equals-ci`.
+
+mod common;
+
+use common::{run, run_err};
+
+const FILES: &str = "<d><f>item10</f><f>Item2</f><f>item1</f><f>ITEM2</f></d>";
+
+fn sorted(collation: &str) -> String {
+    run(
+        &format!(
+            r#"xform version "2.0";
+            <r>{{string-join(
+              for f in .//f order by string(f) collation "{}" return string(f), " ")}}</r>"#,
+            collation
+        ),
+        FILES,
+    )
+}
+
+#[test]
+fn codepoint_order_is_the_default() {
+    assert_eq!(sorted("codepoint"), "<r>ITEM2 Item2 item1 item10</r>");
+    assert_eq!(sorted(""), sorted("codepoint"));
+}
+
+#[test]
+fn case_insensitive_order_ignores_case_and_keeps_ties_stable() {
+    assert_eq!(sorted("ci"), "<r>item1 item10 Item2 ITEM2</r>");
+    assert_eq!(sorted("case-insensitive"), sorted("ci"));
+}
+
+#[test]
+fn natural_order_compares_digit_runs_by_value() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{string-join(sort(("file10", "file2", "file1", "file02"), "natural"), " ")}</r>"#,
+        "<d/>",
+    );
+    // Equal values fall back to codepoint order
+    assert_eq!(out, "<r>file1 file02 file2 file10</r>");
+}
+
+#[test]
+fn compare_follows_the_collation() {
+    let out = run(
+        r#"xform version "2.0";
+        <r a={compare("a2", "a10")} b={compare("a2", "a10", "natural")}
+           c={compare("ABC", "abc", "ci")} d={compare("ABC", "abc")}/>"#,
+        "<d/>",
+    );
+    assert_eq!(out, r#"<r a="1" b="-1" c="0" d="-1"/>"#);
+}
+
+#[test]
+fn unknown_collations_fail() {
+    assert_eq!(
+        run_err(r#"xform version "2.0"; compare("a", "b", "klingon")"#, "<d/>"),
+        r#"XFDY0016: unknown collation "klingon""#
+    );
+}
+
+#[test]
+fn equals_ci_ignores_case_where_equality_does_not() {
+    let out = run(
+        r#"xform version "2.0";
+        <r ci={equals-ci("Foo", "foo")} eq={"Foo" = "foo"} other={equals-ci("Foo", "bar")}
+           found={count(.//t[equals-ci(string(.), "FOO")])}/>"#,
+        "<d><t>foo</t><t>Foo</t><t>food</t></d>",
+    );
+    assert_eq!(out, r#"<r ci="true" eq="false" other="false" found="2"/>"#);
+}
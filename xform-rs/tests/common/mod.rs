@@ -0,0 +1,65 @@
+//! Helpers shared by the integration tests: run a transform given as source
+//! text against an input document given as markup.
+
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+
+use xform::{eval_module_with, serialize_items, EvalOptions, Parser, TraceSink};
+
+/// Output of `transform` over `input`, serialized as `xform` prints it.
+pub fn run(transform: &str, input: &str) -> String {
+    run_with(transform, input, &EvalOptions::default())
+}
+
+pub fn run_with(transform: &str, input: &str, opts: &EvalOptions) -> String {
+    try_run(transform, input, opts).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// The error `transform` fails with over `input`.
+pub fn run_err(transform: &str, input: &str) -> String {
+    match try_run(transform, input, &EvalOptions::default()) {
+        Ok(out) => panic!("expected an error, got {:?}", out),
+        Err(e) => e,
+    }
+}
+
+pub fn try_run(transform: &str, input: &str, opts: &EvalOptions) -> Result<String, String> {
+    let module = Parser::new(transform).parse_module()?;
+    let doc = xform::parse_xml(input)?;
+    serialize_items(&eval_module_with(&module, doc, opts)?)
+}
+
+/// Trace sink keeping one line per event, in the order they came.
+#[derive(Default)]
+pub struct Recorder {
+    pub events: RefCell<Vec<String>>,
+}
+
+impl Recorder {
+    fn push(&self, event: String) {
+        self.events.borrow_mut().push(event);
+    }
+}
+
+impl TraceSink for Recorder {
+    fn on_rule_fired(&self, rule: &str, pattern: &str, node_path: &str) {
+        self.push(format!("rule {} {} at {}", rule, pattern, node_path));
+    }
+
+    fn on_function_call(&self, name: &str, arg_count: usize) {
+        self.push(format!("call {}/{}", name, arg_count));
+    }
+
+    fn on_error(&self, err: &str) {
+        self.push(format!("error {}", err));
+    }
+
+    fn on_construct(&self, node_summary: &str) {
+        self.push(format!("construct {}", node_summary));
+    }
+
+    fn on_items_skipped(&self, name: &str, count: usize) {
+        self.push(format!("skipped {} in {}", count, name));
+    }
+}
@@ -0,0 +1,188 @@
+//! Element constructors and the text written in them.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{run, run_err};
+
+fn build(content: &str) -> String {
+    run(&format!(r#"xform version "2.0"; <a>{}</a>"#, content), "<d/>")
+}
+
+#[test]
+fn character_references_in_text_emit_their_character() {
+    assert_eq!(build("10&#160;kg"), "<a>10\u{a0}kg</a>");
+    assert_eq!(build("10&#xA0;kg"), "<a>10\u{a0}kg</a>");
+    assert_eq!(build("&#x41;&#66;c"), "<a>ABc</a>");
+}
+
+#[test]
+fn other_references_stay_as_written() {
+    // Written text is escaped on output, so a kept `&` comes out as `&amp;`
+    assert_eq!(build("x&#1;y"), "<a>x&amp;#1;y</a>");
+    assert_eq!(build("&#+65;"), "<a>&amp;#+65;</a>");
+    assert_eq!(build("&#xFFFE;&#; &#xZZ;"), "<a>&amp;#xFFFE;&amp;#; &amp;#xZZ;</a>");
+    assert_eq!(build("&amp;"), "<a>&amp;amp;</a>");
+}
+
+#[test]
+fn separated_by_goes_between_items_only() {
+    assert_eq!(build(r#"{for x in ("a", "b", "c") separated-by ", " return x}"#), "<a>a, b, c</a>");
+    assert_eq!(build(r#"{for x in ("a") separated-by ", " return x}"#), "<a>a</a>");
+    assert_eq!(
+        run(
+            r#"xform version "2.0";
+            <p>{for i in .//i separated-by "; " return <b>{string(i)}</b>}</p>"#,
+            "<d><i>1</i><i>2</i></d>"
+        ),
+        "<p><b>1</b>; <b>2</b></p>"
+    );
+}
+
+#[test]
+fn keywords_name_elements_attributes_and_steps() {
+    assert_eq!(
+        build(r#"<if cond={1 + 1}><for/><default let={"x"}/></if>"#),
+        r#"<a><if cond="2"><for/><default let="x"/></if></a>"#
+    );
+    let out = run(
+        r#"xform version "2.0";
+        <r>{string-join(for d in .//default return string(d/@if), ",")}{
+            if (exists(./d/for)) then "for" else ""}</r>"#,
+        r#"<d><default if="a"/><s><default if="b"/></s><for/></d>"#,
+    );
+    assert_eq!(out, "<r>a,bfor</r>");
+}
+
+#[test]
+fn namespaces_declared_on_a_constructor_are_written_out() {
+    let out = run(
+        r#"xform version "2.0";
+        let uri := "http://www.w3.org/2000/svg" in
+        <svg:svg xmlns:svg={uri}><svg:rect width={1}/></svg:svg>"#,
+        "<d/>",
+    );
+    assert_eq!(
+        out,
+        r#"<svg:svg xmlns:svg="http://www.w3.org/2000/svg"><svg:rect width="1"/></svg:svg>"#
+    );
+}
+
+#[test]
+fn boundary_space_decides_which_spaces_survive_between_interpolations() {
+    let p = |prolog: &str| {
+        run(
+            &format!(
+                r#"xform version "2.0"; {}
+                let a := "x" in let b := "y" in
+                let p := <p> {{a}} {{b}} </p> in
+                <r n={{count(textNodes(p))}}>{{p}}<q>Total: {{a}} items</q></r>"#,
+                prolog
+            ),
+            "<d/>",
+        )
+    };
+    // Whitespace-only runs between interpolations go; other text is kept
+    // whole, and adjacent text makes a single text node
+    assert_eq!(p(""), r#"<r n="1"><p>xy</p><q>Total: x items</q></r>"#);
+    assert_eq!(p("declare boundary-space strip;"), p(""));
+    assert_eq!(
+        p("declare boundary-space preserve;"),
+        r#"<r n="1"><p> x y </p><q>Total: x items</q></r>"#
+    );
+}
+
+#[test]
+fn attributes_in_the_content_attach_to_the_element() {
+    let input = r#"<e id="1" class="a"/>"#;
+    let out = run(
+        r#"xform version "2.0"; <div class={"z"}>{./e/@*}{./e/@class}<span/></div>"#,
+        input,
+    );
+    assert_eq!(out, r#"<div class="a" id="1"><span/></div>"#);
+    assert_eq!(
+        run_err(r#"xform version "2.0"; <div><span/>{./e/@id}</div>"#, input),
+        "XFDY0006: attribute id added to <div> after its content"
+    );
+}
+
+#[test]
+fn an_attribute_written_twice_fails_and_newlines_round_trip() {
+    assert_eq!(
+        run_err(r#"xform version "2.0"; <a href={"x"} href={"y"}/>"#, "<d/>"),
+        "XFDY0007: duplicate attribute href on <a>"
+    );
+    let out = build(r#"<e title={"one\ntwo\tthree"}/>"#);
+    assert_eq!(out, r#"<a><e title="one&#10;two&#9;three"/></a>"#);
+    let doc = xform::parse_xml(&out).unwrap();
+    assert_eq!(doc.children[0].children[0].attrs[0].1, "one\ntwo\tthree");
+}
+
+#[test]
+fn nodes_kept_elsewhere_are_copied_into_a_constructor() {
+    let out = run(
+        r#"xform version "2.0";
+        let t := <t><x/></t> in
+        <r>{<w>{t}</w>}{t}{./d}<n same={generateId(./d) = generateId(nth(./d, 1))}/>{./d}</r>"#,
+        "<d><i/></d>",
+    );
+    assert_eq!(
+        out,
+        r#"<r><w><t><x/></t></w><t><x/></t><d><i/></d><n same="true"/><d><i/></d></r>"#
+    );
+}
+
+/// Output of a transform that builds a table of `rows` rows inside `depth`
+/// nested element constructors, and the time it took.
+fn wrapped_table(rows: usize, depth: usize) -> (String, Duration) {
+    let transform = format!(
+        r#"xform version "2.0";
+        {}<table>{{for i in ./d/i return <tr><td>{{string(i)}}</td><td/></tr>}}</table>{}"#,
+        "<w>{".repeat(depth),
+        "}</w>".repeat(depth)
+    );
+    let input = format!("<d>{}</d>", "<i>x</i>".repeat(rows));
+    let started = Instant::now();
+    let out = run(&transform, &input);
+    (out, started.elapsed())
+}
+
+/// Micro-benchmark: wrapping a freshly built subtree in more elements
+/// reuses it rather than copying it at every level.
+#[test]
+fn wrapping_a_fresh_subtree_does_not_copy_it_at_every_level() {
+    const ROWS: usize = 5000;
+    let (flat, built) = wrapped_table(ROWS, 0);
+    let (nested, wrapped) = wrapped_table(ROWS, 40);
+    assert_eq!(nested, format!("{}{}{}", "<w>".repeat(40), flat, "</w>".repeat(40)));
+    eprintln!("{} rows: built {:?}, wrapped 40 deep {:?}", ROWS, built, wrapped);
+    assert!(wrapped < built * 5, "built {:?}, wrapped {:?}", built, wrapped);
+}
+
+#[test]
+fn build_element_takes_its_name_from_a_computed_string() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for f in ./d/field return
+            build-element(string(f/@tag), map("id", f/@id, "n", 1), (string(f), <b/>))}</r>"#,
+        r#"<d><field tag="title" id="t">T</field><field tag="para" id="p">P</field></d>"#,
+    );
+    assert_eq!(out, r#"<r><title id="t" n="1">T<b/></title><para id="p" n="1">P<b/></para></r>"#);
+    let err = run_err(r#"xform version "2.0"; build-element("1x", map(), ())"#, "<d/>");
+    assert_eq!(err, r#"XFDY0002: invalid element name "1x""#);
+}
+
+#[test]
+fn a_spliced_map_sets_attributes_and_later_ones_win() {
+    let out = run(
+        r#"xform version "2.0";
+        let attrs := map("class", "note", "id", "x") in
+        <r><a {attrs}/><b {attrs} class={"warning"}/><c class={"first"} {attrs}/></r>"#,
+        "<d/>",
+    );
+    assert_eq!(
+        out,
+        r#"<r><a class="note" id="x"/><b class="warning" id="x"/><c class="note" id="x"/></r>"#
+    );
+}
@@ -0,0 +1,39 @@
+//! Run-wide counters of `counterNext` and `counterValue`.
+
+mod common;
+
+use common::run;
+
+const BOOK: &str = r#"<book>
+  <chapter><p>a<footnote>one</footnote></p><p>b<footnote>two</footnote></p></chapter>
+  <chapter><p>c</p></chapter>
+  <chapter><p>d<footnote>three</footnote><footnote>four</footnote></p></chapter>
+</book>"#;
+
+#[test]
+fn footnotes_are_numbered_across_chapters() {
+    let out = run(
+        r#"xform version "2.0";
+        rule main match <chapter/> :=
+            <c n={counterNext("chapter")}>{apply(.//footnote)}</c>;
+        rule main match <footnote/> := <note n={counterNext("fn")}>{string(.)}</note>;
+        <r notes={counterValue("fn")}>{apply(.//chapter)}<total>{counterValue("fn")}</total></r>"#,
+        BOOK,
+    );
+    assert_eq!(
+        out,
+        concat!(
+            r#"<r notes="0"><c n="1"><note n="1">one</note><note n="2">two</note></c>"#,
+            r#"<c n="2"/><c n="3"><note n="3">three</note><note n="4">four</note></c>"#,
+            "<total>4</total></r>"
+        )
+    );
+}
+
+#[test]
+fn counters_start_at_zero_for_each_run() {
+    let transform = r#"xform version "2.0";
+        string-join((counterNext("a"), counterNext("a"), counterValue("b")), " ")"#;
+    assert_eq!(run(transform, "<d/>"), "1 2 0");
+    assert_eq!(run(transform, "<d/>"), "1 2 0");
+}
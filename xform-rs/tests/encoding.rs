@@ -0,0 +1,120 @@
+//! Digests and encodings of string values.
+
+mod common;
+
+use common::run;
+#[cfg(feature = "hash")]
+use common::run_err;
+
+fn eval(expr: &str) -> String {
+    run(&format!(r#"xform version "2.0"; {}"#, expr), "<d/>")
+}
+
+#[cfg(feature = "hash")]
+#[test]
+fn digests_match_known_answers() {
+    assert_eq!(
+        eval(r#"sha256("")"#),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        eval(r#"sha256("abc")"#),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(eval(r#"md5("")"#), "d41d8cd98f00b204e9800998ecf8427e");
+    assert_eq!(eval(r#"md5("abc")"#), "900150983cd24fb0d6963f7d28e17f72");
+}
+
+#[cfg(feature = "hash")]
+#[test]
+fn digests_are_of_the_utf8_bytes() {
+    assert_eq!(
+        eval(r#"sha256("é")"#),
+        "4a99557e4033c3539de2eb65472017cad5f9557f7a0625a09f1c3f6e2ba69c4c"
+    );
+    assert_eq!(eval(r#"md5("é")"#), "66ddcd97cfdeabb2f6fb8a999b4bc76f");
+}
+
+#[cfg(feature = "hash")]
+#[test]
+fn base64_round_trips_utf8_text() {
+    assert_eq!(eval(r#"base64Encode("né")"#), "bsOp");
+    assert_eq!(eval(r#"base64Decode("bsOp")"#), "né");
+    assert_eq!(eval(r#"base64Decode(base64Encode("a < b"))"#), "a < b");
+    assert!(run_err(r#"xform version "2.0"; base64Decode("*")"#, "<d/>")
+        .starts_with("XFDY0013: invalid base64"));
+}
+
+#[cfg(not(feature = "hash"))]
+#[test]
+fn digests_need_the_hash_feature() {
+    let err = common::run_err(r#"xform version "2.0"; sha256("abc")"#, "<d/>");
+    assert_eq!(err, "XFST0003: sha256 needs xform built with the `hash` feature");
+}
+
+#[test]
+fn hash_fingerprints_the_serialized_form() {
+    assert_eq!(eval(r#"hash("")"#), "cbf29ce484222325");
+    assert_eq!(eval(r#"hash("a")"#), "af63dc4c8601ec8c");
+    let out = run(
+        r#"xform version "2.0";
+        let h := for p in ./d/p return hash(p) in
+        <r same={nth(h, 1) = nth(h, 2)} other={nth(h, 1) = nth(h, 3)}/>"#,
+        "<d><p a='1'>x</p><p a='1'>x</p><p a='2'>x</p></d>",
+    );
+    assert_eq!(out, r#"<r same="true" other="false"/>"#);
+}
+
+#[test]
+fn codepoints_round_trip_composed_and_decomposed_accents() {
+    assert_eq!(eval(r#"string-join(stringToCodepoints("é"), " ")"#), "233");
+    assert_eq!(eval(r#"string-join(stringToCodepoints("e\u0301"), " ")"#), "101 769");
+    assert_eq!(eval("codepointsToString((101, 769)) = codepointsToString(233)"), "false");
+}
+
+/// Keys of the groups of a composed and a decomposed "é", each key passed
+/// through `key`.
+#[cfg(feature = "unicode")]
+fn accent_groups(key: &str) -> String {
+    eval(&format!(
+        r#"string-join(for g in groupBy((codepointsToString(233), codepointsToString((101, 769))),
+                                         fn(s) := {}) return string(count(groupItems(g))), ",")"#,
+        key
+    ))
+}
+
+#[cfg(feature = "unicode")]
+#[test]
+fn normalized_keys_group_both_spellings_of_an_accent() {
+    assert_eq!(accent_groups("s"), "1,1");
+    assert_eq!(accent_groups(r#"normalizeUnicode(s)"#), "2");
+    assert_eq!(accent_groups(r#"normalizeUnicode(s, "NFD")"#), "2");
+    assert_eq!(
+        eval(r#"string-join(stringToCodepoints(normalizeUnicode("e\u0301", "NFC")), " ")"#),
+        "233"
+    );
+    // The NFC collation orders by the normalized string
+    assert_eq!(eval(r#"compare("\u00e9", "e\u0301", "NFC")"#), "0");
+    assert_eq!(eval(r#"compare("\u00e9", "e\u0301")"#), "1");
+}
+
+#[cfg(not(feature = "unicode"))]
+#[test]
+fn normalization_needs_the_unicode_feature() {
+    let err = common::run_err(r#"xform version "2.0"; normalizeUnicode("é")"#, "<d/>");
+    assert_eq!(err, "XFST0003: NFC needs xform built with the `unicode` feature");
+}
+
+#[test]
+fn encode_for_uri_keeps_only_unreserved_characters() {
+    assert_eq!(eval(r#"encodeForUri("a b/c~d_e.f-g")"#), "a%20b%2Fc~d_e.f-g");
+    assert_eq!(eval(r#"encodeForUri("é")"#), "%C3%A9");
+}
+
+#[test]
+fn escape_html_escapes_markup_characters() {
+    assert_eq!(
+        eval(r#"escapeHtml("<a href='x' title=\"y\">&</a>")"#),
+        "&lt;a href=&#39;x&#39; title=&quot;y&quot;&gt;&amp;&lt;/a&gt;"
+    );
+}
@@ -0,0 +1,98 @@
+//! Expressions: `match` cases, conditionals and operators.
+
+mod common;
+
+use common::{run, run_with};
+use xform::{EvalOptions, Parser};
+
+#[test]
+fn a_case_takes_several_patterns() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for n in ./d/node() return match n:
+            case <b>{t}</b>, <strong>{t}</strong> => <em>{t}</em>;
+            case text(), comment() => "~";
+            default => "?";
+        }</r>"#,
+        "<d><b>x</b>t<strong>y</strong><i>z</i></d>",
+    );
+    assert_eq!(out, "<r><em>x</em>~<em>y</em>?</r>");
+    let err = Parser::new(
+        r#"xform version "2.0"; match . : case <b>{t}</b>, <i>{u}</i> => t; default => ();"#,
+    )
+    .parse_module()
+    .unwrap_err();
+    assert!(err.contains("bind different variables"), "{}", err);
+}
+
+#[test]
+fn if_without_else_gives_the_empty_sequence() {
+    let out = run(
+        r#"xform version "2.0";
+        <r a={if (1 = 0) then "x"}>{
+            string-join((if (1 = 0) then "x", "y", if (1 = 1) then "z"), ",")
+        }{for n in (1, 2, 3) return if (n = 1) then "one" else if (n = 2) then "two"}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, r#"<r a="">y,zonetwo</r>"#);
+}
+
+#[test]
+fn coalescing_takes_the_right_side_only_for_an_empty_left() {
+    let transform = r#"xform version "2.0";
+        <r>{string-join((./d/@lang ?? "en", () ?? "empty", "" ?? "blank", 0 ?? "zero",
+                          "x" ?? number("not a number")), ",")}</r>"#;
+    assert_eq!(run(transform, "<d/>"), "<r>en,empty,,0,x</r>");
+    let opts = EvalOptions { coalesce_empty_string: true, ..Default::default() };
+    assert_eq!(run_with(transform, "<d/>", &opts), "<r>en,empty,blank,0,x</r>");
+}
+
+#[test]
+fn in_tests_membership_by_typed_value() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for s in ./d/s return if (s/@status in ("draft", "review")) then "y" else "n"}{
+            string-join((1 in (1.0, 2), "1" in (1, 2), 3 in (), "a" in "a"), ",")
+        }</r>"#,
+        r#"<d><s status="draft"/><s status="final"/><s status="review"/></d>"#,
+    );
+    assert_eq!(out, "<r>ynytrue,true,false,true</r>");
+}
+
+#[test]
+fn in_after_a_for_or_let_binding_still_binds() {
+    let out = run(
+        r#"xform version "2.0";
+        let xs := (1, 2) in <r>{for x in xs return x in (2, 3)}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, "<r>falsetrue</r>");
+}
+
+#[test]
+fn an_empty_map_is_false_and_other_maps_and_functions_are_true() {
+    let out = run(
+        r#"xform version "2.0";
+        def f(x) := x;
+        <r>{string-join((
+            if (map()) then "full" else "empty",
+            if (map("k", 1)) then "full" else "empty",
+            if (index(())) then "full" else "empty",
+            if (f) then "fn" else "none",
+            if (fn(x) := x) then "fn" else "none"
+        ), ",")}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, "<r>empty,full,empty,fn,fn</r>");
+}
+
+#[test]
+fn an_arrow_is_a_for_over_the_context_item() {
+    let module = Parser::new(r#"xform version "2.0"; ./ul/i -> <li>{.}</li>"#).parse_module();
+    assert!(matches!(module.unwrap().expr, Some(xform::ast::Expr::ForExpr(_))));
+    let out = run(
+        r#"xform version "2.0"; <ul>{./d/i -> <li n={position()}>{string(.)}</li>}</ul>"#,
+        "<d><i>a</i><i>b</i></d>",
+    );
+    assert_eq!(out, r#"<ul><li n="1">a</li><li n="2">b</li></ul>"#);
+}
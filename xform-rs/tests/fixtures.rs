@@ -0,0 +1,38 @@
+//! The fixtures under `tests/fixtures` that every implementation runs: the
+//! output of each `transform.xform` must match `expected.xml`, ignoring
+//! whitespace-only text as the Python runner does.
+
+use std::path::Path;
+
+mod common;
+
+fn normalize(markup: &str) -> String {
+    let markup = markup.trim();
+    let body = match markup.strip_prefix("<?xml") {
+        Some(rest) => rest.split_once("?>").map_or("", |(_, body)| body),
+        None => markup,
+    };
+    let doc = xform::parse_xml(&format!("<_root>{}</_root>", body.trim())).unwrap();
+    xform::serialize(&xform::strip_whitespace(&doc))
+}
+
+#[test]
+fn fixtures_match_expected_output() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/fixtures");
+    let mut cases: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.join("transform.xform").exists())
+        .collect();
+    cases.sort();
+    assert!(!cases.is_empty());
+    let mut failed = Vec::new();
+    for case in &cases {
+        let read = |name: &str| std::fs::read_to_string(case.join(name)).unwrap();
+        let out = common::run(&read("transform.xform"), &read("input.xml"));
+        if normalize(&out) != normalize(&read("expected.xml")) {
+            failed.push(case.file_name().unwrap().to_string_lossy().into_owned());
+        }
+    }
+    assert!(failed.is_empty(), "output differs from expected.xml in {:?}", failed);
+}
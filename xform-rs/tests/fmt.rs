@@ -0,0 +1,110 @@
+//! `format_source`, behind `xform fmt`, and `to_source` on built ASTs.
+
+use std::path::Path;
+
+use xform::ast::{to_source, Expr, LiteralValue, Span};
+use xform::{format_source, Parser};
+
+mod common;
+
+use common::run;
+
+/// The transforms of the shared fixtures, as (case name, source, input).
+fn corpus() -> Vec<(String, String, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/fixtures");
+    let mut cases: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.join("transform.xform").exists())
+        .collect();
+    cases.sort();
+    cases
+        .iter()
+        .map(|case| {
+            let read = |name: &str| std::fs::read_to_string(case.join(name)).unwrap();
+            let name = case.file_name().unwrap().to_string_lossy().into_owned();
+            (name, read("transform.xform"), read("input.xml"))
+        })
+        .collect()
+}
+
+#[test]
+fn formatting_is_idempotent() {
+    for (name, source, _) in corpus() {
+        let once = format_source(&source).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        let twice = format_source(&once).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        assert_eq!(once, twice, "formatting {} again changed it", name);
+    }
+}
+
+#[test]
+fn formatting_keeps_what_a_transform_does() {
+    for (name, source, input) in corpus() {
+        let formatted = format_source(&source).unwrap();
+        assert_eq!(
+            common::run(&formatted, &input),
+            common::run(&source, &input),
+            "formatted {} gives other output",
+            name
+        );
+    }
+}
+
+#[test]
+fn comments_and_quoting_survive() {
+    let source = "xform version \"2.0\";\n# the greeting\ndef greet(n):=concat('hi ', n);\n\
+                  <r a={greet('x')}/>\n";
+    let out = format_source(source).unwrap();
+    assert!(out.contains("# the greeting"), "{}", out);
+    assert!(out.contains("'hi '"), "{}", out);
+    assert!(out.contains("def greet(n) := "), "{}", out);
+    assert_eq!(format_source(&out).unwrap(), out);
+}
+
+#[test]
+fn each_literal_keeps_its_own_spelling() {
+    let source = "xform version \"2.0\";\nvar a := \"x\";\nvar b := 'x';\n\
+                  var c := 1.0;\nvar d := 1.00;\n<r>{a}{b}{c}{d}</r>\n";
+    let out = format_source(source).unwrap();
+    for kept in ["var a := \"x\";", "var b := 'x';", "var c := 1.0;", "var d := 1.00;"] {
+        assert!(out.contains(kept), "{} missing from\n{}", kept, out);
+    }
+}
+
+#[test]
+fn source_that_does_not_parse_is_an_error() {
+    let err = format_source("xform version \"2.0\";\ndef f(x := 1;\n").unwrap_err();
+    assert!(err.contains("pos"), "{}", err);
+}
+
+fn num(n: f64) -> Expr {
+    Expr::Literal(LiteralValue::Num(n), Span::default())
+}
+
+fn binary(op: &str, left: Expr, right: Expr) -> Expr {
+    Expr::BinaryOp { op: op.into(), left: Box::new(left), right: Box::new(right) }
+}
+
+#[test]
+fn built_expressions_are_parenthesized_only_where_needed() {
+    let sum_times = binary("*", binary("+", num(1.0), num(2.0)), num(3.0));
+    assert_eq!(to_source(&sum_times), "(1 + 2) * 3");
+    let plus_product = binary("+", num(1.0), binary("*", num(2.0), num(3.0)));
+    assert_eq!(to_source(&plus_product), "1 + 2 * 3");
+    let minus_difference = binary("-", num(1.0), binary("-", num(2.0), num(3.0)));
+    assert_eq!(to_source(&minus_difference), "1 - (2 - 3)");
+    let quoted = Expr::Literal(LiteralValue::Str("say \"hi\"\n".into()), Span::default());
+    assert_eq!(to_source(&quoted), r#""say \"hi\"\n""#);
+}
+
+#[test]
+fn printed_modules_parse_back_and_do_the_same() {
+    let source = r#"xform version "2.0";
+        def tag(s) := <t n={count(stringToCodepoints(s))}>{s}</t>;
+        <r a={(1 + 2) * 3 - (4 - 5)}>{tag("a \"quoted\" 'word'")}{
+            for i in ./d/i return tag(i)
+        }</r>"#;
+    let input = "<d><i>x&lt;y</i></d>";
+    let printed = Parser::new(source).parse_module().unwrap().to_source();
+    assert_eq!(run(&printed, input), run(source, input), "printed as\n{}", printed);
+}
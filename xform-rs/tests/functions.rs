@@ -0,0 +1,91 @@
+//! User-defined functions, inline functions and `def memo`.
+
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use common::{run, run_err, run_with};
+use xform::{EvalOptions, Profile};
+
+#[test]
+fn memo_does_not_mix_up_closure_arguments() {
+    let out = run(
+        r#"xform version "2.0";
+        def memo applyTo(f, x) := f(x);
+        <r a={applyTo(fn(x) := x + 1, 10)} b={applyTo(fn(x) := x * 100, 10)}/>"#,
+        "<d/>",
+    );
+    assert_eq!(out, r#"<r a="11" b="1000"/>"#);
+}
+
+#[test]
+fn memo_keys_on_the_context_item() {
+    let out = run(
+        r#"xform version "2.0";
+        def memo here() := string(./@n);
+        rule main match <i/> := <o>{here()}</o>;
+        <r>{apply(.//i)}</r>"#,
+        r#"<d><i n="1"/><i n="2"/></d>"#,
+    );
+    assert_eq!(out, "<r><o>1</o><o>2</o></r>");
+}
+
+/// A document of `refs` references to `targets` targets, resolved by a
+/// function declared with `modifier`; returns the output and the nodes the
+/// transform's paths visited.
+fn resolve_refs(modifier: &str, refs: usize, targets: usize) -> (String, u64) {
+    let mut input = String::from("<d>");
+    for i in 0..targets {
+        input.push_str(&format!(r#"<target id="t{0}" title="T{0}"/>"#, i));
+    }
+    for i in 0..refs {
+        input.push_str(&format!(r#"<ref idref="t{}"/>"#, i % targets));
+    }
+    input.push_str("</d>");
+    let transform = format!(
+        r#"xform version "2.0";
+        def {} resolve(key) := string(//target[./@id = key]/@title);
+        <r>{{string-join(for r in .//ref return resolve(string(r/@idref)), ",")}}</r>"#,
+        modifier
+    );
+    let profile = Rc::new(RefCell::new(Profile::default()));
+    let opts = EvalOptions { profile: Some(profile.clone()), ..Default::default() };
+    let out = run_with(&transform, &input, &opts);
+    let visited = profile.borrow().paths.values().map(|p| p.visited).sum();
+    (out, visited)
+}
+
+/// Reference-heavy input: memo resolves each distinct reference once, so the
+/// target search walks the document 20 times instead of 2000.
+#[test]
+fn memo_saves_repeated_lookups() {
+    let (plain, plain_visited) = resolve_refs("", 2000, 20);
+    let (memo, memo_visited) = resolve_refs("memo", 2000, 20);
+    assert_eq!(plain, memo);
+    assert!(memo.starts_with("<r>T0,T1,"), "{}", &memo[..40]);
+    assert!(
+        memo_visited * 20 < plain_visited,
+        "memo visited {} nodes, plain {}",
+        memo_visited,
+        plain_visited
+    );
+}
+
+#[test]
+fn inline_functions_have_no_serialized_form() {
+    let e = run_err(r#"xform version "2.0"; fn(x) := x + 1"#, "<d/>");
+    assert_eq!(e, "XFDY0014: cannot serialize function fn#1");
+}
+
+#[test]
+fn self_tail_calls_loop_instead_of_recursing() {
+    let out = run(
+        r#"xform version "2.0";
+        def count-down(n, acc) := if (n = 0) then acc else count-down(n - 1, acc + 2);
+        def last-of(n) := let m := n - 1 in if (m < 0) then "end" else last-of(m);
+        <r total={count-down(100000, 0)} end={last-of(100000)}/>"#,
+        "<d/>",
+    );
+    assert_eq!(out, r#"<r total="200000" end="end"/>"#);
+}
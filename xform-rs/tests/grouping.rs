@@ -0,0 +1,144 @@
+//! `groupBy`, `group-adjacent` and the one-pass `countBy` and `sumBy`.
+
+mod common;
+
+use common::{run, run_err};
+
+const INVOICE: &str = r#"<invoice>
+  <line cat="tools" qty="2" price="10"/>
+  <line cat="books" qty="1" price="25"/>
+  <line cat="tools" qty="1" price="4.5"/>
+  <line cat="food" qty="6" price="1.25"/>
+  <line cat="books" qty="3" price="8"/>
+</invoice>"#;
+
+/// One `<c>` per group, with the key and the count and total amount
+/// computed by `counts` and `sums`.
+fn per_category(counts: &str, sums: &str) -> String {
+    run(
+        &format!(
+            r#"xform version "2.0";
+            let cat := fn(l) := string(l/@cat) in
+            let amount := fn(l) := number(l/@qty) * number(l/@price) in
+            <r>{{{}}}{{{}}}</r>"#,
+            counts, sums
+        ),
+        INVOICE,
+    )
+}
+
+#[test]
+fn count_by_and_sum_by_match_the_long_hand_composition() {
+    let one_pass = per_category(
+        r#"for c in countBy(.//line, cat)
+           return <c key={lookup(c, "key")} n={lookup(c, "count")}/>"#,
+        r#"for s in sumBy(.//line, cat, amount)
+           return <s key={lookup(s, "key")} total={lookup(s, "sum")}/>"#,
+    );
+    let long_hand = per_category(
+        r#"for g in groupBy(.//line, cat)
+           return <c key={groupKey(g)} n={count(groupItems(g))}/>"#,
+        r#"for g in groupBy(.//line, cat)
+           return <s key={groupKey(g)} total={sum(for l in groupItems(g) return amount(l))}/>"#,
+    );
+    assert_eq!(one_pass, long_hand);
+    assert_eq!(
+        one_pass,
+        concat!(
+            r#"<r><c key="tools" n="2"/><c key="books" n="2"/><c key="food" n="1"/>"#,
+            r#"<s key="tools" total="24.5"/><s key="books" total="49"/>"#,
+            r#"<s key="food" total="7.5"/>"#,
+            "</r>"
+        )
+    );
+}
+
+#[test]
+fn sum_by_without_a_value_function_sums_the_items() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for s in sumBy((1, 2, 3, 4, 5), fn(n) := n mod 2)
+            return <s key={lookup(s, "key")} total={lookup(s, "sum")}/>}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, r#"<r><s key="1" total="9"/><s key="0" total="6"/></r>"#);
+}
+
+/// Keys of the groups `groupBy` makes of `items` with `options` appended.
+fn group_keys(items: &str, options: &str) -> String {
+    run(
+        &format!(
+            r#"xform version "2.0";
+            let groups := groupBy({}, fn(x) := x{}) in
+            <r>{{string-join(for g in groups return string(groupKey(g)), " ")}}</r>"#,
+            items, options
+        ),
+        "<d/>",
+    )
+}
+
+#[test]
+fn groups_come_in_order_of_first_appearance_by_default() {
+    let words = r#"("pear", "apple", "fig", "apple", "banana")"#;
+    assert_eq!(group_keys(words, ""), "<r>pear apple fig banana</r>");
+    assert_eq!(group_keys(words, r#", "insertion""#), "<r>pear apple fig banana</r>");
+}
+
+#[test]
+fn ascending_and_descending_order_groups_by_key() {
+    let words = r#"("pear", "apple", "fig", "apple", "banana")"#;
+    assert_eq!(group_keys(words, r#", "ascending""#), "<r>apple banana fig pear</r>");
+    assert_eq!(group_keys(words, r#", "descending""#), "<r>pear fig banana apple</r>");
+}
+
+#[test]
+fn numeric_keys_are_ordered_as_numbers() {
+    let numbers = r#"("10", "9", "100", "9", "-1")"#;
+    assert_eq!(group_keys(numbers, ""), "<r>10 9 100 -1</r>");
+    assert_eq!(group_keys(numbers, r#", "ascending""#), "<r>-1 9 10 100</r>");
+    assert_eq!(group_keys(numbers, r#", "descending""#), "<r>100 10 9 -1</r>");
+    // One key that is not a number orders them all as strings
+    let mixed = r#"("10", "9", "x")"#;
+    assert_eq!(group_keys(mixed, r#", "ascending""#), "<r>10 9 x</r>");
+}
+
+#[test]
+fn typed_grouping_takes_a_boolean_and_an_unknown_order_fails() {
+    let numbers = r#"("1", "1.0", "2")"#;
+    assert_eq!(group_keys(numbers, ", true()"), "<r>1 2</r>");
+    assert_eq!(group_keys(numbers, r#", false(), "descending""#), "<r>2 1 1.0</r>");
+    let err = |options: &str| {
+        run_err(
+            &format!(r#"xform version "2.0"; groupBy((1, 2), fn(x) := x{})"#, options),
+            "<d/>",
+        )
+    };
+    assert_eq!(err(r#", "ascendng""#), r#"XFDY0002: unknown group order "ascendng""#);
+    assert_eq!(err(", 1"), r#"XFDY0002: groupBy takes a boolean or an order, not "1""#);
+}
+
+#[test]
+fn group_adjacent_starts_a_group_whenever_the_key_changes() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for g in group-adjacent(./d/*, fn(n) := name(n))
+            return <g key={groupKey(g)} n={count(groupItems(g))}/>}</r>"#,
+        "<d><p/><p/><ul/><p/></d>",
+    );
+    assert_eq!(out, r#"<r><g key="p" n="2"/><g key="ul" n="1"/><g key="p" n="1"/></r>"#);
+}
+
+#[test]
+fn groups_keep_input_order_and_typed_keys_coalesce_by_value() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for g in groupBy(./d/i, fn(i) := string(i/@k), true())
+            return <g key={groupKey(g)} type={typeOf(groupKey(g))}>{
+                string-join(groupItems(g), ",")}</g>}</r>"#,
+        r#"<d><i k="1">a</i><i k="x">b</i><i k="1.0">c</i><i k="01">d</i><i k="x">e</i></d>"#,
+    );
+    assert_eq!(
+        out,
+        r#"<r><g key="1" type="number">a,c,d</g><g key="x" type="string">b,e</g></r>"#
+    );
+}
@@ -0,0 +1,41 @@
+//! `parse_html` on the kind of markup found in the wild.
+
+#![cfg(feature = "html")]
+
+use xform::{parse_html, serialize};
+
+fn html(text: &str) -> String {
+    serialize(&parse_html(text))
+}
+
+#[test]
+fn raw_text_end_tags_match_in_any_case() {
+    assert_eq!(
+        html("<SCRIPT>if (a < b) x();</SCRIPT><p>after"),
+        "<script>if (a &lt; b) x();</script><p>after</p>"
+    );
+    assert_eq!(html("<style>p { }</Style ><p>x</p>"), "<style>p { }</style><p>x</p>");
+    assert_eq!(html("<TITLE>a &amp; b</title>"), "<title>a &amp; b</title>");
+}
+
+#[test]
+fn raw_text_ends_only_at_its_own_end_tag() {
+    assert_eq!(
+        html("<script>s = '</scripts></b>';</script><i>x</i>"),
+        "<script>s = '&lt;/scripts&gt;&lt;/b&gt;';</script><i>x</i>"
+    );
+    assert_eq!(html("<textarea>a</textarea/>b"), "<textarea>a</textarea>b");
+}
+
+#[test]
+fn unterminated_raw_text_runs_to_the_end() {
+    assert_eq!(html("<script>x = 1;</scr"), "<script>x = 1;&lt;/scr</script>");
+}
+
+#[test]
+fn messy_markup_closes_the_way_browsers_do() {
+    assert_eq!(
+        html("<UL><LI class=a>one<li>two</ul><P>x<div>y</DIV>"),
+        r#"<ul><li class="a">one</li><li>two</li></ul><p>x</p><div>y</div>"#
+    );
+}
@@ -0,0 +1,60 @@
+//! What a module defines, listed from Rust and from the transform itself.
+
+mod common;
+
+use common::run;
+use xform::Parser;
+
+const LIBRARY: &str = r#"xform version "2.0";
+def slug(title: string, sep: string := "-") := title;
+def total(items) := sum(items);
+def noop() := ();
+rule main match <a/> := <b/>;
+rule toc match <h/> := <entry/>;
+rule toc match <h2/> := <entry/>;
+"#;
+
+#[test]
+fn the_module_lists_its_functions_rule_sets_and_signatures() {
+    let module = Parser::new(&format!("{}()", LIBRARY)).parse_module().unwrap();
+    assert_eq!(module.function_names(), ["noop", "slug", "total"]);
+    assert_eq!(module.rule_sets(), ["main", "toc"]);
+    let signatures: Vec<String> = module
+        .signatures()
+        .into_iter()
+        .map(|(name, params)| {
+            let params: Vec<String> = params
+                .iter()
+                .map(|p| {
+                    let typed = p.type_ref.as_deref().map_or(String::new(), |t| format!(": {}", t));
+                    let default = if p.default.is_some() { " := …" } else { "" };
+                    format!("{}{}{}", p.name, typed, default)
+                })
+                .collect();
+            format!("{}({})", name, params.join(", "))
+        })
+        .collect();
+    assert_eq!(signatures, ["noop()", "slug(title: string, sep: string := …)", "total(items)"]);
+}
+
+#[test]
+fn a_transform_prints_its_own_function_inventory() {
+    let inventory = r#"<fns>{for f in functionNames()
+        return <fn name={f} arity={functionArity(f)}/>}</fns>"#;
+    let out = run(&format!("{}{}", LIBRARY, inventory), "<d/>");
+    assert_eq!(
+        out,
+        concat!(
+            r#"<fns><fn name="noop" arity="0"/><fn name="slug" arity="2"/>"#,
+            r#"<fn name="total" arity="1"/></fns>"#
+        )
+    );
+}
+
+#[test]
+fn function_exists_and_arity_of_unknown_names() {
+    let probe = r#"<r slug={functionExists("slug")} nope={functionExists("nope")}
+        n={count(functionArity("nope"))}/>"#;
+    let out = run(&format!("{}{}", LIBRARY, probe), "<d/>");
+    assert_eq!(out, r#"<r slug="true" nope="false" n="0"/>"#);
+}
@@ -0,0 +1,44 @@
+//! `json-to-xml` and `xml-to-json` through the `json` module.
+
+use xform::json::{json_to_xml, xml_to_json};
+use xform::{parse_xml, serialize};
+
+fn to_json(xml: &str) -> String {
+    xml_to_json(&parse_xml(xml).unwrap()).unwrap()
+}
+
+#[test]
+fn numbers_in_json_grammar_are_written_as_numbers() {
+    for n in ["0", "-0", "5", "-12.5", "1e5", "2.5E-3", "10"] {
+        assert_eq!(to_json(&format!("<number>{}</number>", n)), n);
+    }
+    assert_eq!(to_json("<number> 7 </number>"), "7");
+}
+
+#[test]
+fn other_number_text_is_written_as_a_string() {
+    for n in ["+5", ".5", "5.", "01", "NaN", "inf", "1e", "0x10", ""] {
+        assert_eq!(to_json(&format!("<number>{}</number>", n)), format!("\"{}\"", n));
+    }
+    assert_eq!(
+        to_json(r#"<array><number>1</number><number>+2</number></array>"#),
+        r#"[1,"+2"]"#
+    );
+}
+
+#[test]
+fn parsing_rejects_numbers_outside_the_grammar() {
+    for n in ["5.", "01", "-", "1e", "1e+", "-.5"] {
+        let err = json_to_xml(&format!("[{}]", n)).unwrap_err();
+        assert!(err.contains("invalid number"), "{} gave {}", n, err);
+    }
+    for n in ["+5", ".5"] {
+        assert!(json_to_xml(&format!("[{}]", n)).is_err(), "{}", n);
+    }
+    let doc = json_to_xml("[0, -1.5e3]").unwrap();
+    assert_eq!(
+        serialize(&doc),
+        r#"<array xmlns="http://www.w3.org/2005/xpath-functions"><number>0</number>"#.to_string()
+            + "<number>-1.5e3</number></array>"
+    );
+}
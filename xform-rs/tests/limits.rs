@@ -0,0 +1,91 @@
+//! Resource limits of `EvalOptions` and the `LimitPolicy` they follow.
+
+mod common;
+
+use common::{run_with, try_run};
+use xform::{EvalOptions, LimitPolicy};
+
+const ITEMS: &str = r#"<d><i n="1"/><i n="2"/><i n="3"/><i n="4"/><i n="5"/></d>"#;
+
+fn limit_error(transform: &str, input: &str, opts: &EvalOptions) -> String {
+    match try_run(transform, input, opts) {
+        Ok(out) => panic!("expected a limit error, got {:?}", out),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn step_budget_stops_an_endless_loop() {
+    let opts = EvalOptions { max_steps: Some(10_000), ..Default::default() };
+    let e = limit_error(
+        r#"xform version "2.0";
+        def spin(n) := spin(n + 1);
+        <r>{spin(0)}</r>"#,
+        "<d/>",
+        &opts,
+    );
+    assert_eq!(e, "XFLM0001: step budget of 10000 exceeded");
+}
+
+#[test]
+fn depth_budget_stops_unbounded_recursion() {
+    let transform = r#"xform version "2.0";
+        def down(n) := if (n = 0) then () else (n, down(n - 1));
+        def endless(n) := (n, endless(n + 1));
+        <r>{count(down(10))}</r>"#;
+    let opts = EvalOptions { max_depth: Some(12), ..Default::default() };
+    assert_eq!(run_with(transform, "<d/>", &opts), "<r>10</r>");
+    let endless = transform.replace("down(10)", "endless(0)");
+    assert_eq!(limit_error(&endless, "<d/>", &opts), "XFLM0007: call depth of 12 exceeded");
+}
+
+#[test]
+fn depth_budget_counts_rules_but_not_tail_calls() {
+    let opts = EvalOptions { max_depth: Some(3), ..Default::default() };
+    let tail = r#"xform version "2.0";
+        def countdown(n) := if (n = 0) then "done" else countdown(n - 1);
+        <r>{countdown(100)}</r>"#;
+    assert_eq!(run_with(tail, "<d/>", &opts), "<r>done</r>");
+    let nested = r#"xform version "2.0";
+        rule main match <a/> := <b>{apply(./a)}</b>;
+        <r>{apply(./a)}</r>"#;
+    let input = "<a><a><a><a/></a></a></a>";
+    assert_eq!(limit_error(nested, input, &opts), "XFLM0007: call depth of 3 exceeded");
+}
+
+#[test]
+fn sequence_limit_applies_while_a_loop_runs() {
+    // The fourth iteration would fail with an unknown function; the limit
+    // is reached by the third, before it runs.
+    let opts = EvalOptions { max_seq_len: Some(5), ..Default::default() };
+    let e = limit_error(
+        r#"xform version "2.0";
+        <r>{count(for i in .//i return if (string(./@n) = "4") then nosuch() else (i, i))}</r>"#,
+        ITEMS,
+        &opts,
+    );
+    assert_eq!(e, "XFLM0005: sequence of 6 items exceeds limit of 5");
+}
+
+#[test]
+fn sequence_limit_stops_a_path_walk() {
+    let opts = EvalOptions { max_seq_len: Some(3), ..Default::default() };
+    let e = limit_error(r#"xform version "2.0"; <r>{.//i}</r>"#, ITEMS, &opts);
+    assert_eq!(e, "XFLM0005: sequence of 4 items exceeds limit of 3");
+    assert_eq!(run_with(r#"xform version "2.0"; <r>{count(.//i)}</r>"#, ITEMS, &opts), "<r>5</r>");
+}
+
+#[test]
+fn limit_policy_decides_whether_a_limit_can_be_recovered_from() {
+    // A failing sort key sorts its item by an empty key
+    let transform = r#"xform version "2.0";
+        <r>{string-join(sort(.//i, fn(x) := (x, x, x, x)) -> string(./@n), ",")}</r>"#;
+    let input = r#"<d><i n="1"/><i n="2"/><i n="3"/></d>"#;
+    let catchable = EvalOptions { max_seq_len: Some(3), ..Default::default() };
+    assert_eq!(run_with(transform, input, &catchable), "<r>1,2,3</r>");
+    let fatal = EvalOptions { limit_policy: LimitPolicy::Fatal, ..catchable };
+    assert_eq!(
+        limit_error(transform, input, &fatal),
+        "XFLM0005: sequence of 4 items exceeds limit of 3"
+    );
+}
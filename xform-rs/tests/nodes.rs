@@ -0,0 +1,101 @@
+//! Functions that look at a node's place in its tree through parent links.
+
+mod common;
+
+use common::run;
+
+#[test]
+fn index_in_parent_and_of_type() {
+    let out = run(
+        r#"xform version "2.1";
+        <r all={index-in-parent(.//item[2])} typed={index-of-type(.//item[2])}/>"#,
+        "<list><item/><note/><item/></list>",
+    );
+    assert_eq!(out, r#"<r all="3" typed="2"/>"#);
+}
+
+#[test]
+fn index_in_parent_works_on_built_and_parsed_trees() {
+    let out = run(
+        r#"xform version "2.1";
+        let built := <list><item/><note/><item/></list> in
+        let parsed := parse-xml("<list><note/><item/></list>") in
+        <r built={index-of-type(built/item[2])} parsed={index-in-parent(parsed//item)}/>"#,
+        "<d/>",
+    );
+    assert_eq!(out, r#"<r built="2" parsed="2"/>"#);
+}
+
+#[test]
+fn preserve_space_follows_the_ancestors() {
+    let out = run(
+        r#"xform version "2.0";
+        let built := <pre xml:space={"preserve"}><code>x</code></pre> in
+        <r in={preserveSpace(.//code)} built={preserveSpace(built/code)}
+           off={preserveSpace(.//p)}/>"#,
+        r#"<d><pre xml:space="preserve"><code>x</code></pre><p/></d>"#,
+    );
+    assert_eq!(out, r#"<r in="true" built="true" off="false"/>"#);
+}
+
+/// Sections nested four levels deep, with two branches at the second level.
+const SECTIONS: &str = r#"<doc>
+  <section id="1">
+    <section id="1.1">
+      <section id="1.1.1"><section id="1.1.1.1"><p/></section></section>
+    </section>
+    <section id="1.2"><p/></section>
+  </section>
+  <section id="2"/>
+</doc>"#;
+
+fn ids(expr: &str) -> String {
+    let transform = r#"xform version "2.0"; <r>{string-join(for s in SET return s/@id, " ")}</r>"#;
+    run(&transform.replace("SET", expr), SECTIONS)
+}
+
+#[test]
+fn innermost_keeps_sections_without_nested_ones() {
+    assert_eq!(ids("innermost(.//section)"), "<r>1.1.1.1 1.2 2</r>");
+    assert_eq!(ids("innermost((.//section[./@id = '2'], .//section))"), "<r>1.1.1.1 1.2 2</r>");
+    assert_eq!(ids("innermost((.//section, .//section))"), "<r>1.1.1.1 1.2 2</r>");
+    assert_eq!(ids("innermost(.//section[./@id != '1.1.1.1'])"), "<r>1.1.1 1.2 2</r>");
+}
+
+#[test]
+fn outermost_keeps_sections_outside_the_others() {
+    assert_eq!(ids("outermost(.//section)"), "<r>1 2</r>");
+    assert_eq!(ids("outermost((.//section[./@id = '2'], .//section))"), "<r>1 2</r>");
+    assert_eq!(ids("outermost((.//section, .//section))"), "<r>1 2</r>");
+    assert_eq!(ids("outermost(.//section[./@id != '1'])"), "<r>1.1 1.2 2</r>");
+}
+
+#[test]
+fn has_children_optionally_by_name() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for s in .//section
+            return <s any={hasChildren(s)} p={hasChildren(s, "p")}/>}</r>"#,
+        SECTIONS,
+    );
+    assert_eq!(
+        out,
+        concat!(
+            r#"<r><s any="true" p="false"/><s any="true" p="false"/><s any="true" p="false"/>"#,
+            r#"<s any="true" p="true"/><s any="true" p="true"/><s any="false" p="false"/></r>"#
+        )
+    );
+}
+
+#[test]
+fn innermost_and_outermost_of_a_deep_chain() {
+    let depth = 500;
+    let input = format!("{}{}", "<section>".repeat(depth), "</section>".repeat(depth));
+    let out = run(
+        r#"xform version "2.0";
+        <r n={count(.//section)} inner={count(innermost(.//section))}
+           outer={count(outermost(.//section))} leaf={count(innermost(.//section)/*)}/>"#,
+        &input,
+    );
+    assert_eq!(out, r#"<r n="500" inner="1" outer="1" leaf="0"/>"#);
+}
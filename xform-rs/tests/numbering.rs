@@ -0,0 +1,47 @@
+//! `numberOf`, xsl:number-style numbering of a node among its siblings
+//! and ancestors.
+
+mod common;
+
+use common::run;
+
+#[test]
+fn nested_sections_are_numbered_like_a_table_of_contents() {
+    let input = "<doc><section><title/><section/><section/></section>\
+                 <section><section><section/></section></section></doc>";
+    let out = run(
+        r#"xform version "2.0";
+        <r>{string-join(for s in .//section return numberOf(s, "multiple", "1.1"), " ")}</r>"#,
+        input,
+    );
+    assert_eq!(out, "<r>1 1.1 1.2 2 2.1 2.1.1</r>");
+}
+
+#[test]
+fn number_of_counts_levels() {
+    let input = "<doc><sec><sub/><sub/></sec><sec><sub/><sub/><sub/></sec></doc>";
+    let t = |args: &str| {
+        run(&format!(r#"xform version "2.1"; <r>{{numberOf(.//sub[5]{})}}</r>"#, args), input)
+    };
+    assert_eq!(t(""), "<r>3</r>");
+    assert_eq!(t(r#", "any""#), "<r>5</r>");
+    assert_eq!(t(r#", "single", "(a)""#), "<r>(c)</r>");
+    let input = "<doc><s/><s><s/><s><s/><s/><s/></s></s></doc>";
+    let t = |picture: &str| {
+        let expr = format!(r#"numberOf(.//s[7], "multiple", "{}")"#, picture);
+        run(&format!(r#"xform version "2.1"; <r>{{{}}}</r>"#, expr), input)
+    };
+    assert_eq!(t("1.1"), "<r>2.2.3</r>");
+    assert_eq!(t("A-i"), "<r>B-ii-iii</r>");
+}
+
+#[test]
+fn number_of_works_on_a_built_tree() {
+    let out = run(
+        r#"xform version "2.0";
+        let doc := <doc><sec/><sec><sec/><sec/></sec></doc> in
+        <r>{string-join(for s in doc//sec return numberOf(s, "multiple"), " ")}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, "<r>1 2 2.1 2.2</r>");
+}
@@ -0,0 +1,64 @@
+//! Parse errors, reported with the position they were found at.
+
+use xform::ast::{Expr, PathAxis, PathStartKind};
+use xform::Parser;
+
+fn parse_err(source: &str) -> String {
+    match Parser::new(source).parse_module() {
+        Ok(_) => panic!("expected a parse error for {:?}", source),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn unterminated_raw_strings_are_errors() {
+    assert_eq!(
+        parse_err(r#"xform version "2.0"; <r>{r"abc}</r>"#),
+        "Unterminated raw string at pos 25"
+    );
+    assert_eq!(parse_err("xform version \"2.0\"; r'"), "Unterminated raw string at pos 21");
+}
+
+#[test]
+fn raw_strings_keep_their_contents() {
+    let module = Parser::new(r#"xform version "2.0"; r"a\nb""#).parse_module().unwrap();
+    let doc = xform::parse_xml("<d/>").unwrap();
+    let out = xform::eval_module(&module, doc).unwrap();
+    assert_eq!(xform::serialize_items(&out).unwrap(), r"a\nb");
+}
+
+#[test]
+fn chained_comparisons_suggest_and() {
+    assert_eq!(
+        parse_err(r#"xform version "2.0"; 1 < ./@x < 10"#),
+        "Chained comparison at pos 30: write `1 < ./@x and ./@x < 10`, or parenthesize to \
+         compare the result"
+    );
+    assert!(parse_err(r#"xform version "2.0"; 1 = 1 != 2"#).starts_with("Chained comparison"));
+    assert!(Parser::new(r#"xform version "2.0"; (1 < 2) = true()"#).parse_module().is_ok());
+}
+
+/// The main expression of `xform version "2.0"; <expr>`.
+fn parse_expr(expr: &str) -> Expr {
+    let source = format!(r#"xform version "2.0"; {}"#, expr);
+    Parser::new(&source).parse_module().unwrap().expr.unwrap()
+}
+
+#[test]
+fn paths_continue_after_function_calls() {
+    let Expr::PathExpr(path) = parse_expr("children(.)/name") else {
+        panic!("expected a path");
+    };
+    assert_eq!(path.start.kind, PathStartKind::Expr);
+    assert!(matches!(path.start.base.as_deref(), Some(Expr::FuncCall(f)) if f.name == "children"));
+    assert_eq!(path.steps.len(), 1);
+    assert_eq!(path.steps[0].axis, PathAxis::Child);
+    assert_eq!(path.steps[0].test.name.as_deref(), Some("name"));
+
+    let Expr::PathExpr(path) = parse_expr("(.//a, .//b)//c") else {
+        panic!("expected a path");
+    };
+    assert!(matches!(path.start.base.as_deref(), Some(Expr::Sequence(items)) if items.len() == 2));
+    assert_eq!(path.steps[0].axis, PathAxis::DescOrSelf);
+    assert!(matches!(parse_expr("children(.)"), Expr::FuncCall(_)));
+}
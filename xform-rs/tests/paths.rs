@@ -0,0 +1,233 @@
+//! Path steps, their predicates and the union, difference and intersection
+//! of paths.
+
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use common::{run, run_err, run_with};
+use xform::{EvalOptions, Profile};
+
+const ITEMS: &str = "<d><i>a</i><i>b</i><i>c</i><i>d</i><i>e</i></d>";
+
+#[test]
+fn number_predicate_is_truthy_in_2_0() {
+    let out = run(r#"xform version "2.0"; <r n={count(.//i[2])}/>"#, ITEMS);
+    assert_eq!(out, r#"<r n="5"/>"#);
+}
+
+#[test]
+fn number_predicate_is_positional_in_2_1() {
+    let out = run(r#"xform version "2.1"; <r>{string(.//i[2])}</r>"#, ITEMS);
+    assert_eq!(out, "<r>b</r>");
+    let out = run(r#"xform version "2.1"; <r>{string(.//i[1 + 1])}</r>"#, ITEMS);
+    assert_eq!(out, "<r>b</r>");
+}
+
+#[test]
+fn last_in_a_predicate_counts_the_candidates() {
+    for version in ["2.0", "2.1"] {
+        let out = run(
+            &format!(
+                r#"xform version "{}"; <r>{{string(.//i[position() = last()])}}</r>"#,
+                version
+            ),
+            ITEMS,
+        );
+        assert_eq!(out, "<r>e</r>");
+    }
+    let out = run(r#"xform version "2.1"; <r>{string(.//i[last()])}</r>"#, ITEMS);
+    assert_eq!(out, "<r>e</r>");
+    // The second predicate sees only the candidates the first one kept
+    let out = run(
+        r#"xform version "2.0"; <r>{string(.//i[. != 'e'][position() = last()])}</r>"#,
+        ITEMS,
+    );
+    assert_eq!(out, "<r>d</r>");
+}
+
+#[test]
+fn nested_predicates_each_have_their_own_position() {
+    let input = "<d><s><p>1</p><p>2</p></s><s><p>3</p><p>4</p></s></d>";
+    let out = run(
+        r#"xform version "2.0"; <r>{string(./d/s[position() = 2]/p[position() = 1])}</r>"#,
+        input,
+    );
+    assert_eq!(out, "<r>3</r>");
+    let out = run(
+        r#"xform version "2.0";
+        <r n={count(./d/s[position() = 2][./p[position() = 2] = '4'])}/>"#,
+        input,
+    );
+    assert_eq!(out, r#"<r n="1"/>"#);
+}
+
+#[test]
+fn position_after_an_inner_path_is_the_outer_one() {
+    let input = "<d><s><p>1</p><p>2</p></s><s><p>3</p><p>4</p></s><s><p>3</p></s></d>";
+    // The inner path of the predicate has predicates of its own, and
+    // `position()` after it is again that of `s`
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for s in ./d/s[./p[position() = 1] = '3' and position() = 3] return string(s)}</r>"#,
+        input,
+    );
+    assert_eq!(out, "<r>3</r>");
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for p in ./d/s[position() > 1]/p[position() = last()] return string(p)}</r>"#,
+        input,
+    );
+    assert_eq!(out, "<r>43</r>");
+}
+
+/// Nodes the paths of `transform` visited, as counted by the profiler.
+fn visited(transform: &str, input: &str) -> u64 {
+    let profile = Rc::new(RefCell::new(Profile::default()));
+    let opts = EvalOptions { profile: Some(profile.clone()), ..Default::default() };
+    run_with(transform, input, &opts);
+    let visited = profile.borrow().paths.values().map(|p| p.visited).sum();
+    visited
+}
+
+#[test]
+fn exists_stops_at_the_first_hit_while_count_walks_them_all() {
+    let input = format!("<d><hit/>{}</d>", "<x/>".repeat(1000));
+    for test in ["exists(.//x)", "empty(.//x)"] {
+        let n = visited(&format!(r#"xform version "2.0"; <r t={{{}}}/>"#, test), &input);
+        assert!(n < 10, "{} visited {} nodes", test, n);
+    }
+    let transform = r#"xform version "2.0"; <r n={count(.//x)}/>"#;
+    assert_eq!(run(transform, &input), r#"<r n="1000"/>"#);
+    assert!(visited(transform, &input) > 1000);
+}
+
+#[test]
+fn existence_tests_stop_at_the_first_hit() {
+    let input = format!("<d><hit/>{}</d>", "<x/>".repeat(1000));
+    for test in ["not(empty(.//hit))", "boolean(.//hit)"] {
+        let n = visited(&format!(r#"xform version "2.0"; <r t={{{}}}/>"#, test), &input);
+        assert!(n < 10, "{} visited {} nodes", test, n);
+    }
+    let n = visited(r#"xform version "2.1"; <r>{.//x[1]}</r>"#, &input);
+    assert!(n < 10, "x[1] visited {} nodes", n);
+}
+
+#[test]
+fn union_keeps_document_order_and_rejects_atomic_values() {
+    let out = run(
+        r#"xform version "2.0"; <r>{for n in (.//b | .//a | .//b) return name(n)}</r>"#,
+        "<d><a/><b/><a/></d>",
+    );
+    assert_eq!(out, "<r>aba</r>");
+    assert_eq!(
+        run_err(r#"xform version "2.0"; .//a | "x""#, "<d><a/></d>"),
+        "XFDY0018: union operand is not a node"
+    );
+}
+
+#[test]
+fn paths_continue_after_a_function_call() {
+    let input = "<d><s><name>x</name><p><name>y</name></p></s><name>z</name></d>";
+    let out = run(
+        r#"xform version "2.0"; <r>{for n in children(./d/s)/name return string(n)}</r>"#,
+        input,
+    );
+    assert_eq!(out, "<r>y</r>");
+    let out = run(
+        r#"xform version "2.0"; <r>{for n in children(./d/s)//name return string(n)}</r>"#,
+        input,
+    );
+    assert_eq!(out, "<r>xy</r>");
+    let out = run(
+        r#"xform version "2.0"; <r n={count((./d/s, ./d)/name[. != 'x'])}/>"#,
+        input,
+    );
+    assert_eq!(out, r#"<r n="1"/>"#);
+}
+
+const PARAGRAPHS: &str = "<d><p>a</p><table><p>b</p></table><p>a</p><table><p>c</p></table></d>";
+
+#[test]
+fn except_keeps_nodes_by_identity_in_first_operand_order() {
+    let out = run(
+        r#"xform version "2.0"; <r>{for p in (.//p) except (.//table//p) return string(p)}</r>"#,
+        PARAGRAPHS,
+    );
+    assert_eq!(out, "<r>aa</r>");
+    // The two `<p>a</p>` look equal but are different nodes
+    let out = run(
+        r#"xform version "2.0"; <r n={count((.//p) except (.//p[position() = 1]))}/>"#,
+        PARAGRAPHS,
+    );
+    assert_eq!(out, r#"<r n="3"/>"#);
+}
+
+#[test]
+fn intersect_keeps_nodes_in_both_operands() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{for p in (.//table/p) intersect (.//p[. != 'b']) return string(p)}</r>"#,
+        PARAGRAPHS,
+    );
+    assert_eq!(out, "<r>c</r>");
+    let out = run(
+        r#"xform version "2.0";
+        <r n={count(./d/p intersect parse-xml('<d><p>a</p><p>a</p></d>')/d/p)}/>"#,
+        PARAGRAPHS,
+    );
+    assert_eq!(out, r#"<r n="0"/>"#);
+}
+
+#[test]
+fn atomic_values_compare_by_typed_value() {
+    let out = run(
+        r#"xform version "2.0";
+        <r>{string-join((3, 1, "1", 2, 3, 1.0) except (2, "3"), " ")}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, "<r>3 1 1</r>");
+    let out = run(r#"xform version "2.0"; <r>{(1, "1", 2) intersect (1.0)}</r>"#, "<d/>");
+    assert_eq!(out, "<r>1</r>");
+}
+
+const HEADINGS: &str =
+    "<d><h1>a</h1><p>x</p><h2>b</h2><h3>c</h3><list-item>1</list-item><item-no>2</item-no></d>";
+
+#[test]
+fn glob_steps_match_names_by_prefix_or_suffix() {
+    let names = |path: &str| {
+        let transform =
+            r#"xform version "2.0"; <r>{string-join(for n in PATH return name(n), " ")}</r>"#;
+        run(&transform.replace("PATH", path), HEADINGS)
+    };
+    assert_eq!(names("./d/h*"), "<r>h1 h2 h3</r>");
+    assert_eq!(names("./d/*-item"), "<r>list-item</r>");
+    assert_eq!(names("./d/item*"), "<r>item-no</r>");
+    assert_eq!(names("./d/*"), "<r>h1 p h2 h3 list-item item-no</r>");
+}
+
+#[test]
+fn a_star_between_names_is_multiplication() {
+    let input = "<a><item><price>3</price><qty>4</qty></item><b>2</b><c><d>5</d></c></a>";
+    let out = run(r#"xform version "2.0"; <r>{for i in .//item return i/price*i/qty}</r>"#, input);
+    assert_eq!(out, "<r>12</r>");
+    let out = run(r#"xform version "2.0"; <r>{a/b*a/c/d}</r>"#, input);
+    assert_eq!(out, "<r>10</r>");
+    let out = run(r#"xform version "2.0"; <r>{a/item/price*2}</r>"#, input);
+    assert_eq!(out, "<r>6</r>");
+}
+
+#[test]
+fn a_double_slash_after_a_variable_searches_below_its_node() {
+    let out = run(
+        r#"xform version "2.0";
+        let frag := <list><item n={1}/><group><item n={2}/></group></list> in
+        let item := <item n={0}/> in
+        <r all={count(frag//item)} nested={string(frag/group//item/@n)}
+           self={count(item//item)} input={count(.//item)}/>"#,
+        "<d><item/></d>",
+    );
+    assert_eq!(out, r#"<r all="2" nested="2" self="1" input="1"/>"#);
+}
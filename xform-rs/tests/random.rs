@@ -0,0 +1,49 @@
+//! `uuid()` and `randomNumber()`, seeded and unseeded.
+
+mod common;
+
+use common::run_with;
+use xform::EvalOptions;
+
+const DRAWS: &str = r#"xform version "2.0";
+    <r>{for i in (1, 2, 3) return <n id={uuid()} x={randomNumber()}/>}</r>"#;
+
+fn seeded(seed: Option<u64>) -> String {
+    run_with(DRAWS, "<d/>", &EvalOptions { seed, ..Default::default() })
+}
+
+#[test]
+fn the_same_seed_gives_the_same_output() {
+    assert_eq!(seeded(Some(42)), seeded(Some(42)));
+}
+
+#[test]
+fn different_seeds_give_different_output() {
+    assert_ne!(seeded(Some(1)), seeded(Some(2)));
+}
+
+#[test]
+fn unseeded_runs_differ() {
+    assert_ne!(seeded(None), seeded(None));
+}
+
+#[test]
+fn repeated_calls_advance_the_stream() {
+    let out = seeded(Some(7));
+    let ids: Vec<&str> = out.split("id=\"").skip(1).map(|s| &s[..36]).collect();
+    assert_eq!(ids.len(), 3);
+    assert!(ids[0] != ids[1] && ids[1] != ids[2]);
+    for id in ids {
+        assert_eq!(&id[14..15], "4", "{} is not a version 4 UUID", id);
+    }
+}
+
+#[test]
+fn a_seed_argument_gives_a_fixed_number() {
+    let transform = r#"xform version "2.0";
+        <r same={randomNumber("a") = randomNumber("a")} unit={randomNumber("a") < 1}/>"#;
+    assert_eq!(
+        run_with(transform, "<d/>", &EvalOptions::default()),
+        r#"<r same="true" unit="true"/>"#
+    );
+}
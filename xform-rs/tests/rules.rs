@@ -0,0 +1,94 @@
+//! Rules run with `apply` and `transformWith`.
+
+mod common;
+
+use common::{run, run_err};
+
+const BOOK: &str = r#"<?xml version="1.0"?>
+<book lang="en" xml:space="preserve">
+  <title>Rust &amp; XML</title>
+  <chapter n="1"><title>one</title><p>Text with <b>bold</b> &lt;markup&gt;.</p></chapter>
+  <chapter n="2"><p/></chapter>
+</book>"#;
+
+/// A transform whose only rules are `rules`, with an `upper` function.
+fn with_rules(rules: &str) -> String {
+    format!(
+        r#"xform version "2.0";
+        def upper(s) := codepointsToString(
+            for c in stringToCodepoints(s) return (if (c >= 97 and c <= 122) then c - 32 else c));
+        {}
+        transformWith(/, "main")"#,
+        rules
+    )
+}
+
+#[test]
+fn transform_with_copies_everything_no_rule_matches() {
+    let unchanged = run(r#"xform version "2.0"; /"#, BOOK);
+    let rewritten =
+        run(&with_rules(r#"rule main match <title/> := <title>{upper(string(.))}</title>;"#), BOOK);
+    let expected = unchanged.replace("<title>Rust &amp; XML", "<title>RUST &amp; XML");
+    assert_eq!(rewritten, expected.replace("<title>one", "<title>ONE"));
+    // With a rule that matches nothing, the copy is the input as it serializes
+    let copied = run(&with_rules("rule main match <nothing/> := ();"), BOOK);
+    assert_eq!(copied, unchanged);
+}
+
+#[test]
+fn transform_with_uses_the_named_ruleset() {
+    let transform = r#"xform version "2.0";
+        rule main match <p/> := <main/>;
+        rule strip match <b/> := string(.);
+        <r>{transformWith(.//chapter[./@n = '1'], "strip")}</r>"#;
+    assert_eq!(
+        run(transform, BOOK),
+        r#"<r><chapter n="1"><title>one</title><p>Text with bold &lt;markup&gt;.</p></chapter></r>"#
+    );
+}
+
+#[test]
+fn apply_numbers_each_item_and_nested_applies_keep_their_own() {
+    let transform = r#"xform version "2.0";
+        rule main match <li/> :=
+            <item at={position()} of={last()}>{apply(./b, "inner")}{position()}</item>;
+        rule inner match <b/> := <b at={position()} of={last()}/>;
+        <r>{match ./ul/li: case _ => position(); default => ();}{
+            apply(.//li)}</r>"#;
+    let out = run(transform, "<ul><li><b/><b/><b/></li><li/></ul>");
+    let inner = r#"<b at="1" of="3"/><b at="2" of="3"/><b at="3" of="3"/>"#;
+    assert_eq!(
+        out,
+        format!(r#"<r>12<item at="1" of="2">{}1</item><item at="2" of="2">2</item></r>"#, inner)
+    );
+}
+
+#[test]
+fn apply_copies_unmatched_nodes_only_when_asked() {
+    let rules = r#"rule main match <b/> := <strong>{string(.)}</strong>;"#;
+    let input = "<p>a <b>b</b> <i>c <b>d</b></i></p>";
+    let copied = run(
+        &format!(r#"xform version "2.0"; {} <r>{{apply(./p, "main", true())}}</r>"#, rules),
+        input,
+    );
+    assert_eq!(copied, "<r><p>a <strong>b</strong> <i>c <strong>d</strong></i></p></r>");
+    let err = run_err(
+        &format!(r#"xform version "2.0"; {} <r>{{apply(./p)}}</r>"#, rules),
+        input,
+    );
+    assert!(err.starts_with("XFDY0001"), "{}", err);
+}
+
+#[test]
+fn identity_copies_and_applies_the_current_ruleset_to_children() {
+    let transform = r#"xform version "2.0";
+        rule toc match <doc/> := identity(.);
+        rule toc match <title/> := <h>{string(.)}</h>;
+        <r>{apply(./doc, "toc")}</r>"#;
+    let input = "<doc><sec n='1'><title>A</title><p>x</p><sec><title>B</title></sec></sec></doc>";
+    let out = run(transform, input);
+    assert_eq!(
+        out,
+        r#"<r><doc><sec n="1"><h>A</h><p>x</p><sec><h>B</h></sec></sec></doc></r>"#
+    );
+}
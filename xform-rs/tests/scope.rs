@@ -0,0 +1,79 @@
+//! `scope_warnings`, as `xform --check` reports them.
+
+use xform::{scope_warnings, Parser};
+
+fn warnings(transform: &str) -> Vec<String> {
+    scope_warnings(&Parser::new(transform).parse_module().unwrap())
+}
+
+/// The same body under each `strict-variables` setting.
+fn with_strict(strict: &str, body: &str) -> Vec<String> {
+    warnings(&format!(
+        r#"xform version "2.0"; declare option strict-variables "{}"; {}"#,
+        strict, body
+    ))
+}
+
+const CHILD_NAMES: &str = r#"<expensive>{
+  for i in .//item[number(price/text()) > 10] return <name>{ i/name/text() }</name>
+}</expensive>"#;
+
+#[test]
+fn bare_child_names_are_not_reported_when_lenient() {
+    assert_eq!(with_strict("false", CHILD_NAMES), Vec::<String>::new());
+    let undeclared = format!(r#"xform version "2.0"; {}"#, CHILD_NAMES);
+    assert_eq!(warnings(&undeclared), Vec::<String>::new());
+}
+
+#[test]
+fn bare_child_names_are_reported_when_strict() {
+    assert_eq!(
+        with_strict("true", CHILD_NAMES),
+        ["warning: main expression: variable `price` is not bound, which strict-variables \
+          makes an error"]
+    );
+}
+
+#[test]
+fn variables_used_out_of_scope_are_reported_either_way() {
+    let body = r#"<r>{let total := 1 in total}{total}</r>"#;
+    assert_eq!(
+        with_strict("false", body),
+        ["warning: main expression: variable `total` is not in scope here and is read as a \
+          child element name"]
+    );
+    assert_eq!(
+        with_strict("true", body),
+        ["warning: main expression: variable `total` is not bound, which strict-variables \
+          makes an error"]
+    );
+}
+
+#[test]
+fn shadowing_is_reported_either_way() {
+    let body = r#"<r>{let n := 1 in let n := 2 in n}</r>"#;
+    for strict in ["true", "false"] {
+        assert_eq!(
+            with_strict(strict, body),
+            ["warning: main expression: `n` shadows an enclosing binding"]
+        );
+    }
+}
+
+#[test]
+fn unbound_names_read_children_unless_strict() {
+    let body = r#"<r>{string(price)}</r>"#;
+    let input = "<price>12</price>";
+    let run = |strict: &str| {
+        let module = Parser::new(&format!(
+            r#"xform version "2.0"; declare option strict-variables "{}"; {}"#,
+            strict, body
+        ))
+        .parse_module()
+        .unwrap();
+        let doc = xform::parse_xml(input).unwrap();
+        xform::eval_module(&module, doc).and_then(|out| xform::serialize_items(&out))
+    };
+    assert_eq!(run("false").unwrap(), "<r>12</r>");
+    assert!(run("true").unwrap_err().starts_with("XFST0006: variable price is not bound"));
+}
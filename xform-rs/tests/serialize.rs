@@ -0,0 +1,174 @@
+//! Serialization of results: numbers, text escaping and options.
+
+mod common;
+
+use common::run;
+
+fn eval(expr: &str) -> String {
+    run(&format!(r#"xform version "2.0"; {}"#, expr), "<d/>")
+}
+
+#[test]
+fn float_artifacts_print_cleanly() {
+    assert_eq!(eval("0.1 + 0.2"), "0.3");
+    assert_eq!(eval("1.1 * 3"), "3.3");
+    assert_eq!(eval("0.3 - 0.1"), "0.2");
+    assert_eq!(eval("<r n={0.1 + 0.2} m={1 div 3}/>"), r#"<r n="0.3" m="0.333333333333333"/>"#);
+}
+
+#[test]
+fn integers_print_exactly() {
+    assert_eq!(eval("1760000000000001"), "1760000000000001");
+    assert_eq!(eval("9007199254740992"), "9007199254740992");
+    assert_eq!(eval("-42"), "-42");
+    assert_eq!(eval("100000000 * 1000000000000"), "100000000000000000000");
+    assert_eq!(eval("2.5 * 2"), "5");
+}
+
+#[test]
+fn items_without_an_xml_form_fail_to_serialize() {
+    let err = |expr: &str| {
+        common::try_run(&format!(r#"xform version "2.0"; {}"#, expr), "<d/>", &Default::default())
+            .unwrap_err()
+    };
+    assert_eq!(err(r#"map("b", 1, "a", 2)"#), "XFDY0014: cannot serialize a map [map: a, b]");
+    assert_eq!(err("[1, 2]"), "XFDY0014: cannot serialize an array");
+}
+
+/// `expr` serialized with `opts`.
+fn serialized_with(expr: &str, opts: &xform::SerializeOptions) -> Result<String, String> {
+    let module = xform::Parser::new(&format!(r#"xform version "2.0"; {}"#, expr))
+        .parse_module()
+        .unwrap();
+    let items = xform::eval_module(&module, xform::parse_xml("<d/>").unwrap()).unwrap();
+    xform::serialize_items_with(&items, opts)
+}
+
+/// `expr` serialized with maps and functions rendered rather than rejected.
+fn as_json(expr: &str) -> String {
+    let opts = xform::SerializeOptions { maps_as_json: true, ..Default::default() };
+    serialized_with(expr, &opts).unwrap()
+}
+
+#[test]
+fn functions_render_with_their_arity() {
+    assert_eq!(as_json("def add(a, b) := a + b; add"), "function add#2");
+    assert_eq!(as_json("fn(x) := x"), "function fn#1");
+    assert_eq!(
+        as_json(r#"def add(a, b) := a + b; map("f", add)"#),
+        r#"{"f":"function add#2"}"#
+    );
+    assert_eq!(
+        common::run_err(r#"xform version "2.0"; def add(a, b) := a + b; add"#, "<d/>"),
+        "XFDY0014: cannot serialize function add#2"
+    );
+}
+
+#[test]
+fn constructed_attributes_keep_the_order_they_were_written_in() {
+    assert_eq!(eval("<e b={1} c={2} a={3}/>"), r#"<e b="1" c="2" a="3"/>"#);
+    // An attribute set again from the content stays where it was
+    assert_eq!(
+        eval(r#"<e b={1} a={2}>{parse-xml('<x b="3"/>')/x/@b}</e>"#),
+        r#"<e b="3" a="2"/>"#
+    );
+}
+
+#[test]
+fn sorted_attributes_serialize_constructed_and_parsed_elements_alike() {
+    let canonical = xform::SerializeOptions { sort_attributes: true, ..Default::default() };
+    let constructed =
+        serialized_with("<e c={1} b={2} a={3}><f z={1} y={2}/></e>", &canonical).unwrap();
+    let parsed = serialized_with(
+        r#"parse-xml('<e c="1" b="2" a="3"><f z="1" y="2"/></e>')/e"#,
+        &canonical,
+    )
+    .unwrap();
+    assert_eq!(constructed, r#"<e a="3" b="2" c="1"><f y="2" z="1"/></e>"#);
+    assert_eq!(parsed, constructed);
+    assert_eq!(
+        eval(r#"serialize(<e c={1} a={2}/>, map("sortAttributes", true()))"#),
+        r#"<e a="2" c="1"/>"#
+    );
+}
+
+#[test]
+fn control_characters_serialize_as_well_formed_xml() {
+    // U+0085 and U+009F are allowed but discouraged, so they are written as
+    // references; tab, newline and CR survive in text, CR as a reference
+    let out = eval(
+        "<t a={codepointsToString((9, 10, 133))}>{codepointsToString((9, 13, 133, 159))}</t>",
+    );
+    assert_eq!(out, "<t a=\"&#9;&#10;&#133;\">\t&#13;&#133;&#159;</t>");
+    let doc = xform::parse_xml(&out).unwrap();
+    assert_eq!(doc.children[0].attrs[0].1, "\t\n\u{85}");
+    assert_eq!(doc.children[0].string_value(), "\t\r\u{85}\u{9f}");
+}
+
+#[test]
+fn characters_xml_does_not_allow_follow_invalid_chars() {
+    let with = |invalid_chars| {
+        let opts = xform::SerializeOptions { invalid_chars, ..Default::default() };
+        serialized_with("<t>{codepointsToString((97, 1, 98))}</t>", &opts)
+    };
+    assert_eq!(with(xform::InvalidChars::Replace).unwrap(), "<t>a\u{fffd}b</t>");
+    assert_eq!(with(xform::InvalidChars::Strip).unwrap(), "<t>ab</t>");
+    assert!(with(xform::InvalidChars::Error).unwrap_err().starts_with("XFDY0008"));
+}
+
+#[test]
+fn serialize_gives_the_markup_of_a_node_as_a_string() {
+    let out = run(
+        r#"xform version "2.0";
+        let s := serialize(<e a={"1"}>{"x & "}<b/></e>) in
+        <r n={count(stringToCodepoints(s))}>{s}</r>"#,
+        "<d/>",
+    );
+    assert_eq!(out, r#"<r n="25">&lt;e a="1"&gt;x &amp;amp; &lt;b/&gt;&lt;/e&gt;</r>"#);
+}
+
+#[test]
+fn carriage_returns_tabs_and_illegal_characters_round_trip() {
+    let value = |policy: &str| {
+        let options = if policy.is_empty() {
+            String::new()
+        } else {
+            format!(r#", map("invalidChars", "{}")"#, policy)
+        };
+        let transform = format!(
+            r#"xform version "2.0";
+            serialize(<t a={{codepointsToString((97, 13, 9, 7, 98))}}>{{
+                codepointsToString((99, 13, 9, 7, 100))}}</t>{})"#,
+            options
+        );
+        let out = run(&transform, "<d/>");
+        let doc = xform::parse_xml(&out).unwrap_or_else(|e| panic!("{}: {}", out, e));
+        let t = &doc.children[0];
+        (t.attrs[0].1.clone(), t.string_value())
+    };
+    let replaced = ("a\r\t\u{fffd}b".to_string(), "c\r\t\u{fffd}d".to_string());
+    assert_eq!(value(""), replaced);
+    assert_eq!(value("replace"), replaced);
+    assert_eq!(value("strip"), ("a\r\tb".to_string(), "c\r\td".to_string()));
+    let err = common::run_err(
+        r#"xform version "2.0";
+        serialize(<t>{codepointsToString((99, 7))}</t>, map("invalidChars", "error"))"#,
+        "<d/>",
+    );
+    assert!(err.starts_with("XFDY0008"), "{}", err);
+}
+
+#[test]
+fn serialized_markup_in_a_text_node_is_escaped_once() {
+    assert_eq!(
+        eval(r#"<doc>{serialize(<e a={"x & y"}>{"1 < 2"}</e>)}</doc>"#),
+        r#"<doc>&lt;e a="x &amp;amp; y"&gt;1 &amp;lt; 2&lt;/e&gt;</doc>"#
+    );
+    assert_eq!(
+        eval(r#"<doc>{pretty(<e><f/><g/></e>)}</doc>"#),
+        "<doc>&lt;e&gt;\n  &lt;f/&gt;\n  &lt;g/&gt;\n&lt;/e&gt;</doc>"
+    );
+    let err =
+        common::run_err(r#"xform version "2.0"; serialize(<e/>, map("indnet", 2))"#, "<d/>");
+    assert_eq!(err, r#"XFDY0002: unknown serialization option "indnet""#);
+}
@@ -0,0 +1,65 @@
+//! Plain text resources read with `unparsed-text` and `unparsed-text-lines`.
+
+mod common;
+
+use std::path::PathBuf;
+
+use common::{run_with, try_run};
+use xform::{AccessPolicy, EvalOptions};
+
+fn data_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data")
+}
+
+/// Options reading files of `tests/data`, relative to it.
+fn readable() -> EvalOptions {
+    EvalOptions {
+        access: AccessPolicy::AllowUnder(data_dir()),
+        base_dir: Some(data_dir()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn lines_end_at_lf_or_crlf() {
+    let out = run_with(
+        r#"xform version "2.0";
+        <lines>{for l in unparsed-text-lines("mixed-endings.txt") return <l>{l}</l>}</lines>"#,
+        "<d/>",
+        &readable(),
+    );
+    // The blank line stays; the final line ending adds none
+    assert_eq!(out, "<lines><l>sku,qty</l><l>A-1,3</l><l>B-2,5</l><l/><l>C-3,7</l></lines>");
+}
+
+#[test]
+fn lines_split_into_fields() {
+    let out = run_with(
+        r#"xform version "2.0";
+        let rows := tail(unparsed-text-lines("mixed-endings.txt")) in
+        <total>{sum(for r in rows where r != '' return number(nth(tokenize(r, ","), 2)))}</total>"#,
+        "<d/>",
+        &readable(),
+    );
+    assert_eq!(out, "<total>15</total>");
+}
+
+#[test]
+fn the_text_keeps_its_line_endings() {
+    let out = run_with(
+        r#"xform version "2.0"; count(tokenize(unparsed-text("mixed-endings.txt"), "\r"))"#,
+        "<d/>",
+        &readable(),
+    );
+    assert_eq!(out, "4");
+}
+
+#[test]
+fn reading_needs_access() {
+    let err = try_run(
+        r#"xform version "2.0"; unparsed-text-lines("mixed-endings.txt")"#,
+        "<d/>",
+        &EvalOptions { base_dir: Some(data_dir()), ..Default::default() },
+    );
+    assert_eq!(err.unwrap_err(), "XFSE0001: access denied to mixed-endings.txt");
+}
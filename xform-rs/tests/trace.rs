@@ -0,0 +1,58 @@
+//! The events a `TraceSink` hears of during a run.
+
+mod common;
+
+use std::rc::Rc;
+
+use common::{run_with, try_run, Recorder};
+use xform::{EvalOptions, TraceSink, WriteSink};
+
+const SECTIONS: &str = "<doc><sec>a</sec><sec>b</sec></doc>";
+
+const TITLES: &str = r#"xform version "2.0";
+    rule main match <sec/> := <h n={position()}>{string(.)}</h>;
+    <toc>{apply(.//sec)}</toc>"#;
+
+fn recorded(transform: &str, input: &str) -> (Result<String, String>, Vec<String>) {
+    let recorder = Rc::new(Recorder::default());
+    let opts = EvalOptions { trace: Some(recorder.clone()), ..Default::default() };
+    let result = try_run(transform, input, &opts);
+    let events = recorder.events.borrow().clone();
+    (result, events)
+}
+
+#[test]
+fn events_come_in_evaluation_order() {
+    let (out, events) = recorded(TITLES, SECTIONS);
+    assert_eq!(out.unwrap(), "<toc><h n=\"1\">a</h><h n=\"2\">b</h></toc>");
+    assert_eq!(events, [
+        "call apply/1",
+        "rule main <sec/> at /doc[1]/sec[1]",
+        "call position/0",
+        "call string/1",
+        r#"construct <h n="1">"#,
+        "rule main <sec/> at /doc[1]/sec[2]",
+        "call position/0",
+        "call string/1",
+        r#"construct <h n="2">"#,
+        "construct <toc>",
+    ]);
+}
+
+#[test]
+fn the_error_a_run_ends_with_is_reported_once() {
+    let (out, events) = recorded(r#"xform version "2.0"; <r>{number("x") + nosuch()}</r>"#, "<d/>");
+    let err = out.unwrap_err();
+    assert_eq!(events.last(), Some(&format!("error {}", err)));
+    assert_eq!(events.iter().filter(|e| e.starts_with("error")).count(), 1);
+}
+
+#[test]
+fn write_sink_prints_one_line_per_event() {
+    let sink = Rc::new(WriteSink::new(Vec::new()));
+    let opts = EvalOptions { trace: Some(sink.clone() as Rc<dyn TraceSink>), ..Default::default() };
+    run_with(r#"xform version "2.0"; <r>{count(.//sec)}</r>"#, SECTIONS, &opts);
+    drop(opts);
+    let text = String::from_utf8(Rc::try_unwrap(sink).ok().unwrap().into_inner()).unwrap();
+    assert_eq!(text, "trace: call count/1\ntrace: construct <r>\n");
+}
@@ -0,0 +1,40 @@
+//! `document-uri()` and `resolve-uri()`.
+
+mod common;
+
+use common::{run, run_with};
+use xform::EvalOptions;
+
+fn from_path(transform: &str) -> String {
+    let opts =
+        EvalOptions { document_uri: Some("books/2024/input.xml".into()), ..Default::default() };
+    run_with(&format!(r#"xform version "2.0"; {}"#, transform), "<d/>", &opts)
+}
+
+#[test]
+fn document_uri_is_where_the_input_was_read_from() {
+    assert_eq!(from_path("document-uri()"), "books/2024/input.xml");
+    assert_eq!(from_path("document-uri(/)"), "books/2024/input.xml");
+    assert_eq!(from_path("<r n={count(document-uri(parse-xml('<x/>')))}/>"), r#"<r n="0"/>"#);
+    let parsed = run(r#"xform version "2.0"; <r n={count(document-uri())}/>"#, "<d/>");
+    assert_eq!(parsed, r#"<r n="0"/>"#);
+}
+
+#[test]
+fn relative_uris_resolve_against_the_input_by_default() {
+    assert_eq!(from_path(r#"resolve-uri("figs/a.png")"#), "books/2024/figs/a.png");
+    assert_eq!(from_path(r#"resolve-uri("../2023/index.xml")"#), "books/2023/index.xml");
+    assert_eq!(from_path(r##"resolve-uri("#top")"##), "books/2024/input.xml#top");
+}
+
+#[test]
+fn relative_uris_resolve_against_a_given_base() {
+    let resolve = |rel: &str, base: &str| {
+        run(&format!(r#"xform version "2.0"; resolve-uri("{}", "{}")"#, rel, base), "<d/>")
+    };
+    assert_eq!(resolve("c/d.xml", "http://example.com/a/b.xml"), "http://example.com/a/c/d.xml");
+    assert_eq!(resolve("/x.xml", "http://example.com/a/b.xml"), "http://example.com/x.xml");
+    assert_eq!(resolve("../../x", "/srv/a/b/c.xml"), "/srv/x");
+    assert_eq!(resolve("mailto:a@b.org", "/srv/a.xml"), "mailto:a@b.org");
+    assert_eq!(resolve("a.xml", ""), "a.xml");
+}
@@ -0,0 +1,38 @@
+//! Unbound variables: read as child element names, or errors under
+//! strict variables.
+
+mod common;
+
+use common::try_run;
+use xform::EvalOptions;
+
+/// Binds `title` but reads `titel`.
+const MISSPELLED: &str = r#"let title := string(.//title) in <h>{string(titel)}</h>"#;
+
+const INPUT: &str = "<titel>typo</titel>";
+
+fn strict(strict_variables: bool) -> EvalOptions {
+    EvalOptions { strict_variables, ..Default::default() }
+}
+
+#[test]
+fn a_misspelled_variable_reads_children_unless_strict() {
+    let transform = format!(r#"xform version "2.0"; {}"#, MISSPELLED);
+    assert_eq!(try_run(&transform, INPUT, &strict(false)).unwrap(), "<h>typo</h>");
+    assert_eq!(
+        try_run(&transform, INPUT, &strict(true)).unwrap_err(),
+        "XFST0006: variable titel is not bound (strict-variables)"
+    );
+}
+
+#[test]
+fn the_module_option_overrides_eval_options() {
+    let transform = |on: &str| {
+        format!(
+            r#"xform version "2.0"; declare option strict-variables "{}"; {}"#,
+            on, MISSPELLED
+        )
+    };
+    assert!(try_run(&transform("true"), INPUT, &strict(false)).is_err());
+    assert_eq!(try_run(&transform("false"), INPUT, &strict(true)).unwrap(), "<h>typo</h>");
+}
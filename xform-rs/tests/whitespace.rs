@@ -0,0 +1,69 @@
+//! `xml:space` scoping and whitespace stripping of parsed input.
+
+mod common;
+
+use common::{run, run_with};
+use xform::{parse_xml, serialize, EvalOptions};
+
+#[test]
+fn xml_lang_reads_by_its_local_name_as_before() {
+    let out = run(
+        r#"xform version "2.0"; <r>{string(.//p/@lang)}</r>"#,
+        r#"<d><p xml:lang="de">Hallo</p></d>"#,
+    );
+    assert_eq!(out, "<r>de</r>");
+}
+
+#[test]
+fn xml_space_keeps_its_prefix_and_a_plain_space_attribute_is_not_it() {
+    let doc = parse_xml(r#"<d><pre xml:space="preserve"> a </pre><s space="preserve"> b </s></d>"#);
+    assert_eq!(
+        serialize(&doc.unwrap()),
+        r#"<d><pre xml:space="preserve"> a </pre><s space="preserve"> b </s></d>"#
+    );
+    let out = run(
+        r#"xform version "2.0"; <r a={preserveSpace(.//pre)} b={preserveSpace(.//s)}/>"#,
+        r#"<d><pre xml:space="preserve">x</pre><s space="preserve">y</s></d>"#,
+    );
+    assert_eq!(out, r#"<r a="true" b="false"/>"#);
+}
+
+#[test]
+fn stripping_leaves_preserved_code_alone() {
+    let input = "<topic>\n  <title>T</title>\n  <codeblock xml:space=\"preserve\">\n  \
+                 <b>if</b> x:\n      y\n</codeblock>\n</topic>";
+    let opts = EvalOptions { strip_whitespace: true, ..Default::default() };
+    let out = run_with(r#"xform version "2.0"; copy(.)"#, input, &opts);
+    assert_eq!(
+        out,
+        "<topic><title>T</title><codeblock xml:space=\"preserve\">\n  <b>if</b> x:\n      y\n\
+         </codeblock></topic>"
+    );
+}
+
+#[test]
+fn stripping_keeps_spaces_in_mixed_content() {
+    let input = "<doc>\n  <p>Hello <b>world</b>!</p>\n  <p><b>a</b> <i>b</i></p>\n</doc>";
+    let opts = EvalOptions { strip_whitespace: true, ..Default::default() };
+    let out = run_with(r#"xform version "2.0"; copy(.)"#, input, &opts);
+    // Only whitespace-only text between elements with no other text goes
+    assert_eq!(out, "<doc><p>Hello <b>world</b>!</p><p><b>a</b><i>b</i></p></doc>");
+}
+
+#[test]
+fn strip_space_and_normalize_text_nodes_never_glue_words() {
+    let input = "<doc>\n  <p>Hello <b>big</b> <i>world</i>\n</p>\n  <pre>  a  </pre>\n  \
+                 <q xml:space=\"preserve\"> <b/> </q>\n</doc>";
+    let out = run(r#"xform version "2.0"; stripSpace(./doc, "pre")"#, input);
+    assert_eq!(
+        out,
+        "<doc><p>Hello <b>big</b> <i>world</i>\n</p><pre>  a  </pre>\
+         <q xml:space=\"preserve\"> <b/> </q></doc>"
+    );
+    let out = run(
+        r#"xform version "2.0";
+        normalizeTextNodes(build-element("x", map(), (text(./doc/p), "  and   ", <b/>, "c")))"#,
+        input,
+    );
+    assert_eq!(out, "<x>Hello big world and <b/>c</x>");
+}